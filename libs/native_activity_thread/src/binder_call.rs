@@ -0,0 +1,128 @@
+//
+// Copyright (C) 2025 The Android Open-Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Binder calls which the caller stops waiting for after a timeout.
+
+use anyhow::{Context, Result};
+use log::error;
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use crate::service_error::ServiceError;
+
+/// A call run by `BinderCallWorker`.
+type Call = Box<dyn FnOnce() + Send>;
+
+/// A thread making binder calls on behalf of the looper thread, so that a binder call taking too
+/// long doesn't block the looper. The calls are made in the order they are submitted, and a call
+/// which timed out still completes in the background, before the next call is made.
+pub struct BinderCallWorker {
+    calls: mpsc::Sender<Call>,
+    timeout: Duration,
+}
+
+impl BinderCallWorker {
+    /// Starts a worker whose callers wait at most `timeout` for each call.
+    pub fn new(timeout: Duration) -> Result<Self> {
+        let (calls, call_receiver) = mpsc::channel::<Call>();
+        thread::Builder::new()
+            .name("binder_call_worker".to_string())
+            .spawn(move || {
+                // Exits once the worker is dropped.
+                for call in call_receiver {
+                    call();
+                }
+            })
+            .context("Failed to spawn the binder call worker thread")?;
+        Ok(Self { calls, timeout })
+    }
+
+    /// Makes the binder call `call` to `method` on the worker thread, and waits for its result
+    /// for the timeout of the worker. The failure of a call which timed out is only logged.
+    pub fn call<T: Send + 'static>(
+        &self,
+        method: &'static str,
+        call: impl FnOnce() -> binder::Result<T> + Send + 'static,
+    ) -> Result<T, ServiceError> {
+        let (result_sender, result_receiver) = mpsc::channel();
+        let job = move || {
+            let result = call();
+            if let Err(mpsc::SendError(Err(status))) = result_sender.send(result) {
+                error!("{} failed after timing out: {}", method, status);
+            }
+        };
+        if self.calls.send(Box::new(job)).is_err() {
+            // The worker thread panicked in a call.
+            return Err(ServiceError::binder_call(method)(
+                binder::StatusCode::UNKNOWN_ERROR.into(),
+            ));
+        }
+        match result_receiver.recv_timeout(self.timeout) {
+            Ok(result) => result.map_err(ServiceError::binder_call(method)),
+            Err(RecvTimeoutError::Timeout) => {
+                Err(ServiceError::BinderTimeout { method, timeout: self.timeout })
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(ServiceError::binder_call(method)(binder::StatusCode::UNKNOWN_ERROR.into()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_returns_the_result() {
+        let worker = BinderCallWorker::new(Duration::from_secs(5)).unwrap();
+
+        assert_eq!(worker.call("publishService", || Ok(42)).unwrap(), 42);
+        let err = worker
+            .call("publishService", || -> binder::Result<()> {
+                Err(binder::StatusCode::DEAD_OBJECT.into())
+            })
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::BinderCall { method: "publishService", .. }));
+    }
+
+    #[test]
+    fn slow_call_times_out_and_later_calls_run_in_order() {
+        let worker = BinderCallWorker::new(Duration::from_millis(50)).unwrap();
+        let (order_sender, order_receiver) = mpsc::channel();
+        let slow_order_sender = order_sender.clone();
+
+        let err = worker
+            .call("serviceDoneExecuting", move || {
+                thread::sleep(Duration::from_millis(200));
+                slow_order_sender.send("slow").unwrap();
+                Ok(())
+            })
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::BinderTimeout { method: "serviceDoneExecuting", .. }));
+
+        // The next call waits for the slow call to complete first.
+        let result = worker.call("serviceDoneExecuting", move || {
+            order_sender.send("next").unwrap();
+            Ok(())
+        });
+        assert!(matches!(result, Err(ServiceError::BinderTimeout { .. })));
+        let order: Vec<_> = order_receiver.iter().take(2).collect();
+        assert_eq!(order, ["slow", "next"]);
+    }
+}
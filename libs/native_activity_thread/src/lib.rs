@@ -16,22 +16,49 @@
 //! The crate providing the functionality to manage the native application process.
 
 use activitymanager_structured_aidl::aidl::android::app::IActivityManagerStructured::IActivityManagerStructured;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use binder::{BinderFeatures, ProcessState, Strong};
-use log::{info, LevelFilter};
+use log::{error, info, warn, LevelFilter};
 use native_application_thread_aidl::aidl::android::app::INativeApplicationThread::BnNativeApplicationThread;
+use std::{num::NonZeroUsize, thread, time::Duration};
 
+mod binder_call;
 mod library_loader;
 mod native_activity_thread;
 mod native_application_thread;
-mod task;
+mod panic_hook;
+mod process_state;
+mod service_error;
+pub mod task;
 
 use crate::native_activity_thread::NativeActivityThread;
 use crate::native_application_thread::NativeApplicationThread;
-use crate::task::{run_thread_loop, Handler};
+use crate::task::{run_thread_loop, ErrorStrategy, Handler};
+
+pub use crate::native_activity_thread::LibraryValidator;
 
 static ACTIVITY_MANAGER_SERVICE_NAME: &str = "activity_structured";
 
+/// The number of attempts to get the ActivityManager at startup. In early boot, the process may
+/// start before the ActivityManager is registered.
+const ACTIVITY_MANAGER_ATTEMPTS: u32 = 20;
+
+/// The time between two attempts to get the ActivityManager.
+const ACTIVITY_MANAGER_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The maximum number of requests handled per looper wakeup. The looper thread also services
+/// binder transactions, so a burst of requests must not starve them.
+const HANDLER_TASK_BUDGET: NonZeroUsize = NonZeroUsize::new(16).unwrap();
+
+/// The default number of threads loading the libraries of the services, so that an application
+/// creating several services at once doesn't have its libraries loaded one after the other on the
+/// looper thread.
+pub const DEFAULT_LIBRARY_LOADER_THREADS: NonZeroUsize = NonZeroUsize::new(2).unwrap();
+
+/// The default time the looper thread waits for the calls to the ActivityManager, so that a
+/// momentarily unresponsive ActivityManager doesn't block the handling of the requests.
+pub const DEFAULT_BINDER_CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Start NativeActivityThread to manage the process.
 pub fn run_native_activity_thread(start_seq: i64) -> ! {
     logger::init(
@@ -39,35 +66,225 @@ pub fn run_native_activity_thread(start_seq: i64) -> ! {
             .with_tag_on_device("native_activity_thread")
             .with_max_level(LevelFilter::Trace),
     );
+    // Log the panics to logcat before the process is aborted.
+    panic_hook::install();
     info!("Hello from the native activity thread! start_seq={start_seq}");
 
     // This must be done before creating any Binder client or server.
     ProcessState::start_thread_pool();
 
     let activity_manager = get_activity_manager_proxy().unwrap();
+    let exit = run_native_activity_thread_inner(
+        activity_manager,
+        start_seq,
+        Some(DEFAULT_LIBRARY_LOADER_THREADS),
+        Some(DEFAULT_BINDER_CALL_TIMEOUT),
+        None,
+        None,
+    )
+    .unwrap();
+    match exit {
+        ThreadExit::Broken => {
+            // The ActivityManager notices the death of the process.
+            error!("The handler of the native activity thread is broken. Exiting.");
+            std::process::exit(1);
+        }
+        ThreadExit::Shutdown => {
+            info!("The native activity thread is shut down. Exiting.");
+            std::process::exit(0);
+        }
+    }
+}
+
+/// How `run_native_activity_thread_inner` stopped running NativeActivityThread.
+#[derive(Debug, PartialEq)]
+pub enum ThreadExit {
+    /// All the services were destroyed by a shutdown request.
+    Shutdown,
+    /// The handler was deactivated after failing to handle a request.
+    Broken,
+}
 
+/// Runs NativeActivityThread on the current thread, talking to `activity_manager`, until the
+/// process is shut down or a request can't be handled. Returns an error if the setup failed. The
+/// Binder thread pool must be started beforehand.
+///
+/// The libraries of the services are loaded on `library_loader_threads` threads, or on the
+/// current thread if None. The looper thread waits at most `binder_call_timeout` for the calls
+/// to the ActivityManager, or until they return if None. At most `max_services` services are
+/// hosted at once, or the default maximum if None. The libraries of the services are checked
+/// with `library_validator` before they are loaded, or all accepted if None.
+pub fn run_native_activity_thread_inner(
+    activity_manager: Strong<dyn IActivityManagerStructured>,
+    start_seq: i64,
+    library_loader_threads: Option<NonZeroUsize>,
+    binder_call_timeout: Option<Duration>,
+    max_services: Option<usize>,
+    library_validator: Option<LibraryValidator>,
+) -> Result<ThreadExit> {
     // Prepare the handler of INativeApplicationThread requests from the ActivityManager
-    let handler = Handler::new_on_current_thread(NativeActivityThread::new(
+    let mut handler = Handler::new_on_current_thread(NativeActivityThread::new(
         activity_manager.clone(),
         start_seq,
     ))
-    .unwrap();
+    .context("Failed to create the handler")?;
+    handler.set_task_budget(HANDLER_TASK_BUDGET);
+    // Crash on the spot in debug builds, exit after logging the error in release builds.
+    handler.set_error_strategy(if cfg!(debug_assertions) {
+        ErrorStrategy::Panic
+    } else {
+        ErrorStrategy::Deactivate
+    });
 
-    let sender = handler.get_sender().unwrap();
+    if let Some(threads) = library_loader_threads {
+        let loader_sender =
+            handler.get_sender().context("Failed to get the sender of the handler")?;
+        handler
+            .callback_mut()
+            .enable_async_library_loading(loader_sender, threads)
+            .context("Failed to start the library loader threads")?;
+    }
+    if let Some(timeout) = binder_call_timeout {
+        handler
+            .callback_mut()
+            .set_binder_call_timeout(timeout)
+            .context("Failed to start the binder call worker")?;
+    }
+    if let Some(max_services) = max_services {
+        handler.callback_mut().set_max_services(max_services);
+    }
+    if let Some(library_validator) = library_validator {
+        handler.callback_mut().set_library_validator(library_validator);
+    }
+
+    let sender = handler.get_sender().context("Failed to get the sender of the handler")?;
+    let queued_creates = handler.callback_mut().queued_creates();
     let binder_node = BnNativeApplicationThread::new_binder(
-        NativeApplicationThread::new(sender),
+        NativeApplicationThread::new(sender, queued_creates),
         BinderFeatures::default(),
     );
 
     // Notify the ActivityManager that this process is ready to be used for application.
-    activity_manager.attachNativeApplication(&binder_node.as_binder(), start_seq).unwrap();
+    activity_manager
+        .attachNativeApplication(&binder_node.as_binder(), start_seq)
+        .context("Failed to attach to the ActivityManager")?;
 
     // Start the main thread loop.
-    run_thread_loop().unwrap();
+    run_thread_loop(&handler)?;
 
-    panic!("Shouldn't come here!");
+    if handler.is_broken() {
+        return Ok(ThreadExit::Broken);
+    }
+    Ok(ThreadExit::Shutdown)
 }
 
 fn get_activity_manager_proxy() -> Result<Strong<dyn IActivityManagerStructured>> {
-    binder::check_interface(ACTIVITY_MANAGER_SERVICE_NAME).context("Failed to find ActivityManager")
+    get_interface_with_retry(
+        ACTIVITY_MANAGER_SERVICE_NAME,
+        ACTIVITY_MANAGER_ATTEMPTS,
+        ACTIVITY_MANAGER_RETRY_INTERVAL,
+        binder::check_interface,
+    )
+    .context("Failed to find ActivityManager")
+}
+
+/// Gets the service `name` through `get_interface`, making up to `attempts` attempts
+/// `retry_interval` apart while the service isn't registered yet. Other failures, e.g. a denied
+/// permission, won't go away by waiting and are returned right away.
+fn get_interface_with_retry<T>(
+    name: &str,
+    attempts: u32,
+    retry_interval: Duration,
+    get_interface: impl Fn(&str) -> Result<T, binder::StatusCode>,
+) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match get_interface(name) {
+            Ok(interface) => {
+                if attempt > 1 {
+                    info!("Got {name} after {attempt} attempts");
+                }
+                return Ok(interface);
+            }
+            Err(
+                status @ (binder::StatusCode::NAME_NOT_FOUND | binder::StatusCode::DEAD_OBJECT),
+            ) if attempt < attempts => {
+                warn!(
+                    "{name} isn't available yet ({status}), retrying in {retry_interval:?} \
+                    (attempt {attempt}/{attempts})"
+                );
+            }
+            Err(status) => bail!("Failed to get {name} after {attempt} attempts: {status}"),
+        }
+        thread::sleep(retry_interval);
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::native_activity_thread::tests::MockActivityManager;
+    use activitymanager_structured_aidl::aidl::android::app::IActivityManagerStructured::BnActivityManagerStructured;
+
+    #[test]
+    fn interface_is_retried_until_registered() {
+        let calls = std::cell::Cell::new(0);
+        let get_interface = |_name: &str| {
+            calls.set(calls.get() + 1);
+            match calls.get() {
+                1..=3 => Err(binder::StatusCode::NAME_NOT_FOUND),
+                _ => Ok(42),
+            }
+        };
+
+        let interface = get_interface_with_retry("test", 5, Duration::ZERO, get_interface);
+
+        assert_eq!(interface.unwrap(), 42);
+        assert_eq!(calls.get(), 4);
+    }
+
+    #[test]
+    fn interface_retries_are_bounded() {
+        let calls = std::cell::Cell::new(0);
+        let get_interface = |_name: &str| -> Result<(), _> {
+            calls.set(calls.get() + 1);
+            Err(binder::StatusCode::NAME_NOT_FOUND)
+        };
+
+        let err = get_interface_with_retry("test", 3, Duration::ZERO, get_interface).unwrap_err();
+
+        assert!(format!("{err:#}").contains("after 3 attempts"), "unexpected error: {err:#}");
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn denied_interface_is_not_retried() {
+        let calls = std::cell::Cell::new(0);
+        let get_interface = |_name: &str| -> Result<(), _> {
+            calls.set(calls.get() + 1);
+            Err(binder::StatusCode::PERMISSION_DENIED)
+        };
+
+        let err = get_interface_with_retry("test", 3, Duration::ZERO, get_interface).unwrap_err();
+
+        assert!(format!("{err:#}").contains("PERMISSION_DENIED"), "unexpected error: {err:#}");
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn attach_failure_is_returned() {
+        let activity_manager = BnActivityManagerStructured::new_binder(
+            MockActivityManager {
+                attach_error: Some(binder::StatusCode::DEAD_OBJECT),
+                ..MockActivityManager::default()
+            },
+            BinderFeatures::default(),
+        );
+
+        let err = run_native_activity_thread_inner(activity_manager, 1, None, None, None, None)
+            .unwrap_err();
+
+        assert!(format!("{err:#}").contains("Failed to attach"), "unexpected error: {err:#}");
+    }
 }
@@ -13,14 +13,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use dlext_bindgen::{
     android_create_namespace, android_dlextinfo, android_dlopen_ext, android_namespace_t, dlclose,
     dlsym, ANDROID_DLEXT_USE_NAMESPACE, ANDROID_NAMESPACE_TYPE_SHARED_ISOLATED, RTLD_LOCAL,
 };
+use log::{error, info, warn};
+use native_service_bindgen::ANativeService_createFunc;
 use std::{
-    ffi::{c_void, CString},
+    collections::HashMap,
+    ffi::{c_void, CStr, CString},
+    num::NonZeroUsize,
     ptr::NonNull,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
 };
 
 macro_rules! bail_with_dlerror {
@@ -45,64 +54,247 @@ macro_rules! bail_with_dlerror {
     };
 }
 
+/// The search paths of a namespace and of its ancestors, and its permitted directory. The idle
+/// namespaces with the same key are interchangeable.
+type NamespaceKey = (Vec<Vec<String>>, String);
+
+/// The idle namespaces of a factory, by key. They are never freed, as the linker has no API to
+/// destroy a namespace.
+type NamespacePool = Arc<Mutex<HashMap<NamespaceKey, Vec<LinkerNamespace>>>>;
+
 /// Safe wrapper of a raw pointer to android_namespace_t.
+///
+/// The search path of a namespace can't be changed once it is created, as the linker has no API
+/// to update it. Use `NamespaceFactory::create_child_namespace` to search additional paths.
+///
+/// The linker can't destroy a namespace either. Instead, a namespace is handed back to its
+/// factory when dropped, and reused for the next namespace with the same search paths. It is
+/// only reused if the libraries loaded in it with `LoadedLibrary::new` are all unloaded, so that
+/// they aren't shared with its next user. Otherwise, it is leaked.
 pub struct LinkerNamespace {
     namespace: NonNull<android_namespace_t>,
+    library_paths: Vec<String>,
+    permitted_libs_dir: String,
+    /// The namespace whose libraries this one shares, kept until this one is reused or leaked.
+    parent: Option<Box<LinkerNamespace>>,
+    /// The names of the libraries loaded in the namespace.
+    loaded_libraries: Vec<CString>,
+    /// Where the namespace goes once dropped, or None if it isn't reused.
+    pool: Option<NamespacePool>,
 }
 
+// SAFETY: Linker namespaces are process-wide, they can be used from any thread.
+unsafe impl Send for LinkerNamespace {}
+
 impl LinkerNamespace {
     pub fn as_ptr(&self) -> *mut android_namespace_t {
         self.namespace.as_ptr()
     }
+
+    /// Returns the paths libraries are searched in.
+    pub fn library_paths(&self) -> &[String] {
+        &self.library_paths
+    }
+
+    fn key(&self) -> NamespaceKey {
+        let mut paths = vec![self.library_paths.clone()];
+        let mut ancestor = self.parent.as_deref();
+        while let Some(namespace) = ancestor {
+            paths.push(namespace.library_paths.clone());
+            ancestor = namespace.parent.as_deref();
+        }
+        (paths, self.permitted_libs_dir.clone())
+    }
+
+    /// Returns the extended info making `android_dlopen_ext` look up libraries in the namespace.
+    fn dlextinfo(&self) -> android_dlextinfo {
+        android_dlextinfo {
+            flags: ANDROID_DLEXT_USE_NAMESPACE as u64,
+            reserved_addr: std::ptr::null_mut(),
+            reserved_size: 0,
+            relro_fd: 0,
+            library_fd: 0,
+            library_fd_offset: 0,
+            library_namespace: self.as_ptr(),
+        }
+    }
+
+    /// Returns whether `library` is still loaded in the namespace.
+    fn is_loaded(&self, library: &CStr) -> bool {
+        let dlextinfo = self.dlextinfo();
+        // SAFETY: `library` and `dlextinfo` are valid pointers. With RTLD_NOLOAD, the library is
+        // only looked up, so no initialization routine runs.
+        let library_handle = unsafe {
+            android_dlopen_ext(
+                library.as_ptr(),
+                RTLD_LOCAL as i32 | libc::RTLD_NOLOAD,
+                &dlextinfo as *const android_dlextinfo,
+            )
+        };
+        if library_handle.is_null() {
+            return false;
+        }
+        // SAFETY: The handle was just opened, closing it only drops the reference it took.
+        unsafe { dlclose(library_handle) };
+        true
+    }
+
+    /// Keeps the namespace and its ancestors from being reused.
+    fn leak(&mut self) {
+        self.pool = None;
+        if let Some(parent) = &mut self.parent {
+            parent.leak();
+        }
+    }
+}
+
+impl Drop for LinkerNamespace {
+    fn drop(&mut self) {
+        let Some(pool) = self.pool.take() else {
+            return;
+        };
+        if let Some(library) = self.loaded_libraries.iter().find(|library| self.is_loaded(library))
+        {
+            warn!("Leaking a linker namespace, as {:?} is still loaded in it", library);
+            self.leak();
+            return;
+        }
+        let key = self.key();
+        let idle_namespace = LinkerNamespace {
+            namespace: self.namespace,
+            library_paths: std::mem::take(&mut self.library_paths),
+            permitted_libs_dir: std::mem::take(&mut self.permitted_libs_dir),
+            parent: self.parent.take(),
+            loaded_libraries: Vec::new(),
+            pool: None,
+        };
+        pool.lock().unwrap().entry(key).or_default().push(idle_namespace);
+    }
+}
+
+#[cfg(test)]
+impl LinkerNamespace {
+    /// Creates a placeholder namespace for services hosted in tests. It must not be used to load
+    /// libraries.
+    pub fn for_test() -> Self {
+        Self {
+            namespace: NonNull::dangling(),
+            library_paths: Vec::new(),
+            permitted_libs_dir: String::new(),
+            parent: None,
+            loaded_libraries: Vec::new(),
+            pool: None,
+        }
+    }
 }
 
-/// NamespaceFactory creates linker namespaces.
+/// NamespaceFactory creates linker namespaces. It can be shared by threads creating namespaces
+/// concurrently.
+///
+/// As namespaces can't be destroyed, the factory reuses the namespaces dropped by the destroyed
+/// services, so that creating and destroying services doesn't leak namespaces.
 pub struct NamespaceFactory {
     base_name: String,
     // Used to assign a serial number to each namespace name to make it unique.
-    serial: u32,
+    serial: AtomicU32,
+    idle_namespaces: NamespacePool,
 }
 
 impl NamespaceFactory {
     pub fn new(base_name: String) -> Self {
-        Self { base_name, serial: 0 }
+        Self { base_name, serial: AtomicU32::new(0), idle_namespaces: Default::default() }
+    }
+
+    /// Returns the number of namespaces created by the factory, excluding the reused ones. The
+    /// namespaces are never destroyed.
+    pub fn created_namespace_count(&self) -> u32 {
+        self.serial.load(Ordering::Relaxed)
     }
 
-    /// Create a linker namespace.
+    /// Create a linker namespace, or reuse an idle one searching the same paths.
     pub fn create_linker_namespace(
-        &mut self,
+        &self,
         library_paths: &[String],
         permitted_libs_dir: &str,
     ) -> Result<LinkerNamespace> {
-        let name = CString::new(format!("{}-{}", self.base_name, self.serial))
+        let key = (vec![library_paths.to_vec()], permitted_libs_dir.to_string());
+        match self.take_idle_namespace(&key) {
+            Some(namespace) => Ok(namespace),
+            None => self.create_namespace(library_paths.to_vec(), permitted_libs_dir, None),
+        }
+    }
+
+    /// Takes an idle namespace with the given key, handing it back to the factory once dropped.
+    fn take_idle_namespace(&self, key: &NamespaceKey) -> Option<LinkerNamespace> {
+        let mut namespace =
+            self.idle_namespaces.lock().unwrap().get_mut(key).and_then(|idle| idle.pop())?;
+        namespace.pool = Some(self.idle_namespaces.clone());
+        Some(namespace)
+    }
+
+    fn create_namespace(
+        &self,
+        library_paths: Vec<String>,
+        permitted_libs_dir: &str,
+        parent: Option<LinkerNamespace>,
+    ) -> Result<LinkerNamespace> {
+        // Reserve the serial number up front, so that concurrent calls use different names.
+        let Ok(serial) =
+            self.serial.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |s| s.checked_add(1))
+        else {
+            bail!("too many namespaces were created");
+        };
+        let name = CString::new(format!("{}-{}", self.base_name, serial))
             .context("invalid namespace name")?;
         let ld_path = CString::new(library_paths.join(":")).context("invalid library paths")?;
-        let permitted_libs_dir =
+        let permitted_libs_dir_cstr =
             CString::new(permitted_libs_dir).context("invalid permitted libs dir")?;
-        // SAFETY: `name`, `ld_path`, `permitted_libs_dir` are valid pointers and this function
-        // accepts the null pointer for `default_library_path` and `parent`.
+        let parent_ptr = parent.as_ref().map_or(std::ptr::null_mut(), LinkerNamespace::as_ptr);
+        // SAFETY: `name`, `ld_path`, `permitted_libs_dir_cstr` are valid pointers, `parent_ptr`
+        // is null or points to a valid namespace, and this function accepts the null pointer for
+        // `default_library_path` and `parent`.
         let namespace = unsafe {
             android_create_namespace(
                 name.as_ptr(),
                 ld_path.as_ptr(),
                 /* default_library_path= */ std::ptr::null_mut(),
                 ANDROID_NAMESPACE_TYPE_SHARED_ISOLATED as u64,
-                permitted_libs_dir.as_ptr(),
-                /* parent= */ std::ptr::null_mut(),
+                permitted_libs_dir_cstr.as_ptr(),
+                parent_ptr,
             )
         };
         match NonNull::new(namespace) {
-            Some(namespace) => {
-                if let Some(new_serial) = self.serial.checked_add(1) {
-                    self.serial = new_serial;
-                } else {
-                    bail!("too many namespaces were created");
-                }
-                Ok(LinkerNamespace { namespace })
-            }
+            Some(namespace) => Ok(LinkerNamespace {
+                namespace,
+                library_paths,
+                permitted_libs_dir: permitted_libs_dir.to_string(),
+                parent: parent.map(Box::new),
+                loaded_libraries: Vec::new(),
+                pool: Some(self.idle_namespaces.clone()),
+            }),
             None => bail_with_dlerror!("android_create_namespace failed"),
         }
     }
+
+    /// Create a linker namespace searching `extra_library_paths` after the paths of `parent`, or
+    /// reuse an idle one searching the same paths. The libraries already loaded in `parent` are
+    /// shared with the new namespace, so libraries found in the extra paths can depend on them.
+    pub fn create_child_namespace(
+        &self,
+        parent: LinkerNamespace,
+        extra_library_paths: &[String],
+    ) -> Result<LinkerNamespace> {
+        let library_paths: Vec<String> =
+            parent.library_paths().iter().chain(extra_library_paths).cloned().collect();
+        let mut key = parent.key();
+        key.0.insert(0, library_paths.clone());
+        if let Some(namespace) = self.take_idle_namespace(&key) {
+            // The reused namespace has a parent of its own, `parent` is handed back.
+            return Ok(namespace);
+        }
+        let permitted_libs_dir = parent.permitted_libs_dir.clone();
+        self.create_namespace(library_paths, &permitted_libs_dir, Some(parent))
+    }
 }
 
 /// LoadedLibrary represents a library loaded to the memory space of the process.
@@ -110,22 +302,18 @@ pub struct LoadedLibrary {
     library_handle: *mut c_void,
 }
 
+// SAFETY: Library handles are process-wide, they can be used and closed from any thread.
+unsafe impl Send for LoadedLibrary {}
+
 impl LoadedLibrary {
-    /// Load a library to the process memory space.
+    /// Load a library to the process memory space. The namespace records the library, so that it
+    /// isn't reused while the library is loaded.
     ///
     /// # Safety
     ///
     /// Users must ensure that the initialization and termination routines of the library are safe.
-    pub unsafe fn new(library_name: &str, namespace: &LinkerNamespace) -> Result<Self> {
-        let dlextinfo = android_dlextinfo {
-            flags: ANDROID_DLEXT_USE_NAMESPACE as u64,
-            reserved_addr: std::ptr::null_mut(),
-            reserved_size: 0,
-            relro_fd: 0,
-            library_fd: 0,
-            library_fd_offset: 0,
-            library_namespace: namespace.as_ptr(),
-        };
+    pub unsafe fn new(library_name: &str, namespace: &mut LinkerNamespace) -> Result<Self> {
+        let dlextinfo = namespace.dlextinfo();
         let library = CString::new(library_name).context("Invalid library name")?;
 
         // SAFETY: `library` and `dlextinfo` are valid pointers. The caller ensured that the
@@ -141,18 +329,41 @@ impl LoadedLibrary {
             bail_with_dlerror!("Failed to open the library {}", library_name);
         }
 
+        namespace.loaded_libraries.push(library);
         Ok(Self { library_handle })
     }
 
-    pub fn find_symbol(&self, symbol_name: &str) -> Result<*mut c_void> {
-        let symbol = CString::new(symbol_name).context("Invalid symbol name")?;
-        // SAFETY: `self.library_handle` is a valid library handle and `symbol` is a valid C
-        // string.
-        let symbol_handle = unsafe { dlsym(self.library_handle, symbol.as_ptr()) };
-        if symbol_handle.is_null() {
-            bail_with_dlerror!("Failed to find the symbol {}", symbol_name);
+    /// Looks up the first of `symbol_names` the library exports, so that a library built against
+    /// an older ABI can still be found under its old symbol name. Returns the name which resolved
+    /// with the symbol.
+    pub fn find_symbol<'a>(&self, symbol_names: &'a [String]) -> Result<(&'a str, *mut c_void)> {
+        for symbol_name in symbol_names {
+            let symbol = CString::new(symbol_name.as_str()).context("Invalid symbol name")?;
+            // SAFETY: `self.library_handle` is a valid library handle and `symbol` is a valid C
+            // string.
+            let symbol_handle = unsafe { dlsym(self.library_handle, symbol.as_ptr()) };
+            if !symbol_handle.is_null() {
+                if symbol_name != &symbol_names[0] {
+                    info!(
+                        "Found the fallback symbol {} instead of {}",
+                        symbol_name, symbol_names[0]
+                    );
+                }
+                return Ok((symbol_name, symbol_handle));
+            }
         }
-        Ok(symbol_handle)
+        bail_with_dlerror!("Failed to find any of the symbols {:?}", symbol_names);
+    }
+}
+
+#[cfg(test)]
+impl LoadedLibrary {
+    /// Opens the main program as a stand-in for the library of a service hosted in tests.
+    pub fn for_test() -> Self {
+        // SAFETY: Passing the null pointer opens the main program, which is already loaded.
+        let library_handle = unsafe { libc::dlopen(std::ptr::null(), libc::RTLD_NOW) };
+        assert!(!library_handle.is_null());
+        Self { library_handle }
     }
 }
 
@@ -163,3 +374,225 @@ impl Drop for LoadedLibrary {
         unsafe { dlclose(self.library_handle) };
     }
 }
+
+/// The library of a service, loaded in a linker namespace dedicated to the service.
+pub struct ServiceLibrary {
+    /// Declared before the namespace, so that it is closed before the namespace can be reused.
+    pub library: LoadedLibrary,
+    pub namespace: LinkerNamespace,
+    /// The entry point of the service.
+    pub create_func: ANativeService_createFunc,
+    /// The name under which the entry point was found.
+    pub symbol_name: String,
+}
+
+impl ServiceLibrary {
+    /// Creates a linker namespace searching `library_paths`, loads `library_name` in it and looks
+    /// up the entry point under the first of `base_symbol_names` the library exports. If
+    /// `plugin_library_paths` isn't empty, the library is loaded in a child namespace searching
+    /// them too, so that the service finds the plugins it loads at runtime.
+    ///
+    /// # Safety
+    ///
+    /// Users must ensure that the initialization and termination routines of the library are
+    /// safe, and that each of `base_symbol_names` is an `ANativeService_createFunc`.
+    pub unsafe fn load(
+        namespace_factory: &NamespaceFactory,
+        library_paths: &[String],
+        plugin_library_paths: &[String],
+        permitted_libs_dir: &str,
+        library_name: &str,
+        base_symbol_names: &[String],
+    ) -> Result<Self> {
+        let mut namespace = create_service_namespace(
+            namespace_factory,
+            library_paths,
+            plugin_library_paths,
+            permitted_libs_dir,
+        )?;
+        // SAFETY: The caller ensured that the library is safe to be loaded.
+        let library = unsafe { LoadedLibrary::new(library_name, &mut namespace) }?;
+        let (symbol_name, create_func_addr) = library.find_symbol(base_symbol_names)?;
+        let symbol_name = symbol_name.to_string();
+        // SAFETY:
+        // `create_func_addr` is a valid pointer to a function exported by the loaded library and
+        // it is guaranteed that it can be transmuted into Option<extern "C" fn>.
+        // https://doc.rust-lang.org/std/option/index.html#representation
+        // The caller ensured that the function has the type signature of
+        // `ANativeService_createFunc`.
+        let create_func: ANativeService_createFunc =
+            unsafe { std::mem::transmute(create_func_addr) };
+        Ok(Self { namespace, library, create_func, symbol_name })
+    }
+}
+
+#[cfg(test)]
+impl ServiceLibrary {
+    /// Creates a placeholder library whose entry point is `create_func`, without loading any
+    /// library.
+    pub fn for_test(create_func: ANativeService_createFunc) -> Self {
+        Self {
+            namespace: LinkerNamespace::for_test(),
+            library: LoadedLibrary::for_test(),
+            create_func,
+            symbol_name: "ANativeService_onCreate".to_string(),
+        }
+    }
+}
+
+/// Creates the namespace a service library is loaded in: a namespace searching `library_paths`,
+/// or a child of it also searching `plugin_library_paths` if there are any.
+fn create_service_namespace(
+    namespace_factory: &NamespaceFactory,
+    library_paths: &[String],
+    plugin_library_paths: &[String],
+    permitted_libs_dir: &str,
+) -> Result<LinkerNamespace> {
+    let namespace = namespace_factory.create_linker_namespace(library_paths, permitted_libs_dir)?;
+    if plugin_library_paths.is_empty() {
+        return Ok(namespace);
+    }
+    namespace_factory.create_child_namespace(namespace, plugin_library_paths)
+}
+
+/// A job run by `LoaderPool`.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed pool of threads loading libraries, so that slow loads don't block the thread which
+/// requests them. Jobs are started in the order they are submitted.
+pub struct LoaderPool {
+    jobs: Option<mpsc::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl LoaderPool {
+    pub fn new(size: NonZeroUsize) -> Result<Self> {
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let workers = (0..size.get())
+            .map(|index| {
+                let job_receiver = job_receiver.clone();
+                thread::Builder::new()
+                    .name(format!("library_loader_{index}"))
+                    .spawn(move || loop {
+                        // The lock is released before the job runs.
+                        let job = job_receiver.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(),
+                            // The pool is dropped.
+                            Err(_) => break,
+                        }
+                    })
+                    .context("Failed to spawn a library loader thread")
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { jobs: Some(jobs), workers })
+    }
+
+    /// Runs `job` on a thread of the pool once one is available.
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) -> Result<()> {
+        let jobs = self.jobs.as_ref().context("The loader pool is shut down")?;
+        jobs.send(Box::new(job)).map_err(|_| anyhow!("The library loader threads exited"))
+    }
+}
+
+impl Drop for LoaderPool {
+    /// Waits for the submitted jobs to finish.
+    fn drop(&mut self) {
+        drop(self.jobs.take());
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("A library loader thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn child_namespace_searches_parent_and_extra_paths() {
+        let factory = NamespaceFactory::new("test-namespace".to_string());
+        let parent =
+            factory.create_linker_namespace(&["/data/app/lib".to_string()], "/data/app").unwrap();
+        assert_eq!(parent.library_paths(), ["/data/app/lib"]);
+
+        let child =
+            factory.create_child_namespace(parent, &["/data/app/plugins".to_string()]).unwrap();
+
+        assert_eq!(child.library_paths(), ["/data/app/lib", "/data/app/plugins"]);
+        assert_eq!(factory.created_namespace_count(), 2);
+    }
+
+    #[test]
+    fn service_namespace_searches_plugin_paths_in_child() {
+        let factory = NamespaceFactory::new("test-namespace".to_string());
+        let library_paths = ["/data/app/lib".to_string()];
+
+        let namespace = create_service_namespace(&factory, &library_paths, &[], "/data/app");
+        assert_eq!(namespace.unwrap().library_paths(), library_paths);
+        assert_eq!(factory.created_namespace_count(), 1);
+
+        let plugin_library_paths = ["/data/data/app/plugins".to_string()];
+        let namespace =
+            create_service_namespace(&factory, &library_paths, &plugin_library_paths, "/data/app");
+        assert_eq!(namespace.unwrap().library_paths(), ["/data/app/lib", "/data/data/app/plugins"]);
+        // The namespace searching `library_paths` is reused as the parent.
+        assert_eq!(factory.created_namespace_count(), 2);
+    }
+
+    #[test]
+    fn namespaces_are_reused_after_being_dropped() {
+        let factory = NamespaceFactory::new("test-namespace".to_string());
+        let library_paths = ["/data/app/lib".to_string()];
+        let plugin_library_paths = ["/data/data/app/plugins".to_string()];
+
+        for _ in 0..100 {
+            let first = factory.create_linker_namespace(&library_paths, "/data/app").unwrap();
+            let second = factory.create_linker_namespace(&library_paths, "/data/app").unwrap();
+            // Namespaces in use are never shared.
+            assert_ne!(first.as_ptr(), second.as_ptr());
+            let child = factory.create_child_namespace(first, &plugin_library_paths).unwrap();
+            assert_eq!(child.library_paths(), ["/data/app/lib", "/data/data/app/plugins"]);
+        }
+        let other_paths = factory.create_linker_namespace(&[], "/data/app").unwrap();
+
+        // The two namespaces searching `library_paths`, the child, its parent and `other_paths`.
+        assert_eq!(factory.created_namespace_count(), 5);
+        assert_eq!(other_paths.library_paths(), [] as [String; 0]);
+    }
+
+    #[test]
+    fn namespace_with_library_still_loaded_is_not_reused() {
+        // The process keeps using libc, so it stays loaded once closed.
+        const LIBC: &str = if cfg!(target_os = "android") { "libc.so" } else { "libc.so.6" };
+        let factory = NamespaceFactory::new("test-namespace".to_string());
+        let library_paths = ["/data/app/lib".to_string()];
+        let mut namespace = factory.create_linker_namespace(&library_paths, "/data/app").unwrap();
+        let namespace_ptr = namespace.as_ptr();
+        // SAFETY: libc is already loaded, its initialization routine doesn't run again.
+        let library = unsafe { LoadedLibrary::new(LIBC, &mut namespace) }.unwrap();
+
+        drop(library);
+        drop(namespace);
+        let next = factory.create_linker_namespace(&library_paths, "/data/app").unwrap();
+
+        assert_ne!(next.as_ptr(), namespace_ptr);
+        assert_eq!(factory.created_namespace_count(), 2);
+    }
+
+    #[test]
+    fn find_symbol_falls_back_to_later_names() {
+        let library = LoadedLibrary::for_test();
+        let symbol_names = ["ANativeService_nonexistent".to_string(), "malloc".to_string()];
+
+        let (symbol_name, symbol) = library.find_symbol(&symbol_names).unwrap();
+
+        assert_eq!(symbol_name, "malloc");
+        assert!(!symbol.is_null());
+        assert!(library.find_symbol(&symbol_names[..1]).is_err());
+        assert!(library.find_symbol(&[]).is_err());
+    }
+}
@@ -14,37 +14,205 @@
 // limitations under the License.
 
 use activitymanager_structured_aidl::aidl::android::app::IActivityManagerStructured::{
-    IActivityManagerStructured, SERVICE_DONE_EXECUTING_ANON, SERVICE_DONE_EXECUTING_REBIND,
+    IActivityManagerStructured, SERVICE_CAPABILITY_BIND, SERVICE_CAPABILITY_BIND_WITH_INTENT,
+    SERVICE_CAPABILITY_DESTROY, SERVICE_CAPABILITY_REBIND, SERVICE_CAPABILITY_TRIM_MEMORY,
+    SERVICE_CAPABILITY_UNBIND, SERVICE_DONE_EXECUTING_ANON, SERVICE_DONE_EXECUTING_REBIND,
     SERVICE_DONE_EXECUTING_STOP, SERVICE_DONE_EXECUTING_UNBIND,
 };
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
 use atrace::AtraceTag;
 use binder::{
     unstable_api::{new_spibinder, AIBinder as SysAIBinder},
     SpIBinder, Strong,
 };
 use libactivity_manager_procstate_aidl::aidl::android::app::ProcessStateEnum::ProcessStateEnum;
+use log::{error, info, warn};
 use native_service_bindgen::{
     ANativeService, ANativeServiceCallbacks,
     ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_BACKGROUND,
-    ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_UI_HIDDEN, ANativeService_createFunc,
+    ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_UI_HIDDEN,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    ffi::{c_char, CString},
+    fmt::Write,
+    num::NonZeroUsize,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
 };
-use std::{collections::BTreeMap, ffi::CString};
 
-use crate::library_loader::{LinkerNamespace, LoadedLibrary, NamespaceFactory};
+use crate::binder_call::BinderCallWorker;
+use crate::library_loader::{
+    LinkerNamespace, LoadedLibrary, LoaderPool, NamespaceFactory, ServiceLibrary,
+};
 use crate::native_application_thread::{
-    BindServiceRequest, CreateServiceRequest, DestroyServiceRequest,
-    NativeApplicationThreadRequest, UnbindServiceRequest,
+    BindServiceRequest, CreateServiceRequest, DestroyServiceRequest, ForegroundStateChangedRequest,
+    LibraryLoadedRequest, NativeApplicationThreadRequest, QueuedCreates, TrimMemoryRequest,
+    UnbindServiceRequest,
 };
-use crate::task::HandlerCallback;
+use crate::process_state::{process_state_from_i32, ProcessStateExt};
+use crate::service_error::ServiceError;
+use crate::task::{HandlerCallback, Responder, Sender, TaskFilter, TaskOutcome};
 
 struct NativeService {
-    /// The linker namespace for the service. All libraries are loaded in this namespace.
-    _namespace: LinkerNamespace,
     /// The library which has the ANativeService_createFunc implementation for the service.
+    /// Declared before the namespace, so that it is closed before the namespace can be reused.
     _library: LoadedLibrary,
+    /// The linker namespace for the service. All libraries are loaded in this namespace.
+    _namespace: LinkerNamespace,
     /// ANativeService instance associated with the service.
     service: Box<ANativeService>,
+    /// The name of the library which has the entry point of the service.
+    library_name: String,
+    /// The name of the entry point of the service.
+    base_symbol_name: String,
+    /// When the service was created.
+    created_at: Instant,
+    /// The number of bind requests, including rebinds, received by the service.
+    bind_count: u64,
+    /// The number of unbind requests received by the service.
+    unbind_count: u64,
+    /// Whether the service is associated with UI, and so interested in UI_HIDDEN trim requests.
+    has_ui: bool,
+}
+
+impl NativeService {
+    fn new(
+        namespace: LinkerNamespace,
+        library: LoadedLibrary,
+        service: Box<ANativeService>,
+        library_name: String,
+        base_symbol_name: String,
+        has_ui: bool,
+    ) -> Self {
+        Self {
+            _library: library,
+            _namespace: namespace,
+            service,
+            library_name,
+            base_symbol_name,
+            created_at: Instant::now(),
+            bind_count: 0,
+            unbind_count: 0,
+            has_ui,
+        }
+    }
+
+    fn uptime(&self) -> Duration {
+        self.created_at.elapsed()
+    }
+}
+
+#[cfg(test)]
+impl NativeService {
+    /// Creates a service with the given callbacks without loading any library.
+    fn for_test(callbacks: ANativeServiceCallbacks) -> Self {
+        Self::new(
+            LinkerNamespace::for_test(),
+            LoadedLibrary::for_test(),
+            Box::new(ANativeService { callbacks }),
+            "libtest_service.so".to_string(),
+            "ANativeService_onCreate".to_string(),
+            false,
+        )
+    }
+}
+
+/// Loads the library of the service created by a request. It is called on the library loader
+/// threads when libraries are loaded asynchronously.
+type LoadLibraryFn = fn(&NamespaceFactory, &CreateServiceRequest) -> Result<ServiceLibrary>;
+
+fn load_service_library(
+    namespace_factory: &NamespaceFactory,
+    req: &CreateServiceRequest,
+) -> Result<ServiceLibrary> {
+    // SAFETY: `CreateServiceRequest` ensures that the library is safe to be loaded and that its
+    // entry point is an `ANativeService_createFunc`.
+    unsafe {
+        ServiceLibrary::load(
+            namespace_factory,
+            &req.library_paths,
+            &req.plugin_library_paths,
+            &req.permitted_libs_dir,
+            &req.library_name,
+            &req.base_symbol_names,
+        )
+    }
+}
+
+/// Checks the library of a service before it is loaded, e.g. that it is in an allowed directory,
+/// given the library name and the paths it is searched in. An error rejects the load.
+pub type LibraryValidator = Arc<dyn Fn(&str, &[String]) -> Result<()> + Send + Sync>;
+
+/// Loads the library of `req` with `load_library`, once `validator` accepted it if set.
+fn load_validated_library(
+    load_library: LoadLibraryFn,
+    validator: Option<&LibraryValidator>,
+    namespace_factory: &NamespaceFactory,
+    req: &CreateServiceRequest,
+) -> Result<ServiceLibrary, ServiceError> {
+    if let Some(validator) = validator {
+        validator(&req.library_name, &req.search_paths())
+            .with_context(|| req.library_name.clone())
+            .map_err(ServiceError::LibraryRejected)?;
+    }
+    load_library(namespace_factory, req).map_err(ServiceError::LibraryLoad)
+}
+
+/// The default maximum number of services a process hosts at once, including the services being
+/// created. Each service has its own linker namespace and library, so an application creating
+/// services without bound would exhaust the memory of the process.
+const DEFAULT_MAX_SERVICES: usize = 256;
+
+/// A callback of a native service run by `NativeActivityThread`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LifecycleCallback {
+    Create,
+    Bind,
+    Rebind,
+    Unbind,
+    Destroy,
+    TrimMemory,
+    ForegroundStateChanged,
+}
+
+/// Reported to the lifecycle observer of a `NativeActivityThread` around the callbacks of the
+/// services, whether or not the service implements the callback.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LifecycleEvent {
+    /// The callback of the service with the token is about to run.
+    Started(LifecycleCallback, SpIBinder),
+    /// The callback of the service with the token returned.
+    Finished(LifecycleCallback, SpIBinder),
+}
+
+/// Reports the lifecycle events of the services, if an observer is set.
+#[derive(Default)]
+struct LifecycleObserver(Option<mpsc::Sender<LifecycleEvent>>);
+
+impl LifecycleObserver {
+    fn started(&self, callback: LifecycleCallback, token: &SpIBinder) {
+        self.notify(|| LifecycleEvent::Started(callback, token.clone()));
+    }
+
+    fn finished(&self, callback: LifecycleCallback, token: &SpIBinder) {
+        self.notify(|| LifecycleEvent::Finished(callback, token.clone()));
+    }
+
+    /// Sends the event built by `event` to the observer. The event isn't built without observer.
+    fn notify(&self, event: impl FnOnce() -> LifecycleEvent) {
+        if let Some(observer) = &self.0 {
+            // The observer going away doesn't matter to the services.
+            let _ = observer.send(event());
+        }
+    }
+}
+
+/// Loads the libraries of the services off the handler thread.
+struct AsyncLibraryLoader {
+    pool: LoaderPool,
+    /// Sends the loaded libraries back to the handler.
+    sender: Arc<Sender<NativeApplicationThreadRequest>>,
 }
 
 /// NativeActivityThread manages the lifecycle of a native process. It receives requests through
@@ -53,8 +221,33 @@ pub struct NativeActivityThread {
     activity_manager: Strong<dyn IActivityManagerStructured>,
     start_seq: i64,
     services: BTreeMap<SpIBinder, NativeService>,
-    namespace_factory: NamespaceFactory,
-    process_state: i32,
+    namespace_factory: Arc<NamespaceFactory>,
+    load_library: LoadLibraryFn,
+    /// Checks the libraries before they are loaded. None accepts every library.
+    library_validator: Option<LibraryValidator>,
+    /// Set if the libraries are loaded off the handler thread.
+    async_loader: Option<AsyncLibraryLoader>,
+    /// Set if the calls to the ActivityManager time out.
+    binder_call_worker: Option<BinderCallWorker>,
+    /// The requests received for the services whose library is being loaded. They are handled,
+    /// in order, once the service is created.
+    loading_services: BTreeMap<SpIBinder, Vec<NativeApplicationThreadRequest>>,
+    process_state: ProcessStateEnum,
+    /// The token of the service destroyed by the last handled request, if any.
+    destroyed_service_token: Option<SpIBinder>,
+    /// Tokens of the services whose destroy request was received before their create request
+    /// was handled. They are destroyed as soon as they are created.
+    deferred_destroys: BTreeSet<SpIBinder>,
+    /// Tokens of the services whose create request is sent but not handled yet.
+    queued_creates: QueuedCreates,
+    /// Requests received while their service was created, left to handle after one of them
+    /// failed transiently.
+    requeued_tasks: Vec<NativeApplicationThreadRequest>,
+    /// Set once all the services are destroyed by a shutdown request.
+    shut_down: bool,
+    lifecycle_observer: LifecycleObserver,
+    /// The maximum number of services hosted at once, including the services being created.
+    max_services: usize,
 }
 
 impl NativeActivityThread {
@@ -63,32 +256,175 @@ impl NativeActivityThread {
             activity_manager,
             start_seq,
             services: BTreeMap::new(),
-            namespace_factory: NamespaceFactory::new(format!("native_app_{}", start_seq)),
-            process_state: ProcessStateEnum::UNKNOWN.0,
+            namespace_factory: Arc::new(NamespaceFactory::new(format!("native_app_{}", start_seq))),
+            load_library: load_service_library,
+            library_validator: None,
+            async_loader: None,
+            binder_call_worker: None,
+            loading_services: BTreeMap::new(),
+            process_state: ProcessStateEnum::UNKNOWN,
+            destroyed_service_token: None,
+            deferred_destroys: BTreeSet::new(),
+            queued_creates: QueuedCreates::default(),
+            requeued_tasks: Vec::new(),
+            shut_down: false,
+            lifecycle_observer: LifecycleObserver::default(),
+            max_services: DEFAULT_MAX_SERVICES,
         }
     }
 
-    fn handle_create_service_request(&mut self, req: CreateServiceRequest) -> Result<()> {
+    /// Returns the set tracking the create requests sent to the handler, to be shared with the
+    /// `NativeApplicationThread` sending them.
+    pub fn queued_creates(&self) -> QueuedCreates {
+        self.queued_creates.clone()
+    }
+
+    /// Loads the libraries of the services on `threads` threads instead of the handler thread, so
+    /// that slow loads don't delay the other requests. The loaded libraries are sent back to the
+    /// handler through `sender`, and the services are created on the handler thread. The requests
+    /// about a service received while its library is loading are handled once it is created.
+    pub fn enable_async_library_loading(
+        &mut self,
+        sender: Sender<NativeApplicationThreadRequest>,
+        threads: NonZeroUsize,
+    ) -> Result<()> {
+        let pool = LoaderPool::new(threads)?;
+        self.async_loader = Some(AsyncLibraryLoader { pool, sender: Arc::new(sender) });
+        Ok(())
+    }
+
+    /// Stops waiting for the calls to the ActivityManager after `timeout`, so that an unresponsive
+    /// ActivityManager doesn't block the handler thread. The calls are made on a worker thread, in
+    /// order, and complete in the background once they time out.
+    pub fn set_binder_call_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.binder_call_worker = Some(BinderCallWorker::new(timeout)?);
+        Ok(())
+    }
+
+    /// Sets the maximum number of services hosted at once, including the services being created.
+    /// The create requests beyond it are rejected. Defaults to `DEFAULT_MAX_SERVICES`.
+    pub fn set_max_services(&mut self, max_services: usize) {
+        self.max_services = max_services;
+    }
+
+    /// Checks the library of each service with `validator` before loading it. The services whose
+    /// library is rejected are reported as stopped without loading it.
+    pub fn set_library_validator(&mut self, validator: LibraryValidator) {
+        self.library_validator = Some(validator);
+    }
+
+    /// Sends the lifecycle events of the services to `observer`, e.g. to check the order in
+    /// which the callbacks of the services run in tests.
+    #[cfg(test)]
+    pub fn set_lifecycle_observer(&mut self, observer: mpsc::Sender<LifecycleEvent>) {
+        self.lifecycle_observer = LifecycleObserver(Some(observer));
+    }
+
+    /// Calls `method` of the ActivityManager through `call`, waiting for it at most for the
+    /// binder call timeout if set.
+    fn call_activity_manager<T: Send + 'static>(
+        &self,
+        method: &'static str,
+        call: impl FnOnce(&dyn IActivityManagerStructured) -> binder::Result<T> + Send + 'static,
+    ) -> Result<T, ServiceError> {
+        let activity_manager = self.activity_manager.clone();
+        match &self.binder_call_worker {
+            Some(worker) => worker.call(method, move || call(&*activity_manager)),
+            None => call(&*activity_manager).map_err(ServiceError::binder_call(method)),
+        }
+    }
+
+    /// Reports to the ActivityManager that the service `token` handled a request of `type_`.
+    fn service_done_executing(&self, token: &SpIBinder, type_: i32) -> Result<(), ServiceError> {
+        let token = token.clone();
+        self.call_activity_manager("serviceDoneExecuting", move |activity_manager| {
+            activity_manager.serviceDoneExecuting(&token, type_, 0, 0)
+        })
+    }
+
+    /// Returns true if a live service was created from the given entry point.
+    fn is_entry_point_in_use(&self, library_name: &str, base_symbol_name: &str) -> bool {
+        self.services.values().any(|service| {
+            service.library_name == library_name && service.base_symbol_name == base_symbol_name
+        })
+    }
+
+    fn handle_create_service_request(
+        &mut self,
+        req: CreateServiceRequest,
+    ) -> Result<(), ServiceError> {
         atrace::trace_method!(AtraceTag::ActivityManager);
-        // Create a linker namespace dedicated to the service. A process could host multiple
-        // services but their namespaces must be isolated.
-        let namespace = self
-            .namespace_factory
-            .create_linker_namespace(&req.library_paths, &req.permitted_libs_dir)?;
-
-        // SAFETY: The application is responsible for implementing the initialization and
-        // termination routines of the library safely.
-        let library = unsafe { LoadedLibrary::new(&req.library_name, &namespace)? };
-        let create_func_addr = library.find_symbol(&req.base_symbol_name)?;
-
-        // SAFETY:
-        // `create_func_addr` is a valid pointer to a function exported by the loaded library and
-        // it is guaranteed that it can be transmuted into Option<extern "C" fn>.
-        // https://doc.rust-lang.org/std/option/index.html#representation
-        // The type signature `ANativeService_createFunc` is a part of NDK API and the application
-        // must implement the entry point function of the native service with this type signature.
-        let create_func: ANativeService_createFunc =
-            unsafe { std::mem::transmute(create_func_addr) };
+        self.queued_creates.lock().unwrap().remove(&req.service_token);
+        if self.services.len() + self.loading_services.len() >= self.max_services {
+            // Report the service as stopped, so that the ActivityManager doesn't wait for it, and
+            // drop the requests already queued for it.
+            self.deferred_destroys.remove(&req.service_token);
+            self.destroyed_service_token = Some(req.service_token.clone());
+            self.service_done_executing(&req.service_token, SERVICE_DONE_EXECUTING_STOP)?;
+            return Err(ServiceError::TooManyServices(self.max_services));
+        }
+        // The library is loaded in a linker namespace dedicated to the service. A process could
+        // host multiple services but their namespaces must be isolated.
+        let Some(async_loader) = &self.async_loader else {
+            let library = load_validated_library(
+                self.load_library,
+                self.library_validator.as_ref(),
+                &self.namespace_factory,
+                &req,
+            );
+            return self.finish_create_service(req, library);
+        };
+        let service_token = req.service_token.clone();
+        let namespace_factory = self.namespace_factory.clone();
+        let load_library = self.load_library;
+        let library_validator = self.library_validator.clone();
+        let sender = async_loader.sender.clone();
+        async_loader
+            .pool
+            .execute(move || {
+                let library = load_validated_library(
+                    load_library,
+                    library_validator.as_ref(),
+                    &namespace_factory,
+                    &req,
+                );
+                let loaded = LibraryLoadedRequest { request: req, library };
+                if let Err(e) = sender.send(NativeApplicationThreadRequest::LibraryLoaded(loaded)) {
+                    // The handler is gone, so is the process.
+                    error!("Failed to send a loaded library to the handler: {e:#}");
+                }
+            })
+            .map_err(ServiceError::LibraryLoad)?;
+        self.loading_services.insert(service_token, Vec::new());
+        Ok(())
+    }
+
+    /// Creates the service of `req` from its loaded library.
+    fn finish_create_service(
+        &mut self,
+        req: CreateServiceRequest,
+        library: Result<ServiceLibrary, ServiceError>,
+    ) -> Result<(), ServiceError> {
+        let ServiceLibrary { namespace, library, create_func, symbol_name } = match library {
+            Ok(library) => library,
+            Err(e @ ServiceError::LibraryRejected(_)) => {
+                // As for the services beyond the maximum, only this service is affected.
+                self.deferred_destroys.remove(&req.service_token);
+                self.destroyed_service_token = Some(req.service_token.clone());
+                self.service_done_executing(&req.service_token, SERVICE_DONE_EXECUTING_STOP)?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        if self.is_entry_point_in_use(&req.library_name, &symbol_name) {
+            // This is allowed, but each service has its own namespace and its own copy of the
+            // library, so a library expecting process-wide singletons gets one per service.
+            warn!(
+                "{} in {} is already used by another live service. Each service has its own \
+                copy of the library and of its global state.",
+                symbol_name, req.library_name
+            );
+        }
 
         let mut service = Box::new(ANativeService {
             callbacks: ANativeServiceCallbacks {
@@ -97,71 +433,257 @@ impl NativeActivityThread {
                 onRebind: None,
                 onDestroy: None,
                 onTrimMemory: None,
+                onBindWithIntent: None,
+                onForegroundStateChanged: None,
             },
         });
 
+        self.lifecycle_observer.started(LifecycleCallback::Create, &req.service_token);
         if let Some(create_func) = create_func {
             // SAFETY: Passing a reference to a valid variable.
             unsafe { create_func(&mut *service) };
         }
+        self.lifecycle_observer.finished(LifecycleCallback::Create, &req.service_token);
 
-        self.activity_manager
-            .serviceDoneExecuting(&req.service_token, SERVICE_DONE_EXECUTING_ANON, 0, 0)
-            .context("Failed to call serviceDoneExecuting")?;
+        if let Err(e) = validate_callbacks(&service.callbacks) {
+            // Let the service release what it allocated in create_func before rejecting it.
+            if let Some(on_destroy) = service.callbacks.onDestroy {
+                // SAFETY: Passing a reference to a valid variable.
+                unsafe { on_destroy(&mut *service) };
+            }
+            error!("{} in {} is not a valid native service: {}", symbol_name, req.library_name, e);
+            return Err(e);
+        }
 
-        self.services.insert(
+        // Let the framework know which requests the service can handle before it sends them. The
+        // framework still sends every request to a service whose capabilities it doesn't know, so
+        // the service is kept if this fails.
+        let service_token = req.service_token.clone();
+        let capabilities = service_capabilities(&service.callbacks);
+        if let Err(e) =
+            self.call_activity_manager("setServiceCapabilities", move |activity_manager| {
+                activity_manager.setServiceCapabilities(&service_token, capabilities)
+            })
+        {
+            warn!("Failed to report the capabilities of {}: {}", symbol_name, e);
+        }
+
+        self.add_service(
             req.service_token,
-            NativeService { _namespace: namespace, _library: library, service },
-        );
+            NativeService::new(
+                namespace,
+                library,
+                service,
+                req.library_name,
+                symbol_name,
+                req.has_ui,
+            ),
+        )
+    }
+
+    /// Creates the service whose library was loaded off the handler thread, then handles the
+    /// requests received for it in the meantime.
+    fn handle_library_loaded_request(
+        &mut self,
+        req: LibraryLoadedRequest,
+    ) -> TaskOutcome<NativeApplicationThreadRequest> {
+        atrace::trace_method!(AtraceTag::ActivityManager);
+        let token = req.request.service_token.clone();
+        let mut parked: VecDeque<_> =
+            self.loading_services.remove(&token).unwrap_or_default().into();
+        match ignore_binder_timeout(self.finish_create_service(req.request, req.library)) {
+            Ok(()) => {}
+            Err(e @ ServiceError::LibraryRejected(_)) => error!("Rejected a service: {}", e),
+            Err(e) => return TaskOutcome::Fatal(e.into()),
+        }
+        // The requests received for a service rejected or destroyed right away are dropped.
+        if let Some(destroyed) = &self.destroyed_service_token {
+            parked.retain(drop_tasks_of_destroyed_service(destroyed.clone()));
+        }
+        while let Some(task) = parked.pop_front() {
+            match self.handle_task(task) {
+                TaskOutcome::Done => {}
+                // The failed request is retried like any other, and the next ones are handled
+                // after it.
+                TaskOutcome::Retry(task) => {
+                    self.requeued_tasks = parked.into();
+                    return TaskOutcome::Retry(task);
+                }
+                TaskOutcome::Fatal(e) => return TaskOutcome::Fatal(e),
+            }
+            if let Some(destroyed) = &self.destroyed_service_token {
+                parked.retain(drop_tasks_of_destroyed_service(destroyed.clone()));
+            }
+        }
+        TaskOutcome::Done
+    }
+
+    /// Adds a created service and reports its creation, then destroys it right away if its
+    /// destroy request was deferred.
+    fn add_service(
+        &mut self,
+        token: SpIBinder,
+        service: NativeService,
+    ) -> Result<(), ServiceError> {
+        // The service is added before the report, so that it isn't leaked if the report fails.
+        self.services.insert(token.clone(), service);
+        ignore_binder_timeout(self.service_done_executing(&token, SERVICE_DONE_EXECUTING_ANON))?;
+        if self.deferred_destroys.remove(&token) {
+            info!("Destroying a service whose destroy request was received before its creation");
+            self.handle_destroy_service_request(DestroyServiceRequest { service_token: token })?;
+        }
         Ok(())
     }
 
-    fn handle_destroy_service_request(&mut self, req: DestroyServiceRequest) -> Result<()> {
+    fn handle_destroy_service_request(
+        &mut self,
+        req: DestroyServiceRequest,
+    ) -> Result<(), ServiceError> {
         atrace::trace_method!(AtraceTag::ActivityManager);
         // Remove the service not to process requests for it anymore.
-        let mut service = self.services.remove(&req.service_token).context("service not found")?;
+        let Some(mut service) = self.services.remove(&req.service_token) else {
+            // The create request of the service may still be queued behind this request. Destroy
+            // the service once it is created, and report the destroy then. The requests received
+            // while the library of the service loads are handled once it is created instead.
+            if !self.queued_creates.lock().unwrap().contains(&req.service_token) {
+                return Err(ServiceError::ServiceNotFound);
+            }
+            info!("Deferring the destroy request of a service which is not created yet");
+            self.deferred_destroys.insert(req.service_token);
+            return Ok(());
+        };
+        self.lifecycle_observer.started(LifecycleCallback::Destroy, &req.service_token);
         if let Some(on_destroy) = service.service.callbacks.onDestroy {
             let native_service = service.service.as_mut();
             // SAFETY: Passing a reference to a valid variable.
             unsafe { on_destroy(native_service) };
         }
-        self.activity_manager
-            .serviceDoneExecuting(&req.service_token, SERVICE_DONE_EXECUTING_STOP, 0, 0)
-            .context("Failed to call serviceDoneExecuting")?;
+        self.lifecycle_observer.finished(LifecycleCallback::Destroy, &req.service_token);
+        // The service is gone even if the report fails, so are its pending requests.
+        self.destroyed_service_token = Some(req.service_token.clone());
+        self.service_done_executing(&req.service_token, SERVICE_DONE_EXECUTING_STOP)
+    }
+
+    /// Destroys all the services and finishes the thread. A failure to report a destroyed service
+    /// to the ActivityManager doesn't prevent the other services from being destroyed.
+    fn handle_shutdown_request(&mut self) -> Result<(), ServiceError> {
+        atrace::trace_method!(AtraceTag::ActivityManager);
+        let mut services = std::mem::take(&mut self.services);
+        info!("Shutting down {} services", services.len());
+        for (token, service) in services.iter_mut() {
+            self.lifecycle_observer.started(LifecycleCallback::Destroy, token);
+            if let Some(on_destroy) = service.service.callbacks.onDestroy {
+                let native_service = service.service.as_mut();
+                // SAFETY: Passing a reference to a valid variable.
+                unsafe { on_destroy(native_service) };
+            }
+            self.lifecycle_observer.finished(LifecycleCallback::Destroy, token);
+        }
+        // The deferred destroys and the services being created are reported too, so that the
+        // ActivityManager doesn't wait for services which will never be created.
+        let deferred_destroys = std::mem::take(&mut self.deferred_destroys);
+        let loading_services = std::mem::take(&mut self.loading_services);
+        for token in services.keys().chain(&deferred_destroys).chain(loading_services.keys()) {
+            if let Err(e) = self.service_done_executing(token, SERVICE_DONE_EXECUTING_STOP) {
+                error!("Failed to report a destroyed service: {}", e);
+            }
+        }
+        // Unload the libraries only after all the services are destroyed.
+        drop(services);
+        self.shut_down = true;
         Ok(())
     }
 
-    fn handle_bind_service_request(&mut self, req: BindServiceRequest) -> Result<()> {
+    /// Binds the service, publishing the binder returned by `onBind` or `onBindWithIntent`.
+    ///
+    /// If the binder can't be published, the binding is undone: the reference to the binder is
+    /// released and `onUnbind` is called with the same intent token, as if the client unbound, so
+    /// that the service can release what it allocated for the binding.
+    fn handle_bind_service_request(&mut self, req: BindServiceRequest) -> Result<(), ServiceError> {
         atrace::trace_method!(AtraceTag::ActivityManager);
-        let service = self.services.get_mut(&req.service_token).context("service not found")?;
+        let service =
+            self.services.get_mut(&req.service_token).ok_or(ServiceError::ServiceNotFound)?;
+        service.bind_count += 1;
         let intent_token = req.intent_hash;
 
         if !req.rebind {
-            let on_bind = service.service.callbacks.onBind.context("onBind must be implemented")?;
-            let native_service = service.service.as_mut();
+            let native_service = service.service.as_mut() as *mut ANativeService;
             let action_cstr = req.action.and_then(|s| CString::new(s).ok());
             let action_ptr = action_cstr.as_ref().map_or(std::ptr::null(), |s| s.as_ptr());
             let data_cstr = req.data.and_then(|s| CString::new(s).ok());
             let data_ptr = data_cstr.as_ref().map_or(std::ptr::null(), |s| s.as_ptr());
 
-            // SAFETY: `ANativeService_onBindCallback` accepts the null pointer or
-            // a pointer to a valid C string for `action` and `data`. We pass a reference to a valid
-            // vairble for `service`.
-            let service_binder_ptr =
-                unsafe { on_bind(native_service, intent_token, action_ptr, data_ptr) };
+            // Services implementing neither callback are rejected at creation.
+            self.lifecycle_observer.started(LifecycleCallback::Bind, &req.service_token);
+            let (callback_name, service_binder_ptr) = if let Some(on_bind_with_intent) =
+                service.service.callbacks.onBindWithIntent
+            {
+                let category_cstrs: Vec<CString> =
+                    req.categories.into_iter().filter_map(|s| CString::new(s).ok()).collect();
+                let category_ptrs: Vec<*const c_char> =
+                    category_cstrs.iter().map(|s| s.as_ptr()).collect();
+                let (extras_ptr, extras_size) =
+                    req.extras.as_ref().map_or((std::ptr::null(), 0), |e| (e.as_ptr(), e.len()));
+                // SAFETY: `ANativeService_onBindWithIntentCallback` accepts the null pointer or a
+                // pointer to a valid C string for `action` and `data`. `category_ptrs` points to
+                // `categories_count` valid C strings and `extras_ptr` is the null pointer or
+                // points to `extras_size` bytes, all alive during the call. `native_service`
+                // points to a valid variable.
+                let ptr = unsafe {
+                    on_bind_with_intent(
+                        native_service,
+                        intent_token,
+                        action_ptr,
+                        data_ptr,
+                        category_ptrs.as_ptr(),
+                        category_ptrs.len(),
+                        extras_ptr,
+                        extras_size,
+                    )
+                };
+                ("onBindWithIntent", ptr)
+            } else {
+                let on_bind = service
+                    .service
+                    .callbacks
+                    .onBind
+                    .ok_or(ServiceError::MissingCallback("onBind"))?;
+                // SAFETY: `ANativeService_onBindCallback` accepts the null pointer or
+                // a pointer to a valid C string for `action` and `data`. We pass a reference to a
+                // valid vairble for `service`.
+                let ptr = unsafe { on_bind(native_service, intent_token, action_ptr, data_ptr) };
+                ("onBind", ptr)
+            };
+            self.lifecycle_observer.finished(LifecycleCallback::Bind, &req.service_token);
             if service_binder_ptr.is_null() {
-                bail!("onBind returned the null pointer");
+                return Err(ServiceError::NullBinder(callback_name));
             }
 
             let service_binder =
                 // SAFETY: The application is responsible for implementing `onBind` to return a
                 // valid ABinder pointer.
                 unsafe { new_spibinder(service_binder_ptr as *mut SysAIBinder) }
-                    .context("Failed to create SpIBinder from ABinder")?;
-            self.activity_manager
-                .publishService(&req.service_token, &req.bind_token, &service_binder)
-                .context("Failed to call publishService")?;
+                    .ok_or(ServiceError::NullBinder(callback_name))?;
+            let on_unbind = service.service.callbacks.onUnbind;
+            let (service_token, bind_token) = (req.service_token.clone(), req.bind_token);
+            let published_binder = service_binder.clone();
+            match self.call_activity_manager("publishService", move |activity_manager| {
+                activity_manager.publishService(&service_token, &bind_token, &published_binder)
+            }) {
+                Ok(()) => {}
+                // The binder may still be published, so the binding is kept.
+                Err(e @ ServiceError::BinderTimeout { .. }) => return Err(e),
+                Err(e) => {
+                    drop(service_binder);
+                    if let Some(on_unbind) = on_unbind {
+                        // SAFETY: `native_service` points to a valid variable.
+                        unsafe { on_unbind(native_service, intent_token) };
+                    }
+                    return Err(e);
+                }
+            }
         } else {
+            self.lifecycle_observer.started(LifecycleCallback::Rebind, &req.service_token);
             if let Some(on_rebind) = service.service.callbacks.onRebind {
                 let native_service = service.service.as_mut();
 
@@ -170,18 +692,23 @@ impl NativeActivityThread {
                     on_rebind(native_service, intent_token);
                 }
             }
-            self.activity_manager
-                .serviceDoneExecuting(&req.service_token, SERVICE_DONE_EXECUTING_REBIND, 0, 0)
-                .context("Failed to call serviceDoneExecuting")?;
+            self.lifecycle_observer.finished(LifecycleCallback::Rebind, &req.service_token);
+            self.service_done_executing(&req.service_token, SERVICE_DONE_EXECUTING_REBIND)?;
         }
         Ok(())
     }
 
-    fn handle_unbind_service_request(&mut self, req: UnbindServiceRequest) -> Result<()> {
+    fn handle_unbind_service_request(
+        &mut self,
+        req: UnbindServiceRequest,
+    ) -> Result<(), ServiceError> {
         atrace::trace_method!(AtraceTag::ActivityManager);
-        let service = self.services.get_mut(&req.service_token).context("service not found")?;
+        let service =
+            self.services.get_mut(&req.service_token).ok_or(ServiceError::ServiceNotFound)?;
+        service.unbind_count += 1;
         let intent_token = req.intent_hash;
 
+        self.lifecycle_observer.started(LifecycleCallback::Unbind, &req.service_token);
         let request_on_rebind = if let Some(on_unbind) = service.service.callbacks.onUnbind {
             let native_service = service.service.as_mut() as *mut ANativeService;
             // SAFETY: Passing a reference to a valid variable.
@@ -189,58 +716,234 @@ impl NativeActivityThread {
         } else {
             false
         };
+        self.lifecycle_observer.finished(LifecycleCallback::Unbind, &req.service_token);
         if request_on_rebind {
-            self.activity_manager
-                .unbindFinished(&req.service_token, &req.bind_token)
-                .context("Failed to call unbindFinished")?;
+            let (service_token, bind_token) = (req.service_token, req.bind_token);
+            self.call_activity_manager("unbindFinished", move |activity_manager| {
+                activity_manager.unbindFinished(&service_token, &bind_token)
+            })?;
         } else {
-            self.activity_manager
-                .serviceDoneExecuting(&req.service_token, SERVICE_DONE_EXECUTING_UNBIND, 0, 0)
-                .context("Failed to call serviceDoneExecuting")?;
+            self.service_done_executing(&req.service_token, SERVICE_DONE_EXECUTING_UNBIND)?;
         }
         Ok(())
     }
 
-    fn handle_trim_memory_request(&mut self, level: i32) -> Result<()> {
+    /// Delivers a trim memory request to the targeted services, or all of them if none is
+    /// targeted. Requests are gated as follows:
+    ///
+    /// | Level      | Process state                  | Delivered to             |
+    /// |------------|--------------------------------|--------------------------|
+    /// | BACKGROUND | IMPORTANT_FOREGROUND or higher | No service               |
+    /// | BACKGROUND | Lower                          | Targeted services        |
+    /// | UI_HIDDEN  | Any                            | Targeted services w/ UI  |
+    fn handle_trim_memory_request(&mut self, req: TrimMemoryRequest) -> Result<(), ServiceError> {
         atrace::trace_method!(AtraceTag::ActivityManager);
+        let level = req.level;
         if level != ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_BACKGROUND
             && level != ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_UI_HIDDEN
         {
-            bail!("Received an unexpected level: {}", level);
+            return Err(ServiceError::UnexpectedTrimMemoryLevel(level));
         }
-        if self.process_state <= ProcessStateEnum::IMPORTANT_FOREGROUND.0
+        if self.process_state.is_as_foreground_as(ProcessStateEnum::IMPORTANT_FOREGROUND)
             && level == ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_BACKGROUND
         {
             return Ok(());
         }
-        for service in self.services.values_mut() {
+        let services: Vec<(&SpIBinder, &mut NativeService)> = match &req.service_token {
+            Some(token) => {
+                vec![(token, self.services.get_mut(token).ok_or(ServiceError::ServiceNotFound)?)]
+            }
+            None => {
+                // The services still loading are trimmed once they are created.
+                for (token, parked) in &mut self.loading_services {
+                    parked.push(NativeApplicationThreadRequest::TrimMemory(TrimMemoryRequest {
+                        level,
+                        service_token: Some(token.clone()),
+                    }));
+                }
+                self.services.iter_mut().collect()
+            }
+        };
+        for (token, service) in services {
+            // Hiding the UI doesn't free anything for services without UI.
+            if level == ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_UI_HIDDEN
+                && !service.has_ui
+            {
+                continue;
+            }
+            self.lifecycle_observer.started(LifecycleCallback::TrimMemory, token);
             if let Some(on_trim_memory) = service.service.callbacks.onTrimMemory {
                 let native_service = service.service.as_mut();
                 // SAFETY: Passing a reference to a valid variable.
                 unsafe { on_trim_memory(native_service, level) };
             }
+            self.lifecycle_observer.finished(LifecycleCallback::TrimMemory, token);
         }
         Ok(())
     }
 
-    fn handle_bind_application_request(&mut self) -> Result<()> {
+    fn handle_foreground_state_changed_request(
+        &mut self,
+        req: &ForegroundStateChangedRequest,
+    ) -> Result<(), ServiceError> {
+        atrace::trace_method!(AtraceTag::ActivityManager);
+        let service =
+            self.services.get_mut(&req.service_token).ok_or(ServiceError::ServiceNotFound)?;
+        self.lifecycle_observer
+            .started(LifecycleCallback::ForegroundStateChanged, &req.service_token);
+        if let Some(on_foreground_state_changed) =
+            service.service.callbacks.onForegroundStateChanged
+        {
+            let native_service = service.service.as_mut();
+            // SAFETY: Passing a reference to a valid variable.
+            unsafe {
+                on_foreground_state_changed(native_service, req.fgs_type, req.has_notification)
+            };
+        }
+        self.lifecycle_observer
+            .finished(LifecycleCallback::ForegroundStateChanged, &req.service_token);
+        let service_token = req.service_token.clone();
+        let (fgs_type, has_notification) = (req.fgs_type, req.has_notification);
+        self.call_activity_manager("setServiceForeground", move |activity_manager| {
+            activity_manager.setServiceForeground(&service_token, fgs_type, has_notification)
+        })
+    }
+
+    fn handle_bind_application_request(&mut self) -> Result<(), ServiceError> {
         atrace::trace_method!(AtraceTag::ActivityManager);
         // We don't support calling Application.onCreate in native processes.
-        self.activity_manager
-            .finishAttachApplication(self.start_seq, 0)
-            .context("Failed to call finishAttachApplication")
+        let start_seq = self.start_seq;
+        self.call_activity_manager("finishAttachApplication", move |activity_manager| {
+            activity_manager.finishAttachApplication(start_seq, 0)
+        })
     }
 
-    fn handle_set_process_state(&mut self, state: i32) -> Result<()> {
+    fn handle_set_process_state(&mut self, state: i32) -> Result<(), ServiceError> {
         atrace::trace_method!(AtraceTag::ActivityManager);
-        self.process_state = state;
+        self.process_state = process_state_from_i32(state);
+        Ok(())
+    }
+
+    fn handle_dump_request(&mut self, responder: Responder<String>) -> Result<(), ServiceError> {
+        responder.respond(self.dump());
         Ok(())
     }
+
+    /// Describes the state of the process and its services.
+    fn dump(&self) -> String {
+        let mut out = String::new();
+        // Writing to a String never fails.
+        let _ = writeln!(out, "NativeActivityThread start_seq={}", self.start_seq);
+        let _ = writeln!(out, "  process_state={:?}", self.process_state);
+        let _ = writeln!(out, "  deferred destroys: {}", self.deferred_destroys.len());
+        let _ = writeln!(out, "  loading services: {}", self.loading_services.len());
+        let _ = writeln!(
+            out,
+            "  linker namespaces created: {}",
+            self.namespace_factory.created_namespace_count()
+        );
+        let _ = writeln!(out, "  services ({}):", self.services.len());
+        for service in self.services.values() {
+            let _ = writeln!(
+                out,
+                "    {} in {}: uptime={}s binds={} unbinds={}",
+                service.base_symbol_name,
+                service.library_name,
+                service.uptime().as_secs(),
+                service.bind_count,
+                service.unbind_count
+            );
+        }
+        out
+    }
 }
 
-impl HandlerCallback<NativeApplicationThreadRequest> for NativeActivityThread {
-    fn handle_task(&mut self, task: NativeApplicationThreadRequest) -> Result<()> {
+/// Checks that the callbacks populated by `ANativeService_createFunc` are sufficient to run the
+/// service. `onBind` or `onBindWithIntent` is mandatory because native services are only
+/// reachable through binding; `onRebind` and the other callbacks are optional.
+fn validate_callbacks(callbacks: &ANativeServiceCallbacks) -> Result<(), ServiceError> {
+    if callbacks.onBind.is_none() && callbacks.onBindWithIntent.is_none() {
+        return Err(ServiceError::MissingCallback("onBind"));
+    }
+    Ok(())
+}
+
+/// Returns the `SERVICE_CAPABILITY_*` bitmask of the callbacks implemented by a service.
+fn service_capabilities(callbacks: &ANativeServiceCallbacks) -> i32 {
+    [
+        (callbacks.onBind.is_some(), SERVICE_CAPABILITY_BIND),
+        (callbacks.onUnbind.is_some(), SERVICE_CAPABILITY_UNBIND),
+        (callbacks.onRebind.is_some(), SERVICE_CAPABILITY_REBIND),
+        (callbacks.onDestroy.is_some(), SERVICE_CAPABILITY_DESTROY),
+        (callbacks.onTrimMemory.is_some(), SERVICE_CAPABILITY_TRIM_MEMORY),
+        (callbacks.onBindWithIntent.is_some(), SERVICE_CAPABILITY_BIND_WITH_INTENT),
+    ]
+    .into_iter()
+    .filter(|(implemented, _)| *implemented)
+    .fold(0, |capabilities, (_, capability)| capabilities | capability)
+}
+
+/// Returns a filter dropping the tasks about the destroyed service `token`, up to a task creating
+/// it again.
+fn drop_tasks_of_destroyed_service(token: SpIBinder) -> TaskFilter<NativeApplicationThreadRequest> {
+    let mut recreated = false;
+    Box::new(move |task| {
+        if recreated {
+            return true;
+        }
         match task {
+            NativeApplicationThreadRequest::CreateService(req) => {
+                recreated = req.service_token == token;
+                true
+            }
+            _ => task.service_token() != Some(&token),
+        }
+    })
+}
+
+/// Logs the timeout of a call to the ActivityManager and treats the request as handled, as the
+/// call completes in the background. Retrying the request would make the call twice, and giving
+/// up on the process for a slow ActivityManager would be worse than going on with the next
+/// requests.
+fn ignore_binder_timeout(result: Result<(), ServiceError>) -> Result<(), ServiceError> {
+    match result {
+        Err(e @ ServiceError::BinderTimeout { .. }) => {
+            error!("{}", e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Converts the result of a request into the outcome of its task. Transient failures are retried,
+/// so this must only be used for requests without side effects other than the failed call.
+fn retry_on_transient_error(
+    result: Result<(), ServiceError>,
+    task: NativeApplicationThreadRequest,
+) -> TaskOutcome<NativeApplicationThreadRequest> {
+    match ignore_binder_timeout(result) {
+        Ok(()) => TaskOutcome::Done,
+        Err(e) if e.is_transient() => {
+            warn!("Retrying after a transient failure: {}", e);
+            TaskOutcome::Retry(task)
+        }
+        Err(e) => TaskOutcome::Fatal(e.into()),
+    }
+}
+
+impl HandlerCallback<NativeApplicationThreadRequest> for NativeActivityThread {
+    fn handle_task(
+        &mut self,
+        task: NativeApplicationThreadRequest,
+    ) -> TaskOutcome<NativeApplicationThreadRequest> {
+        if let Some(parked) =
+            task.service_token().and_then(|token| self.loading_services.get_mut(token))
+        {
+            // The service is being created, the request is handled once it is.
+            parked.push(task);
+            return TaskOutcome::Done;
+        }
+        let result = match task {
             NativeApplicationThreadRequest::CreateService(req) => {
                 self.handle_create_service_request(req)
             }
@@ -248,20 +951,1492 @@ impl HandlerCallback<NativeApplicationThreadRequest> for NativeActivityThread {
                 self.handle_destroy_service_request(req)
             }
             NativeApplicationThreadRequest::BindService(req) => {
-                self.handle_bind_service_request(req)
+                let service_token = req.service_token.clone();
+                match self.handle_bind_service_request(req) {
+                    // A bind received for a service which was never created or was already
+                    // destroyed only fails that bind. The ActivityManager is told the bind is
+                    // done, so that it doesn't wait for it.
+                    Err(ServiceError::ServiceNotFound) => {
+                        error!("Ignored a bind of an unknown service");
+                        if let Err(e) =
+                            self.service_done_executing(&service_token, SERVICE_DONE_EXECUTING_ANON)
+                        {
+                            error!("Failed to report the failed bind: {}", e);
+                        }
+                        Ok(())
+                    }
+                    result => result,
+                }
             }
             NativeApplicationThreadRequest::UnbindService(req) => {
                 self.handle_unbind_service_request(req)
             }
-            NativeApplicationThreadRequest::TrimMemory(level) => {
-                self.handle_trim_memory_request(level)
-            }
+            NativeApplicationThreadRequest::TrimMemory(req) => self.handle_trim_memory_request(req),
             NativeApplicationThreadRequest::BindApplication => {
-                self.handle_bind_application_request()
+                let result = self.handle_bind_application_request();
+                return retry_on_transient_error(result, task);
             }
             NativeApplicationThreadRequest::SetProcessState(state) => {
                 self.handle_set_process_state(state)
             }
+            NativeApplicationThreadRequest::ForegroundStateChanged(ref req) => {
+                let result = self.handle_foreground_state_changed_request(req);
+                return retry_on_transient_error(result, task);
+            }
+            NativeApplicationThreadRequest::Dump(responder) => self.handle_dump_request(responder),
+            NativeApplicationThreadRequest::Shutdown => self.handle_shutdown_request(),
+            NativeApplicationThreadRequest::LibraryLoaded(req) => {
+                return self.handle_library_loaded_request(req);
+            }
+        };
+        match ignore_binder_timeout(result) {
+            // Only the rejected service is affected, the others keep running.
+            Err(e @ (ServiceError::TooManyServices(_) | ServiceError::LibraryRejected(_))) => {
+                error!("Rejected a service: {}", e);
+                TaskOutcome::Done
+            }
+            result => result.map_err(Into::into).into(),
+        }
+    }
+
+    fn take_pending_task_filter(&mut self) -> Option<TaskFilter<NativeApplicationThreadRequest>> {
+        let token = self.destroyed_service_token.take()?;
+        Some(drop_tasks_of_destroyed_service(token))
+    }
+
+    fn take_requeued_tasks(&mut self) -> Vec<NativeApplicationThreadRequest> {
+        std::mem::take(&mut self.requeued_tasks)
+    }
+
+    fn is_finished(&self) -> bool {
+        self.shut_down
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::native_application_thread::NativeApplicationThread;
+    use crate::task::{run_thread_loop_once, Handler};
+    use activitymanager_structured_aidl::aidl::android::app::IActivityManagerStructured::BnActivityManagerStructured;
+    use anyhow::anyhow;
+    use binder::{unstable_api::AsNative, BinderFeatures, Interface};
+    use native_application_thread_aidl::aidl::android::app::INativeApplicationThread::INativeApplicationThread;
+    use native_service_bindgen::AIBinder;
+    use std::ffi::c_char;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{Arc, Condvar, Mutex};
+
+    /// A call received by `MockActivityManager`.
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) enum AmCall {
+        ServiceDoneExecuting { token: SpIBinder, type_: i32 },
+        PublishService { token: SpIBinder },
+        UnbindFinished { token: SpIBinder },
+        FinishAttachApplication,
+        SetServiceForeground { token: SpIBinder, fgs_type: i32, has_notification: bool },
+        SetServiceCapabilities { token: SpIBinder, capabilities: i32 },
+    }
+
+    #[derive(Default)]
+    pub(crate) struct MockActivityManager {
+        pub(crate) calls: Arc<Mutex<Vec<AmCall>>>,
+        /// Error returned by attachNativeApplication, if any.
+        pub(crate) attach_error: Option<binder::StatusCode>,
+        /// Error returned by publishService, if any.
+        pub(crate) publish_service_error: Option<binder::StatusCode>,
+        /// Error returned by setServiceCapabilities, if any.
+        pub(crate) set_service_capabilities_error: Option<binder::StatusCode>,
+        /// How long serviceDoneExecuting takes to return.
+        pub(crate) service_done_executing_delay: Duration,
+        /// Number of calls to setServiceForeground left to fail transiently.
+        pub(crate) set_service_foreground_failures: AtomicU32,
+    }
+
+    impl Interface for MockActivityManager {}
+
+    impl IActivityManagerStructured for MockActivityManager {
+        fn attachNativeApplication(&self, _app: &SpIBinder, _start_seq: i64) -> binder::Result<()> {
+            match self.attach_error {
+                Some(error) => Err(error.into()),
+                None => Ok(()),
+            }
+        }
+
+        fn finishAttachApplication(&self, _start_seq: i64, _timestamp: i64) -> binder::Result<()> {
+            self.calls.lock().unwrap().push(AmCall::FinishAttachApplication);
+            Ok(())
+        }
+
+        fn serviceDoneExecuting(
+            &self,
+            token: &SpIBinder,
+            type_: i32,
+            _start_id: i32,
+            _res: i32,
+        ) -> binder::Result<()> {
+            std::thread::sleep(self.service_done_executing_delay);
+            self.calls
+                .lock()
+                .unwrap()
+                .push(AmCall::ServiceDoneExecuting { token: token.clone(), type_ });
+            Ok(())
+        }
+
+        fn publishService(
+            &self,
+            token: &SpIBinder,
+            _bind_token: &SpIBinder,
+            _service: &SpIBinder,
+        ) -> binder::Result<()> {
+            self.calls.lock().unwrap().push(AmCall::PublishService { token: token.clone() });
+            match self.publish_service_error {
+                Some(error) => Err(error.into()),
+                None => Ok(()),
+            }
+        }
+
+        fn unbindFinished(&self, token: &SpIBinder, _bind_token: &SpIBinder) -> binder::Result<()> {
+            self.calls.lock().unwrap().push(AmCall::UnbindFinished { token: token.clone() });
+            Ok(())
+        }
+
+        fn setServiceForeground(
+            &self,
+            token: &SpIBinder,
+            fgs_type: i32,
+            has_notification: bool,
+        ) -> binder::Result<()> {
+            self.calls.lock().unwrap().push(AmCall::SetServiceForeground {
+                token: token.clone(),
+                fgs_type,
+                has_notification,
+            });
+            let failures = self.set_service_foreground_failures.load(Ordering::Relaxed);
+            if failures > 0 {
+                self.set_service_foreground_failures.store(failures - 1, Ordering::Relaxed);
+                return Err(binder::StatusCode::FAILED_TRANSACTION.into());
+            }
+            Ok(())
+        }
+
+        fn setServiceCapabilities(
+            &self,
+            token: &SpIBinder,
+            capabilities: i32,
+        ) -> binder::Result<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(AmCall::SetServiceCapabilities { token: token.clone(), capabilities });
+            match self.set_service_capabilities_error {
+                Some(error) => Err(error.into()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    /// Creates a `NativeActivityThread` talking to a `MockActivityManager`, and returns it with
+    /// the calls recorded by the mock.
+    fn new_thread_with_mock_am() -> (NativeActivityThread, Arc<Mutex<Vec<AmCall>>>) {
+        new_thread_with(MockActivityManager::default())
+    }
+
+    /// Creates a `NativeActivityThread` talking to `mock`, and returns it with the calls recorded
+    /// by the mock.
+    fn new_thread_with(
+        mock: MockActivityManager,
+    ) -> (NativeActivityThread, Arc<Mutex<Vec<AmCall>>>) {
+        let calls = mock.calls.clone();
+        let activity_manager =
+            BnActivityManagerStructured::new_binder(mock, BinderFeatures::default());
+        (NativeActivityThread::new(activity_manager, 1), calls)
+    }
+
+    /// Creates a binder object to be used as a service or bind token.
+    fn new_token() -> SpIBinder {
+        BnActivityManagerStructured::new_binder(
+            MockActivityManager::default(),
+            BinderFeatures::default(),
+        )
+        .as_binder()
+    }
+
+    fn empty_callbacks() -> ANativeServiceCallbacks {
+        ANativeServiceCallbacks {
+            onBind: None,
+            onUnbind: None,
+            onRebind: None,
+            onDestroy: None,
+            onTrimMemory: None,
+            onBindWithIntent: None,
+            onForegroundStateChanged: None,
+        }
+    }
+
+    unsafe extern "C" fn stub_on_bind(
+        _service: *mut ANativeService,
+        _intent_token: i32,
+        _action: *const c_char,
+        _data: *const c_char,
+    ) -> *mut AIBinder {
+        std::ptr::null_mut()
+    }
+
+    unsafe extern "C" fn stub_on_rebind(_service: *mut ANativeService, _intent_token: i32) {}
+
+    #[test]
+    fn service_without_on_bind_is_rejected() {
+        let callbacks =
+            ANativeServiceCallbacks { onRebind: Some(stub_on_rebind), ..empty_callbacks() };
+        let err = validate_callbacks(&callbacks).unwrap_err();
+        assert!(err.to_string().contains("onBind"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn service_with_only_on_bind_is_accepted() {
+        let callbacks = ANativeServiceCallbacks { onBind: Some(stub_on_bind), ..empty_callbacks() };
+        assert!(validate_callbacks(&callbacks).is_ok());
+    }
+
+    thread_local! {
+        /// The arguments of the calls to `recording_on_foreground_state_changed`.
+        static FOREGROUND_STATE_CHANGES: std::cell::RefCell<Vec<(*mut ANativeService, i32, bool)>> =
+            const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    unsafe extern "C" fn recording_on_foreground_state_changed(
+        service: *mut ANativeService,
+        fgs_type: i32,
+        has_notification: bool,
+    ) {
+        FOREGROUND_STATE_CHANGES
+            .with(|changes| changes.borrow_mut().push((service, fgs_type, has_notification)));
+    }
+
+    #[test]
+    fn foreground_state_change_is_reported_to_service_and_activity_manager() {
+        let (mut thread, calls) = new_thread_with_mock_am();
+        let token = new_token();
+        let callbacks = ANativeServiceCallbacks {
+            onBind: Some(stub_on_bind),
+            onForegroundStateChanged: Some(recording_on_foreground_state_changed),
+            ..empty_callbacks()
+        };
+        thread.services.insert(token.clone(), NativeService::for_test(callbacks));
+        let service_ptr =
+            thread.services.get_mut(&token).unwrap().service.as_mut() as *mut ANativeService;
+
+        assert!(matches!(
+            thread.handle_task(NativeApplicationThreadRequest::ForegroundStateChanged(
+                ForegroundStateChangedRequest {
+                    service_token: token.clone(),
+                    fgs_type: 8,
+                    has_notification: true,
+                },
+            )),
+            TaskOutcome::Done
+        ));
+
+        FOREGROUND_STATE_CHANGES.with(|changes| {
+            assert_eq!(*changes.borrow(), [(service_ptr, 8, true)]);
+        });
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [AmCall::SetServiceForeground { token, fgs_type: 8, has_notification: true }]
+        );
+    }
+
+    #[test]
+    fn foreground_state_change_for_unknown_service_fails() {
+        let (mut thread, calls) = new_thread_with_mock_am();
+
+        let res = thread.handle_task(NativeApplicationThreadRequest::ForegroundStateChanged(
+            ForegroundStateChangedRequest {
+                service_token: new_token(),
+                fgs_type: 8,
+                has_notification: false,
+            },
+        ));
+
+        assert!(matches!(res, TaskOutcome::Fatal(_)));
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_transient_binder_failures_are_retried() {
+        let failed_transaction = ServiceError::binder_call("setServiceForeground")(
+            binder::StatusCode::FAILED_TRANSACTION.into(),
+        );
+        assert!(matches!(
+            retry_on_transient_error(
+                Err(failed_transaction),
+                NativeApplicationThreadRequest::BindApplication
+            ),
+            TaskOutcome::Retry(NativeApplicationThreadRequest::BindApplication)
+        ));
+
+        let dead_object = ServiceError::binder_call("setServiceForeground")(
+            binder::StatusCode::DEAD_OBJECT.into(),
+        );
+        assert!(matches!(
+            retry_on_transient_error(
+                Err(dead_object),
+                NativeApplicationThreadRequest::BindApplication
+            ),
+            TaskOutcome::Fatal(_)
+        ));
+        assert!(matches!(
+            retry_on_transient_error(
+                Err(ServiceError::ServiceNotFound),
+                NativeApplicationThreadRequest::BindApplication
+            ),
+            TaskOutcome::Fatal(_)
+        ));
+    }
+
+    #[test]
+    fn services_sharing_entry_point_are_detected() {
+        let (mut thread, _calls) = new_thread_with_mock_am();
+        thread.load_library =
+            |_namespace_factory, _req| Ok(ServiceLibrary::for_test(Some(create_bindable_service)));
+        let (token, other_token) = (new_token(), new_token());
+        let library_name = CreateServiceRequest::for_test(token.clone()).library_name;
+        let symbol_name = "ANativeService_onCreate";
+        let create = |token: &SpIBinder| {
+            NativeApplicationThreadRequest::CreateService(CreateServiceRequest::for_test(
+                token.clone(),
+            ))
+        };
+        let destroy = |token: &SpIBinder| {
+            NativeApplicationThreadRequest::DestroyService(DestroyServiceRequest {
+                service_token: token.clone(),
+            })
+        };
+
+        // No warning for the first service.
+        assert!(!thread.is_entry_point_in_use(&library_name, symbol_name));
+        assert!(matches!(thread.handle_task(create(&token)), TaskOutcome::Done));
+        assert!(thread.is_entry_point_in_use(&library_name, symbol_name));
+        assert!(!thread.is_entry_point_in_use(&library_name, "ANativeService_onCreateOther"));
+        assert!(!thread.is_entry_point_in_use("libother.so", symbol_name));
+
+        // The second service from the same entry point is warned about above, and still hosted.
+        assert!(matches!(thread.handle_task(create(&other_token)), TaskOutcome::Done));
+        assert_eq!(thread.services.len(), 2);
+
+        // The entry point is in use as long as any of its services is alive.
+        assert!(matches!(thread.handle_task(destroy(&token)), TaskOutcome::Done));
+        assert!(thread.is_entry_point_in_use(&library_name, symbol_name));
+        assert!(matches!(thread.handle_task(destroy(&other_token)), TaskOutcome::Done));
+        assert!(!thread.is_entry_point_in_use(&library_name, symbol_name));
+    }
+
+    #[test]
+    fn pending_requests_for_destroyed_service_are_dropped() {
+        let (mut thread, _calls) = new_thread_with_mock_am();
+        let (token, other_token) = (new_token(), new_token());
+        let callbacks = ANativeServiceCallbacks { onBind: Some(stub_on_bind), ..empty_callbacks() };
+        thread.services.insert(token.clone(), NativeService::for_test(callbacks));
+        let foreground_request = |service_token: &SpIBinder| {
+            NativeApplicationThreadRequest::ForegroundStateChanged(ForegroundStateChangedRequest {
+                service_token: service_token.clone(),
+                fgs_type: 8,
+                has_notification: false,
+            })
+        };
+        assert!(thread.take_pending_task_filter().is_none());
+
+        assert!(matches!(
+            thread.handle_task(NativeApplicationThreadRequest::DestroyService(
+                DestroyServiceRequest { service_token: token.clone() }
+            )),
+            TaskOutcome::Done
+        ));
+
+        let mut keep = thread.take_pending_task_filter().unwrap();
+        assert!(!keep(&foreground_request(&token)));
+        assert!(keep(&foreground_request(&other_token)));
+        let trim_memory_request = |service_token: Option<&SpIBinder>| {
+            NativeApplicationThreadRequest::TrimMemory(TrimMemoryRequest {
+                level: ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_UI_HIDDEN,
+                service_token: service_token.cloned(),
+            })
+        };
+        assert!(keep(&trim_memory_request(None)));
+        assert!(!keep(&trim_memory_request(Some(&token))));
+        assert!(keep(&trim_memory_request(Some(&other_token))));
+        assert!(thread.take_pending_task_filter().is_none());
+    }
+
+    #[test]
+    fn bind_and_unbind_requests_are_counted() {
+        let (mut thread, _calls) = new_thread_with_mock_am();
+        let token = new_token();
+        let callbacks = ANativeServiceCallbacks {
+            onBind: Some(stub_on_bind),
+            onRebind: Some(stub_on_rebind),
+            ..empty_callbacks()
+        };
+        thread.services.insert(token.clone(), NativeService::for_test(callbacks));
+
+        for _ in 0..3 {
+            assert!(matches!(
+                thread.handle_task(NativeApplicationThreadRequest::BindService(
+                    BindServiceRequest::for_test(token.clone(), new_token(), 1, true)
+                )),
+                TaskOutcome::Done
+            ));
+            assert!(matches!(
+                thread.handle_task(NativeApplicationThreadRequest::UnbindService(
+                    UnbindServiceRequest {
+                        service_token: token.clone(),
+                        bind_token: new_token(),
+                        intent_hash: 1,
+                    }
+                )),
+                TaskOutcome::Done
+            ));
+        }
+        assert!(matches!(
+            thread.handle_task(NativeApplicationThreadRequest::BindService(
+                BindServiceRequest::for_test(token.clone(), new_token(), 2, true)
+            )),
+            TaskOutcome::Done
+        ));
+
+        let service = &thread.services[&token];
+        assert_eq!(service.bind_count, 4);
+        assert_eq!(service.unbind_count, 3);
+        let dump = thread.dump();
+        assert!(dump.contains("binds=4 unbinds=3"), "unexpected dump: {dump}");
+    }
+
+    #[test]
+    fn dump_reports_the_handler_not_responding_in_time() {
+        let (thread, _calls) = new_thread_with_mock_am();
+        let queued_creates = thread.queued_creates();
+        let handler = Handler::new_on_current_thread(thread).unwrap();
+        let app_thread =
+            NativeApplicationThread::new(handler.get_sender().unwrap(), queued_creates)
+                .with_dump_timeout(Duration::from_millis(10));
+
+        // The handler doesn't run while the dump waits.
+        let dump = std::thread::spawn(move || {
+            let mut dump = Vec::new();
+            app_thread.dump(&mut dump, &[]).unwrap();
+            String::from_utf8(dump).unwrap()
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(dump, "Failed to dump the state: No response within 10ms\n");
+    }
+
+    #[test]
+    fn request_for_unknown_service_fails_with_service_not_found() {
+        let (mut thread, _calls) = new_thread_with_mock_am();
+
+        let err = thread
+            .handle_unbind_service_request(UnbindServiceRequest {
+                service_token: new_token(),
+                bind_token: new_token(),
+                intent_hash: 1,
+            })
+            .unwrap_err();
+
+        assert!(matches!(err, ServiceError::ServiceNotFound), "unexpected error: {err:?}");
+    }
+
+    #[test]
+    fn bind_of_unknown_service_is_reported_and_keeps_handler_running() {
+        let (mut thread, calls) = new_thread_with_mock_am();
+        let token = new_token();
+
+        let outcome = thread.handle_task(NativeApplicationThreadRequest::BindService(
+            BindServiceRequest::for_test(token.clone(), new_token(), 1, false),
+        ));
+
+        assert!(matches!(outcome, TaskOutcome::Done));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [AmCall::ServiceDoneExecuting { token, type_: SERVICE_DONE_EXECUTING_ANON }]
+        );
+        assert!(!thread.is_finished());
+        // The next requests are still handled.
+        let outcome =
+            thread.handle_task(NativeApplicationThreadRequest::TrimMemory(TrimMemoryRequest {
+                level: ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_UI_HIDDEN,
+                service_token: None,
+            }));
+        assert!(matches!(outcome, TaskOutcome::Done));
+    }
+
+    #[test]
+    fn null_on_bind_return_fails_with_null_binder() {
+        let (mut thread, calls) = new_thread_with_mock_am();
+        let token = new_token();
+        let callbacks = ANativeServiceCallbacks { onBind: Some(stub_on_bind), ..empty_callbacks() };
+        thread.services.insert(token.clone(), NativeService::for_test(callbacks));
+
+        let err = thread
+            .handle_bind_service_request(BindServiceRequest::for_test(token, new_token(), 1, false))
+            .unwrap_err();
+
+        assert!(matches!(err, ServiceError::NullBinder("onBind")), "unexpected error: {err:?}");
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    thread_local! {
+        /// The categories and extras received by `recording_on_bind_with_intent`.
+        static RECEIVED_INTENT: std::cell::RefCell<Option<(Vec<String>, Vec<u8>)>> =
+            const { std::cell::RefCell::new(None) };
+    }
+
+    unsafe extern "C" fn recording_on_bind_with_intent(
+        _service: *mut ANativeService,
+        _intent_token: i32,
+        _action: *const c_char,
+        _data: *const c_char,
+        categories: *const *const c_char,
+        categories_count: usize,
+        extras: *const u8,
+        extras_size: usize,
+    ) -> *mut AIBinder {
+        // SAFETY: The caller passes `categories_count` valid C strings.
+        let categories = unsafe { std::slice::from_raw_parts(categories, categories_count) }
+            .iter()
+            // SAFETY: Each category is a valid C string.
+            .map(|&category| unsafe { std::ffi::CStr::from_ptr(category) })
+            .map(|category| category.to_string_lossy().into_owned())
+            .collect();
+        // SAFETY: The caller passes `extras_size` valid bytes.
+        let extras = unsafe { std::slice::from_raw_parts(extras, extras_size) }.to_vec();
+        RECEIVED_INTENT.with(|received| *received.borrow_mut() = Some((categories, extras)));
+        std::ptr::null_mut()
+    }
+
+    #[test]
+    fn bind_passes_categories_and_extras_to_on_bind_with_intent() {
+        let (mut thread, _calls) = new_thread_with_mock_am();
+        let token = new_token();
+        let callbacks = ANativeServiceCallbacks {
+            onBind: Some(stub_on_bind),
+            onBindWithIntent: Some(recording_on_bind_with_intent),
+            ..empty_callbacks()
+        };
+        thread.services.insert(token.clone(), NativeService::for_test(callbacks));
+
+        let err = thread
+            .handle_bind_service_request(BindServiceRequest {
+                action: Some("android.intent.action.MAIN".to_string()),
+                categories: vec![
+                    "android.intent.category.DEFAULT".to_string(),
+                    "com.example.category.PLUGIN".to_string(),
+                ],
+                extras: Some(vec![1, 2, 3]),
+                ..BindServiceRequest::for_test(token, new_token(), 1, false)
+            })
+            .unwrap_err();
+
+        assert!(matches!(err, ServiceError::NullBinder("onBindWithIntent")), "{err:?}");
+        let received = RECEIVED_INTENT.with(|received| received.borrow_mut().take());
+        assert_eq!(
+            received,
+            Some((
+                vec![
+                    "android.intent.category.DEFAULT".to_string(),
+                    "com.example.category.PLUGIN".to_string()
+                ],
+                vec![1, 2, 3]
+            ))
+        );
+    }
+
+    thread_local! {
+        /// The services which received `recording_on_trim_memory`.
+        static TRIMMED_SERVICES: std::cell::RefCell<Vec<*mut ANativeService>> =
+            const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    unsafe extern "C" fn recording_on_trim_memory(service: *mut ANativeService, _level: i32) {
+        TRIMMED_SERVICES.with(|trimmed| trimmed.borrow_mut().push(service));
+    }
+
+    #[test]
+    fn targeted_trim_memory_reaches_only_its_service() {
+        let (mut thread, _calls) = new_thread_with_mock_am();
+        let (token, other_token) = (new_token(), new_token());
+        let callbacks = ANativeServiceCallbacks {
+            onBind: Some(stub_on_bind),
+            onTrimMemory: Some(recording_on_trim_memory),
+            ..empty_callbacks()
+        };
+        for token in [&token, &other_token] {
+            let service = NativeService { has_ui: true, ..NativeService::for_test(callbacks) };
+            thread.services.insert(token.clone(), service);
+        }
+        let service_ptr = |thread: &mut NativeActivityThread, token: &SpIBinder| {
+            thread.services.get_mut(token).unwrap().service.as_mut() as *mut ANativeService
+        };
+        let level = ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_UI_HIDDEN;
+
+        thread
+            .handle_trim_memory_request(TrimMemoryRequest {
+                level,
+                service_token: Some(token.clone()),
+            })
+            .unwrap();
+        let trimmed = TRIMMED_SERVICES.with(|trimmed| std::mem::take(&mut *trimmed.borrow_mut()));
+        assert_eq!(trimmed, [service_ptr(&mut thread, &token)]);
+
+        thread
+            .handle_trim_memory_request(TrimMemoryRequest { level, service_token: None })
+            .unwrap();
+        let trimmed = TRIMMED_SERVICES.with(|trimmed| std::mem::take(&mut *trimmed.borrow_mut()));
+        assert_eq!(trimmed.len(), 2);
+        assert!(trimmed.contains(&service_ptr(&mut thread, &other_token)));
+
+        let err = thread
+            .handle_trim_memory_request(TrimMemoryRequest {
+                level,
+                service_token: Some(new_token()),
+            })
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::ServiceNotFound), "unexpected error: {err:?}");
+    }
+
+    thread_local! {
+        /// The services which received `recording_on_destroy`.
+        static DESTROYED_SERVICES: std::cell::RefCell<Vec<*mut ANativeService>> =
+            const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    unsafe extern "C" fn recording_on_destroy(service: *mut ANativeService) {
+        DESTROYED_SERVICES.with(|destroyed| destroyed.borrow_mut().push(service));
+    }
+
+    #[test]
+    fn shutdown_destroys_all_services() {
+        let (mut thread, calls) = new_thread_with_mock_am();
+        let callbacks = ANativeServiceCallbacks {
+            onBind: Some(stub_on_bind),
+            onDestroy: Some(recording_on_destroy),
+            ..empty_callbacks()
+        };
+        let tokens = [new_token(), new_token(), new_token()];
+        let mut service_ptrs = Vec::new();
+        for token in &tokens {
+            let mut service = NativeService::for_test(callbacks);
+            service_ptrs.push(service.service.as_mut() as *mut ANativeService);
+            thread.services.insert(token.clone(), service);
+        }
+
+        assert!(matches!(
+            thread.handle_task(NativeApplicationThreadRequest::Shutdown),
+            TaskOutcome::Done
+        ));
+
+        let mut destroyed =
+            DESTROYED_SERVICES.with(|destroyed| std::mem::take(&mut *destroyed.borrow_mut()));
+        destroyed.sort();
+        service_ptrs.sort();
+        assert_eq!(destroyed, service_ptrs);
+        assert!(thread.services.is_empty());
+        assert!(thread.is_finished());
+        let calls = calls.lock().unwrap();
+        for token in &tokens {
+            assert!(calls.contains(&AmCall::ServiceDoneExecuting {
+                token: token.clone(),
+                type_: SERVICE_DONE_EXECUTING_STOP
+            }));
+        }
+    }
+
+    #[test]
+    fn service_capabilities_match_populated_callbacks() {
+        assert_eq!(service_capabilities(&empty_callbacks()), 0);
+
+        let callbacks = ANativeServiceCallbacks {
+            onBind: Some(stub_on_bind),
+            onRebind: Some(stub_on_rebind),
+            onDestroy: Some(recording_on_destroy),
+            ..empty_callbacks()
+        };
+        assert_eq!(
+            service_capabilities(&callbacks),
+            SERVICE_CAPABILITY_BIND | SERVICE_CAPABILITY_REBIND | SERVICE_CAPABILITY_DESTROY
+        );
+
+        let callbacks = ANativeServiceCallbacks {
+            onBindWithIntent: Some(recording_on_bind_with_intent),
+            onTrimMemory: Some(recording_on_trim_memory),
+            ..empty_callbacks()
+        };
+        assert_eq!(
+            service_capabilities(&callbacks),
+            SERVICE_CAPABILITY_BIND_WITH_INTENT | SERVICE_CAPABILITY_TRIM_MEMORY
+        );
+    }
+
+    #[test]
+    fn failed_capability_report_keeps_service() {
+        let (mut thread, calls) = new_thread_with(MockActivityManager {
+            set_service_capabilities_error: Some(binder::StatusCode::DEAD_OBJECT),
+            ..MockActivityManager::default()
+        });
+        thread.load_library = load_bindable_service;
+        let token = new_token();
+
+        thread
+            .handle_create_service_request(CreateServiceRequest::for_test(token.clone()))
+            .unwrap();
+
+        assert!(thread.services.contains_key(&token));
+        assert_eq!(
+            calls.lock().unwrap().last(),
+            Some(&AmCall::ServiceDoneExecuting {
+                token: token.clone(),
+                type_: SERVICE_DONE_EXECUTING_ANON
+            })
+        );
+    }
+
+    #[test]
+    fn ui_hidden_reaches_only_services_with_ui() {
+        let (mut thread, _calls) = new_thread_with_mock_am();
+        let (ui_token, non_ui_token) = (new_token(), new_token());
+        let callbacks = ANativeServiceCallbacks {
+            onBind: Some(stub_on_bind),
+            onTrimMemory: Some(recording_on_trim_memory),
+            ..empty_callbacks()
+        };
+        let ui_service = NativeService { has_ui: true, ..NativeService::for_test(callbacks) };
+        thread.services.insert(ui_token.clone(), ui_service);
+        thread.services.insert(non_ui_token.clone(), NativeService::for_test(callbacks));
+        let ui_service_ptr =
+            thread.services.get_mut(&ui_token).unwrap().service.as_mut() as *mut ANativeService;
+
+        thread
+            .handle_trim_memory_request(TrimMemoryRequest {
+                level: ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_UI_HIDDEN,
+                service_token: None,
+            })
+            .unwrap();
+        let trimmed = TRIMMED_SERVICES.with(|trimmed| std::mem::take(&mut *trimmed.borrow_mut()));
+        assert_eq!(trimmed, [ui_service_ptr]);
+
+        thread
+            .handle_trim_memory_request(TrimMemoryRequest {
+                level: ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_UI_HIDDEN,
+                service_token: Some(non_ui_token),
+            })
+            .unwrap();
+        let trimmed = TRIMMED_SERVICES.with(|trimmed| std::mem::take(&mut *trimmed.borrow_mut()));
+        assert!(trimmed.is_empty(), "UI_HIDDEN should not reach a service without UI");
+
+        // BACKGROUND is delivered regardless of UI in a background process.
+        thread.process_state = ProcessStateEnum::CACHED_EMPTY;
+        thread
+            .handle_trim_memory_request(TrimMemoryRequest {
+                level: ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_BACKGROUND,
+                service_token: None,
+            })
+            .unwrap();
+        let trimmed = TRIMMED_SERVICES.with(|trimmed| std::mem::take(&mut *trimmed.borrow_mut()));
+        assert_eq!(trimmed.len(), 2);
+    }
+
+    #[test]
+    fn background_trim_memory_is_gated_by_process_state() {
+        let (mut thread, _calls) = new_thread_with_mock_am();
+        let token = new_token();
+        let callbacks = ANativeServiceCallbacks {
+            onBind: Some(stub_on_bind),
+            onTrimMemory: Some(recording_on_trim_memory),
+            ..empty_callbacks()
+        };
+        let service = NativeService { has_ui: true, ..NativeService::for_test(callbacks) };
+        thread.services.insert(token.clone(), service);
+        let service_ptr =
+            thread.services.get_mut(&token).unwrap().service.as_mut() as *mut ANativeService;
+        let trim = |thread: &mut NativeActivityThread, level| {
+            thread
+                .handle_trim_memory_request(TrimMemoryRequest {
+                    level,
+                    service_token: Some(token.clone()),
+                })
+                .unwrap();
+            TRIMMED_SERVICES.with(|trimmed| std::mem::take(&mut *trimmed.borrow_mut()))
+        };
+
+        for state in [ProcessStateEnum::TOP, ProcessStateEnum::IMPORTANT_FOREGROUND] {
+            thread.process_state = state;
+            let trimmed = trim(
+                &mut thread,
+                ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_BACKGROUND,
+            );
+            assert!(trimmed.is_empty(), "BACKGROUND should not reach a service in {state:?}");
+            let trimmed = trim(
+                &mut thread,
+                ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_UI_HIDDEN,
+            );
+            assert_eq!(trimmed, [service_ptr], "UI_HIDDEN should reach the service in {state:?}");
+        }
+
+        thread.process_state = ProcessStateEnum::IMPORTANT_BACKGROUND;
+        let trimmed =
+            trim(&mut thread, ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_BACKGROUND);
+        assert_eq!(trimmed, [service_ptr]);
+    }
+
+    unsafe extern "C" fn create_destroyable_service(service: *mut ANativeService) {
+        // SAFETY: `service` points to a valid variable.
+        unsafe {
+            (*service).callbacks.onBind = Some(stub_on_bind);
+            (*service).callbacks.onDestroy = Some(recording_on_destroy);
+        }
+    }
+
+    fn load_destroyable_service(
+        _namespace_factory: &NamespaceFactory,
+        _req: &CreateServiceRequest,
+    ) -> Result<ServiceLibrary> {
+        Ok(ServiceLibrary::for_test(Some(create_destroyable_service)))
+    }
+
+    #[test]
+    fn destroy_received_while_service_is_created_runs_once_it_is_created() {
+        let (mut thread, calls) = new_thread_with_mock_am();
+        thread.load_library = load_destroyable_service;
+        let queued_creates = thread.queued_creates();
+        let mut handler = Handler::new_on_current_thread(thread).unwrap();
+        let loader_sender = handler.get_sender().unwrap();
+        handler
+            .callback_mut()
+            .enable_async_library_loading(loader_sender, NonZeroUsize::MIN)
+            .unwrap();
+        let app_thread =
+            NativeApplicationThread::new(handler.get_sender().unwrap(), queued_creates.clone());
+        let token = new_token();
+
+        app_thread
+            .scheduleCreateService(
+                &token,
+                &[],
+                &[],
+                "",
+                "libtest_service.so",
+                &["ANativeService_onCreate".to_string()],
+                0,
+                false,
+            )
+            .unwrap();
+        app_thread.scheduleDestroyService(&token).unwrap();
+        let stopped = AmCall::ServiceDoneExecuting {
+            token: token.clone(),
+            type_: SERVICE_DONE_EXECUTING_STOP,
+        };
+        while !calls.lock().unwrap().contains(&stopped) {
+            run_thread_loop_once().unwrap();
+        }
+
+        let destroyed =
+            DESTROYED_SERVICES.with(|destroyed| std::mem::take(&mut *destroyed.borrow_mut()));
+        assert_eq!(destroyed.len(), 1);
+        assert!(handler.callback_mut().services.is_empty());
+        assert!(handler.callback_mut().deferred_destroys.is_empty());
+        assert!(queued_creates.lock().unwrap().is_empty());
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                AmCall::SetServiceCapabilities {
+                    token: token.clone(),
+                    capabilities: SERVICE_CAPABILITY_BIND | SERVICE_CAPABILITY_DESTROY
+                },
+                AmCall::ServiceDoneExecuting { token, type_: SERVICE_DONE_EXECUTING_ANON },
+                stopped,
+            ]
+        );
+    }
+
+    #[test]
+    fn request_received_while_service_is_created_is_retried_after_transient_failure() {
+        let (mut thread, calls) = new_thread_with(MockActivityManager {
+            set_service_foreground_failures: AtomicU32::new(1),
+            ..Default::default()
+        });
+        thread.load_library = load_destroyable_service;
+        let queued_creates = thread.queued_creates();
+        let mut handler = Handler::new_on_current_thread(thread).unwrap();
+        let loader_sender = handler.get_sender().unwrap();
+        handler
+            .callback_mut()
+            .enable_async_library_loading(loader_sender, NonZeroUsize::MIN)
+            .unwrap();
+        let app_thread =
+            NativeApplicationThread::new(handler.get_sender().unwrap(), queued_creates);
+        let token = new_token();
+
+        app_thread
+            .scheduleCreateService(
+                &token,
+                &[],
+                &[],
+                "",
+                "libtest_service.so",
+                &["ANativeService_onCreate".to_string()],
+                0,
+                false,
+            )
+            .unwrap();
+        app_thread.scheduleForegroundStateChanged(&token, 1, true).unwrap();
+        app_thread.scheduleDestroyService(&token).unwrap();
+        let stopped = AmCall::ServiceDoneExecuting {
+            token: token.clone(),
+            type_: SERVICE_DONE_EXECUTING_STOP,
+        };
+        while !calls.lock().unwrap().contains(&stopped) {
+            run_thread_loop_once().unwrap();
+        }
+
+        DESTROYED_SERVICES.with(|destroyed| destroyed.borrow_mut().clear());
+        assert!(!handler.is_broken());
+        let foreground = AmCall::SetServiceForeground {
+            token: token.clone(),
+            fgs_type: 1,
+            has_notification: true,
+        };
+        // The failed request is handled again before the destroy parked after it.
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                AmCall::SetServiceCapabilities {
+                    token: token.clone(),
+                    capabilities: SERVICE_CAPABILITY_BIND | SERVICE_CAPABILITY_DESTROY
+                },
+                AmCall::ServiceDoneExecuting { token, type_: SERVICE_DONE_EXECUTING_ANON },
+                foreground.clone(),
+                foreground,
+                stopped,
+            ]
+        );
+    }
+
+    #[test]
+    fn create_without_base_symbol_name_is_rejected() {
+        let (thread, _calls) = new_thread_with_mock_am();
+        let queued_creates = thread.queued_creates();
+        let handler = Handler::new_on_current_thread(thread).unwrap();
+        let app_thread =
+            NativeApplicationThread::new(handler.get_sender().unwrap(), queued_creates.clone());
+
+        let result = app_thread.scheduleCreateService(
+            &new_token(),
+            &[],
+            &[],
+            "",
+            "libtest_service.so",
+            &[],
+            0,
+            false,
+        );
+
+        assert!(result.is_err());
+        assert!(queued_creates.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn destroy_is_deferred_only_if_create_is_queued() {
+        let (mut thread, calls) = new_thread_with_mock_am();
+        thread.load_library = load_destroyable_service;
+
+        let err = thread
+            .handle_destroy_service_request(DestroyServiceRequest { service_token: new_token() })
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::ServiceNotFound), "unexpected error: {err:?}");
+        assert!(thread.deferred_destroys.is_empty());
+
+        // The create request was sent first, but is handled after the destroy request.
+        let token = new_token();
+        thread.queued_creates().lock().unwrap().insert(token.clone());
+        thread
+            .handle_destroy_service_request(DestroyServiceRequest { service_token: token.clone() })
+            .unwrap();
+        assert!(thread.deferred_destroys.contains(&token));
+        assert!(calls.lock().unwrap().is_empty());
+
+        thread
+            .handle_create_service_request(CreateServiceRequest::for_test(token.clone()))
+            .unwrap();
+
+        let destroyed =
+            DESTROYED_SERVICES.with(|destroyed| std::mem::take(&mut *destroyed.borrow_mut()));
+        assert_eq!(destroyed.len(), 1);
+        assert!(thread.services.is_empty());
+        assert!(thread.deferred_destroys.is_empty());
+        assert_eq!(
+            calls.lock().unwrap().last(),
+            Some(&AmCall::ServiceDoneExecuting { token, type_: SERVICE_DONE_EXECUTING_STOP })
+        );
+    }
+
+    thread_local! {
+        static UNBOUND_SERVICES: std::cell::RefCell<Vec<(*mut ANativeService, i32)>> =
+            const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    unsafe extern "C" fn publishable_on_bind(
+        _service: *mut ANativeService,
+        _intent_token: i32,
+        _action: *const c_char,
+        _data: *const c_char,
+    ) -> *mut AIBinder {
+        // Hand out a strong reference to a binder, as onBind implementations do.
+        let mut binder = new_token();
+        let binder_ptr = binder.as_native_mut();
+        std::mem::forget(binder);
+        binder_ptr as *mut AIBinder
+    }
+
+    unsafe extern "C" fn recording_on_unbind(
+        service: *mut ANativeService,
+        intent_token: i32,
+    ) -> bool {
+        UNBOUND_SERVICES.with(|unbound| unbound.borrow_mut().push((service, intent_token)));
+        false
+    }
+
+    #[test]
+    fn failed_publish_unbinds_service() {
+        let (mut thread, calls) = new_thread_with(MockActivityManager {
+            publish_service_error: Some(binder::StatusCode::DEAD_OBJECT),
+            ..MockActivityManager::default()
+        });
+        let token = new_token();
+        let callbacks = ANativeServiceCallbacks {
+            onBind: Some(publishable_on_bind),
+            onUnbind: Some(recording_on_unbind),
+            ..empty_callbacks()
+        };
+        let mut service = NativeService::for_test(callbacks);
+        let service_ptr = service.service.as_mut() as *mut ANativeService;
+        thread.services.insert(token.clone(), service);
+
+        let err = thread
+            .handle_bind_service_request(BindServiceRequest::for_test(
+                token.clone(),
+                new_token(),
+                7,
+                false,
+            ))
+            .unwrap_err();
+
+        assert!(
+            matches!(err, ServiceError::BinderCall { method: "publishService", .. }),
+            "unexpected error: {err:?}"
+        );
+        assert_eq!(*calls.lock().unwrap(), [AmCall::PublishService { token }]);
+        let unbound = UNBOUND_SERVICES.with(|unbound| std::mem::take(&mut *unbound.borrow_mut()));
+        assert_eq!(unbound, [(service_ptr, 7)]);
+    }
+
+    #[test]
+    fn request_debug_shows_token_identity_only() {
+        let (service_token, bind_token) = (new_token(), new_token());
+        let bind_request = BindServiceRequest {
+            extras: Some(vec![0xde, 0xad, 0xbe, 0xef]),
+            ..BindServiceRequest::for_test(service_token.clone(), bind_token.clone(), 3, false)
+        };
+        let create_request = CreateServiceRequest::for_test(service_token.clone());
+
+        let bind_debug = format!("{:?}", bind_request);
+        let create_debug = format!("{:?}", create_request);
+        info!("Built {} and {}", bind_debug, create_debug);
+
+        assert!(
+            bind_debug.contains(&format!("service_token: {:p}", service_token.as_native())),
+            "unexpected debug: {bind_debug}"
+        );
+        assert!(
+            bind_debug.contains(&format!("bind_token: {:p}", bind_token.as_native())),
+            "unexpected debug: {bind_debug}"
+        );
+        assert!(bind_debug.contains("intent_hash: 3"), "unexpected debug: {bind_debug}");
+        assert!(bind_debug.contains("extras_size: Some(4)"), "unexpected debug: {bind_debug}");
+        assert!(
+            create_debug.contains("library_name: \"libnonexistent_service.so\""),
+            "unexpected debug: {create_debug}"
+        );
+        assert!(format!("{:?}", NativeApplicationThreadRequest::CreateService(create_request))
+            .starts_with("CreateService(CreateServiceRequest {"));
+    }
+
+    /// The number of services created at once by `services_are_created_concurrently`.
+    const CONCURRENT_SERVICES: usize = 3;
+
+    /// The number of libraries whose loading started, notified on each start.
+    static STARTED_LOADS: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+
+    unsafe extern "C" fn create_bindable_service(service: *mut ANativeService) {
+        // SAFETY: `service` points to a valid variable.
+        unsafe { (*service).callbacks.onBind = Some(publishable_on_bind) };
+    }
+
+    /// Loads a placeholder library once `CONCURRENT_SERVICES` libraries are being loaded.
+    fn load_library_concurrently(
+        _namespace_factory: &NamespaceFactory,
+        _req: &CreateServiceRequest,
+    ) -> Result<ServiceLibrary> {
+        let (started, all_started) = &STARTED_LOADS;
+        let mut started = started.lock().unwrap();
+        *started += 1;
+        all_started.notify_all();
+        let (_started, wait) = all_started
+            .wait_timeout_while(started, Duration::from_secs(5), |started| {
+                *started < CONCURRENT_SERVICES
+            })
+            .unwrap();
+        if wait.timed_out() {
+            return Err(anyhow!("The libraries were not loaded concurrently"));
+        }
+        Ok(ServiceLibrary::for_test(Some(create_bindable_service)))
+    }
+
+    #[test]
+    fn services_are_created_concurrently() {
+        let (mut thread, calls) = new_thread_with_mock_am();
+        thread.load_library = load_library_concurrently;
+        let mut handler = Handler::new_on_current_thread(thread).unwrap();
+        let loader_sender = handler.get_sender().unwrap();
+        handler
+            .callback_mut()
+            .enable_async_library_loading(
+                loader_sender,
+                NonZeroUsize::new(CONCURRENT_SERVICES).unwrap(),
+            )
+            .unwrap();
+        let sender = handler.get_sender().unwrap();
+
+        let tokens: Vec<SpIBinder> = (0..CONCURRENT_SERVICES).map(|_| new_token()).collect();
+        for token in &tokens {
+            sender
+                .send(NativeApplicationThreadRequest::CreateService(
+                    CreateServiceRequest::for_test(token.clone()),
+                ))
+                .unwrap();
+            // Bound before the service is created, so the request waits for the creation.
+            sender
+                .send(NativeApplicationThreadRequest::BindService(BindServiceRequest::for_test(
+                    token.clone(),
+                    new_token(),
+                    1,
+                    false,
+                )))
+                .unwrap();
+        }
+        let published_count = || {
+            calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|call| matches!(call, AmCall::PublishService { .. }))
+                .count()
+        };
+        while published_count() < CONCURRENT_SERVICES {
+            run_thread_loop_once().unwrap();
+        }
+
+        let calls = calls.lock().unwrap();
+        for token in &tokens {
+            let created = AmCall::ServiceDoneExecuting {
+                token: token.clone(),
+                type_: SERVICE_DONE_EXECUTING_ANON,
+            };
+            let published = AmCall::PublishService { token: token.clone() };
+            let created_at = calls.iter().position(|call| *call == created);
+            let published_at = calls.iter().position(|call| *call == published);
+            assert!(
+                created_at.is_some_and(|created_at| Some(created_at) < published_at),
+                "unexpected calls: {calls:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn slow_service_done_executing_times_out_without_failing_the_request() {
+        let (mut thread, calls) = new_thread_with(MockActivityManager {
+            service_done_executing_delay: Duration::from_millis(500),
+            ..Default::default()
+        });
+        thread.set_binder_call_timeout(Duration::from_millis(20)).unwrap();
+        let token = new_token();
+        let callbacks = ANativeServiceCallbacks { onBind: Some(stub_on_bind), ..empty_callbacks() };
+        thread.services.insert(token.clone(), NativeService::for_test(callbacks));
+
+        let start = Instant::now();
+        let outcome = thread.handle_task(NativeApplicationThreadRequest::DestroyService(
+            DestroyServiceRequest { service_token: token.clone() },
+        ));
+
+        assert!(matches!(outcome, TaskOutcome::Done));
+        assert!(start.elapsed() < Duration::from_millis(500), "the call wasn't timed out");
+        assert!(thread.services.is_empty());
+        // The requests pending for the destroyed service are dropped all the same.
+        assert!(thread.take_pending_task_filter().is_some());
+        // The call still completes in the background.
+        while calls.lock().unwrap().is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [AmCall::ServiceDoneExecuting { token, type_: SERVICE_DONE_EXECUTING_STOP }]
+        );
+    }
+
+    #[test]
+    fn slow_create_report_times_out_and_keeps_the_service() {
+        let (mut thread, _calls) = new_thread_with(MockActivityManager {
+            service_done_executing_delay: Duration::from_millis(500),
+            ..Default::default()
+        });
+        thread.set_binder_call_timeout(Duration::from_millis(20)).unwrap();
+        thread.load_library = load_destroyable_service;
+        let token = new_token();
+
+        let outcome = thread.handle_task(NativeApplicationThreadRequest::CreateService(
+            CreateServiceRequest::for_test(token.clone()),
+        ));
+
+        assert!(matches!(outcome, TaskOutcome::Done));
+        assert!(thread.services.contains_key(&token));
+    }
+
+    #[test]
+    fn slow_create_report_of_loaded_library_is_not_fatal() {
+        let (mut thread, _calls) = new_thread_with(MockActivityManager {
+            service_done_executing_delay: Duration::from_millis(500),
+            ..Default::default()
+        });
+        thread.set_binder_call_timeout(Duration::from_millis(20)).unwrap();
+        let token = new_token();
+        let request = CreateServiceRequest::for_test(token.clone());
+        let library = load_destroyable_service(&thread.namespace_factory, &request)
+            .map_err(ServiceError::LibraryLoad);
+
+        let outcome = thread.handle_task(NativeApplicationThreadRequest::LibraryLoaded(
+            LibraryLoadedRequest { request, library },
+        ));
+
+        assert!(matches!(outcome, TaskOutcome::Done));
+        assert!(thread.services.contains_key(&token));
+    }
+
+    fn load_bindable_service(
+        _namespace_factory: &NamespaceFactory,
+        _req: &CreateServiceRequest,
+    ) -> Result<ServiceLibrary> {
+        Ok(ServiceLibrary::for_test(Some(create_bindable_service)))
+    }
+
+    #[test]
+    fn lifecycle_events_follow_request_order() {
+        let (mut thread, _calls) = new_thread_with_mock_am();
+        thread.load_library = load_bindable_service;
+        let (observer, events) = mpsc::channel();
+        thread.set_lifecycle_observer(observer);
+        let token = new_token();
+
+        for request in [
+            NativeApplicationThreadRequest::CreateService(CreateServiceRequest::for_test(
+                token.clone(),
+            )),
+            NativeApplicationThreadRequest::BindService(BindServiceRequest::for_test(
+                token.clone(),
+                new_token(),
+                1,
+                false,
+            )),
+            NativeApplicationThreadRequest::UnbindService(UnbindServiceRequest {
+                service_token: token.clone(),
+                bind_token: new_token(),
+                intent_hash: 1,
+            }),
+            NativeApplicationThreadRequest::DestroyService(DestroyServiceRequest {
+                service_token: token.clone(),
+            }),
+        ] {
+            assert!(matches!(thread.handle_task(request), TaskOutcome::Done));
+        }
+        drop(thread);
+
+        let events: Vec<LifecycleEvent> = events.iter().collect();
+        let expected: Vec<LifecycleEvent> = [
+            LifecycleCallback::Create,
+            LifecycleCallback::Bind,
+            LifecycleCallback::Unbind,
+            LifecycleCallback::Destroy,
+        ]
+        .into_iter()
+        .flat_map(|callback| {
+            [
+                LifecycleEvent::Started(callback, token.clone()),
+                LifecycleEvent::Finished(callback, token.clone()),
+            ]
+        })
+        .collect();
+        assert_eq!(events, expected);
+    }
+
+    thread_local! {
+        static LOADED_LIBRARIES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    fn counting_load_bindable_service(
+        namespace_factory: &NamespaceFactory,
+        req: &CreateServiceRequest,
+    ) -> Result<ServiceLibrary> {
+        LOADED_LIBRARIES.set(LOADED_LIBRARIES.get() + 1);
+        load_bindable_service(namespace_factory, req)
+    }
+
+    #[test]
+    fn library_rejected_by_validator_is_not_loaded() {
+        let (mut thread, calls) = new_thread_with_mock_am();
+        thread.load_library = counting_load_bindable_service;
+        thread.set_library_validator(Arc::new(|library_name: &str, library_paths: &[String]| {
+            if library_paths.iter().all(|path| path.starts_with("/system/lib64")) {
+                Ok(())
+            } else {
+                Err(anyhow!("{library_name} is searched outside /system/lib64: {library_paths:?}"))
+            }
+        }));
+        let allowed_token = new_token();
+        let mut allowed = CreateServiceRequest::for_test(allowed_token.clone());
+        allowed.library_paths = vec!["/system/lib64/service".to_string()];
+        let token = new_token();
+        let mut rejected = CreateServiceRequest::for_test(token.clone());
+        rejected.library_paths = vec!["/data/local/tmp".to_string()];
+
+        for request in [allowed, rejected] {
+            let outcome =
+                thread.handle_task(NativeApplicationThreadRequest::CreateService(request));
+            assert!(matches!(outcome, TaskOutcome::Done));
+        }
+
+        assert_eq!(LOADED_LIBRARIES.get(), 1);
+        assert!(thread.services.contains_key(&allowed_token));
+        assert!(!thread.services.contains_key(&token));
+        // The rejected service is reported as stopped, and its pending requests are dropped.
+        assert_eq!(
+            calls.lock().unwrap().last(),
+            Some(&AmCall::ServiceDoneExecuting { token, type_: SERVICE_DONE_EXECUTING_STOP })
+        );
+        assert!(thread.take_pending_task_filter().is_some());
+    }
+
+    #[test]
+    fn library_rejected_off_the_handler_thread_drops_the_parked_requests() {
+        let (mut thread, calls) = new_thread_with_mock_am();
+        let token = new_token();
+        let bind = NativeApplicationThreadRequest::BindService(BindServiceRequest::for_test(
+            token.clone(),
+            new_token(),
+            1,
+            false,
+        ));
+        thread.loading_services.insert(token.clone(), vec![bind]);
+        let library = Err(ServiceError::LibraryRejected(anyhow!("outside /system/lib64")));
+
+        let outcome = thread.handle_task(NativeApplicationThreadRequest::LibraryLoaded(
+            LibraryLoadedRequest {
+                request: CreateServiceRequest::for_test(token.clone()),
+                library,
+            },
+        ));
+
+        assert!(matches!(outcome, TaskOutcome::Done));
+        assert!(thread.services.is_empty());
+        assert!(thread.loading_services.is_empty());
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [AmCall::ServiceDoneExecuting { token, type_: SERVICE_DONE_EXECUTING_STOP }]
+        );
+    }
+
+    unsafe extern "C" fn create_trimmable_service(service: *mut ANativeService) {
+        // SAFETY: `service` points to a valid variable.
+        unsafe {
+            (*service).callbacks.onBind = Some(stub_on_bind);
+            (*service).callbacks.onTrimMemory = Some(recording_on_trim_memory);
         }
     }
+
+    #[test]
+    fn trim_memory_of_all_services_reaches_services_still_loading() {
+        let (mut thread, _calls) = new_thread_with_mock_am();
+        thread.process_state = ProcessStateEnum::SERVICE;
+        let token = new_token();
+        thread.loading_services.insert(token.clone(), Vec::new());
+
+        let outcome =
+            thread.handle_task(NativeApplicationThreadRequest::TrimMemory(TrimMemoryRequest {
+                level: ANativeServiceTrimMemoryLevel_ANATIVE_SERVICE_TRIM_MEMORY_BACKGROUND,
+                service_token: None,
+            }));
+        assert!(matches!(outcome, TaskOutcome::Done));
+        assert!(TRIMMED_SERVICES.with(|trimmed| trimmed.borrow().is_empty()));
+
+        let outcome = thread.handle_task(NativeApplicationThreadRequest::LibraryLoaded(
+            LibraryLoadedRequest {
+                request: CreateServiceRequest::for_test(token.clone()),
+                library: Ok(ServiceLibrary::for_test(Some(create_trimmable_service))),
+            },
+        ));
+
+        assert!(matches!(outcome, TaskOutcome::Done));
+        let service_ptr =
+            thread.services.get_mut(&token).unwrap().service.as_mut() as *mut ANativeService;
+        let trimmed = TRIMMED_SERVICES.with(|trimmed| std::mem::take(&mut *trimmed.borrow_mut()));
+        assert_eq!(trimmed, [service_ptr]);
+    }
+
+    #[test]
+    fn create_beyond_max_services_is_rejected() {
+        let (mut thread, calls) = new_thread_with_mock_am();
+        thread.load_library = counting_load_bindable_service;
+        thread.set_max_services(2);
+        let tokens: Vec<SpIBinder> = (0..3).map(|_| new_token()).collect();
+
+        for token in &tokens {
+            assert!(matches!(
+                thread.handle_task(NativeApplicationThreadRequest::CreateService(
+                    CreateServiceRequest::for_test(token.clone())
+                )),
+                TaskOutcome::Done
+            ));
+        }
+
+        assert_eq!(LOADED_LIBRARIES.get(), 2);
+        assert_eq!(thread.services.len(), 2);
+        assert!(!thread.services.contains_key(&tokens[2]));
+        assert_eq!(
+            calls.lock().unwrap().last(),
+            Some(&AmCall::ServiceDoneExecuting {
+                token: tokens[2].clone(),
+                type_: SERVICE_DONE_EXECUTING_STOP
+            })
+        );
+        // The existing services keep working.
+        assert!(matches!(
+            thread.handle_task(NativeApplicationThreadRequest::BindService(
+                BindServiceRequest::for_test(tokens[0].clone(), new_token(), 1, false)
+            )),
+            TaskOutcome::Done
+        ));
+        assert_eq!(
+            calls.lock().unwrap().last(),
+            Some(&AmCall::PublishService { token: tokens[0].clone() })
+        );
+    }
 }
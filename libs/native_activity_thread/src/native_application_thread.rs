@@ -13,20 +13,46 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use binder::{Interface, SpIBinder};
+use binder::{unstable_api::AsNative, Interface, SpIBinder};
 use log::info;
 use native_application_thread_aidl::aidl::android::app::INativeApplicationThread::INativeApplicationThread;
-use std::{marker::PhantomData, thread};
+use std::{
+    collections::BTreeSet,
+    ffi::CStr,
+    fmt,
+    io::Write,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
 
-use crate::task::Sender;
+use crate::library_loader::ServiceLibrary;
+use crate::service_error::ServiceError;
+use crate::task::{Responder, Sender};
 
+/// Formats a binder token by its identity only, the address of its binder object.
+struct Token<'a>(&'a SpIBinder);
+
+impl fmt::Debug for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:p}", self.0.as_native())
+    }
+}
+
+#[derive(Clone)]
 pub struct CreateServiceRequest {
     pub service_token: SpIBinder,
     pub library_paths: Vec<String>,
+    /// Where the plugins the service loads at runtime are searched, after `library_paths`.
+    pub plugin_library_paths: Vec<String>,
     pub permitted_libs_dir: String,
     pub library_name: String,
-    pub base_symbol_name: String,
+    /// The names the entry point may be exported under, tried in order.
+    pub base_symbol_names: Vec<String>,
     pub _process_state: i32,
+    /// Whether the service is associated with UI of the application.
+    pub has_ui: bool,
     // Have a private field to ensure instances are not created outside the module.
     _marker: PhantomData<()>,
 }
@@ -34,32 +60,91 @@ pub struct CreateServiceRequest {
 impl CreateServiceRequest {
     /// # Safety
     ///
-    /// Users must ensure that `library_name` specifies a safe dynamic library and it has a
-    /// function named `base_symbol_name` with the type signature `ANativeService_createFunc`.
+    /// Users must ensure that `library_name` specifies a safe dynamic library and that the
+    /// functions it exports under `base_symbol_names` have the type signature
+    /// `ANativeService_createFunc`.
     unsafe fn new(
         service_token: SpIBinder,
         library_paths: Vec<String>,
         permitted_libs_dir: String,
         library_name: String,
-        base_symbol_name: String,
+        base_symbol_names: Vec<String>,
         process_state: i32,
+        has_ui: bool,
     ) -> Self {
         Self {
             service_token,
             library_paths,
+            plugin_library_paths: Vec::new(),
             permitted_libs_dir,
             library_name,
-            base_symbol_name,
+            base_symbol_names,
             _process_state: process_state,
+            has_ui,
             _marker: PhantomData,
         }
     }
+
+    /// Sets where the plugins the service loads at runtime are searched.
+    pub fn with_plugin_library_paths(mut self, plugin_library_paths: Vec<String>) -> Self {
+        self.plugin_library_paths = plugin_library_paths;
+        self
+    }
+
+    /// Returns all the paths the library of the service and its plugins are searched in.
+    pub fn search_paths(&self) -> Vec<String> {
+        self.library_paths.iter().chain(&self.plugin_library_paths).cloned().collect()
+    }
 }
 
+#[cfg(test)]
+impl CreateServiceRequest {
+    /// Creates a request for a service in a library which doesn't exist, so that handling the
+    /// request fails to load it.
+    pub fn for_test(service_token: SpIBinder) -> Self {
+        Self {
+            service_token,
+            library_paths: Vec::new(),
+            plugin_library_paths: Vec::new(),
+            permitted_libs_dir: String::new(),
+            library_name: "libnonexistent_service.so".to_string(),
+            base_symbol_names: vec!["ANativeService_onCreate".to_string()],
+            _process_state: 0,
+            has_ui: false,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl fmt::Debug for CreateServiceRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CreateServiceRequest")
+            .field("service_token", &Token(&self.service_token))
+            .field("library_paths", &self.library_paths)
+            .field("plugin_library_paths", &self.plugin_library_paths)
+            .field("permitted_libs_dir", &self.permitted_libs_dir)
+            .field("library_name", &self.library_name)
+            .field("base_symbol_names", &self.base_symbol_names)
+            .field("process_state", &self._process_state)
+            .field("has_ui", &self.has_ui)
+            .finish()
+    }
+}
+
+#[derive(Clone)]
 pub struct DestroyServiceRequest {
     pub service_token: SpIBinder,
 }
 
+impl fmt::Debug for DestroyServiceRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DestroyServiceRequest")
+            .field("service_token", &Token(&self.service_token))
+            .finish()
+    }
+}
+
+#[derive(Clone)]
 pub struct BindServiceRequest {
     pub service_token: SpIBinder,
     pub bind_token: SpIBinder,
@@ -69,49 +154,222 @@ pub struct BindServiceRequest {
     pub rebind: bool,
     pub _process_state: i32,
     pub _bind_seq: i64,
+    /// Categories of the intent. Empty if the intent has none.
+    pub categories: Vec<String>,
+    /// Extras of the intent, as a serialized bundle.
+    pub extras: Option<Vec<u8>>,
 }
 
+#[cfg(test)]
+impl BindServiceRequest {
+    /// Creates a request with an intent without action, data, categories or extras.
+    pub fn for_test(
+        service_token: SpIBinder,
+        bind_token: SpIBinder,
+        intent_hash: i32,
+        rebind: bool,
+    ) -> Self {
+        Self {
+            service_token,
+            bind_token,
+            intent_hash,
+            action: None,
+            data: None,
+            rebind,
+            _process_state: 0,
+            _bind_seq: 0,
+            categories: Vec::new(),
+            extras: None,
+        }
+    }
+}
+
+impl fmt::Debug for BindServiceRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BindServiceRequest")
+            .field("service_token", &Token(&self.service_token))
+            .field("bind_token", &Token(&self.bind_token))
+            .field("intent_hash", &self.intent_hash)
+            .field("action", &self.action)
+            .field("data", &self.data)
+            .field("rebind", &self.rebind)
+            .field("process_state", &self._process_state)
+            .field("bind_seq", &self._bind_seq)
+            .field("categories", &self.categories)
+            // The bundle is opaque, only its size is meaningful in logs.
+            .field("extras_size", &self.extras.as_ref().map(Vec::len))
+            .finish()
+    }
+}
+
+#[derive(Clone)]
 pub struct UnbindServiceRequest {
     pub service_token: SpIBinder,
     pub bind_token: SpIBinder,
     pub intent_hash: i32,
 }
 
+impl fmt::Debug for UnbindServiceRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnbindServiceRequest")
+            .field("service_token", &Token(&self.service_token))
+            .field("bind_token", &Token(&self.bind_token))
+            .field("intent_hash", &self.intent_hash)
+            .finish()
+    }
+}
+
+#[derive(Clone)]
+pub struct TrimMemoryRequest {
+    pub level: i32,
+    /// The service to trim, or None to trim all the services of the process.
+    pub service_token: Option<SpIBinder>,
+}
+
+impl fmt::Debug for TrimMemoryRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrimMemoryRequest")
+            .field("level", &self.level)
+            .field("service_token", &self.service_token.as_ref().map(Token))
+            .finish()
+    }
+}
+
+#[derive(Clone)]
+pub struct ForegroundStateChangedRequest {
+    pub service_token: SpIBinder,
+    pub fgs_type: i32,
+    pub has_notification: bool,
+}
+
+impl fmt::Debug for ForegroundStateChangedRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForegroundStateChangedRequest")
+            .field("service_token", &Token(&self.service_token))
+            .field("fgs_type", &self.fgs_type)
+            .field("has_notification", &self.has_notification)
+            .finish()
+    }
+}
+
+/// The result of loading the library of a service off the handler thread. Sent by the library
+/// loader pool, not by the ActivityManager.
+pub struct LibraryLoadedRequest {
+    /// The request creating the service.
+    pub request: CreateServiceRequest,
+    pub library: Result<ServiceLibrary, ServiceError>,
+}
+
+impl fmt::Debug for LibraryLoadedRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LibraryLoadedRequest")
+            .field("request", &self.request)
+            .field("loaded", &self.library.is_ok())
+            .finish()
+    }
+}
+
+#[derive(Debug)]
 pub enum NativeApplicationThreadRequest {
     CreateService(CreateServiceRequest),
     DestroyService(DestroyServiceRequest),
     BindService(BindServiceRequest),
     UnbindService(UnbindServiceRequest),
-    TrimMemory(i32),
+    TrimMemory(TrimMemoryRequest),
     BindApplication,
     SetProcessState(i32),
+    ForegroundStateChanged(ForegroundStateChangedRequest),
+    /// Requests a description of the state of the process for dumpsys.
+    Dump(Responder<String>),
+    /// Requests to destroy all the services and stop the thread loop.
+    Shutdown,
+    /// Continues a `CreateService` request once the library of the service is loaded.
+    LibraryLoaded(LibraryLoadedRequest),
+}
+
+impl NativeApplicationThreadRequest {
+    /// Returns the token of the service the request is about, if it is about a single service.
+    pub fn service_token(&self) -> Option<&SpIBinder> {
+        match self {
+            Self::CreateService(req) => Some(&req.service_token),
+            Self::DestroyService(req) => Some(&req.service_token),
+            Self::BindService(req) => Some(&req.service_token),
+            Self::UnbindService(req) => Some(&req.service_token),
+            Self::TrimMemory(req) => req.service_token.as_ref(),
+            Self::ForegroundStateChanged(req) => Some(&req.service_token),
+            Self::BindApplication
+            | Self::SetProcessState(_)
+            | Self::Dump(_)
+            | Self::Shutdown
+            | Self::LibraryLoaded(_) => None,
+        }
+    }
 }
 
 /// NativeApplicationThread is used as a "Binder node" to accept requests for managing the process
 /// for application use.
+/// How long a dump waits for the state of the handler, which may be busy or stuck in a service.
+const DUMP_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct NativeApplicationThread {
     sender: Sender<NativeApplicationThreadRequest>,
+    queued_creates: QueuedCreates,
+    dump_timeout: Duration,
 }
 
+/// The tokens of the services whose create request was sent to the handler but not handled yet.
+pub type QueuedCreates = Arc<Mutex<BTreeSet<SpIBinder>>>;
+
 impl NativeApplicationThread {
-    pub(crate) fn new(sender: Sender<NativeApplicationThreadRequest>) -> NativeApplicationThread {
-        Self { sender }
+    /// Creates the binder node sending the requests to `sender`. The create requests are tracked
+    /// in `queued_creates` until the handler handles them.
+    pub(crate) fn new(
+        sender: Sender<NativeApplicationThreadRequest>,
+        queued_creates: QueuedCreates,
+    ) -> NativeApplicationThread {
+        Self { sender, queued_creates, dump_timeout: DUMP_TIMEOUT }
     }
 }
 
-impl Interface for NativeApplicationThread {}
+#[cfg(test)]
+impl NativeApplicationThread {
+    /// Sets how long a dump waits for the state of the handler.
+    pub fn with_dump_timeout(mut self, dump_timeout: Duration) -> Self {
+        self.dump_timeout = dump_timeout;
+        self
+    }
+}
+
+impl Interface for NativeApplicationThread {
+    fn dump(&self, writer: &mut dyn Write, _args: &[&CStr]) -> binder::Result<()> {
+        let state = self
+            .sender
+            .send_and_wait(NativeApplicationThreadRequest::Dump, self.dump_timeout)
+            .unwrap_or_else(|e| format!("Failed to dump the state: {:#}\n", e));
+        writer.write_all(state.as_bytes()).map_err(|_| binder::StatusCode::UNKNOWN_ERROR)?;
+        Ok(())
+    }
+}
 
 impl INativeApplicationThread for NativeApplicationThread {
     fn scheduleCreateService(
         &self,
         service_token: &SpIBinder,
         library_paths: &[String],
+        plugin_library_paths: &[String],
         permitted_libs_dir: &str,
         library_name: &str,
-        base_symbol_name: &str,
+        base_symbol_names: &[String],
         _process_state: i32,
+        has_ui: bool,
     ) -> binder::Result<()> {
         info!("scheduleCreateService thread id={:?}", thread::current().id());
+        if base_symbol_names.is_empty() {
+            return Err(binder::Status::new_exception_str(
+                binder::ExceptionCode::ILLEGAL_ARGUMENT,
+                Some("No base symbol name"),
+            ));
+        }
         // SAFETY: We trust that the caller of this function requests to load a library specified
         // by the application according to the native service specification. The application is
         // responsible for implementing a safe library and an entry point function of its native
@@ -122,11 +380,15 @@ impl INativeApplicationThread for NativeApplicationThread {
                 library_paths.to_vec(),
                 permitted_libs_dir.to_string(),
                 library_name.to_string(),
-                base_symbol_name.to_string(),
+                base_symbol_names.to_vec(),
                 _process_state,
+                has_ui,
             )
-        };
+        }
+        .with_plugin_library_paths(plugin_library_paths.to_vec());
+        self.queued_creates.lock().unwrap().insert(service_token.clone());
         self.sender.send(NativeApplicationThreadRequest::CreateService(req)).map_err(|e| {
+            self.queued_creates.lock().unwrap().remove(service_token);
             binder::Status::new_exception_str(
                 binder::ExceptionCode::SERVICE_SPECIFIC,
                 Some(format!("Failed to send a task: {:?}", e)),
@@ -160,6 +422,8 @@ impl INativeApplicationThread for NativeApplicationThread {
         rebind: bool,
         process_state: i32,
         bind_seq: i64,
+        categories: &[String],
+        extras: Option<&[u8]>,
     ) -> binder::Result<()> {
         info!("scheduleBindService thread id={:?}", thread::current().id());
         if let Some(s) = action {
@@ -175,6 +439,8 @@ impl INativeApplicationThread for NativeApplicationThread {
                 rebind,
                 _process_state: process_state,
                 _bind_seq: bind_seq,
+                categories: categories.to_vec(),
+                extras: extras.map(|e| e.to_vec()),
             }))
             .map_err(|e| {
                 binder::Status::new_exception_str(
@@ -207,14 +473,23 @@ impl INativeApplicationThread for NativeApplicationThread {
         Ok(())
     }
 
-    fn scheduleTrimMemory(&self, level: i32) -> binder::Result<()> {
+    fn scheduleTrimMemory(
+        &self,
+        level: i32,
+        service_token: Option<&SpIBinder>,
+    ) -> binder::Result<()> {
         info!("scheduleLowMemory thread id={:?}", thread::current().id());
-        self.sender.send(NativeApplicationThreadRequest::TrimMemory(level)).map_err(|e| {
-            binder::Status::new_exception_str(
-                binder::ExceptionCode::SERVICE_SPECIFIC,
-                Some(format!("Failed to send a task: {:?}", e)),
-            )
-        })?;
+        self.sender
+            .send(NativeApplicationThreadRequest::TrimMemory(TrimMemoryRequest {
+                level,
+                service_token: service_token.cloned(),
+            }))
+            .map_err(|e| {
+                binder::Status::new_exception_str(
+                    binder::ExceptionCode::SERVICE_SPECIFIC,
+                    Some(format!("Failed to send a task: {:?}", e)),
+                )
+            })?;
         Ok(())
     }
 
@@ -239,4 +514,39 @@ impl INativeApplicationThread for NativeApplicationThread {
         })?;
         Ok(())
     }
+
+    fn scheduleShutdown(&self) -> binder::Result<()> {
+        info!("scheduleShutdown thread id={:?}", thread::current().id());
+        self.sender.send(NativeApplicationThreadRequest::Shutdown).map_err(|e| {
+            binder::Status::new_exception_str(
+                binder::ExceptionCode::SERVICE_SPECIFIC,
+                Some(format!("Failed to send a task: {:?}", e)),
+            )
+        })?;
+        Ok(())
+    }
+
+    fn scheduleForegroundStateChanged(
+        &self,
+        service_token: &SpIBinder,
+        fgs_type: i32,
+        has_notification: bool,
+    ) -> binder::Result<()> {
+        info!("scheduleForegroundStateChanged thread id={:?}", thread::current().id());
+        self.sender
+            .send(NativeApplicationThreadRequest::ForegroundStateChanged(
+                ForegroundStateChangedRequest {
+                    service_token: service_token.clone(),
+                    fgs_type,
+                    has_notification,
+                },
+            ))
+            .map_err(|e| {
+                binder::Status::new_exception_str(
+                    binder::ExceptionCode::SERVICE_SPECIFIC,
+                    Some(format!("Failed to send a task: {:?}", e)),
+                )
+            })?;
+        Ok(())
+    }
 }
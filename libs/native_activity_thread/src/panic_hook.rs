@@ -0,0 +1,95 @@
+//
+// Copyright (C) 2025 The Android Open-Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The panic hook of the native activity thread.
+
+use log::error;
+use std::backtrace::Backtrace;
+use std::panic::{self, PanicHookInfo};
+use std::thread;
+
+/// Installs a panic hook logging the panic message and a backtrace, then running the previously
+/// installed hook. The default hook only prints to stderr, which isn't captured by logcat.
+///
+/// The hook doesn't stop the panic, so the handlers catching it (e.g. `ErrorStrategy::Deactivate`)
+/// and the abort of the process work as before. The logger must be initialized beforehand.
+pub fn install() {
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        error!("{}", panic_message(info, &Backtrace::force_capture()));
+        previous_hook(info);
+    }));
+}
+
+/// Describes the panic reported to the hook.
+fn panic_message(info: &PanicHookInfo, backtrace: &Backtrace) -> String {
+    let thread = thread::current();
+    let thread_name = thread.name().unwrap_or("<unnamed>");
+    let payload = info
+        .payload()
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    let location = info
+        .location()
+        .map(|location| location.to_string())
+        .unwrap_or_else(|| "<unknown>".to_owned());
+    format!("Thread '{thread_name}' panicked at {location}: {payload}\n{backtrace}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn panic_message_has_payload_and_location() {
+        const THREAD_NAME: &str = "panic_hook_test";
+        let messages = Arc::new(Mutex::new(Vec::new()));
+
+        // The hook is global. Only record the panic of the test thread, as other tests may panic
+        // meanwhile.
+        let previous_hook = panic::take_hook();
+        let recorded_messages = messages.clone();
+        panic::set_hook(Box::new(move |info| {
+            if thread::current().name() == Some(THREAD_NAME) {
+                let message = panic_message(info, &Backtrace::disabled());
+                recorded_messages.lock().unwrap().push(message);
+            }
+        }));
+        let result = thread::Builder::new()
+            .name(THREAD_NAME.to_owned())
+            .spawn(|| panic!("service {} is broken", 42))
+            .unwrap()
+            .join();
+        panic::set_hook(previous_hook);
+
+        assert!(result.is_err());
+        let messages = messages.lock().unwrap();
+        assert_eq!(messages.len(), 1, "unexpected messages: {messages:?}");
+        assert!(
+            messages[0].starts_with(&format!("Thread '{THREAD_NAME}' panicked at ")),
+            "unexpected message: {}",
+            messages[0]
+        );
+        assert!(messages[0].contains("panic_hook.rs:"), "unexpected message: {}", messages[0]);
+        assert!(
+            messages[0].contains(": service 42 is broken\n"),
+            "unexpected message: {}",
+            messages[0]
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//
+// Copyright (C) 2025 The Android Open-Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for the process states sent by the ActivityManager.
+
+use libactivity_manager_procstate_aidl::aidl::android::app::ProcessStateEnum::ProcessStateEnum;
+use log::warn;
+
+/// Converts a process state received from the ActivityManager. Unrecognized values are logged
+/// and converted to `UNKNOWN`.
+pub fn process_state_from_i32(state: i32) -> ProcessStateEnum {
+    match ProcessStateEnum::enum_values().into_iter().find(|known| known.0 == state) {
+        Some(known) => known,
+        None => {
+            warn!("Unrecognized process state {state}, using UNKNOWN");
+            ProcessStateEnum::UNKNOWN
+        }
+    }
+}
+
+/// Comparisons of process states by importance.
+pub trait ProcessStateExt {
+    /// Returns whether the process is at least as foreground as in `other`. Lower states are
+    /// more foreground. `UNKNOWN` is more foreground than any other state, so that the process
+    /// isn't treated as a background process until its state is known.
+    fn is_as_foreground_as(&self, other: ProcessStateEnum) -> bool;
+}
+
+impl ProcessStateExt for ProcessStateEnum {
+    fn is_as_foreground_as(&self, other: ProcessStateEnum) -> bool {
+        match (*self, other) {
+            (ProcessStateEnum::UNKNOWN, _) => true,
+            (_, ProcessStateEnum::UNKNOWN) => false,
+            _ => self.0 <= other.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_states_are_converted() {
+        for state in ProcessStateEnum::enum_values() {
+            assert_eq!(process_state_from_i32(state.0), state);
+        }
+    }
+
+    #[test]
+    fn unrecognized_states_are_unknown() {
+        assert_eq!(process_state_from_i32(-2), ProcessStateEnum::UNKNOWN);
+        assert_eq!(
+            process_state_from_i32(ProcessStateEnum::NONEXISTENT.0 + 1),
+            ProcessStateEnum::UNKNOWN
+        );
+        assert_eq!(process_state_from_i32(i32::MAX), ProcessStateEnum::UNKNOWN);
+    }
+
+    #[test]
+    fn foreground_comparison() {
+        let threshold = ProcessStateEnum::IMPORTANT_FOREGROUND;
+        assert!(ProcessStateEnum::TOP.is_as_foreground_as(threshold));
+        assert!(ProcessStateEnum::IMPORTANT_FOREGROUND.is_as_foreground_as(threshold));
+        assert!(!ProcessStateEnum::IMPORTANT_BACKGROUND.is_as_foreground_as(threshold));
+        assert!(!ProcessStateEnum::CACHED_EMPTY.is_as_foreground_as(threshold));
+        assert!(ProcessStateEnum::UNKNOWN.is_as_foreground_as(threshold));
+        assert!(ProcessStateEnum::UNKNOWN.is_as_foreground_as(ProcessStateEnum::PERSISTENT));
+        assert!(!ProcessStateEnum::PERSISTENT.is_as_foreground_as(ProcessStateEnum::UNKNOWN));
+    }
+}
@@ -0,0 +1,92 @@
+//
+// Copyright (C) 2025 The Android Open-Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{error::Error, fmt, time::Duration};
+
+/// The failure modes of the requests handled by NativeActivityThread.
+#[derive(Debug)]
+pub enum ServiceError {
+    /// No live service is associated with the token of the request.
+    ServiceNotFound,
+    /// The library of the service couldn't be loaded or doesn't export its entry point.
+    LibraryLoad(anyhow::Error),
+    /// The library validator rejected the library of the service, so it wasn't loaded.
+    LibraryRejected(anyhow::Error),
+    /// The service didn't implement a mandatory callback.
+    MissingCallback(&'static str),
+    /// A callback returned the null pointer where a binder object was expected.
+    NullBinder(&'static str),
+    /// A call to the ActivityManager failed.
+    BinderCall { method: &'static str, status: binder::Status },
+    /// A call to the ActivityManager didn't return in time. It still completes in the background.
+    BinderTimeout { method: &'static str, timeout: Duration },
+    /// The request has an unexpected trim memory level.
+    UnexpectedTrimMemoryLevel(i32),
+    /// The process already hosts the maximum number of services, so no service is created.
+    TooManyServices(usize),
+}
+
+impl ServiceError {
+    /// Returns a function converting the status of a failed call to `method` of the
+    /// ActivityManager, to be used with `map_err`.
+    pub fn binder_call(method: &'static str) -> impl FnOnce(binder::Status) -> Self {
+        move |status| Self::BinderCall { method, status }
+    }
+
+    /// Returns true if the error may not happen again when retrying, i.e. a binder transaction
+    /// failed because the binder buffer of the ActivityManager was temporarily exhausted.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::BinderCall { status, .. }
+                if status.exception_code() == binder::ExceptionCode::TRANSACTION_FAILED
+                    && status.transaction_error() == binder::StatusCode::FAILED_TRANSACTION
+        )
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ServiceNotFound => write!(f, "service not found"),
+            Self::LibraryLoad(e) => write!(f, "Failed to load the service library: {:#}", e),
+            Self::LibraryRejected(e) => write!(f, "The service library was rejected: {:#}", e),
+            Self::MissingCallback(callback) => write!(f, "{} must be implemented", callback),
+            Self::NullBinder(callback) => write!(f, "{} returned the null pointer", callback),
+            Self::BinderCall { method, status } => {
+                write!(f, "Failed to call {}: {}", method, status)
+            }
+            Self::BinderTimeout { method, timeout } => {
+                write!(f, "{} didn't return within {:?}", method, timeout)
+            }
+            Self::UnexpectedTrimMemoryLevel(level) => {
+                write!(f, "Received an unexpected level: {}", level)
+            }
+            Self::TooManyServices(max_services) => {
+                write!(f, "The process already hosts {} services", max_services)
+            }
+        }
+    }
+}
+
+impl Error for ServiceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::LibraryLoad(e) | Self::LibraryRejected(e) => Some(e.as_ref()),
+            Self::BinderCall { status, .. } => Some(status),
+            _ => None,
+        }
+    }
+}
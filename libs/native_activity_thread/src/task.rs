@@ -13,6 +13,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+//! A handler running the tasks sent from other threads on a looper thread.
+
 use anyhow::{anyhow, bail, Context, Result};
 use log::{error, info};
 use looper_bindgen::{
@@ -20,13 +22,31 @@ use looper_bindgen::{
     ALooper_removeFd, ALOOPER_EVENT_INPUT, ALOOPER_POLL_CALLBACK, ALOOPER_POLL_ERROR,
 };
 use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
     ffi::{c_int, c_void},
+    fmt,
+    num::NonZeroUsize,
     os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
-    sync::mpsc::{self, channel, TryRecvError},
+    panic::{self, AssertUnwindSafe},
+    ptr::NonNull,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, channel, TryRecvError},
+        Arc,
+    },
     thread,
+    time::Duration,
 };
 
 const ALOOPER_CALLBACK_FUNC_RETURN_VALUE_CONTINUE: c_int = 1;
+const ALOOPER_CALLBACK_FUNC_RETURN_VALUE_UNREGISTER: c_int = 0;
+
+thread_local! {
+    // Set while a `Handler` is alive on this thread.
+    static HAS_HANDLER: Cell<bool> = const { Cell::new(false) };
+}
 
 macro_rules! retry_eintr {
     ($libc_call:expr) => {
@@ -51,6 +71,10 @@ macro_rules! retry_eintr {
 pub struct Sender<T: Send> {
     tx: mpsc::Sender<T>,
     waker_fd: OwnedFd,
+    // Set while the eventfd has been written and the handler hasn't started handling tasks yet.
+    wake_pending: Arc<AtomicBool>,
+    // The thread the associated `Handler` runs on.
+    handler_thread: thread::ThreadId,
 }
 
 impl<T: Send> Sender<T> {
@@ -60,15 +84,107 @@ impl<T: Send> Sender<T> {
         self.wake()
     }
 
+    /// Send a task built around a `Responder` and block until the `Handler` responds through it,
+    /// for at most `timeout`.
+    ///
+    /// This must not be called on the thread of the associated `Handler` since the handler
+    /// couldn't run while this function is waiting.
+    pub fn send_and_wait<R: Send>(
+        &self,
+        build_task: impl FnOnce(Responder<R>) -> T,
+        timeout: Duration,
+    ) -> Result<R> {
+        if thread::current().id() == self.handler_thread {
+            bail!("send_and_wait can't be called on the thread of the handler");
+        }
+        let (response_tx, response_rx) = mpsc::sync_channel(1);
+        self.send(build_task(Responder { tx: response_tx }))?;
+        response_rx.recv_timeout(timeout).map_err(|e| match e {
+            mpsc::RecvTimeoutError::Timeout => anyhow!("No response within {:?}", timeout),
+            mpsc::RecvTimeoutError::Disconnected => {
+                anyhow!("The task was dropped without a response")
+            }
+        })
+    }
+
     fn wake(&self) -> Result<()> {
-        let res = retry_eintr!(
-            // SAFETY: `self.waker_fd` is a valid eventfd.
-            unsafe { libc::eventfd_write(self.waker_fd.as_raw_fd(), 1) }
-        );
-        if let Err(e) = res {
-            bail!("Failed to write to the waker fd: {}", e);
+        wake_handler(&self.waker_fd, &self.wake_pending)
+    }
+}
+
+/// A slot carried by a task to send a response back to `Sender::send_and_wait`.
+pub struct Responder<R: Send> {
+    tx: mpsc::SyncSender<R>,
+}
+
+impl<R: Send> Responder<R> {
+    /// Send the response to the waiting sender.
+    pub fn respond(self, response: R) {
+        // The waiting sender may have given up waiting.
+        let _ = self.tx.send(response);
+    }
+}
+
+impl<R: Send> fmt::Debug for Responder<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Responder").finish_non_exhaustive()
+    }
+}
+
+/// Writes to the eventfd of a handler unless it was already written since the handler last started
+/// handling tasks, in which case the handler will see the tasks sent in the meantime anyway.
+fn wake_handler(event_fd: &OwnedFd, wake_pending: &AtomicBool) -> Result<()> {
+    if wake_pending.swap(true, Ordering::AcqRel) {
+        return Ok(());
+    }
+    write_eventfd(event_fd)
+}
+
+fn write_eventfd(event_fd: &OwnedFd) -> Result<()> {
+    let res = retry_eintr!(
+        // SAFETY: `event_fd` is a valid eventfd.
+        unsafe { libc::eventfd_write(event_fd.as_raw_fd(), 1) }
+    );
+    if let Err(e) = res {
+        bail!("Failed to write to the waker fd: {}", e);
+    }
+    Ok(())
+}
+
+/// What `Handler` does when it fails to handle tasks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorStrategy {
+    /// Panic on the looper thread. As the panic can't unwind through the looper, this aborts the
+    /// process.
+    Panic,
+    /// Log the error, unregister the handler from the looper and mark it as broken. The owner of
+    /// the handler is expected to check `Handler::is_broken` and shut down.
+    Deactivate,
+}
+
+/// A predicate selecting the pending tasks to keep. See `HandlerCallback::take_pending_task_filter`.
+pub type TaskFilter<T> = Box<dyn FnMut(&T) -> bool>;
+
+/// The maximum number of times a task is retried before its failure is considered fatal.
+const MAX_TASK_RETRIES: u32 = 3;
+
+/// The result of handling a task.
+pub enum TaskOutcome<T> {
+    /// The task was handled.
+    Done,
+    /// The task failed transiently. It is handled again before the other pending tasks, after the
+    /// looper serviced its other fds. A task failing more than `MAX_TASK_RETRIES` times is fatal.
+    Retry(T),
+    /// The task failed and the handler can't recover from it.
+    Fatal(anyhow::Error),
+}
+
+impl<T> From<Result<()>> for TaskOutcome<T> {
+    fn from(result: Result<()>) -> Self {
+        match result {
+            Ok(()) => TaskOutcome::Done,
+            Err(e) => TaskOutcome::Fatal(e),
         }
-        Ok(())
     }
 }
 
@@ -76,45 +192,148 @@ impl<T: Send> Sender<T> {
 pub trait HandlerCallback<T: Send> {
     /// Handle a task.
     /// This function is called on the same thread that created the `Handler` owning the callback.
-    /// If this function returns Err, the handler is deactivated and this function will never be
-    /// called anymore even if there is a sent task.
-    fn handle_task(&mut self, task: T) -> Result<()>;
+    /// If this function returns `TaskOutcome::Fatal`, the handler is deactivated and this
+    /// function will never be called anymore even if there is a sent task.
+    fn handle_task(&mut self, task: T) -> TaskOutcome<T>;
+
+    /// Called after each handled task. If this returns a predicate, the pending tasks for which
+    /// it returns false are dropped without being handled. The predicate is called on the pending
+    /// tasks in the order they were sent.
+    fn take_pending_task_filter(&mut self) -> Option<TaskFilter<T>> {
+        None
+    }
+
+    /// Called after each handled task. The returned tasks are handled, in order, before the
+    /// pending tasks, and after the handled task if it is retried.
+    fn take_requeued_tasks(&mut self) -> Vec<T> {
+        Vec::new()
+    }
+
+    /// Called after each handled task. Once this returns true, the handler stops handling tasks
+    /// and unregisters itself from the looper, which makes `run_thread_loop` return.
+    fn is_finished(&self) -> bool {
+        false
+    }
 }
 
 struct HandlerInner<T: Send, C: HandlerCallback<T>> {
     callback: C,
     event_fd: OwnedFd,
+    wake_pending: Arc<AtomicBool>,
     tx: mpsc::Sender<T>,
     rx: mpsc::Receiver<T>,
+    // Tasks received from `rx` but not handled yet. They are handled before the tasks in `rx`.
+    pending: VecDeque<T>,
+    task_budget: NonZeroUsize,
+    error_strategy: ErrorStrategy,
+    // Set once the handler is deactivated after an error.
+    broken: bool,
+    // Set once the handler unregistered itself because the callback is finished.
+    finished: bool,
+    // The number of times the task at the front of `pending` was retried.
+    retry_count: u32,
 }
 
 impl<T: Send, C: HandlerCallback<T>> HandlerInner<T, C> {
-    fn handle_tasks(&mut self) -> Result<()> {
-        loop {
-            let req = self.rx.try_recv();
+    /// Handles at most `task_budget` tasks. Returns true if the budget ran out, i.e. there may be
+    /// tasks left in the queue.
+    fn handle_tasks(&mut self) -> Result<bool> {
+        for _ in 0..self.task_budget.get() {
+            let req = match self.pending.pop_front() {
+                Some(req) => Ok(req),
+                None => self.rx.try_recv(),
+            };
             match req {
-                Ok(req) => self.callback.handle_task(req)?,
-                Err(TryRecvError::Empty) => return Ok(()),
+                Ok(req) => {
+                    let outcome = self.callback.handle_task(req);
+                    for task in self.callback.take_requeued_tasks().into_iter().rev() {
+                        self.pending.push_front(task);
+                    }
+                    match outcome {
+                        TaskOutcome::Done => self.retry_count = 0,
+                        TaskOutcome::Retry(req) => {
+                            if self.retry_count >= MAX_TASK_RETRIES {
+                                bail!("The task failed after {} retries", MAX_TASK_RETRIES);
+                            }
+                            self.retry_count += 1;
+                            self.pending.push_front(req);
+                            // Give the other fds a chance to be serviced before retrying.
+                            return Ok(true);
+                        }
+                        TaskOutcome::Fatal(e) => return Err(e),
+                    }
+                    if self.callback.is_finished() {
+                        return Ok(false);
+                    }
+                    if let Some(filter) = self.callback.take_pending_task_filter() {
+                        self.retain_pending(filter);
+                    }
+                }
+                Err(TryRecvError::Empty) => return Ok(false),
                 Err(TryRecvError::Disconnected) => bail!("mpsc disconnected"),
             }
         }
+        Ok(true)
     }
+
+    /// Drops the pending tasks for which `keep` returns false.
+    fn retain_pending(&mut self, mut keep: impl FnMut(&T) -> bool) {
+        self.pending.extend(self.rx.try_iter());
+        let pending_count = self.pending.len();
+        self.pending.retain(|task| keep(task));
+        let dropped_count = pending_count - self.pending.len();
+        if dropped_count > 0 {
+            info!("Dropped {} pending tasks", dropped_count);
+        }
+    }
+}
+
+/// Whether a callback registered with `Handler::add_input_fd` stays registered after it runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FdCallbackMode {
+    /// The callback runs every time the fd is readable, until the fd is removed.
+    Persistent,
+    /// The callback runs the first time the fd is readable, then the fd is unregistered.
+    OneShot,
+}
+
+/// The input fds registered with `Handler::add_input_fd`, and their callbacks.
+type InputFds = Rc<RefCell<HashMap<RawFd, NonNull<InputFd>>>>;
+
+/// A callback registered with `Handler::add_input_fd`.
+struct InputFd {
+    callback: Box<dyn FnMut(RawFd)>,
+    mode: FdCallbackMode,
+    // To stop tracking the fd once a one-shot callback ran.
+    input_fds: InputFds,
 }
 
 /// A struct representing a task handler.
+///
+/// A thread has at most one `Handler` at a time. The handler registers itself to the looper of
+/// the thread, which is shared with any other user of the looper on the thread, so two handlers
+/// would wake and unregister each other's fds through the same looper.
 pub struct Handler<T: Send, C: HandlerCallback<T>> {
     // This makes Handler !Send.
     looper: *mut ALooper,
     // Wrap members used for task handling with Box to ensure they are alive during the handler is
     // registered to the looper.
     inner: Box<HandlerInner<T, C>>,
+    input_fds: InputFds,
 }
 
 impl<T: Send, C: HandlerCallback<T>> Handler<T, C> {
+    /// Creates a handler on the looper of the current thread, preparing the looper if needed.
+    /// Fails if the thread already has a handler.
     pub fn new_on_current_thread(callback: C) -> Result<Self> {
+        if HAS_HANDLER.get() {
+            bail!("The thread {:?} already has a handler", thread::current().id());
+        }
         // SAFETY: 0 is a valid argument.
         let looper = unsafe { ALooper_prepare(0) };
-        assert!(!looper.is_null());
+        if looper.is_null() {
+            bail!("Failed to prepare the looper");
+        }
 
         // SAFETY: Passing valid arguments.
         let fd: RawFd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
@@ -125,9 +344,24 @@ impl<T: Send, C: HandlerCallback<T>> Handler<T, C> {
         let event_fd = unsafe { OwnedFd::from_raw_fd(fd) };
 
         let (tx, rx) = channel::<T>();
-        let mut inner = Box::new(HandlerInner { callback, event_fd, tx, rx });
+        let task_budget = NonZeroUsize::MAX;
+        let pending = VecDeque::new();
+        let wake_pending = Arc::new(AtomicBool::new(false));
+        let mut inner = Box::new(HandlerInner {
+            callback,
+            event_fd,
+            wake_pending,
+            tx,
+            rx,
+            pending,
+            task_budget,
+            error_strategy: ErrorStrategy::Panic,
+            broken: false,
+            finished: false,
+            retry_count: 0,
+        });
         let inner_ptr = &mut *inner as *mut HandlerInner<T, C> as *mut c_void;
-        let handler = Self { looper, inner };
+        let handler = Self { looper, inner, input_fds: InputFds::default() };
 
         // SAFETY: `inner_ptr` outlives the duration `poll_callback` is registered.
         unsafe {
@@ -140,16 +374,146 @@ impl<T: Send, C: HandlerCallback<T>> Handler<T, C> {
             )
         }
         .context("Failed to add the waker fd")?;
+        HAS_HANDLER.set(true);
 
         info!("A handler is activated on the thread {:?}", thread::current().id());
 
         Ok(handler)
     }
 
+    /// Spawns a thread running a handler with `callback` until the handler is broken or finished.
+    /// Returns the thread, whose result is the one of `run_thread_loop`, along with a sender to the
+    /// handler once the handler is ready. Fails if the handler can't be set up on the thread.
+    pub fn spawn(callback: C) -> Result<(thread::JoinHandle<Result<()>>, Sender<T>)>
+    where
+        T: 'static,
+        C: Send + 'static,
+    {
+        let (ready_tx, ready_rx) = channel();
+        let handler_thread = thread::Builder::new()
+            .spawn(move || {
+                let setup = Self::new_on_current_thread(callback).and_then(|handler| {
+                    let sender = handler.get_sender()?;
+                    Ok((handler, sender))
+                });
+                let handler = match setup {
+                    Ok((handler, sender)) => {
+                        // The caller may have given up waiting, e.g. if it panicked.
+                        let _ = ready_tx.send(Ok(sender));
+                        handler
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        bail!("The handler couldn't be set up");
+                    }
+                };
+                run_thread_loop(&handler)
+            })
+            .context("Failed to spawn the handler thread")?;
+        match ready_rx.recv() {
+            Ok(Ok(sender)) => Ok((handler_thread, sender)),
+            Ok(Err(e)) => {
+                // The thread exits right away.
+                let _ = handler_thread.join();
+                Err(e.context("Failed to set up the handler on its thread"))
+            }
+            Err(_) => {
+                let _ = handler_thread.join();
+                bail!("The handler thread panicked while setting up the handler")
+            }
+        }
+    }
+
     pub fn get_sender(&self) -> Result<Sender<T>> {
         let tx = self.inner.tx.clone();
         let waker_fd = self.inner.event_fd.try_clone().context("Failed to clone the eventfd")?;
-        Ok(Sender::<T> { tx, waker_fd })
+        // `Handler` is !Send, so this is the thread the handler runs on.
+        let handler_thread = thread::current().id();
+        let wake_pending = self.inner.wake_pending.clone();
+        Ok(Sender::<T> { tx, waker_fd, wake_pending, handler_thread })
+    }
+
+    /// Set the maximum number of tasks handled per wakeup. Once the budget is used up, the handler
+    /// returns to the looper so that other fds registered to it (e.g. binder) are serviced before
+    /// the remaining tasks are handled. By default, the handler drains the whole queue.
+    pub fn set_task_budget(&mut self, task_budget: NonZeroUsize) {
+        self.inner.task_budget = task_budget;
+    }
+
+    /// Set what the handler does when it fails to handle tasks. Defaults to
+    /// `ErrorStrategy::Panic`.
+    pub fn set_error_strategy(&mut self, error_strategy: ErrorStrategy) {
+        self.inner.error_strategy = error_strategy;
+    }
+
+    /// Returns the callback handling the tasks.
+    pub fn callback_mut(&mut self) -> &mut C {
+        &mut self.inner.callback
+    }
+
+    /// Returns true if the handler was deactivated after an error. A broken handler never handles
+    /// tasks again.
+    pub fn is_broken(&self) -> bool {
+        self.inner.broken
+    }
+
+    /// Returns true if the handler stopped because its callback is finished. A finished handler
+    /// never handles tasks again.
+    pub fn is_finished(&self) -> bool {
+        self.inner.finished
+    }
+
+    /// Registers `callback` to run on the looper thread when `fd` is readable. With
+    /// `FdCallbackMode::OneShot`, the fd is unregistered after the callback runs once. The fd must
+    /// stay open while it's registered. Fails if the fd is already registered.
+    pub fn add_input_fd(
+        &self,
+        fd: RawFd,
+        mode: FdCallbackMode,
+        callback: impl FnMut(RawFd) + 'static,
+    ) -> Result<()> {
+        if self.input_fds.borrow().contains_key(&fd) {
+            bail!("The fd {fd} is already registered");
+        }
+        let input_fd = Box::new(InputFd {
+            callback: Box::new(callback),
+            mode,
+            input_fds: self.input_fds.clone(),
+        });
+        let input_fd_ptr = NonNull::from(Box::leak(input_fd));
+        // SAFETY: `input_fd_ptr` is freed only after the fd is unregistered, either by
+        // `input_fd_callback` when it returns UNREGISTER or by `remove_input_fd`.
+        let result = unsafe {
+            self.add_fd(
+                fd,
+                ALOOPER_POLL_CALLBACK,
+                ALOOPER_EVENT_INPUT as c_int,
+                Some(input_fd_callback),
+                input_fd_ptr.as_ptr() as *mut c_void,
+            )
+        };
+        if let Err(e) = result {
+            // SAFETY: The fd failed to register, so nothing else refers to `input_fd_ptr`.
+            drop(unsafe { Box::from_raw(input_fd_ptr.as_ptr()) });
+            return Err(e);
+        }
+        self.input_fds.borrow_mut().insert(fd, input_fd_ptr);
+        Ok(())
+    }
+
+    /// Unregisters an fd registered with `add_input_fd`. Fails if the fd isn't registered, e.g.
+    /// because its one-shot callback already ran.
+    pub fn remove_input_fd(&self, fd: RawFd) -> Result<()> {
+        let input_fd_ptr = self
+            .input_fds
+            .borrow_mut()
+            .remove(&fd)
+            .with_context(|| format!("The fd {fd} isn't registered"))?;
+        let result = self.remove_fd(fd);
+        // SAFETY: The fd is no longer registered, so `input_fd_callback` won't run with
+        // `input_fd_ptr` again.
+        drop(unsafe { Box::from_raw(input_fd_ptr.as_ptr()) });
+        result
     }
 
     /// # Safety
@@ -184,8 +548,7 @@ impl<T: Send, C: HandlerCallback<T>> Handler<T, C> {
 
     /// This function is supposed to be used as a callback function for `ALooper_addFd`.
     /// There's no easy way to tell the caller of `ALooper_pollOnce` that an error occurred, so
-    /// this function will panic instead of silently unregistering itself from the looper in such
-    /// cases.
+    /// errors are handled according to the `ErrorStrategy` of the handler.
     ///
     /// # Safety
     ///
@@ -197,28 +560,98 @@ impl<T: Send, C: HandlerCallback<T>> Handler<T, C> {
         let inner = unsafe { inner_ptr.as_mut() }.unwrap();
         assert_eq!(fd, inner.event_fd.as_raw_fd());
 
+        let result = match inner.error_strategy {
+            ErrorStrategy::Panic => Self::handle_wake(inner),
+            // Don't let a panic of the callback unwind through the looper.
+            ErrorStrategy::Deactivate => {
+                panic::catch_unwind(AssertUnwindSafe(|| Self::handle_wake(inner)))
+                    .unwrap_or_else(|_| Err(anyhow!("The handler callback panicked")))
+            }
+        };
+        match result {
+            Ok(()) if inner.callback.is_finished() => {
+                inner.finished = true;
+                ALOOPER_CALLBACK_FUNC_RETURN_VALUE_UNREGISTER
+            }
+            Ok(()) => ALOOPER_CALLBACK_FUNC_RETURN_VALUE_CONTINUE,
+            Err(e) if inner.error_strategy == ErrorStrategy::Panic => panic!("{e:#}"),
+            Err(e) => {
+                error!("Deactivating the handler: {e:#}");
+                inner.broken = true;
+                ALOOPER_CALLBACK_FUNC_RETURN_VALUE_UNREGISTER
+            }
+        }
+    }
+
+    /// Consumes a wake of the eventfd and handles the sent tasks.
+    fn handle_wake(inner: &mut HandlerInner<T, C>) -> Result<()> {
         let mut val = std::mem::MaybeUninit::<libc::eventfd_t>::uninit();
         let res = retry_eintr!(
             // SAFETY: `inner.event_fd` is a valid eventfd and `val` is properly allocated.
             unsafe { libc::eventfd_read(inner.event_fd.as_raw_fd(), val.as_mut_ptr()) }
         );
         if let Err(e) = res {
-            panic!("Failed to read from the event fd: {e}");
+            bail!("Failed to read from the event fd: {e}");
         }
+        // Tasks sent from now on may not be seen by `handle_tasks`, so they must wake the handler
+        // again.
+        inner.wake_pending.store(false, Ordering::Release);
 
-        let res = inner.handle_tasks();
-        if let Err(e) = res {
-            panic!("Failed to handle a task: {e}");
+        let has_pending_tasks = inner.handle_tasks().context("Failed to handle a task")?;
+        if has_pending_tasks {
+            // Wake up the looper again to handle the rest of the tasks after the other fds are
+            // serviced.
+            wake_handler(&inner.event_fd, &inner.wake_pending)
+                .context("Failed to rearm the event fd")?;
         }
-        ALOOPER_CALLBACK_FUNC_RETURN_VALUE_CONTINUE
+        Ok(())
     }
 }
 
 impl<T: Send, C: HandlerCallback<T>> Drop for Handler<T, C> {
     fn drop(&mut self) {
-        if self.remove_fd(self.inner.event_fd.as_raw_fd()).is_err() {
+        // A broken or finished handler already unregistered itself.
+        if !self.inner.broken
+            && !self.inner.finished
+            && self.remove_fd(self.inner.event_fd.as_raw_fd()).is_err()
+        {
             error!("Failed to remove the event fd");
         }
+        let input_fds: Vec<RawFd> = self.input_fds.borrow().keys().copied().collect();
+        for fd in input_fds {
+            if let Err(e) = self.remove_input_fd(fd) {
+                error!("Failed to remove the input fd {fd}: {e:#}");
+            }
+        }
+        HAS_HANDLER.set(false);
+    }
+}
+
+/// The looper callback of the fds registered with `Handler::add_input_fd`. Returns the value
+/// telling the looper whether to keep the fd registered, according to its `FdCallbackMode`. A
+/// callback which panics is unregistered, like a handler with `ErrorStrategy::Deactivate`.
+///
+/// # Safety
+///
+/// `data` must be a valid pointer to the `InputFd` of `fd` while this callback is registered.
+unsafe extern "C" fn input_fd_callback(fd: RawFd, _events: c_int, data: *mut c_void) -> c_int {
+    let input_fd_ptr = data as *mut InputFd;
+    // SAFETY: `input_fd_ptr` is a valid InputFd pointer.
+    let input_fd = unsafe { input_fd_ptr.as_mut() }.unwrap();
+    // Don't let a panic of the callback unwind through the looper.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| (input_fd.callback)(fd)));
+    if result.is_err() {
+        error!("Unregistering the input fd {fd} whose callback panicked");
+    }
+    match input_fd.mode {
+        FdCallbackMode::Persistent if result.is_ok() => ALOOPER_CALLBACK_FUNC_RETURN_VALUE_CONTINUE,
+        FdCallbackMode::Persistent | FdCallbackMode::OneShot => {
+            input_fd.input_fds.borrow_mut().remove(&fd);
+            // SAFETY: The fd is no longer tracked and the looper unregisters it on return, so
+            // nothing refers to `input_fd_ptr` anymore.
+            drop(unsafe { Box::from_raw(input_fd_ptr) });
+            ALOOPER_CALLBACK_FUNC_RETURN_VALUE_UNREGISTER
+        }
     }
 }
 
@@ -234,9 +667,564 @@ pub fn run_thread_loop_once() -> Result<()> {
     Ok(())
 }
 
-/// Run the server loop on this thread. This function will never return until an error occurs.
-pub fn run_thread_loop() -> Result<()> {
-    loop {
+/// Run the server loop on this thread. This function will never return until an error occurs or
+/// `handler` is broken or finished.
+pub fn run_thread_loop<T: Send, C: HandlerCallback<T>>(handler: &Handler<T, C>) -> Result<()> {
+    while !handler.is_broken() && !handler.is_finished() {
         run_thread_loop_once()?;
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        cell::RefCell,
+        rc::Rc,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Task(u32),
+        Fd,
+    }
+
+    struct RecordingCallback {
+        events: Rc<RefCell<Vec<Event>>>,
+    }
+
+    impl HandlerCallback<u32> for RecordingCallback {
+        fn handle_task(&mut self, task: u32) -> TaskOutcome<u32> {
+            self.events.borrow_mut().push(Event::Task(task));
+            TaskOutcome::Done
+        }
+    }
+
+    unsafe extern "C" fn pipe_callback(fd: RawFd, _events: c_int, data: *mut c_void) -> c_int {
+        let mut buf = [0u8; 1];
+        // SAFETY: `fd` is a valid pipe fd and `buf` is properly allocated.
+        unsafe { libc::read(fd, buf.as_mut_ptr() as *mut c_void, 1) };
+        // SAFETY: `data` points to the events vector owned by the test.
+        let events = unsafe { &*(data as *const RefCell<Vec<Event>>) };
+        events.borrow_mut().push(Event::Fd);
+        ALOOPER_CALLBACK_FUNC_RETURN_VALUE_CONTINUE
+    }
+
+    #[test]
+    fn task_budget_yields_to_other_fds() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut handler =
+            Handler::new_on_current_thread(RecordingCallback { events: events.clone() }).unwrap();
+        handler.set_task_budget(NonZeroUsize::new(2).unwrap());
+
+        let mut fds = [0; 2];
+        // SAFETY: `fds` is properly allocated.
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        // SAFETY: `fds` are valid owned fds.
+        let (read_fd, write_fd) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        // SAFETY: `events` outlives the registration of `pipe_callback`.
+        unsafe {
+            handler.add_fd(
+                read_fd.as_raw_fd(),
+                ALOOPER_POLL_CALLBACK,
+                ALOOPER_EVENT_INPUT as c_int,
+                Some(pipe_callback),
+                Rc::as_ptr(&events) as *mut c_void,
+            )
+        }
+        .unwrap();
+
+        let sender = handler.get_sender().unwrap();
+        for i in 0..5 {
+            sender.send(i).unwrap();
+        }
+        // SAFETY: `write_fd` is a valid pipe fd and the buffer is 1 byte long.
+        assert_eq!(
+            unsafe { libc::write(write_fd.as_raw_fd(), [0u8].as_ptr() as *const c_void, 1) },
+            1
+        );
+
+        while events.borrow().len() < 6 {
+            run_thread_loop_once().unwrap();
+        }
+        handler.remove_fd(read_fd.as_raw_fd()).unwrap();
+
+        let events = events.borrow();
+        let tasks: Vec<&Event> = events.iter().filter(|e| **e != Event::Fd).collect();
+        assert_eq!(
+            tasks,
+            [&Event::Task(0), &Event::Task(1), &Event::Task(2), &Event::Task(3), &Event::Task(4)]
+        );
+        let fd_position = events.iter().position(|e| *e == Event::Fd).unwrap();
+        assert!(fd_position < 4, "the pipe fd was not serviced between task batches: {events:?}");
+    }
+
+    enum DoublerTask {
+        Double(u32, Responder<u32>),
+        Stop,
+    }
+
+    struct Doubler {
+        stopped: Arc<AtomicBool>,
+    }
+
+    impl HandlerCallback<DoublerTask> for Doubler {
+        fn handle_task(&mut self, task: DoublerTask) -> TaskOutcome<DoublerTask> {
+            match task {
+                DoublerTask::Double(value, responder) => responder.respond(value * 2),
+                DoublerTask::Stop => self.stopped.store(true, Ordering::Relaxed),
+            }
+            TaskOutcome::Done
+        }
+    }
+
+    #[test]
+    fn send_and_wait_round_trips_value() {
+        let (sender_tx, sender_rx) = channel();
+        let handler_thread = thread::spawn(move || {
+            let stopped = Arc::new(AtomicBool::new(false));
+            let handler =
+                Handler::new_on_current_thread(Doubler { stopped: stopped.clone() }).unwrap();
+            sender_tx.send(handler.get_sender().unwrap()).unwrap();
+            while !stopped.load(Ordering::Relaxed) {
+                run_thread_loop_once().unwrap();
+            }
+        });
+        let sender = sender_rx.recv().unwrap();
+
+        let doubled = sender
+            .send_and_wait(|responder| DoublerTask::Double(21, responder), Duration::from_secs(5));
+
+        sender.send(DoublerTask::Stop).unwrap();
+        handler_thread.join().unwrap();
+        assert_eq!(doubled.unwrap(), 42);
+    }
+
+    #[test]
+    fn send_and_wait_on_handler_thread_fails() {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let handler = Handler::new_on_current_thread(Doubler { stopped }).unwrap();
+        let sender = handler.get_sender().unwrap();
+
+        assert!(sender
+            .send_and_wait(|responder| DoublerTask::Double(1, responder), Duration::from_secs(5))
+            .is_err());
+    }
+
+    #[test]
+    fn send_and_wait_times_out_without_response() {
+        let (sender_tx, sender_rx) = channel();
+        let (done_tx, done_rx) = channel::<()>();
+        let handler_thread = thread::spawn(move || {
+            let stopped = Arc::new(AtomicBool::new(false));
+            let handler = Handler::new_on_current_thread(Doubler { stopped }).unwrap();
+            sender_tx.send(handler.get_sender().unwrap()).unwrap();
+            // The handler never runs.
+            let _ = done_rx.recv();
+        });
+        let sender = sender_rx.recv().unwrap();
+
+        let err = sender
+            .send_and_wait(|responder| DoublerTask::Double(1, responder), Duration::from_millis(10))
+            .unwrap_err();
+
+        drop(done_tx);
+        handler_thread.join().unwrap();
+        assert!(err.to_string().contains("No response within 10ms"), "unexpected error: {err}");
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum TokenTask {
+        Work(u32),
+        Cancel(u32),
+    }
+
+    struct CancellingCallback {
+        handled: Rc<RefCell<Vec<TokenTask>>>,
+        cancelled_token: Option<u32>,
+    }
+
+    impl HandlerCallback<TokenTask> for CancellingCallback {
+        fn handle_task(&mut self, task: TokenTask) -> TaskOutcome<TokenTask> {
+            if let TokenTask::Cancel(token) = task {
+                self.cancelled_token = Some(token);
+            }
+            self.handled.borrow_mut().push(task);
+            TaskOutcome::Done
+        }
+
+        fn take_pending_task_filter(&mut self) -> Option<TaskFilter<TokenTask>> {
+            let cancelled_token = self.cancelled_token.take()?;
+            Some(Box::new(move |task| *task != TokenTask::Work(cancelled_token)))
+        }
+    }
+
+    #[test]
+    fn pending_tasks_are_filtered_after_task() {
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        let handler = Handler::new_on_current_thread(CancellingCallback {
+            handled: handled.clone(),
+            cancelled_token: None,
+        })
+        .unwrap();
+        let sender = handler.get_sender().unwrap();
+
+        sender.send(TokenTask::Work(1)).unwrap();
+        sender.send(TokenTask::Cancel(1)).unwrap();
+        sender.send(TokenTask::Work(1)).unwrap();
+        sender.send(TokenTask::Work(2)).unwrap();
+        sender.send(TokenTask::Work(1)).unwrap();
+        sender.send(TokenTask::Work(2)).unwrap();
+        run_thread_loop_once().unwrap();
+
+        assert_eq!(
+            *handled.borrow(),
+            [TokenTask::Work(1), TokenTask::Cancel(1), TokenTask::Work(2), TokenTask::Work(2)]
+        );
+    }
+
+    /// Reads and resets the counter of an eventfd, i.e. the number of writes since the last read.
+    fn read_eventfd_counter(event_fd: &OwnedFd) -> libc::eventfd_t {
+        let mut val: libc::eventfd_t = 0;
+        // SAFETY: `event_fd` is a valid eventfd and `val` is properly allocated.
+        if unsafe { libc::eventfd_read(event_fd.as_raw_fd(), &mut val) } == -1 {
+            return 0;
+        }
+        val
+    }
+
+    #[test]
+    fn rapid_sends_coalesce_wakes() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let handler =
+            Handler::new_on_current_thread(RecordingCallback { events: events.clone() }).unwrap();
+        let sender = handler.get_sender().unwrap();
+
+        for i in 0..100 {
+            sender.send(i).unwrap();
+        }
+        assert_eq!(read_eventfd_counter(&sender.waker_fd), 1);
+
+        // Restore the wake consumed above.
+        write_eventfd(&sender.waker_fd).unwrap();
+        run_thread_loop_once().unwrap();
+        assert_eq!(*events.borrow(), (0..100).map(Event::Task).collect::<Vec<_>>());
+
+        // Once the tasks are handled, the next send wakes the handler again.
+        sender.send(100).unwrap();
+        run_thread_loop_once().unwrap();
+        assert_eq!(events.borrow().last(), Some(&Event::Task(100)));
+    }
+
+    struct FailingCallback {
+        handled: Rc<RefCell<Vec<u32>>>,
+    }
+
+    impl HandlerCallback<u32> for FailingCallback {
+        fn handle_task(&mut self, task: u32) -> TaskOutcome<u32> {
+            match task {
+                0 => TaskOutcome::Fatal(anyhow!("injected failure")),
+                1 => panic!("injected panic"),
+                _ => {
+                    self.handled.borrow_mut().push(task);
+                    TaskOutcome::Done
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn failing_task_marks_handler_broken() {
+        for failing_task in [0, 1] {
+            let handled = Rc::new(RefCell::new(Vec::new()));
+            let mut handler =
+                Handler::new_on_current_thread(FailingCallback { handled: handled.clone() })
+                    .unwrap();
+            handler.set_error_strategy(ErrorStrategy::Deactivate);
+            let sender = handler.get_sender().unwrap();
+
+            sender.send(2).unwrap();
+            run_thread_loop_once().unwrap();
+            assert!(!handler.is_broken());
+
+            sender.send(failing_task).unwrap();
+            sender.send(3).unwrap();
+            run_thread_loop(&handler).unwrap();
+            assert!(handler.is_broken(), "task {failing_task} should break the handler");
+            assert_eq!(*handled.borrow(), [2]);
+        }
+    }
+
+    struct FinishingCallback {
+        handled: Rc<RefCell<Vec<u32>>>,
+    }
+
+    impl HandlerCallback<u32> for FinishingCallback {
+        fn handle_task(&mut self, task: u32) -> TaskOutcome<u32> {
+            self.handled.borrow_mut().push(task);
+            TaskOutcome::Done
+        }
+
+        fn is_finished(&self) -> bool {
+            self.handled.borrow().contains(&0)
+        }
+    }
+
+    #[test]
+    fn finished_callback_stops_thread_loop() {
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        let handler =
+            Handler::new_on_current_thread(FinishingCallback { handled: handled.clone() }).unwrap();
+        let sender = handler.get_sender().unwrap();
+
+        sender.send(1).unwrap();
+        sender.send(0).unwrap();
+        sender.send(2).unwrap();
+        run_thread_loop(&handler).unwrap();
+
+        assert!(handler.is_finished());
+        assert!(!handler.is_broken());
+        assert_eq!(*handled.borrow(), [1, 0]);
+    }
+
+    /// Callback failing transiently `failures` times on each task before handling it.
+    struct FlakyCallback {
+        failures: u32,
+        attempts: Rc<RefCell<Vec<u32>>>,
+        handled: Rc<RefCell<Vec<u32>>>,
+    }
+
+    impl HandlerCallback<u32> for FlakyCallback {
+        fn handle_task(&mut self, task: u32) -> TaskOutcome<u32> {
+            self.attempts.borrow_mut().push(task);
+            let attempt_count = self.attempts.borrow().iter().filter(|t| **t == task).count();
+            if attempt_count as u32 <= self.failures {
+                return TaskOutcome::Retry(task);
+            }
+            self.handled.borrow_mut().push(task);
+            TaskOutcome::Done
+        }
+    }
+
+    #[test]
+    fn retried_task_eventually_succeeds() {
+        let attempts = Rc::new(RefCell::new(Vec::new()));
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        let handler = Handler::new_on_current_thread(FlakyCallback {
+            failures: 2,
+            attempts: attempts.clone(),
+            handled: handled.clone(),
+        })
+        .unwrap();
+        let sender = handler.get_sender().unwrap();
+
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        while handled.borrow().len() < 2 {
+            run_thread_loop_once().unwrap();
+        }
+
+        assert!(!handler.is_broken());
+        // A retried task is handled again before the tasks sent after it.
+        assert_eq!(*attempts.borrow(), [1, 1, 1, 2, 2, 2]);
+        assert_eq!(*handled.borrow(), [1, 2]);
+    }
+
+    /// Callback retrying task 0 once, requeuing the tasks 1 and 2 after it on the first attempt.
+    struct RequeuingCallback {
+        handled: Rc<RefCell<Vec<u32>>>,
+        requeued: Vec<u32>,
+        retried: bool,
+    }
+
+    impl HandlerCallback<u32> for RequeuingCallback {
+        fn handle_task(&mut self, task: u32) -> TaskOutcome<u32> {
+            if task == 0 && !self.retried {
+                self.retried = true;
+                self.requeued = vec![1, 2];
+                return TaskOutcome::Retry(task);
+            }
+            self.handled.borrow_mut().push(task);
+            TaskOutcome::Done
+        }
+
+        fn take_requeued_tasks(&mut self) -> Vec<u32> {
+            std::mem::take(&mut self.requeued)
+        }
+    }
+
+    #[test]
+    fn requeued_tasks_are_handled_after_retried_task() {
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        let handler = Handler::new_on_current_thread(RequeuingCallback {
+            handled: handled.clone(),
+            requeued: Vec::new(),
+            retried: false,
+        })
+        .unwrap();
+        let sender = handler.get_sender().unwrap();
+
+        sender.send(0).unwrap();
+        sender.send(3).unwrap();
+        while handled.borrow().len() < 4 {
+            run_thread_loop_once().unwrap();
+        }
+
+        assert!(!handler.is_broken());
+        assert_eq!(*handled.borrow(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn task_retried_too_many_times_breaks_handler() {
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        let mut handler = Handler::new_on_current_thread(FlakyCallback {
+            failures: MAX_TASK_RETRIES + 1,
+            attempts: Rc::new(RefCell::new(Vec::new())),
+            handled: handled.clone(),
+        })
+        .unwrap();
+        handler.set_error_strategy(ErrorStrategy::Deactivate);
+        let sender = handler.get_sender().unwrap();
+
+        sender.send(1).unwrap();
+        run_thread_loop(&handler).unwrap();
+
+        assert!(handler.is_broken());
+        assert!(handled.borrow().is_empty());
+    }
+
+    #[test]
+    fn second_handler_on_thread_fails() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let handler =
+            Handler::new_on_current_thread(RecordingCallback { events: events.clone() }).unwrap();
+
+        assert!(
+            Handler::new_on_current_thread(RecordingCallback { events: events.clone() }).is_err()
+        );
+
+        // The thread can have a handler again once the first one is dropped.
+        drop(handler);
+        assert!(Handler::new_on_current_thread(RecordingCallback { events }).is_ok());
+    }
+
+    #[test]
+    fn one_shot_input_fd_fires_once_then_is_unregistered() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let handler =
+            Handler::new_on_current_thread(RecordingCallback { events: events.clone() }).unwrap();
+
+        let mut fds = [0; 2];
+        // SAFETY: `fds` is properly allocated.
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        // SAFETY: `fds` are valid owned fds.
+        let (read_fd, write_fd) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let fd_events = events.clone();
+        handler
+            .add_input_fd(read_fd.as_raw_fd(), FdCallbackMode::OneShot, move |fd| {
+                let mut buf = [0u8; 1];
+                // SAFETY: `fd` is a valid pipe fd and `buf` is properly allocated.
+                unsafe { libc::read(fd, buf.as_mut_ptr() as *mut c_void, 1) };
+                fd_events.borrow_mut().push(Event::Fd);
+            })
+            .unwrap();
+
+        // The pipe stays readable after the callback reads the first byte.
+        // SAFETY: `write_fd` is a valid pipe fd and the buffer is 2 bytes long.
+        assert_eq!(
+            unsafe { libc::write(write_fd.as_raw_fd(), [0u8; 2].as_ptr() as *const c_void, 2) },
+            2
+        );
+        while events.borrow().is_empty() {
+            run_thread_loop_once().unwrap();
+        }
+        assert!(handler.input_fds.borrow().is_empty());
+
+        handler.get_sender().unwrap().send(0).unwrap();
+        while events.borrow().len() < 2 {
+            run_thread_loop_once().unwrap();
+        }
+
+        assert_eq!(*events.borrow(), [Event::Fd, Event::Task(0)]);
+        assert!(handler.remove_input_fd(read_fd.as_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn panicking_input_fd_is_unregistered() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let handler =
+            Handler::new_on_current_thread(RecordingCallback { events: events.clone() }).unwrap();
+
+        let mut fds = [0; 2];
+        // SAFETY: `fds` is properly allocated.
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        // SAFETY: `fds` are valid owned fds.
+        let (read_fd, write_fd) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let fd_events = events.clone();
+        handler
+            .add_input_fd(read_fd.as_raw_fd(), FdCallbackMode::Persistent, move |_fd| {
+                fd_events.borrow_mut().push(Event::Fd);
+                panic!("The fd callback panicked");
+            })
+            .unwrap();
+
+        // SAFETY: `write_fd` is a valid pipe fd and the buffer is 1 byte long.
+        assert_eq!(
+            unsafe { libc::write(write_fd.as_raw_fd(), [0u8; 1].as_ptr() as *const c_void, 1) },
+            1
+        );
+        while events.borrow().is_empty() {
+            run_thread_loop_once().unwrap();
+        }
+        assert!(handler.input_fds.borrow().is_empty());
+
+        // The handler keeps handling its tasks, and the still readable fd is no longer polled.
+        handler.get_sender().unwrap().send(0).unwrap();
+        while events.borrow().len() < 2 {
+            run_thread_loop_once().unwrap();
+        }
+
+        assert_eq!(*events.borrow(), [Event::Fd, Event::Task(0)]);
+    }
+
+    /// Sums the tasks until it handles 0.
+    struct SummingCallback {
+        total: Arc<std::sync::atomic::AtomicU32>,
+        finished: bool,
+    }
+
+    impl HandlerCallback<u32> for SummingCallback {
+        fn handle_task(&mut self, task: u32) -> TaskOutcome<u32> {
+            self.total.fetch_add(task, Ordering::Relaxed);
+            self.finished = task == 0;
+            TaskOutcome::Done
+        }
+
+        fn is_finished(&self) -> bool {
+            self.finished
+        }
+    }
+
+    #[test]
+    fn spawned_handler_runs_tasks_until_finished() {
+        let total = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let (handler_thread, sender) =
+            Handler::spawn(SummingCallback { total: total.clone(), finished: false }).unwrap();
+
+        assert_ne!(handler_thread.thread().id(), thread::current().id());
+        for task in [1, 2, 3, 0] {
+            sender.send(task).unwrap();
+        }
+
+        handler_thread.join().unwrap().unwrap();
+        assert_eq!(total.load(Ordering::Relaxed), 6);
+    }
 }
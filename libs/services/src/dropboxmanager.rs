@@ -13,12 +13,53 @@
 // limitations under the License.
 
 //! Rust interface to the dropbox service.
-use anyhow::Result;
+//!
+//! The calls to the service are synchronous binder calls. Async code, e.g. running on a Tokio
+//! runtime, uses the `*_async` variants, which make the binder call on the blocking thread pool.
+use anyhow::{bail, Context, Result};
 use binder::{wait_for_interface, Strong};
 use dropboxmanager_aidl::aidl::com::android::internal::os::IDropBoxManagerService::IDropBoxManagerService;
 
 const INTERFACE_NAME: &str = "dropbox";
 
+/// Flag of entries without contents, see DropBoxManager.java IS_EMPTY.
+pub const IS_EMPTY: i32 = 1;
+
+/// Flag of text entries, see DropBoxManager.java IS_TEXT.
+pub const IS_TEXT: i32 = 2;
+
+/// Flag of gzipped entries, see DropBoxManager.java IS_GZIPPED.
+pub const IS_GZIPPED: i32 = 4;
+
+/// Checks that `flags` is a combination of the entry flags the service accepts for an entry with
+/// `data`. `IS_EMPTY` excludes the other flags and any contents.
+fn validate_flags(flags: i32, data: &[u8]) -> Result<()> {
+    if flags & !(IS_EMPTY | IS_TEXT | IS_GZIPPED) != 0 {
+        bail!("Invalid dropbox flags {:#x}: unknown flags", flags);
+    }
+    if flags & IS_EMPTY != 0 {
+        if flags != IS_EMPTY {
+            bail!("Invalid dropbox flags {:#x}: IS_EMPTY excludes the other flags", flags);
+        }
+        if !data.is_empty() {
+            bail!("Invalid dropbox flags {:#x}: IS_EMPTY with {} bytes of data", flags, data.len());
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `tag` can be used in the name of a dropbox file: it must be non-empty and contain
+/// neither a path separator nor NUL.
+fn validate_tag(tag: &str) -> Result<()> {
+    if tag.is_empty() {
+        bail!("Invalid dropbox tag: the tag is empty");
+    }
+    if let Some(c) = tag.chars().find(|c| matches!(c, '/' | '\0')) {
+        bail!("Invalid dropbox tag {:?}: contains {:?}", tag, c);
+    }
+    Ok(())
+}
+
 /// Interface to the DropBox system service.
 pub struct DropBoxManager {
     binder: Strong<dyn IDropBoxManagerService>,
@@ -31,8 +72,41 @@ impl DropBoxManager {
     }
 
     /// Creates a dropbox entry with the supplied tag. The supplied text is passed as bytes to create the file contents.
+    /// Fails without creating an entry if the tag is invalid.
     pub fn add_text(&self, tag: &str, text: &str) -> Result<()> {
-        self.binder.addData(tag, text.as_bytes(), 2 /* DropBoxManager.java IS_TEXT */)?;
+        validate_tag(tag)?;
+        self.binder.addData(tag, text.as_bytes(), IS_TEXT)?;
+        Ok(())
+    }
+
+    /// Creates a dropbox entry with the supplied tag, binary contents and flags, e.g. `IS_GZIPPED`
+    /// for contents already compressed. Fails without creating an entry if the tag or the flags
+    /// are invalid.
+    pub fn add_data(&self, tag: &str, data: &[u8], flags: i32) -> Result<()> {
+        validate_tag(tag)?;
+        validate_flags(flags, data)?;
+        self.binder.addData(tag, data, flags)?;
+        Ok(())
+    }
+
+    /// Same as `add_text`, without blocking the calling task. Must be called from a Tokio runtime.
+    pub async fn add_text_async(&self, tag: &str, text: &str) -> Result<()> {
+        self.add_entry_async(tag, text.as_bytes().to_vec(), IS_TEXT).await
+    }
+
+    /// Same as `add_data`, without blocking the calling task. Must be called from a Tokio runtime.
+    pub async fn add_data_async(&self, tag: &str, data: Vec<u8>, flags: i32) -> Result<()> {
+        self.add_entry_async(tag, data, flags).await
+    }
+
+    async fn add_entry_async(&self, tag: &str, data: Vec<u8>, flags: i32) -> Result<()> {
+        validate_tag(tag)?;
+        validate_flags(flags, &data)?;
+        let binder = self.binder.clone();
+        let tag = tag.to_string();
+        tokio::task::spawn_blocking(move || binder.addData(&tag, &data, flags))
+            .await
+            .context("The dropbox call did not complete")??;
         Ok(())
     }
 }
@@ -45,19 +119,63 @@ mod tests {
 
     const DROPBOX_PATH: &str = "/data/system/dropbox";
     const TAG: &str = "foo";
+    // The tests run in parallel, each adds entries with its own tag.
+    const ASYNC_TAG: &str = "foo_async";
     const CONTENT: &str = "bar\nbaz\n";
 
     #[test]
     fn add_text() {
-        let _ = find_dropbox_files(true).unwrap();
+        let _ = find_dropbox_files(TAG, true).unwrap();
         let manager = DropBoxManager::new().unwrap();
         manager.add_text(TAG, CONTENT).unwrap();
-        let path_buf = find_dropbox_files(false).unwrap().unwrap();
+        let path_buf = find_dropbox_files(TAG, false).unwrap().unwrap();
+        let content = fs::read_to_string(path_buf.as_path()).unwrap();
+        assert_eq!(content, CONTENT);
+    }
+
+    #[tokio::test]
+    async fn add_text_async() {
+        let _ = find_dropbox_files(ASYNC_TAG, true).unwrap();
+        let manager = DropBoxManager::new().unwrap();
+        manager.add_text_async(ASYNC_TAG, CONTENT).await.unwrap();
+        let path_buf = find_dropbox_files(ASYNC_TAG, false).unwrap().unwrap();
         let content = fs::read_to_string(path_buf.as_path()).unwrap();
         assert_eq!(content, CONTENT);
     }
 
-    fn find_dropbox_files(delete_them: bool) -> Result<Option<PathBuf>> {
+    #[test]
+    fn tag_with_path_separator_is_invalid() {
+        let e = validate_tag("foo/bar").unwrap_err();
+        assert!(e.to_string().contains("foo/bar"), "{e}");
+        assert!(validate_tag("foo\0bar").is_err());
+        assert!(validate_tag("").is_err());
+    }
+
+    #[test]
+    fn valid_tag() {
+        validate_tag(TAG).unwrap();
+        validate_tag("system_server_native_crash").unwrap();
+    }
+
+    #[test]
+    fn valid_flags() {
+        for flags in [0, IS_TEXT, IS_GZIPPED, IS_TEXT | IS_GZIPPED] {
+            validate_flags(flags, CONTENT.as_bytes()).unwrap();
+        }
+        validate_flags(IS_EMPTY, &[]).unwrap();
+    }
+
+    #[test]
+    fn invalid_flags() {
+        assert!(validate_flags(IS_EMPTY | IS_TEXT, &[]).is_err());
+        assert!(validate_flags(IS_EMPTY | IS_GZIPPED, &[]).is_err());
+        assert!(validate_flags(IS_EMPTY, CONTENT.as_bytes()).is_err());
+        let e = validate_flags(8, CONTENT.as_bytes()).unwrap_err();
+        assert!(e.to_string().contains("0x8"), "{e}");
+        assert!(validate_flags(-1, CONTENT.as_bytes()).is_err());
+    }
+
+    fn find_dropbox_files(tag: &str, delete_them: bool) -> Result<Option<PathBuf>> {
         let mut found = None;
         for entry in fs::read_dir(DROPBOX_PATH)? {
             let entry = entry?;
@@ -69,7 +187,8 @@ mod tests {
                 continue;
             };
             let filename = filename.to_string_lossy();
-            if !filename.starts_with(TAG) {
+            // Entry files are named "<tag>@<timestamp>.<extension>".
+            if !filename.starts_with(&format!("{tag}@")) {
                 continue;
             }
 
@@ -14,4 +14,7 @@
 
 //! Uevent utils
 
+/// Mock uevent socket for tests, also available to other crates with the "test-util" feature.
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
 pub mod netlink;
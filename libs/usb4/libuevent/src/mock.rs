@@ -0,0 +1,106 @@
+// Copyright (C) 2025 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mock uevent socket, to drive the users of `AsyncUEventSocket` with scripted uevents in tests,
+//! without netlink permissions.
+
+use crate::netlink::AsyncUEventSocket;
+use anyhow::Result;
+use async_trait::async_trait;
+use kobject_uevent::{ActionType, UEvent};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio::sync::{mpsc, Mutex};
+
+/// Uevent socket yielding the results pushed into its paired sender, in order. Once the sender
+/// is dropped and the pushed results are read, it behaves like an idle socket.
+pub struct MockUEventSocket {
+    receiver: Mutex<mpsc::UnboundedReceiver<Result<UEvent>>>,
+}
+
+impl MockUEventSocket {
+    /// Creates a mock socket and the sender scripting its uevents. Errors pushed into the sender
+    /// are returned by `read` as read failures.
+    pub fn new() -> (Self, mpsc::UnboundedSender<Result<UEvent>>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Self { receiver: Mutex::new(receiver) }, sender)
+    }
+}
+
+#[async_trait]
+impl AsyncUEventSocket for MockUEventSocket {
+    async fn read(&self) -> Result<UEvent> {
+        // Holding the lock across the await keeps this cancel-safe: a result is only taken from
+        // the channel when it is returned.
+        match self.receiver.lock().await.recv().await {
+            Some(uevent_result) => uevent_result,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Builds a synthetic uevent, with the environment the kernel would send for it.
+pub fn build_uevent(action: ActionType, subsystem: &str, devpath: &str) -> UEvent {
+    let action_name = match action {
+        ActionType::Add => "add",
+        ActionType::Remove => "remove",
+        ActionType::Change => "change",
+        ActionType::Move => "move",
+        ActionType::Online => "online",
+        ActionType::Offline => "offline",
+        ActionType::Bind => "bind",
+        ActionType::Unbind => "unbind",
+    };
+    let env = HashMap::from([
+        ("ACTION".to_string(), action_name.to_string()),
+        ("DEVPATH".to_string(), devpath.to_string()),
+        ("SUBSYSTEM".to_string(), subsystem.to_string()),
+    ]);
+    UEvent {
+        action,
+        devpath: PathBuf::from(devpath),
+        subsystem: subsystem.to_string(),
+        env,
+        seq: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_scripted_uevents_in_order() {
+        let (socket, sender) = MockUEventSocket::new();
+        let add = build_uevent(ActionType::Add, "thunderbolt", "/devices/domain0/0-0/0-1");
+        let remove = build_uevent(ActionType::Remove, "thunderbolt", "/devices/domain0/0-0/0-1");
+        sender.send(Ok(add.clone())).unwrap();
+        sender.send(Err(anyhow::anyhow!("read failure"))).unwrap();
+        sender.send(Ok(remove.clone())).unwrap();
+
+        assert_eq!(socket.read().await.unwrap(), add);
+        assert!(socket.read().await.is_err());
+        assert_eq!(socket.read().await.unwrap(), remove);
+    }
+
+    #[tokio::test]
+    async fn is_idle_once_the_sender_is_dropped() {
+        let (socket, sender) = MockUEventSocket::new();
+        drop(sender);
+
+        let read = tokio::time::timeout(std::time::Duration::from_millis(10), socket.read()).await;
+
+        assert!(read.is_err(), "read should be pending");
+    }
+}
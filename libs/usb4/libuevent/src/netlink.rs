@@ -22,7 +22,9 @@ use nix::sys::socket;
 use tokio::io::unix::AsyncFd;
 
 use async_trait::async_trait;
+use nix::errno::Errno;
 use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::time::Duration;
 
 // ueventd uses buffer size of 16M by default - but we go with 1MB buffer.
 // If the consumer of this library is really slow to dequeue packets we risk
@@ -31,6 +33,18 @@ use std::os::fd::{AsFd, AsRawFd, OwnedFd};
 // 1MB should not be a concern here.
 const UEVENT_BUF_SIZE: usize = 1024 * 1024;
 
+/// Parses a kernel uevent netlink packet.
+///
+/// This is the boundary between untrusted packet bytes and the rest of the crate. It doesn't need
+/// a socket, so it can be fed arbitrary inputs from fuzz targets.
+pub fn parse_uevent(bytes: &[u8]) -> Result<kobject_uevent::UEvent> {
+    if bytes.is_empty() {
+        bail!("Empty uevent packet");
+    }
+    kobject_uevent::UEvent::from_netlink_packet(bytes)
+        .map_err(|e| anyhow!("Malformed uevent packet of {} bytes: {e}", bytes.len()))
+}
+
 fn create_socket() -> Result<OwnedFd> {
     let addr = socket::NetlinkAddr::new(0, 0xffffffff);
     let s = socket::socket(
@@ -46,6 +60,41 @@ fn create_socket() -> Result<OwnedFd> {
     Ok(s)
 }
 
+/// Returns whether a socket creation failure may not happen again, i.e. the process or the
+/// system temporarily ran out of resources, as it can during boot.
+fn is_transient_socket_error(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<Errno>(),
+        Some(
+            Errno::EAGAIN
+                | Errno::EINTR
+                | Errno::EMFILE
+                | Errno::ENFILE
+                | Errno::ENOBUFS
+                | Errno::ENOMEM
+        )
+    )
+}
+
+/// Calls `create` up to `attempts` times, waiting `delay` between attempts, until it succeeds or
+/// fails with a non-transient error.
+fn retry_transient<T>(
+    attempts: u32,
+    delay: Duration,
+    mut create: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match create() {
+            Err(e) if attempt < attempts && is_transient_socket_error(&e) => {
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result.with_context(|| format!("Failed after {attempt} attempt(s)")),
+        }
+    }
+}
+
 /// Socket for listening on KObject Uevents
 pub struct NetlinkKObjectUEventSocket {
     fd: OwnedFd,
@@ -86,7 +135,7 @@ impl NetlinkKObjectUEventSocket {
         if count == 0 {
             bail!("Netlink socket recv return 0 bytes");
         }
-        kobject_uevent::UEvent::from_netlink_packet(&buffer[0..count]).map_err(|e| anyhow!("{e}"))
+        parse_uevent(&buffer[0..count])
     }
 }
 
@@ -94,6 +143,9 @@ impl NetlinkKObjectUEventSocket {
 #[async_trait]
 pub trait AsyncUEventSocket: Send + Sync {
     /// Waits for data from netlink socket and returns parsed uevent from read data.
+    ///
+    /// Implementations must be cancel-safe: the future is awaited in `tokio::select!` loops, and
+    /// dropping it before completion must not lose a uevent.
     async fn read(&self) -> Result<kobject_uevent::UEvent>;
 }
 
@@ -105,7 +157,26 @@ pub struct AsyncNetlinkKObjectUEventSocket {
 impl AsyncNetlinkKObjectUEventSocket {
     /// Create async listener on netlink socket for uevents.
     pub fn create() -> Result<Self> {
-        let fd = create_socket()?;
+        Self::from_fd(create_socket()?)
+    }
+
+    /// Like `create`, but retries up to `attempts` attempts in total, `delay` apart, while the
+    /// creation fails for lack of resources. Blocks the calling thread between attempts.
+    pub fn create_with_retry(attempts: u32, delay: Duration) -> Result<Self> {
+        Self::create_with_retry_from(attempts, delay, create_socket)
+    }
+
+    /// Like `create_with_retry`, with the sockets created by `create_socket`.
+    fn create_with_retry_from(
+        attempts: u32,
+        delay: Duration,
+        create_socket: impl FnMut() -> Result<OwnedFd>,
+    ) -> Result<Self> {
+        Self::from_fd(retry_transient(attempts, delay, create_socket)?)
+    }
+
+    /// Create async listener on an already set up non-blocking datagram socket.
+    fn from_fd(fd: OwnedFd) -> Result<Self> {
         let afd = AsyncFd::new(fd)?;
 
         Ok(Self { afd })
@@ -114,6 +185,11 @@ impl AsyncNetlinkKObjectUEventSocket {
 #[async_trait]
 impl AsyncUEventSocket for AsyncNetlinkKObjectUEventSocket {
     /// Waits for data from netlink socket and returns parsed uevent from read data.
+    ///
+    /// This is cancel-safe. The only await point is waiting for readiness, which consumes no
+    /// data. Once the socket is readable, a whole datagram is received and parsed without
+    /// yielding. `try_io` clears the readiness only when `recv` would block, i.e. when there was
+    /// no datagram to lose.
     async fn read(&self) -> Result<kobject_uevent::UEvent> {
         let mut buffer = [0u8; UEVENT_BUF_SIZE];
 
@@ -129,9 +205,139 @@ impl AsyncUEventSocket for AsyncNetlinkKObjectUEventSocket {
                     bail!("Netlink socket read returned 0 bytes");
                 }
 
-                return kobject_uevent::UEvent::from_netlink_packet(&buffer[0..bytes_read])
-                    .map_err(|e| anyhow!("{e}"));
+                return parse_uevent(&buffer[0..bytes_read]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kobject_uevent::ActionType;
+
+    fn build_packet(header: &str, properties: &[(&str, String)]) -> Vec<u8> {
+        let mut packet = header.as_bytes().to_vec();
+        for (key, value) in properties {
+            packet.push(0);
+            packet.extend_from_slice(format!("{key}={value}").as_bytes());
+        }
+        packet.push(0);
+        packet
+    }
+
+    fn basic_properties() -> Vec<(&'static str, String)> {
+        vec![
+            ("ACTION", "add".to_string()),
+            ("DEVPATH", "/devices/domain0/0-0/0-1".to_string()),
+            ("SUBSYSTEM", "thunderbolt".to_string()),
+            ("SEQNUM", "1234".to_string()),
+        ]
+    }
+
+    #[test]
+    fn parse_uevent_rejects_empty_packet() {
+        let err = parse_uevent(&[]).unwrap_err();
+        assert!(err.to_string().contains("Empty"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn parse_uevent_rejects_truncated_packets() {
+        let packet = build_packet("add@/devices/domain0/0-0/0-1", &basic_properties());
+        for len in [1, 4, "add@/devices/domain0/0-0/0-1".len()] {
+            let err = parse_uevent(&packet[..len]).unwrap_err();
+            assert!(err.to_string().contains("Malformed"), "unexpected error: {err}");
+        }
+    }
+
+    #[test]
+    fn parse_uevent_accepts_property_heavy_packet() {
+        let mut properties = basic_properties();
+        let extra_properties: Vec<(String, String)> =
+            (0..200).map(|i| (format!("PROPERTY_{i}"), "x".repeat(i))).collect();
+        properties.extend(extra_properties.iter().map(|(k, v)| (k.as_str(), v.clone())));
+        let packet = build_packet("add@/devices/domain0/0-0/0-1", &properties);
+
+        let uevent = parse_uevent(&packet).unwrap();
+
+        assert_eq!(uevent.action, ActionType::Add);
+        assert_eq!(uevent.subsystem, "thunderbolt");
+        assert_eq!(uevent.seq, 1234);
+        assert_eq!(uevent.env.get("PROPERTY_199"), Some(&"x".repeat(199)));
+    }
+
+    #[tokio::test]
+    async fn cancelled_reads_do_not_lose_uevents() {
+        const UEVENT_COUNT: u64 = 200;
+        let (reader, writer) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        reader.set_nonblocking(true).unwrap();
+        let socket = AsyncNetlinkKObjectUEventSocket::from_fd(reader.into()).unwrap();
+        let mut events = tokio::time::interval(std::time::Duration::from_micros(50));
+
+        let writer_task = tokio::task::spawn_blocking(move || {
+            for seq in 0..UEVENT_COUNT {
+                let mut properties = basic_properties();
+                properties[3].1 = seq.to_string();
+                let packet = build_packet("add@/devices/domain0/0-0/0-1", &properties);
+                writer.send(&packet).unwrap();
+                std::thread::sleep(std::time::Duration::from_micros(seq % 7 * 20));
+            }
+        });
+
+        // Race the reads against another event source, like the policy task does with service
+        // events, so that many of them are cancelled.
+        let mut seqs = Vec::new();
+        let mut cancelled_reads = 0;
+        while seqs.len() < UEVENT_COUNT as usize {
+            tokio::select! {
+                uevent = socket.read() => seqs.push(uevent.unwrap().seq),
+                _ = events.tick() => cancelled_reads += 1,
             }
         }
+        writer_task.await.unwrap();
+
+        assert_eq!(seqs, (0..UEVENT_COUNT).collect::<Vec<_>>());
+        assert!(cancelled_reads > 0);
+    }
+
+    #[tokio::test]
+    async fn create_with_retry_retries_transient_failures() {
+        let mut attempts = 0;
+        let socket = AsyncNetlinkKObjectUEventSocket::create_with_retry_from(
+            3,
+            std::time::Duration::ZERO,
+            || {
+                attempts += 1;
+                if attempts == 1 {
+                    return Err(Errno::ENOBUFS.into());
+                }
+                let (reader, _writer) = std::os::unix::net::UnixDatagram::pair()?;
+                reader.set_nonblocking(true)?;
+                Ok(reader.into())
+            },
+        );
+
+        assert!(socket.is_ok(), "unexpected error: {:#}", socket.err().unwrap());
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn create_with_retry_gives_up() {
+        let mut attempts = 0;
+        let err = retry_transient(3, std::time::Duration::ZERO, || -> Result<()> {
+            attempts += 1;
+            Err(Errno::EMFILE.into())
+        })
+        .unwrap_err();
+        assert_eq!(attempts, 3);
+        assert!(format!("{err:#}").contains("3 attempt(s)"), "unexpected error: {err:#}");
+
+        attempts = 0;
+        retry_transient(3, std::time::Duration::ZERO, || -> Result<()> {
+            attempts += 1;
+            Err(Errno::EPERM.into())
+        })
+        .unwrap_err();
+        assert_eq!(attempts, 1, "non-transient errors shouldn't be retried");
     }
 }
@@ -13,37 +13,392 @@
 // limitations under the License.
 
 //! # Policy Engine java bindings
-use jni::objects::JObject;
-use jni::sys::{jboolean, jint};
-use jni::JNIEnv;
-use log::trace;
-use std::sync::{Arc, LazyLock, Mutex};
+use jni::objects::{GlobalRef, JIntArray, JLongArray, JObject, JObjectArray, JString, JValue};
+use jni::sys::{jboolean, jint, jintArray, jlong, jlongArray, jobjectArray, jsize, jstring};
+use jni::{JNIEnv, JavaVM};
+use log::{error, info, trace, warn, LevelFilter};
+use std::collections::HashSet;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard, Once, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
 use usb4_policies::{
-    common::{TunnelControl, UserId},
-    policy_engine::PolicyEngine,
+    common::{Subsystem, TunnelControl, UserId},
+    pci_authorizer::{EngineHealth, PciAuthState, PciAuthorizerHandle, PolicyEvent},
+    policy_engine::{PolicyEngine, DUMP_TIMEOUT, QUERY_TIMEOUT, SHUTDOWN_TIMEOUT},
+    sysfs::{SysfsUtils, ThunderboltDevice},
 };
 
-// Singleton of PolicyEngine to use for JNI. Will get created on first use.
-static POLICY_ENGINE: LazyLock<Arc<Mutex<PolicyEngine>>> =
-    LazyLock::new(|| Arc::new(Mutex::new(PolicyEngine::new())));
+// Singleton of PolicyEngine to use for JNI. Created by the first successful `nativeInit`, so that
+// a failed creation is retried by the next one.
+static POLICY_ENGINE: Mutex<Option<PolicyEngine>> = Mutex::new(None);
 
-/// Initializes policy engine.
+/// Handle of the policy engine, set once the engine is created. The calls going through it don't
+/// lock the engine, so that they neither wait for each other nor deadlock when called back from
+/// the policy events.
+static POLICY_HANDLE: OnceLock<PciAuthorizerHandle> = OnceLock::new();
+
+/// Thread delivering the policy events to Java, between `nativeInit` and `nativeDestroy`.
+static POLICY_EVENT_DISPATCHER: Mutex<Option<PolicyEventDispatcher>> = Mutex::new(None);
+
+/// Java exception thrown by `nativeInit` when the policy engine can't be created.
+const INIT_ERROR_CLASS: &str = "java/lang/IllegalStateException";
+
+/// Java class describing a thunderbolt device, and the signature of its constructor taking the
+/// name, authorized state, unique id, vendor name and device name of the device.
+const THUNDERBOLT_DEVICE_CLASS: &str = "com/android/server/usb/Usb4Manager$ThunderboltDevice";
+const THUNDERBOLT_DEVICE_CONSTRUCTOR_SIG: &str =
+    "(Ljava/lang/String;ZLjava/lang/String;Ljava/lang/String;Ljava/lang/String;)V";
+
+/// Method of Usb4Manager called when a device is denied, with the unique id of the device, or
+/// null, and the state which denied it (see `auth_state_to_jint`).
+const ON_DEVICE_DENIED_METHOD: &str = "onDeviceDenied";
+const ON_DEVICE_DENIED_SIG: &str = "(Ljava/lang/String;I)V";
+
+/// Method of Usb4Manager called when the authorization state changes, with the previous and the
+/// new state (see `auth_state_to_jint`).
+const ON_STATE_CHANGED_METHOD: &str = "onPolicyStateChanged";
+const ON_STATE_CHANGED_SIG: &str = "(II)V";
+
+/// Indices of the fields of the array returned by `getHealth`. They must match the constants of
+/// Usb4Manager.
+const HEALTH_TASK_ALIVE: usize = 0;
+const HEALTH_DEGRADED: usize = 1;
+const HEALTH_AUTH_STATE: usize = 2;
+const HEALTH_LAST_UEVENT_AGE_MS: usize = 3;
+const HEALTH_UEVENT_ERRORS: usize = 4;
+const HEALTH_TUNNELING_SUPPORTED: usize = 5;
+const HEALTH_LAST_HEARTBEAT_AGE_MS: usize = 6;
+const HEALTH_FIELD_COUNT: usize = 7;
+
+/// Flags of the subsystems passed to `setEnabledSubsystems`. They must match the constants of
+/// Usb4Manager.
+const SUBSYSTEM_FLAG_THUNDERBOLT: jint = 1 << 0;
+const SUBSYSTEM_FLAG_PCI: jint = 1 << 1;
+
+/// Tag of the logs of the policy engine.
+const LOG_TAG: &str = "Usb4Policy";
+
+static LOGGER_INIT: Once = Once::new();
+
+/// Maps an `android.util.Log` priority to the level filter showing logs of that priority and
+/// above. Unknown priorities map to `Info`.
+fn log_level_filter(priority: jint) -> LevelFilter {
+    match priority {
+        2 => LevelFilter::Trace,     // Log.VERBOSE
+        3 => LevelFilter::Debug,     // Log.DEBUG
+        4 => LevelFilter::Info,      // Log.INFO
+        5 => LevelFilter::Warn,      // Log.WARN
+        6 | 7 => LevelFilter::Error, // Log.ERROR, Log.ASSERT
+        _ => LevelFilter::Info,
+    }
+}
+
+/// The locked policy engine, which is known to be created.
+struct PolicyEngineGuard(MutexGuard<'static, Option<PolicyEngine>>);
+
+impl Deref for PolicyEngineGuard {
+    type Target = PolicyEngine;
+
+    fn deref(&self) -> &PolicyEngine {
+        self.0.as_ref().expect("The policy engine is checked on creation of the guard")
+    }
+}
+
+impl DerefMut for PolicyEngineGuard {
+    fn deref_mut(&mut self) -> &mut PolicyEngine {
+        self.0.as_mut().expect("The policy engine is checked on creation of the guard")
+    }
+}
+
+/// Returns the policy engine, or None if it isn't created yet. The error is logged.
+fn policy_engine() -> Option<PolicyEngineGuard> {
+    let engine = POLICY_ENGINE.lock().unwrap();
+    if engine.is_none() {
+        error!("The policy engine is unavailable, nativeInit didn't succeed");
+        return None;
+    }
+    Some(PolicyEngineGuard(engine))
+}
+
+/// Returns the handle of the policy engine, or None if it isn't created yet. The error is logged.
+fn policy_handle() -> Option<&'static PciAuthorizerHandle> {
+    let handle = POLICY_HANDLE.get();
+    if handle.is_none() {
+        error!("The policy engine is unavailable, nativeInit didn't succeed");
+    }
+    handle
+}
+
+/// Initializes the logger with `tag` on the first call. Later calls only update the level.
+fn init_logger(tag: &str, level: LevelFilter) {
+    LOGGER_INIT.call_once(|| {
+        logger::init(logger::Config::default().with_tag_on_device(tag).with_max_level(level));
+    });
+    log::set_max_level(level);
+}
+
+/// Maps an authorization state to the value passed to Java. The values must match the
+/// constants of Usb4Manager.
+fn auth_state_to_jint(state: PciAuthState) -> jint {
+    match state {
+        PciAuthState::Disabled => 0,
+        PciAuthState::DenyNoUser => 1,
+        PciAuthState::DeferNewDevices => 2,
+        PciAuthState::Authorized => 3,
+    }
+}
+
+/// Maps the flags passed to `setEnabledSubsystems` to the enabled subsystems. Unknown flags are
+/// logged and ignored.
+fn subsystems_from_flags(flags: jint) -> HashSet<Subsystem> {
+    let known_flags = [
+        (SUBSYSTEM_FLAG_THUNDERBOLT, Subsystem::Thunderbolt),
+        (SUBSYSTEM_FLAG_PCI, Subsystem::Pci),
+    ];
+    let unknown_flags = known_flags.iter().fold(flags, |flags, (flag, _)| flags & !flag);
+    if unknown_flags != 0 {
+        warn!("Ignoring unknown subsystem flags {:#x}", unknown_flags);
+    }
+    known_flags
+        .into_iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, subsystem)| subsystem)
+        .collect()
+}
+
+/// Flattens the health of the engine into the array returned by `getHealth`. Booleans are 0 or
+/// 1, and unknown values are -1. The ages of the last uevent and heartbeat are measured from
+/// `now`.
+fn health_to_jlongs(health: &EngineHealth, now: Instant) -> [jlong; HEALTH_FIELD_COUNT] {
+    let mut fields = [0; HEALTH_FIELD_COUNT];
+    fields[HEALTH_TASK_ALIVE] = jlong::from(health.task_alive);
+    fields[HEALTH_DEGRADED] = jlong::from(health.degraded);
+    fields[HEALTH_AUTH_STATE] =
+        health.auth_state.map_or(-1, |state| auth_state_to_jint(state).into());
+    let age_ms = |instant: Instant| {
+        now.saturating_duration_since(instant).as_millis().try_into().unwrap_or(jlong::MAX)
+    };
+    fields[HEALTH_LAST_UEVENT_AGE_MS] = health.last_uevent.map_or(-1, age_ms);
+    fields[HEALTH_LAST_HEARTBEAT_AGE_MS] = health.last_heartbeat.map_or(-1, age_ms);
+    fields[HEALTH_UEVENT_ERRORS] =
+        health.uevent_errors_since_success.try_into().unwrap_or(jlong::MAX);
+    fields[HEALTH_TUNNELING_SUPPORTED] = health.tunneling_supported.map_or(-1, jlong::from);
+    fields
+}
+
+/// Initializes policy engine. `log_level` is an `android.util.Log` priority.
+/// The policy events are delivered to the callbacks of `obj`. Throws an IllegalStateException if
+/// the policy engine can't be created.
 #[no_mangle]
 pub extern "system" fn Java_com_android_server_usb_Usb4Manager_nativeInit<'a>(
-    _env: JNIEnv<'a>,
-    _obj: JObject<'a>,
+    mut env: JNIEnv<'a>,
+    obj: JObject<'a>,
+    log_level: jint,
 ) {
-    logger::init(
-        logger::Config::default()
-            .with_tag_on_device("Usb4Policy")
-            .with_max_level(log::LevelFilter::Info),
-    );
+    init_logger(LOG_TAG, log_level_filter(log_level));
 
-    // Initialize policy engine.
-    let _unused = POLICY_ENGINE.lock().unwrap();
+    // Initialize policy engine, unless a previous call did.
+    let mut engine = POLICY_ENGINE.lock().unwrap();
+    if engine.is_none() {
+        match PolicyEngine::try_new() {
+            Ok(created) => {
+                // The engine is only created once, so the handle can't be set already.
+                let _ = POLICY_HANDLE.set(created.pci_authorizer.handle());
+                *engine = Some(created);
+            }
+            Err(e) => {
+                let message = format!("Failed to create the policy engine: {:#}", e);
+                error!("{}", message);
+                if let Err(e) = env.throw_new(INIT_ERROR_CLASS, message) {
+                    error!("Failed to throw the init error: {}", e);
+                }
+                return;
+            }
+        }
+    }
+    let policy_events = engine.as_mut().and_then(PolicyEngine::take_policy_events);
+    drop(engine);
+    if let Some(policy_events) = policy_events {
+        if let Err(e) = start_policy_event_dispatcher(&env, &obj, policy_events) {
+            error!("Failed to start the policy event dispatcher: {}", e);
+        }
+    }
     trace!("Native init complete!");
 }
 
+/// Starts a thread calling the callbacks of `manager` for each policy event, until
+/// `nativeDestroy`.
+fn start_policy_event_dispatcher(
+    env: &JNIEnv,
+    manager: &JObject,
+    policy_events: mpsc::Receiver<PolicyEvent>,
+) -> jni::errors::Result<()> {
+    let callbacks =
+        JavaPolicyCallbacks { vm: env.get_java_vm()?, manager: env.new_global_ref(manager)? };
+    match PolicyEventDispatcher::start(policy_events, callbacks) {
+        Ok(dispatcher) => *POLICY_EVENT_DISPATCHER.lock().unwrap() = Some(dispatcher),
+        Err(e) => error!("Failed to spawn the policy event thread: {}", e),
+    }
+    Ok(())
+}
+
+/// Receiver of the policy events.
+trait PolicyEventCallbacks {
+    fn on_device_denied(
+        &mut self,
+        unique_id: Option<&str>,
+        reason: PciAuthState,
+    ) -> jni::errors::Result<()>;
+
+    fn on_state_changed(&mut self, from: PciAuthState, to: PciAuthState)
+        -> jni::errors::Result<()>;
+}
+
+/// Calls the callbacks of a Usb4Manager.
+struct JavaPolicyCallbacks {
+    vm: JavaVM,
+    /// Released when the dispatcher thread exits.
+    manager: GlobalRef,
+}
+
+impl JavaPolicyCallbacks {
+    /// Returns the environment of the dispatcher thread. The thread stays attached to the JVM
+    /// until it exits.
+    fn env(&self) -> jni::errors::Result<JNIEnv<'_>> {
+        self.vm.attach_current_thread_permanently()
+    }
+}
+
+impl PolicyEventCallbacks for JavaPolicyCallbacks {
+    fn on_device_denied(
+        &mut self,
+        unique_id: Option<&str>,
+        reason: PciAuthState,
+    ) -> jni::errors::Result<()> {
+        let mut env = self.env()?;
+        // The thread never returns to Java, so the local references must be freed here.
+        env.with_local_frame(1, |env| {
+            let unique_id = new_optional_string(env, unique_id)?;
+            env.call_method(
+                &self.manager,
+                ON_DEVICE_DENIED_METHOD,
+                ON_DEVICE_DENIED_SIG,
+                &[JValue::Object(&unique_id), JValue::Int(auth_state_to_jint(reason))],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn on_state_changed(
+        &mut self,
+        from: PciAuthState,
+        to: PciAuthState,
+    ) -> jni::errors::Result<()> {
+        let mut env = self.env()?;
+        env.call_method(
+            &self.manager,
+            ON_STATE_CHANGED_METHOD,
+            ON_STATE_CHANGED_SIG,
+            &[JValue::Int(auth_state_to_jint(from)), JValue::Int(auth_state_to_jint(to))],
+        )?;
+        Ok(())
+    }
+}
+
+/// Thread draining the policy events into callbacks.
+struct PolicyEventDispatcher {
+    stop: oneshot::Sender<()>,
+    /// Returns the receiver of the policy events once stopped.
+    thread: thread::JoinHandle<mpsc::Receiver<PolicyEvent>>,
+}
+
+impl PolicyEventDispatcher {
+    fn start(
+        mut policy_events: mpsc::Receiver<PolicyEvent>,
+        mut callbacks: impl PolicyEventCallbacks + Send + 'static,
+    ) -> std::io::Result<Self> {
+        let (stop, stopped) = oneshot::channel();
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let thread =
+            thread::Builder::new().name("usb4_policy_events".to_string()).spawn(move || {
+                runtime.block_on(drain_policy_events(&mut policy_events, &mut callbacks, stopped));
+                policy_events
+            })?;
+        Ok(Self { stop, thread })
+    }
+
+    /// Stops the thread after the delivery in progress, if any, and returns the receiver of the
+    /// policy events. Returns None if the thread panicked.
+    fn stop(self) -> Option<mpsc::Receiver<PolicyEvent>> {
+        // The thread may have exited on its own if the channel is closed.
+        let _ = self.stop.send(());
+        self.thread.join().ok()
+    }
+}
+
+/// Delivers the policy events to `callbacks` until the channel is closed or `stopped` fires.
+async fn drain_policy_events(
+    policy_events: &mut mpsc::Receiver<PolicyEvent>,
+    callbacks: &mut impl PolicyEventCallbacks,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            // Stop requests take precedence over the pending events, which are kept in the
+            // channel for the next dispatcher.
+            biased;
+            _ = &mut stopped => {
+                info!("Policy event dispatcher stopped.");
+                return;
+            }
+            event = policy_events.recv() => {
+                let Some(event) = event else {
+                    info!("Policy event channel closed.");
+                    return;
+                };
+                if let Err(e) = dispatch_policy_event(callbacks, &event) {
+                    error!("Failed to deliver {:?}: {}", event, e);
+                }
+            }
+        }
+    }
+}
+
+fn dispatch_policy_event(
+    callbacks: &mut impl PolicyEventCallbacks,
+    event: &PolicyEvent,
+) -> jni::errors::Result<()> {
+    match event {
+        PolicyEvent::DeviceDenied { unique_id, reason } => {
+            callbacks.on_device_denied(unique_id.as_deref(), *reason)
+        }
+        PolicyEvent::StateChanged { from, to } => callbacks.on_state_changed(*from, *to),
+    }
+}
+
+/// Stops delivering the policy events to the Usb4Manager passed to `nativeInit`, and releases
+/// its global reference. A later `nativeInit` delivers the events to its Usb4Manager.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_nativeDestroy<'a>(
+    _env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+) {
+    let Some(dispatcher) = POLICY_EVENT_DISPATCHER.lock().unwrap().take() else {
+        return;
+    };
+    // The engine isn't locked while waiting for the thread, as the callbacks may call into it.
+    let Some(policy_events) = dispatcher.stop() else {
+        error!("The policy event thread panicked. Dropping the policy events.");
+        return;
+    };
+    if let Some(mut engine) = policy_engine() {
+        engine.restore_policy_events(policy_events);
+    }
+    trace!("Native destroy complete!");
+}
+
 /// Enables or disables PCI tunnels.
 #[no_mangle]
 pub extern "system" fn Java_com_android_server_usb_Usb4Manager_enablePciTunnels<'a>(
@@ -52,8 +407,10 @@ pub extern "system" fn Java_com_android_server_usb_Usb4Manager_enablePciTunnels<
     enable: jboolean,
 ) {
     trace!("enablePciTunnels with {}", enable != 0);
-    let mut engine = POLICY_ENGINE.lock().unwrap();
-    engine.enable_pci_tunnels(enable != 0);
+    let Some(control) = policy_handle() else {
+        return;
+    };
+    control.enable_pci_tunnels(enable != 0);
 }
 
 /// Updates the screen lock state.
@@ -64,8 +421,10 @@ pub extern "system" fn Java_com_android_server_usb_Usb4Manager_updateLockState<'
     locked: jboolean,
 ) {
     trace!("updateLockState with {}", locked != 0);
-    let mut engine = POLICY_ENGINE.lock().unwrap();
-    engine.update_lock_state(locked != 0);
+    let Some(control) = policy_handle() else {
+        return;
+    };
+    control.update_lock_state(locked != 0);
 }
 
 /// Updates the logged-in state for a user.
@@ -77,6 +436,463 @@ pub extern "system" fn Java_com_android_server_usb_Usb4Manager_updateLoggedInSta
     user_id: jint,
 ) {
     trace!("updateLoggedInstate with {} = {}", user_id as usize, logged_in != 0);
-    let mut engine = POLICY_ENGINE.lock().unwrap();
-    engine.update_logged_in_state(logged_in != 0, UserId(user_id as usize));
+    let Some(control) = policy_handle() else {
+        return;
+    };
+    control.update_logged_in_state(logged_in != 0, UserId(user_id as usize));
+}
+
+/// Replaces the set of logged-in users.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_setLoggedInUsers<'a>(
+    env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+    user_ids: JIntArray<'a>,
+) {
+    let user_ids = match read_int_array(&env, &user_ids) {
+        Ok(user_ids) => user_ids,
+        Err(e) => {
+            error!("setLoggedInUsers failed to read the user ids: {}", e);
+            return;
+        }
+    };
+    trace!("setLoggedInUsers with {:?}", user_ids);
+    let Some(control) = policy_handle() else {
+        return;
+    };
+    control.set_logged_in_users(
+        user_ids.into_iter().map(|user_id| UserId(user_id as usize)).collect::<HashSet<_>>(),
+    );
+}
+
+/// Pauses or resumes the enforcement of the policy, e.g. while a device is being provisioned.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_setEnforcementPaused<'a>(
+    _env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+    paused: jboolean,
+) {
+    trace!("setEnforcementPaused with {}", paused != 0);
+    let Some(control) = policy_handle() else {
+        return;
+    };
+    control.set_enforcement_paused(paused != 0);
+}
+
+/// Replaces the set of subsystems the engine acts on, as flags (see `subsystems_from_flags`).
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_setEnabledSubsystems<'a>(
+    _env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+    flags: jint,
+) {
+    let subsystems = subsystems_from_flags(flags);
+    trace!("setEnabledSubsystems with {:?}", subsystems);
+    let Some(control) = policy_handle() else {
+        return;
+    };
+    control.set_enabled_subsystems(subsystems);
+}
+
+/// Notifies the engine that the device resumed from suspend, during which uevents may have been
+/// missed.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_notifyResumed<'a>(
+    _env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+) {
+    trace!("notifyResumed");
+    let Some(handle) = policy_handle() else {
+        return;
+    };
+    handle.notify_resumed();
+}
+
+/// Removes the tunneled PCI device at the address `bdf`, e.g. "0000:05:00.0", leaving the other
+/// devices in place. Returns false on failure.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_removePciDevice<'a>(
+    mut env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+    bdf: JString<'a>,
+) -> jboolean {
+    let bdf: String = match env.get_string(&bdf) {
+        Ok(bdf) => bdf.into(),
+        Err(e) => {
+            error!("removePciDevice failed to read the device address: {}", e);
+            return jboolean::from(false);
+        }
+    };
+    trace!("removePciDevice with {}", bdf);
+    let Some(control) = policy_handle() else {
+        return jboolean::from(false);
+    };
+    match control.remove_pci_device(&bdf) {
+        Ok(()) => jboolean::from(true),
+        Err(e) => {
+            error!("removePciDevice failed: {:#}", e);
+            jboolean::from(false)
+        }
+    }
+}
+
+/// Returns the ids of the users the policy considers logged in, sorted. Returns null on failure.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_getLoggedInUsers<'a>(
+    env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+) -> jintArray {
+    let Some(handle) = policy_handle() else {
+        return std::ptr::null_mut();
+    };
+    let user_ids = match handle.logged_in_users(QUERY_TIMEOUT) {
+        Ok(user_ids) => user_ids,
+        Err(e) => {
+            error!("getLoggedInUsers failed: {:#}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut user_ids: Vec<jint> = user_ids.into_iter().map(|user_id| user_id.0 as jint).collect();
+    user_ids.sort_unstable();
+    trace!("getLoggedInUsers returns {:?}", user_ids);
+    match new_int_array(&env, &user_ids) {
+        Ok(array) => array.into_raw(),
+        Err(e) => {
+            error!("getLoggedInUsers failed to create the user id array: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Checks that the policy task is running, restarting it if it died.
+/// Returns false if the task had to be restarted.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_ensurePolicyTaskAlive<'a>(
+    _env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+) -> jboolean {
+    let Some(handle) = policy_handle() else {
+        return jboolean::from(false);
+    };
+    let alive = !handle.restart_task();
+    if !alive {
+        error!("Policy task died and was restarted");
+    }
+    jboolean::from(alive)
+}
+
+/// Blocks until the policy updates sent so far are applied to sysfs, for at most `timeout_ms`
+/// milliseconds. Returns false on timeout or failure.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_flushPendingPolicy<'a>(
+    _env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+    timeout_ms: jlong,
+) -> jboolean {
+    trace!("flushPendingPolicy with timeout {}ms", timeout_ms);
+    let Some(handle) = policy_handle() else {
+        return jboolean::from(false);
+    };
+    match handle.flush(Duration::from_millis(timeout_ms.max(0) as u64)) {
+        Ok(()) => jboolean::from(true),
+        Err(e) => {
+            error!("flushPendingPolicy failed: {:#}", e);
+            jboolean::from(false)
+        }
+    }
+}
+
+/// Deauthorizes all the devices if `deauthorize` is set, then stops the policy task, before an
+/// orderly reboot. Once a call succeeds, the next ones have no effect. Returns false on timeout
+/// or failure.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_prepareForShutdown<'a>(
+    _env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+    deauthorize: jboolean,
+) -> jboolean {
+    trace!("prepareForShutdown with {}", deauthorize != 0);
+    let Some(handle) = policy_handle() else {
+        return jboolean::from(false);
+    };
+    match handle.prepare_for_shutdown(deauthorize != 0, SHUTDOWN_TIMEOUT) {
+        Ok(()) => jboolean::from(true),
+        Err(e) => {
+            error!("prepareForShutdown failed: {:#}", e);
+            jboolean::from(false)
+        }
+    }
+}
+
+/// Returns the health of the policy engine for the watchdog, without waiting for the policy
+/// task. See `health_to_jlongs` for the layout of the array. Returns null on failure.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_getHealth<'a>(
+    env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+) -> jlongArray {
+    let Some(handle) = policy_handle() else {
+        return std::ptr::null_mut();
+    };
+    let health = handle.health();
+    trace!("getHealth returns {:?}", health);
+    match new_long_array(&env, &health_to_jlongs(&health, Instant::now())) {
+        Ok(array) => array.into_raw(),
+        Err(e) => {
+            error!("getHealth failed to create the health array: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Returns a human-readable report of the state of the policy engine, for dumpsys. Returns null
+/// on failure.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_dump<'a>(
+    env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+) -> jstring {
+    let Some(handle) = policy_handle() else {
+        return std::ptr::null_mut();
+    };
+    let report = handle.dump(DUMP_TIMEOUT);
+    match env.new_string(report) {
+        Ok(report) => report.into_raw(),
+        Err(e) => {
+            error!("dump failed to create the report string: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Lists the connected thunderbolt devices. Returns null on failure.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_usb_Usb4Manager_listThunderboltDevices<'a>(
+    mut env: JNIEnv<'a>,
+    _obj: JObject<'a>,
+) -> jobjectArray {
+    let devices = match SysfsUtils::from_env().list_thunderbolt_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            error!("listThunderboltDevices failed to list the devices: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    trace!("listThunderboltDevices found {} devices", devices.len());
+    match new_thunderbolt_device_array(&mut env, &devices) {
+        Ok(array) => array.into_raw(),
+        Err(e) => {
+            error!("listThunderboltDevices failed to create the device array: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+fn new_thunderbolt_device_array<'a>(
+    env: &mut JNIEnv<'a>,
+    devices: &[ThunderboltDevice],
+) -> jni::errors::Result<JObjectArray<'a>> {
+    let class = env.find_class(THUNDERBOLT_DEVICE_CLASS)?;
+    let array = env.new_object_array(devices.len() as jsize, &class, JObject::null())?;
+    for (index, device) in devices.iter().enumerate() {
+        let name = JObject::from(env.new_string(&device.name)?);
+        let unique_id = new_optional_string(env, device.unique_id.as_deref())?;
+        let vendor_name = new_optional_string(env, device.vendor_name.as_deref())?;
+        let device_name = new_optional_string(env, device.device_name.as_deref())?;
+        let object = env.new_object(
+            &class,
+            THUNDERBOLT_DEVICE_CONSTRUCTOR_SIG,
+            &[
+                JValue::Object(&name),
+                JValue::Bool(jboolean::from(device.authorized)),
+                JValue::Object(&unique_id),
+                JValue::Object(&vendor_name),
+                JValue::Object(&device_name),
+            ],
+        )?;
+        env.set_object_array_element(&array, index as jsize, object)?;
+    }
+    Ok(array)
+}
+
+fn new_optional_string<'a>(
+    env: &mut JNIEnv<'a>,
+    value: Option<&str>,
+) -> jni::errors::Result<JObject<'a>> {
+    match value {
+        Some(value) => Ok(env.new_string(value)?.into()),
+        None => Ok(JObject::null()),
+    }
+}
+
+fn read_int_array(env: &JNIEnv, array: &JIntArray) -> jni::errors::Result<Vec<jint>> {
+    let len = env.get_array_length(array)?;
+    let mut values = vec![0; len as usize];
+    env.get_int_array_region(array, 0, &mut values)?;
+    Ok(values)
+}
+
+fn new_int_array<'a>(env: &JNIEnv<'a>, values: &[jint]) -> jni::errors::Result<JIntArray<'a>> {
+    let array = env.new_int_array(values.len() as jsize)?;
+    env.set_int_array_region(&array, 0, values)?;
+    Ok(array)
+}
+
+fn new_long_array<'a>(env: &JNIEnv<'a>, values: &[jlong]) -> jni::errors::Result<JLongArray<'a>> {
+    let array = env.new_long_array(values.len() as jsize)?;
+    env.set_long_array_region(&array, 0, values)?;
+    Ok(array)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_priority_maps_to_level_filter() {
+        assert_eq!(log_level_filter(2), LevelFilter::Trace);
+        assert_eq!(log_level_filter(3), LevelFilter::Debug);
+        assert_eq!(log_level_filter(4), LevelFilter::Info);
+        assert_eq!(log_level_filter(5), LevelFilter::Warn);
+        assert_eq!(log_level_filter(6), LevelFilter::Error);
+        assert_eq!(log_level_filter(7), LevelFilter::Error);
+    }
+
+    #[test]
+    fn unknown_log_priority_maps_to_info() {
+        assert_eq!(log_level_filter(0), LevelFilter::Info);
+        assert_eq!(log_level_filter(-1), LevelFilter::Info);
+        assert_eq!(log_level_filter(100), LevelFilter::Info);
+    }
+
+    #[test]
+    fn init_logger_is_idempotent() {
+        init_logger(LOG_TAG, LevelFilter::Info);
+        init_logger(LOG_TAG, LevelFilter::Trace);
+        assert_eq!(log::max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn subsystem_flags_map_to_subsystems() {
+        assert_eq!(subsystems_from_flags(0), HashSet::new());
+        assert_eq!(
+            subsystems_from_flags(SUBSYSTEM_FLAG_THUNDERBOLT),
+            HashSet::from([Subsystem::Thunderbolt])
+        );
+        assert_eq!(
+            subsystems_from_flags(SUBSYSTEM_FLAG_THUNDERBOLT | SUBSYSTEM_FLAG_PCI),
+            HashSet::from(Subsystem::ALL)
+        );
+        // Unknown flags are ignored.
+        assert_eq!(
+            subsystems_from_flags(1 << 8 | SUBSYSTEM_FLAG_PCI),
+            HashSet::from([Subsystem::Pci])
+        );
+    }
+
+    #[test]
+    fn health_is_flattened_for_java() {
+        let now = Instant::now();
+        let health = EngineHealth {
+            task_alive: true,
+            degraded: false,
+            auth_state: Some(PciAuthState::Authorized),
+            last_uevent: Some(now - Duration::from_millis(1500)),
+            last_heartbeat: Some(now - Duration::from_millis(20)),
+            uevent_errors_since_success: 2,
+            tunneling_supported: Some(true),
+        };
+
+        assert_eq!(health_to_jlongs(&health, now), [1, 0, 3, 1500, 2, 1, 20]);
+    }
+
+    #[test]
+    fn unknown_health_fields_are_negative() {
+        let health = EngineHealth {
+            task_alive: false,
+            degraded: true,
+            auth_state: None,
+            last_uevent: None,
+            last_heartbeat: None,
+            uevent_errors_since_success: 0,
+            tunneling_supported: None,
+        };
+
+        assert_eq!(health_to_jlongs(&health, Instant::now()), [0, 1, -1, -1, 0, -1, -1]);
+    }
+
+    /// Records the policy events in place of a Usb4Manager, failing to deliver denied devices
+    /// without unique id.
+    struct RecordingCallbacks(std::sync::mpsc::Sender<PolicyEvent>);
+
+    impl PolicyEventCallbacks for RecordingCallbacks {
+        fn on_device_denied(
+            &mut self,
+            unique_id: Option<&str>,
+            reason: PciAuthState,
+        ) -> jni::errors::Result<()> {
+            let unique_id = unique_id.ok_or(jni::errors::Error::JavaException)?;
+            let event =
+                PolicyEvent::DeviceDenied { unique_id: Some(unique_id.to_string()), reason };
+            self.0.send(event).unwrap();
+            Ok(())
+        }
+
+        fn on_state_changed(
+            &mut self,
+            from: PciAuthState,
+            to: PciAuthState,
+        ) -> jni::errors::Result<()> {
+            self.0.send(PolicyEvent::StateChanged { from, to }).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dispatcher_delivers_policy_events_in_order() {
+        let (event_sender, policy_events) = mpsc::channel(4);
+        let (delivered_sender, delivered) = std::sync::mpsc::channel();
+        let dispatcher =
+            PolicyEventDispatcher::start(policy_events, RecordingCallbacks(delivered_sender))
+                .unwrap();
+        let state_changed = PolicyEvent::StateChanged {
+            from: PciAuthState::Disabled,
+            to: PciAuthState::DenyNoUser,
+        };
+        let denied = PolicyEvent::DeviceDenied {
+            unique_id: Some("denied-uuid".to_string()),
+            reason: PciAuthState::DenyNoUser,
+        };
+        event_sender.try_send(state_changed.clone()).unwrap();
+        // Failing to deliver an event doesn't stop the dispatcher.
+        event_sender
+            .try_send(PolicyEvent::DeviceDenied { unique_id: None, reason: PciAuthState::Disabled })
+            .unwrap();
+        event_sender.try_send(denied.clone()).unwrap();
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(delivered.recv_timeout(timeout).unwrap(), state_changed);
+        assert_eq!(delivered.recv_timeout(timeout).unwrap(), denied);
+        assert!(dispatcher.stop().is_some());
+    }
+
+    #[test]
+    fn stopped_dispatcher_returns_the_open_receiver() {
+        let (event_sender, policy_events) = mpsc::channel(4);
+        let (delivered_sender, delivered) = std::sync::mpsc::channel();
+        let dispatcher =
+            PolicyEventDispatcher::start(policy_events, RecordingCallbacks(delivered_sender))
+                .unwrap();
+
+        let mut policy_events = dispatcher.stop().unwrap();
+        let state_changed = PolicyEvent::StateChanged {
+            from: PciAuthState::Disabled,
+            to: PciAuthState::DenyNoUser,
+        };
+        event_sender.try_send(state_changed.clone()).unwrap();
+
+        // The events sent after the stop are left for the next dispatcher.
+        assert_eq!(policy_events.try_recv().unwrap(), state_changed);
+        assert!(delivered.try_recv().is_err());
+    }
 }
@@ -16,13 +16,34 @@
 //!
 //! This module contains shared data structures and traits used across the crate.
 
+use anyhow::Result;
 use std::collections::HashSet;
 
 /// Newtype to hold user ids.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct UserId(pub usize);
 
+/// Identifies a thunderbolt device across reconnections: its unique id, or its kernel name if it
+/// doesn't expose one. See `ThunderboltDevice::key`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
+pub struct DeviceId(pub String);
+
+/// Subsystem whose tunnels the policy engine handles.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Subsystem {
+    /// Thunderbolt devices, whose authorization sets up their PCI tunnels.
+    Thunderbolt,
+    /// Tunneled PCI devices, which are removed when the devices are denied.
+    Pci,
+}
+
+impl Subsystem {
+    /// All the subsystems, enabled by default.
+    pub const ALL: [Subsystem; 2] = [Subsystem::Thunderbolt, Subsystem::Pci];
+}
+
 /// Holds the live state variables that determine the authorization policy.
+#[derive(Clone, Debug)]
 pub struct PolicySourceData {
     /// A flag indicating if the PCI tunneling feature is globally enabled.
     pub pci_tunnels_enabled: bool,
@@ -30,15 +51,23 @@ pub struct PolicySourceData {
     pub is_locked: bool,
     /// A set tracking the IDs of all currently logged-in users.
     pub logged_in_users: HashSet<UserId>,
+    /// The subsystems the engine acts on. The devices of the other subsystems are left as they
+    /// are, e.g. on products only supporting DisplayPort tunnels.
+    pub enabled_subsystems: HashSet<Subsystem>,
 }
 
 impl PolicySourceData {
     /// Creates a new `PolicySourceData` with default, restrictive values.
     ///
-    /// By default, tunnels are disabled, the screen is considered locked, and no
-    /// users are logged in.
+    /// By default, tunnels are disabled, the screen is considered locked, no
+    /// users are logged in, and all the subsystems are enabled.
     pub fn new() -> Self {
-        Self { pci_tunnels_enabled: false, is_locked: true, logged_in_users: HashSet::new() }
+        Self {
+            pci_tunnels_enabled: false,
+            is_locked: true,
+            logged_in_users: HashSet::new(),
+            enabled_subsystems: HashSet::from(Subsystem::ALL),
+        }
     }
 }
 
@@ -52,13 +81,34 @@ impl Default for PolicySourceData {
 ///
 /// This trait is implemented by the `PolicyEngine` and provides the entry points
 /// for a consumer of this library to notify the engine of system state changes.
+/// The methods take `&self`, so that cloneable handles can implement it and be
+/// shared between threads without a lock.
 pub trait TunnelControl {
     /// Enables or disables the PCI tunneling feature globally.
-    fn enable_pci_tunnels(&mut self, enable: bool);
+    fn enable_pci_tunnels(&self, enable: bool);
 
     /// Notifies the engine of a screen lock state change.
-    fn update_lock_state(&mut self, locked: bool);
+    fn update_lock_state(&self, locked: bool);
 
     /// Notifies the engine of a user login or logout event.
-    fn update_logged_in_state(&mut self, logged_in: bool, user_id: UserId);
+    fn update_logged_in_state(&self, logged_in: bool, user_id: UserId);
+
+    /// Replaces the set of logged-in users at once, so that the policy is only recalculated for
+    /// the final set.
+    fn set_logged_in_users(&self, user_ids: HashSet<UserId>);
+
+    /// Pauses or resumes the enforcement of the policy. While paused, the engine keeps tracking
+    /// the policy inputs but leaves the devices as they are. On resume, the devices are brought
+    /// to the state of the current inputs at once.
+    fn set_enforcement_paused(&self, paused: bool);
+
+    /// Replaces the set of subsystems the engine acts on. Disabling `Subsystem::Thunderbolt` stops
+    /// the engine from touching the devices, without deauthorizing the authorized ones. Disabling
+    /// `Subsystem::Pci` leaves the PCI devices in place when the devices are denied, and makes
+    /// `remove_pci_device` fail.
+    fn set_enabled_subsystems(&self, subsystems: HashSet<Subsystem>);
+
+    /// Removes the tunneled PCI device at the address `bdf`, e.g. "0000:05:00.0", and the
+    /// devices behind it, e.g. once it is identified as malicious. The other devices stay.
+    fn remove_pci_device(&self, bdf: &str) -> Result<()>;
 }
@@ -0,0 +1,112 @@
+// Copyright (C) 2025 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Config
+//!
+//! This module reads the device configuration of the initial policy.
+//!
+//! The config file uses the flat `key = value` subset of TOML, e.g.:
+//!
+//! ```toml
+//! # Tunnel PCI by default on this device.
+//! pci_tunnels_enabled = true
+//! idle_timeout_secs = 600
+//! # The kernel doesn't report the IOMMU DMA protection, which the device always has.
+//! trust_unknown_dma_protection = true
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info, warn};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Location of the config file on the device.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/usb4/policy.toml";
+
+/// Initial policy of the engine, before any update from the framework.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PolicyConfig {
+    /// Whether PCI tunnels are enabled until the framework says otherwise.
+    pub pci_tunnels_enabled: bool,
+    /// Idle period after which an authorized device is deauthorized. None disables the timeout.
+    pub idle_deauthorize_timeout: Option<Duration>,
+    /// Whether devices are authorized when a thunderbolt domain doesn't report its IOMMU DMA
+    /// protection. Only meant for devices known to have it on kernels which don't report it.
+    pub trust_unknown_dma_protection: bool,
+}
+
+impl PolicyConfig {
+    /// Parses the content of a config file. Unknown keys are ignored.
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for (index, line) in content.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| anyhow!("Line {}: expected `key = value`", line_number))?;
+            match key {
+                "pci_tunnels_enabled" => {
+                    config.pci_tunnels_enabled = value
+                        .parse::<bool>()
+                        .with_context(|| format!("Line {}: invalid {}", line_number, key))?;
+                }
+                "trust_unknown_dma_protection" => {
+                    config.trust_unknown_dma_protection = value
+                        .parse::<bool>()
+                        .with_context(|| format!("Line {}: invalid {}", line_number, key))?;
+                }
+                "idle_timeout_secs" => {
+                    let secs = value
+                        .parse::<u64>()
+                        .with_context(|| format!("Line {}: invalid {}", line_number, key))?;
+                    config.idle_deauthorize_timeout =
+                        if secs == 0 { None } else { Some(Duration::from_secs(secs)) };
+                }
+                _ if key.is_empty() => bail!("Line {}: missing key", line_number),
+                _ => warn!("Line {}: ignoring unknown key {:?}", line_number, key),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Loads the config file at `path`. A missing or malformed file results in the default,
+    /// restrictive, config.
+    pub fn load(path: &Path) -> Self {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                error!("Failed to read {:?}, using the default policy: {}", path, e);
+                return Self::default();
+            }
+        };
+        match Self::parse(&content) {
+            Ok(config) => {
+                info!("Loaded {:?}: {:?}", path, config);
+                config
+            }
+            Err(e) => {
+                error!("Malformed {:?}, using the default policy: {:#}", path, e);
+                Self::default()
+            }
+        }
+    }
+}
@@ -25,9 +25,13 @@
 
 /// Defines shared data structures and the primary control trait.
 pub mod common;
+/// Reads the device configuration of the initial policy.
+pub mod config;
 /// Implements the core authorization logic and Uevent handling.
 pub mod pci_authorizer;
 /// Provides the main public-facing API for the library.
 pub mod policy_engine;
 /// Provided sysfs utilities
 pub mod sysfs;
+/// Publishes the systrace counters of the policy engine.
+mod trace;
@@ -12,19 +12,48 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::common::{PolicySourceData, TunnelControl, UserId};
-use crate::sysfs::SysfsUtils;
-use anyhow::Result;
+use crate::common::{DeviceId, PolicySourceData, Subsystem, TunnelControl, UserId};
+use crate::config::{PolicyConfig, DEFAULT_CONFIG_PATH};
+use crate::sysfs::{SweepOutcome, SysfsUtils, ThunderboltEntryType};
+use crate::trace;
+use anyhow::{bail, Context, Result};
 use kobject_uevent::ActionType;
-use log::{error, info};
-use std::path::Path;
-use std::sync::Arc;
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use uevent::netlink::{AsyncNetlinkKObjectUEventSocket, AsyncUEventSocket};
 
 /// Message queue size.
 const MESSAGE_QUEUE_SIZE: usize = 10;
 
+/// Attempts to create the netlink uevent socket, which can transiently fail during boot.
+const NETLINK_SOCKET_ATTEMPTS: u32 = 5;
+
+/// Delay between two attempts to create the netlink uevent socket.
+const NETLINK_SOCKET_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Minimum interval between two logs of uevent read errors.
+const UEVENT_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Checks of the readiness of an added device before authorizing it anyway.
+const DEVICE_READY_ATTEMPTS: u32 = 10;
+
+/// Delay between two checks of the readiness of an added device.
+const DEVICE_READY_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Longest interval between two heartbeats of an idle task. A task whose last heartbeat is much
+/// older is stuck. Usb4Manager.HEARTBEAT_INTERVAL_MS must match it.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Longest wait for room in the uevent observer, as the task handles nothing else meanwhile.
+pub const MAX_UEVENT_OBSERVER_RETRY: Duration = Duration::from_millis(20);
+
 /// Enum for the PCI authorization state machine.
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum PciAuthState {
@@ -38,212 +67,2561 @@ pub enum PciAuthState {
     Authorized,
 }
 
+/// Bulk sysfs action taken when the authorization state changes, or when the sweep of the
+/// current state is resumed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TransitionAction {
+    /// Authorizes all the devices.
+    AuthorizeAll,
+    /// Authorizes the devices which aren't, checking each device instead of trusting the state
+    /// known so far.
+    ReconcileAuthorized,
+    /// Deauthorizes all the devices.
+    DeauthorizeAll,
+    /// Leaves the devices as they are.
+    LeaveDevices,
+}
+
+/// Returns the bulk sysfs action of the transition from `old` to `new`. Every transition is
+/// listed, so that a new state doesn't compile until its transitions are decided.
+fn action_for_transition(old: PciAuthState, new: PciAuthState) -> TransitionAction {
+    use PciAuthState::{Authorized, DeferNewDevices, DenyNoUser, Disabled};
+    match (old, new) {
+        // Devices may have been deauthorized while deferred, e.g. during suspend.
+        (DeferNewDevices, Authorized) => TransitionAction::ReconcileAuthorized,
+        (Disabled | DenyNoUser | Authorized, Authorized) => TransitionAction::AuthorizeAll,
+        (Disabled | DenyNoUser | DeferNewDevices | Authorized, Disabled | DenyNoUser) => {
+            TransitionAction::DeauthorizeAll
+        }
+        // The devices already authorized stay, new devices are deferred as they are added. The
+        // devices on the boot ACL denied before are authorized on the transition.
+        (Disabled | DenyNoUser | DeferNewDevices | Authorized, DeferNewDevices) => {
+            TransitionAction::LeaveDevices
+        }
+    }
+}
+
+/// Event of the policy, reported to the policy event observer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyEvent {
+    /// A thunderbolt device was plugged in but not authorized because of the policy.
+    DeviceDenied {
+        /// Unique id of the device, if exposed.
+        unique_id: Option<String>,
+        /// The state which denied the device.
+        reason: PciAuthState,
+    },
+    /// The authorization state changed, e.g. after the screen was locked.
+    StateChanged { from: PciAuthState, to: PciAuthState },
+}
+
+/// Health of the policy engine, as reported by `PciAuthorizer::health`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineHealth {
+    /// Whether the task is running.
+    pub task_alive: bool,
+    /// Whether a bulk sysfs operation of the task panicked.
+    pub degraded: bool,
+    /// The state the task last applied, or None if it didn't apply any yet.
+    pub auth_state: Option<PciAuthState>,
+    /// When the task last read a uevent, or None if it didn't read any yet. Only tells how
+    /// recent the device activity is, see `last_heartbeat` for the liveness of the task.
+    pub last_uevent: Option<Instant>,
+    /// When the event loop of the task last ran, or None if it didn't start yet. The task beats
+    /// at least every `HEARTBEAT_INTERVAL`, even without any event.
+    pub last_heartbeat: Option<Instant>,
+    /// Number of failed uevent reads since the last successful one.
+    pub uevent_errors_since_success: u64,
+    /// Whether the platform supports PCI tunneling, or None if the task didn't check yet. See
+    /// `SysfsUtils::tunneling_supported`.
+    pub tunneling_supported: Option<bool>,
+}
+
+/// Time from the `Add` uevent of a device to its authorization, whether on its uevent or by a
+/// later sweep, e.g. once the screen is unlocked. Measures the latency of a dock connection as the
+/// user perceives it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AuthorizationLatency {
+    /// Number of devices measured.
+    pub count: u64,
+    /// Latency of the last device authorized, or None if none was yet.
+    pub last: Option<Duration>,
+    /// Highest latency measured.
+    pub max: Duration,
+    /// Sum of the latencies measured, see `average`.
+    pub total: Duration,
+}
+
+impl AuthorizationLatency {
+    /// Returns the average latency, or None if no device was measured yet.
+    pub fn average(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let average = self.total.as_nanos() / u128::from(self.count);
+        Some(Duration::from_nanos(average.try_into().unwrap_or(u64::MAX)))
+    }
+
+    fn record(&mut self, latency: Duration) {
+        self.count += 1;
+        self.last = Some(latency);
+        self.max = self.max.max(latency);
+        self.total = self.total.saturating_add(latency);
+    }
+}
+
+/// Decides the authorization state from the policy inputs.
+pub trait AuthPolicy: Send + Sync {
+    /// Returns the authorization state for `policy_data`.
+    fn auth_state(&self, policy_data: &PolicySourceData) -> PciAuthState;
+}
+
+impl std::fmt::Debug for dyn AuthPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AuthPolicy")
+    }
+}
+
+/// The default policy: devices are authorized while tunnels are enabled, a user is logged in and
+/// the screen is unlocked. New devices are deferred while the screen is locked.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultAuthPolicy;
+
+impl AuthPolicy for DefaultAuthPolicy {
+    fn auth_state(&self, policy_data: &PolicySourceData) -> PciAuthState {
+        let allow_flag = policy_data.pci_tunnels_enabled;
+        let screen_unlocked = !policy_data.is_locked;
+        let has_logged_in_users = !policy_data.logged_in_users.is_empty();
+
+        match (allow_flag, has_logged_in_users, screen_unlocked) {
+            (false, _, _) => PciAuthState::Disabled,
+            (true, false, _) => PciAuthState::DenyNoUser,
+            (true, true, false) => PciAuthState::DeferNewDevices,
+            (true, true, true) => PciAuthState::Authorized,
+        }
+    }
+}
+
+/// Kernel subsystem a uevent originates from.
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum UeventSubsystem {
+    Thunderbolt,
+    Pci,
+    Other(String),
+}
+
+impl From<&str> for UeventSubsystem {
+    fn from(subsystem: &str) -> Self {
+        match subsystem {
+            "thunderbolt" => UeventSubsystem::Thunderbolt,
+            "pci" => UeventSubsystem::Pci,
+            other => UeventSubsystem::Other(other.to_string()),
+        }
+    }
+}
+
 /// Event sent from PciAuthorizer to PciHotplugService
 #[derive(Debug, Clone)]
 enum PciServiceEvent {
     EnablePciTunnels(bool),
     UpdateLockState(bool),
-    UpdateLoggedInState { logged_in: bool, user_id: UserId },
+    UpdateLoggedInState {
+        logged_in: bool,
+        user_id: UserId,
+    },
+    SetLoggedInUsers(HashSet<UserId>),
+    SetIdleTimeout(Option<Duration>),
+    SetPolicy(Arc<dyn AuthPolicy>),
+    /// Sent after `TaskFlags::enforcement_paused` changes.
+    SetEnforcementPaused(bool),
+    SetEnabledSubsystems(HashSet<Subsystem>),
+    /// Stops the idle timer of the device, which then stays authorized while idle.
+    CancelIdleTimer(DeviceId),
+    /// Sent after a resume from suspend, during which uevents may have been missed.
+    Resumed,
+    /// Marker replied to once all the events sent before it are handled.
+    Flush(std::sync::mpsc::Sender<()>),
+    /// Requests a snapshot of the state of the task.
+    Snapshot(std::sync::mpsc::Sender<TaskSnapshot>),
+    /// Stops the task, once all the devices are deauthorized if `deauthorize` is set. `done` is
+    /// replied to with the result of the deauthorization before the task stops.
+    PrepareForShutdown {
+        deauthorize: bool,
+        done: std::sync::mpsc::Sender<Result<()>>,
+    },
     Shutdown,
 }
 
+/// Counters of the work done by a PciAuthorizerTask since it started.
+#[derive(Debug, Clone, Default)]
+struct TaskMetrics {
+    uevents_received: u64,
+    uevent_errors: u64,
+    devices_authorized: u64,
+    authorization_failures: u64,
+    idle_deauthorizations: u64,
+    /// Uevents not sent to the uevent observer because it was lagging behind.
+    observer_drops: u64,
+    /// Devices plugged in but not authorized because of the policy.
+    devices_denied: u64,
+    /// Bulk authorizations and deauthorizations of all the devices.
+    sweeps: u64,
+    /// Uevents ignored because they have no subsystem, e.g. header-only uevents.
+    empty_subsystem_uevents: u64,
+    authorization_latency: AuthorizationLatency,
+}
+
+/// State of a PciAuthorizerTask, as reported by `PciAuthorizer::dump`.
+#[derive(Debug, Clone)]
+struct TaskSnapshot {
+    auth_state: PciAuthState,
+    policy_data: PolicySourceData,
+    /// The devices with an idle timer, and the time left before they are deauthorized.
+    idle_timers: Vec<(DeviceId, Duration)>,
+    metrics: TaskMetrics,
+}
+
+/// Recent activity of a PciAuthorizerTask, readable even if the task is stuck.
+#[derive(Debug, Clone, Copy, Default)]
+struct TaskActivity {
+    auth_state: Option<PciAuthState>,
+    last_uevent: Option<Instant>,
+    last_heartbeat: Option<Instant>,
+    uevent_errors_since_success: u64,
+    tunneling_supported: Option<bool>,
+}
+
+/// Throttles a log emitted on every occurrence of an error.
+struct ErrorLogThrottle {
+    interval: Duration,
+    last_logged: Option<Instant>,
+    /// Number of errors not logged since `last_logged`.
+    suppressed: u64,
+}
+
+impl ErrorLogThrottle {
+    fn new(interval: Duration) -> Self {
+        Self { interval, last_logged: None, suppressed: 0 }
+    }
+
+    /// Records an error occurring at `now`. Returns the number of errors suppressed since the last
+    /// log if this one should be logged, or None if it should be suppressed.
+    fn record(&mut self, now: Instant) -> Option<u64> {
+        if self.last_logged.is_some_and(|last| now.duration_since(last) < self.interval) {
+            self.suppressed += 1;
+            return None;
+        }
+        self.last_logged = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+/// Idle timer of an authorized thunderbolt device.
+struct IdleTimer {
+    /// Kernel name of the device, e.g. "0-1". Uevents refer to the device by this name.
+    name: String,
+    deadline: tokio::time::Instant,
+}
+
+/// Idle timers of the authorized thunderbolt devices, keyed by device key (see
+/// `SysfsUtils::device_key`), so that a device reconnected under another name replaces its old
+/// timer.
+struct IdleTimers {
+    /// Idle period after which a device is deauthorized. None disables the timers.
+    timeout: Option<Duration>,
+    timers: HashMap<String, IdleTimer>,
+    /// Names of the devices whose timer was cancelled, keyed by device key. They don't get a
+    /// timer again until they are unplugged.
+    cancelled: HashMap<String, String>,
+}
+
+impl IdleTimers {
+    fn new(timeout: Option<Duration>) -> Self {
+        Self { timeout, timers: HashMap::new(), cancelled: HashMap::new() }
+    }
+
+    /// Changes the timeout. Running timers restart with the new timeout.
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        match timeout {
+            Some(timeout) => {
+                let deadline = tokio::time::Instant::now() + timeout;
+                for timer in self.timers.values_mut() {
+                    timer.deadline = deadline;
+                }
+            }
+            None => self.timers.clear(),
+        }
+    }
+
+    /// Starts or restarts the timer of the device `key`, named `name`, unless it was cancelled.
+    fn start(&mut self, key: String, name: &str) {
+        if self.cancelled.contains_key(&key) {
+            return;
+        }
+        if let Some(timeout) = self.timeout {
+            let deadline = tokio::time::Instant::now() + timeout;
+            self.timers.insert(key, IdleTimer { name: name.to_string(), deadline });
+        }
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.timers.contains_key(key)
+    }
+
+    /// Restarts the timer of the device named `name` if it has one.
+    fn touch(&mut self, name: &str) {
+        if let Some(timeout) = self.timeout {
+            for timer in self.timers.values_mut().filter(|timer| timer.name == name) {
+                timer.deadline = tokio::time::Instant::now() + timeout;
+            }
+        }
+    }
+
+    /// Removes the timer of the device named `name`. Removed devices are referred to by name, as
+    /// their unique id can't be read anymore.
+    fn remove(&mut self, name: &str) {
+        self.timers.retain(|_, timer| timer.name != name);
+    }
+
+    /// Removes the timer of the device `key`, named `name`, until it is unplugged. Returns false
+    /// if it had no timer.
+    fn cancel(&mut self, key: &str, name: &str) -> bool {
+        self.cancelled.insert(key.to_string(), name.to_string());
+        self.timers.remove(key).is_some()
+    }
+
+    /// Forgets the device named `name`, which was unplugged: its timer and its cancellation.
+    fn forget(&mut self, name: &str) {
+        self.remove(name);
+        self.cancelled.retain(|_, cancelled_name| cancelled_name != name);
+    }
+
+    fn clear(&mut self) {
+        self.timers.clear();
+    }
+
+    /// Returns the devices with a timer, and the time left on their timer at `now`.
+    fn remaining(&self, now: tokio::time::Instant) -> Vec<(DeviceId, Duration)> {
+        let mut remaining: Vec<_> = self
+            .timers
+            .iter()
+            .map(|(key, timer)| {
+                (DeviceId(key.clone()), timer.deadline.saturating_duration_since(now))
+            })
+            .collect();
+        remaining.sort_unstable();
+        remaining
+    }
+
+    /// Forgets the devices whose name isn't in `names`.
+    fn retain_devices(&mut self, names: &HashSet<&str>) {
+        self.timers.retain(|_, timer| names.contains(timer.name.as_str()));
+        self.cancelled.retain(|_, name| names.contains(name.as_str()));
+    }
+
+    fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    fn next_deadline(&self) -> Option<tokio::time::Instant> {
+        self.timers.values().map(|timer| timer.deadline).min()
+    }
+
+    /// Removes the timers expired at `now` and returns the names of their devices.
+    fn take_expired(&mut self, now: tokio::time::Instant) -> Vec<String> {
+        let mut expired = Vec::new();
+        self.timers.retain(|_, timer| {
+            if timer.deadline <= now {
+                expired.push(timer.name.clone());
+                return false;
+            }
+            true
+        });
+        expired
+    }
+}
+
+/// Authorization of an added device deferred until the device is ready.
+struct PendingAuthorization {
+    /// Readiness checks made so far.
+    attempts: u32,
+    deadline: tokio::time::Instant,
+}
+
+/// Device plugged in and not authorized yet, to measure its authorization latency.
+struct PluggedInDevice {
+    /// Kernel name of the device, to drop the entry once the device is removed.
+    name: String,
+    /// When the `Add` uevent of the device was read.
+    added_at: tokio::time::Instant,
+}
+
 /// Internal service that runs an async event loop for uevents and policy updates.
 struct PciAuthorizerTask {
     uevent_socket: Arc<dyn AsyncUEventSocket>,
     event_receiver: mpsc::Receiver<PciServiceEvent>,
     sysfs_utils: SysfsUtils,
     policy_data: PolicySourceData,
+    auth_policy: Arc<dyn AuthPolicy>,
     current_pci_auth_state: PciAuthState,
+    /// Set when the devices may not reflect `current_pci_auth_state`, because the last bulk sysfs
+    /// operation was cancelled or enforcement was paused.
+    sweep_pending: bool,
+    /// Whether the platform supports PCI tunneling. The state stays `Disabled` otherwise.
+    tunneling_supported: bool,
+    flags: TaskFlags,
+    uevent_error_throttle: ErrorLogThrottle,
+    log_uevent_error: Box<dyn Fn(&str) + Send>,
+    log_link_downgrade: Box<dyn Fn(&str) + Send>,
+    idle_timers: IdleTimers,
+    /// Added devices not ready to be authorized yet, keyed by devpath.
+    pending_authorizations: HashMap<PathBuf, PendingAuthorization>,
+    /// Devices plugged in and not authorized yet, keyed by device id.
+    plugged_in: HashMap<DeviceId, PluggedInDevice>,
+    metrics: TaskMetrics,
+    observers: TaskObservers,
 }
 
-impl PciAuthorizerTask {
-    /// Calculates PciAuthState from PolicySourceData.
-    fn calculate_auth_state(policy_data: &PolicySourceData) -> PciAuthState {
-        let allow_flag = policy_data.pci_tunnels_enabled;
-        let screen_unlocked = !policy_data.is_locked;
-        let has_logged_in_users = !policy_data.logged_in_users.is_empty();
+/// Flags shared between a PciAuthorizer and its task.
+#[derive(Clone, Default)]
+struct TaskFlags {
+    /// Updated by the task as it handles events.
+    activity: Arc<Mutex<TaskActivity>>,
+    /// Set when a bulk sysfs operation panicked.
+    degraded: Arc<AtomicBool>,
+    /// Set when a policy update changing the decision is sent, so that the bulk sysfs operation
+    /// in progress is cancelled in favor of the newer policy.
+    cancel_sweep: Arc<AtomicBool>,
+    /// Set to authorize devices even when the thunderbolt domains lack IOMMU DMA protection.
+    allow_unprotected_dma: Arc<AtomicBool>,
+    /// Set to authorize devices when a thunderbolt domain doesn't report its IOMMU DMA
+    /// protection.
+    trust_unknown_dma_protection: Arc<AtomicBool>,
+    /// Set while the task keeps its state up to date without touching sysfs.
+    enforcement_paused: Arc<AtomicBool>,
+    /// Set while a `Resumed` event waits to be handled, so that repeated resumes don't queue
+    /// several reconciliations.
+    resume_pending: Arc<AtomicBool>,
+}
 
-        match (allow_flag, has_logged_in_users, screen_unlocked) {
-            (false, _, _) => PciAuthState::Disabled,
-            (true, false, _) => PciAuthState::DenyNoUser,
-            (true, true, false) => PciAuthState::DeferNewDevices,
-            (true, true, true) => PciAuthState::Authorized,
-        }
-    }
+/// Channels the task reports to, in addition to the sysfs changes.
+#[derive(Clone, Default)]
+struct TaskObservers {
+    /// Receives a copy of every uevent read, before the policy handles it.
+    uevents: Option<mpsc::Sender<kobject_uevent::UEvent>>,
+    /// How long to wait for room in `uevents` before dropping a uevent. None drops it right away.
+    uevent_retry: Option<Duration>,
+    /// Receives the events of the policy.
+    policy_events: Option<mpsc::Sender<PolicyEvent>>,
+}
 
+impl PciAuthorizerTask {
     /// Handles a received uevent.
     fn handle_uevent_result(&mut self, uevent_result: Result<kobject_uevent::UEvent>) {
         match uevent_result {
             Ok(uevent) => {
-                if self.current_pci_auth_state == PciAuthState::Authorized
-                    && uevent.subsystem.as_str() == "thunderbolt"
-                    && uevent.action == ActionType::Add
+                self.metrics.uevents_received += 1;
+                let uevents_received = self.metrics.uevents_received;
+                trace::counter(trace::UEVENTS_RECEIVED, || {
+                    uevents_received.try_into().unwrap_or(i64::MAX)
+                });
+                {
+                    let mut activity = self.flags.activity.lock().unwrap();
+                    activity.last_uevent = Some(Instant::now());
+                    activity.uevent_errors_since_success = 0;
+                }
+                let subsystem = UeventSubsystem::from(uevent.subsystem.as_str());
+                if subsystem == UeventSubsystem::Other(String::new()) {
+                    self.metrics.empty_subsystem_uevents += 1;
+                    debug!(
+                        "Ignoring uevent without subsystem: {:?} {} ({} so far)",
+                        uevent.action,
+                        uevent.devpath.display(),
+                        self.metrics.empty_subsystem_uevents
+                    );
+                    return;
+                }
+                let device_name = uevent.devpath.file_name().and_then(|name| name.to_str());
+                if subsystem == UeventSubsystem::Thunderbolt
+                    && device_name.is_some_and(|name| {
+                        ThunderboltEntryType::from_name(name) == ThunderboltEntryType::Domain
+                    })
+                {
+                    // A domain coming or going, e.g. once the thunderbolt driver probes, may
+                    // change whether the platform supports PCI tunneling.
+                    self.recheck_tunneling_supported();
+                }
+                if subsystem != UeventSubsystem::Thunderbolt || !self.is_thunderbolt_enabled() {
+                    return;
+                }
+                if uevent.action == ActionType::Add {
+                    self.note_plugged_in(&uevent.devpath);
+                }
+                if uevent.action == ActionType::Add
+                    && !self.is_enforcement_paused()
+                    && self.should_authorize_new_device(&uevent.devpath)
                 {
-                    let path = uevent.devpath.as_path();
-                    let relative_path = path.strip_prefix("/").unwrap();
-                    let full_path = Path::new("/sys/").join(relative_path);
-                    if let Err(e) = self.sysfs_utils.authorize_thunderbolt_dev(full_path.as_path())
-                    {
-                        error!(
-                            "Failed to authorize device on uevent {}: {}",
-                            full_path.display(),
-                            e
+                    self.authorize_added_device(&uevent.devpath, 0);
+                } else if let Some(device_name) = device_name {
+                    if uevent.action == ActionType::Remove {
+                        self.pending_authorizations.remove(&uevent.devpath);
+                        self.idle_timers.forget(device_name);
+                        self.plugged_in.retain(|_, plugged_in| plugged_in.name != device_name);
+                    } else {
+                        // Any other event of the device counts as activity.
+                        self.idle_timers.touch(device_name);
+                    }
+                    if uevent.action == ActionType::Add {
+                        self.report_denied_device(&uevent.devpath);
+                    }
+                    if uevent.action == ActionType::Change {
+                        self.check_link_speed(
+                            &self.sysfs_utils.devpath_to_syspath(&uevent.devpath),
                         );
                     }
                 }
             }
             Err(e) => {
-                error!("Error reading uevent: {}. Uevent listener might stop if this persists.", e);
+                self.metrics.uevent_errors += 1;
+                self.flags.activity.lock().unwrap().uevent_errors_since_success += 1;
+                let Some(suppressed) = self.uevent_error_throttle.record(Instant::now()) else {
+                    return;
+                };
+                let mut message = format!(
+                    "Error reading uevent: {}. Uevent listener might stop if this persists.",
+                    e
+                );
+                if suppressed > 0 {
+                    message.push_str(&format!(" ({} similar errors suppressed)", suppressed));
+                }
+                (self.log_uevent_error)(&message);
             }
         }
     }
 
-    /// Handles a received service event. Returns true if the service should continue running.
-    fn handle_service_event(&mut self, service_event: PciServiceEvent) -> bool {
-        match service_event {
-            PciServiceEvent::EnablePciTunnels(enable) => {
-                self.policy_data.pci_tunnels_enabled = enable;
-            }
-            PciServiceEvent::UpdateLockState(locked) => {
-                self.policy_data.is_locked = locked;
+    /// Authorizes the device added at `devpath`, or checks again shortly if it isn't ready to be
+    /// authorized yet. `attempts` is the number of readiness checks made so far. Devices still not
+    /// ready after `DEVICE_READY_ATTEMPTS` checks are authorized anyway.
+    fn authorize_added_device(&mut self, devpath: &Path, attempts: u32) {
+        self.pending_authorizations.remove(devpath);
+        let full_path = self.sysfs_utils.devpath_to_syspath(devpath);
+        let ready = self.sysfs_utils.device_ready(&full_path).unwrap_or_else(|e| {
+            error!("Failed to check whether {} is ready: {}", full_path.display(), e);
+            true
+        });
+        if !ready {
+            if attempts + 1 < DEVICE_READY_ATTEMPTS {
+                debug!("{} isn't ready to be authorized yet", full_path.display());
+                let deadline = tokio::time::Instant::now() + DEVICE_READY_RETRY_DELAY;
+                self.pending_authorizations.insert(
+                    devpath.to_path_buf(),
+                    PendingAuthorization { attempts: attempts + 1, deadline },
+                );
+                return;
             }
-            PciServiceEvent::UpdateLoggedInState { logged_in, user_id } => {
-                if logged_in {
-                    self.policy_data.logged_in_users.insert(user_id);
-                } else {
-                    self.policy_data.logged_in_users.remove(&user_id);
+            warn!(
+                "{} still isn't ready after {} checks. Authorizing it anyway.",
+                full_path.display(),
+                DEVICE_READY_ATTEMPTS
+            );
+        }
+        match self.sysfs_utils.authorize_thunderbolt_dev(full_path.as_path()) {
+            Ok(()) => {
+                self.metrics.devices_authorized += 1;
+                let key = self.sysfs_utils.device_key(&full_path);
+                self.record_authorization_latency(&DeviceId(key.clone()));
+                if let Some(device_name) = devpath.file_name().and_then(|name| name.to_str()) {
+                    self.idle_timers.start(key, device_name);
                 }
+                self.check_link_speed(&full_path);
             }
-            PciServiceEvent::Shutdown => {
-                return false; // Signal to stop the loop
+            Err(e) => {
+                self.metrics.authorization_failures += 1;
+                error!("Failed to authorize device on uevent {}: {}", full_path.display(), e);
             }
         }
-        // After any policy update, recalculate and handle state transition
-        let old_state = self.current_pci_auth_state;
-        let new_state = Self::calculate_auth_state(&self.policy_data);
+    }
 
-        if old_state == new_state {
-            return true;
+    /// Checks again the readiness of the pending devices whose retry delay elapsed. The policy is
+    /// checked again too, as it may have changed meanwhile.
+    fn retry_pending_authorizations(&mut self) {
+        let now = tokio::time::Instant::now();
+        let due: Vec<_> = self
+            .pending_authorizations
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(devpath, pending)| (devpath.clone(), pending.attempts))
+            .collect();
+        for (devpath, attempts) in due {
+            if self.is_enforcement_paused() || !self.should_authorize_new_device(&devpath) {
+                self.pending_authorizations.remove(&devpath);
+                continue;
+            }
+            self.authorize_added_device(&devpath, attempts);
         }
+    }
 
-        info!("State transition: {:?} -> {:?}", old_state, new_state);
-        self.current_pci_auth_state = new_state;
+    /// Notes when the device added at `devpath` was plugged in, to measure its authorization
+    /// latency once it is authorized, on its uevent or by a later sweep.
+    fn note_plugged_in(&mut self, devpath: &Path) {
+        // Only devices are authorized, not domains.
+        let Some(name) = SysfsUtils::parse_thunderbolt_devpath(devpath).and_then(|id| id.route)
+        else {
+            return;
+        };
+        let key = self.sysfs_utils.device_key(&self.sysfs_utils.devpath_to_syspath(devpath));
+        let added_at = tokio::time::Instant::now();
+        self.plugged_in.insert(DeviceId(key), PluggedInDevice { name, added_at });
+    }
 
-        match (old_state, new_state) {
-            (_, PciAuthState::Authorized) => {
-                if let Err(e) = self.sysfs_utils.authorize_all_devices() {
-                    error!("Failed to authorize all devices: {}", e);
-                }
-            }
-            (_, PciAuthState::DenyNoUser) | (_, PciAuthState::Disabled) => {
-                if let Err(e) = self.sysfs_utils.deauthorize_all_devices() {
-                    error!("Failed to deauthorize all devices: {}", e);
-                }
-            }
-            _ => { /* Other transitions require no immediate bulk action. */ }
+    /// Records the authorization latency of `device`, if it was plugged in while the task ran.
+    fn record_authorization_latency(&mut self, device: &DeviceId) {
+        if let Some(plugged_in) = self.plugged_in.remove(device) {
+            let latency = plugged_in.added_at.elapsed();
+            debug!("Authorized {} {:?} after it was plugged in", plugged_in.name, latency);
+            self.metrics.authorization_latency.record(latency);
         }
-        true // Keep running
     }
 
-    /// Runs the event loop.
-    async fn run(mut self) {
-        info!("PciAuthorizerTask started.");
-        loop {
-            tokio::select! {
-                uevent_result = self.uevent_socket.read() => {
-                    self.handle_uevent_result(uevent_result);
-                }
-                Some(service_event) = self.event_receiver.recv() => {
-                    if !self.handle_service_event(service_event) {
-                        info!("Shutdown event received.");
-                        break;
-                    }
-                }
-                else => {
-                    info!("Event channel closed. Shutting down.");
-                    break;
+    /// Records the authorization latency of the plugged in devices authorized by a sweep.
+    fn record_latencies_of_authorized_devices(&mut self) {
+        if self.plugged_in.is_empty() {
+            return;
+        }
+        match self.sysfs_utils.list_thunderbolt_devices() {
+            Ok(devices) => {
+                for device in devices.iter().filter(|device| device.authorized) {
+                    self.record_authorization_latency(&DeviceId(device.key().to_string()));
                 }
             }
+            Err(e) => error!("Failed to list devices to measure their authorization: {}", e),
         }
     }
-}
-
-/// Orchestrates authorization policy and interacts with the PciAuthorizerTask.
-pub struct PciAuthorizer {
-    event_sender: mpsc::Sender<PciServiceEvent>,
-    service_task_handle: Option<tokio::task::JoinHandle<()>>,
-}
-
-impl PciAuthorizer {
-    /// Creates a new PciAuthorizer.
-    pub fn new(sysfs_utils: SysfsUtils, uevent_socket: Arc<dyn AsyncUEventSocket>) -> Self {
-        let (tx, rx) = mpsc::channel(MESSAGE_QUEUE_SIZE);
-
-        let service_policy_data = PolicySourceData::default();
-        let initial_auth_state = PciAuthorizerTask::calculate_auth_state(&service_policy_data);
-
-        let service = PciAuthorizerTask {
-            uevent_socket,
-            event_receiver: rx,
-            sysfs_utils,
-            policy_data: service_policy_data,
-            current_pci_auth_state: initial_auth_state,
-        };
-        let service_task_handle = tokio::spawn(service.run());
 
-        Self { event_sender: tx, service_task_handle: Some(service_task_handle) }
+    /// Reports a device added but not authorized, if the policy denied it.
+    fn report_denied_device(&mut self, devpath: &Path) {
+        // Only devices are denied, not domains.
+        let is_device =
+            SysfsUtils::parse_thunderbolt_devpath(devpath).is_some_and(|id| id.route.is_some());
+        if self.current_pci_auth_state == PciAuthState::Authorized
+            || !is_device
+            || !self.is_pci_authorization_required()
+        {
+            return;
+        }
+        let full_path = self.sysfs_utils.devpath_to_syspath(devpath);
+        let unique_id = self.sysfs_utils.read_unique_id(&full_path).unwrap_or_else(|e| {
+            error!("Failed to read the unique id of {}: {}", full_path.display(), e);
+            None
+        });
+        info!("Denied {} in state {:?}", full_path.display(), self.current_pci_auth_state);
+        self.metrics.devices_denied += 1;
+        self.notify_policy_event(PolicyEvent::DeviceDenied {
+            unique_id,
+            reason: self.current_pci_auth_state,
+        });
     }
 
-    fn send_event(&mut self, event: PciServiceEvent) {
-        match self.event_sender.try_send(event) {
-            Ok(_) => {}
-            Err(mpsc::error::TrySendError::Full(_)) => {
-                error!("Event channel full. Policy update might be delayed/lost.");
+    /// Sends `event` to the policy event observer. The event is dropped if the observer is full,
+    /// and the observer is forgotten once its receiver is closed.
+    fn notify_policy_event(&mut self, event: PolicyEvent) {
+        let Some(observer) = &self.observers.policy_events else {
+            return;
+        };
+        match observer.try_send(event) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(event)) => {
+                warn!("Policy event observer is full, dropping {:?}", event);
             }
             Err(mpsc::error::TrySendError::Closed(_)) => {
-                error!("Event channel closed. Service might have crashed.");
+                info!("Policy event observer closed.");
+                self.observers.policy_events = None;
             }
         }
     }
-}
 
-impl Default for PciAuthorizer {
-    /// Creates a default `PciAuthorizer`.
-    fn default() -> Self {
-        let sysfs_utils = SysfsUtils::default();
-        let uevent_socket_concrete =
-            Arc::new(AsyncNetlinkKObjectUEventSocket::create().expect(
-                "Failed to create AsyncNetlinkKObjectUEventSocket in PciAuthorizer default",
+    /// Sends a copy of `uevent` to the observer. The copy is dropped if the observer is lagging
+    /// behind, after waiting for it for the retry period if any, and the observer is forgotten
+    /// once its receiver is closed.
+    async fn notify_uevent_observer(&mut self, uevent: &kobject_uevent::UEvent) {
+        let Some(observer) = &self.observers.uevents else {
+            return;
+        };
+        let closed = match self.observers.uevent_retry {
+            None => match observer.try_send(uevent.clone()) {
+                Ok(()) => false,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    self.metrics.observer_drops += 1;
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => true,
+            },
+            // Waiting for the observer delays the policy, but only while the observer lags.
+            Some(retry) => match observer.send_timeout(uevent.clone(), retry).await {
+                Ok(()) => false,
+                Err(mpsc::error::SendTimeoutError::Timeout(_)) => {
+                    self.metrics.observer_drops += 1;
+                    false
+                }
+                Err(mpsc::error::SendTimeoutError::Closed(_)) => true,
+            },
+        };
+        if closed {
+            info!("Uevent observer closed.");
+            self.observers.uevents = None;
+        }
+    }
+
+    /// Logs when an authorized device negotiated a link slower than the maximum it supports,
+    /// which usually points at a bad cable or dock. This doesn't affect authorization.
+    fn check_link_speed(&self, devpath: &Path) {
+        // Domains and host routers have no authorization, and aren't checked either.
+        if !self.sysfs_utils.is_authorized(devpath).unwrap_or(false) {
+            return;
+        }
+        let (link_speed, max_bandwidth) = match (
+            self.sysfs_utils.read_link_speed(devpath),
+            self.sysfs_utils.read_max_link_bandwidth(devpath),
+        ) {
+            (Ok(Some(link_speed)), Ok(Some(max_bandwidth))) => (link_speed, max_bandwidth),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("Failed to read the link speed of {}: {}", devpath.display(), e);
+                return;
+            }
+            _ => return,
+        };
+        if link_speed.bandwidth() < max_bandwidth {
+            (self.log_link_downgrade)(&format!(
+                "Link of {} downgraded to {} Gb/s out of {} Gb/s: {:?}",
+                devpath.display(),
+                link_speed.bandwidth(),
+                max_bandwidth,
+                link_speed
             ));
-        let uevent_socket_trait: Arc<dyn AsyncUEventSocket> = uevent_socket_concrete;
-        Self::new(sysfs_utils, uevent_socket_trait)
+        }
     }
-}
 
-impl TunnelControl for PciAuthorizer {
-    fn enable_pci_tunnels(&mut self, enable: bool) {
-        self.send_event(PciServiceEvent::EnablePciTunnels(enable));
+    /// Returns whether a newly added device should be authorized in the current state. Devices
+    /// preauthorized by the boot ACL are authorized even while new devices are deferred.
+    fn should_authorize_new_device(&self, devpath: &Path) -> bool {
+        if !self.is_pci_authorization_required() {
+            return false;
+        }
+        match self.current_pci_auth_state {
+            PciAuthState::Authorized => true,
+            PciAuthState::DeferNewDevices => {
+                let full_path = self.sysfs_utils.devpath_to_syspath(devpath);
+                match self.sysfs_utils.is_on_boot_acl(&full_path) {
+                    Ok(on_acl) => on_acl,
+                    Err(e) => {
+                        error!("Failed to check the boot ACL for {}: {}", full_path.display(), e);
+                        false
+                    }
+                }
+            }
+            PciAuthState::Disabled | PciAuthState::DenyNoUser => false,
+        }
     }
 
-    fn update_lock_state(&mut self, locked: bool) {
-        self.send_event(PciServiceEvent::UpdateLockState(locked));
+    fn is_enforcement_paused(&self) -> bool {
+        self.flags.enforcement_paused.load(Ordering::Relaxed)
     }
 
-    fn update_logged_in_state(&mut self, logged_in: bool, user_id: UserId) {
-        self.send_event(PciServiceEvent::UpdateLoggedInState { logged_in, user_id });
+    /// Returns whether the sweep cancelled by a policy update is to be resumed. The update usually
+    /// resumes it when it is handled, so the sweep is only resumed once no update is queued.
+    fn should_resume_sweep(&self) -> bool {
+        self.sweep_pending && !self.is_enforcement_paused() && self.event_receiver.is_empty()
     }
-}
 
-impl Drop for PciAuthorizer {
-    fn drop(&mut self) {
-        info!("PciAuthorizer dropping. Shutting down PciAuthorizerTask.");
+    /// Returns whether the engine authorizes the thunderbolt devices.
+    fn is_thunderbolt_enabled(&self) -> bool {
+        self.policy_data.enabled_subsystems.contains(&Subsystem::Thunderbolt)
+    }
 
-        if self.event_sender.try_send(PciServiceEvent::Shutdown).is_err() {
-            error!("Failed to send shutdown signal to PciAuthorizerTask or channel already closed. Task might not shut down via signal.");
+    /// Checks whether the platform supports PCI tunneling, and reports it. Errors are logged and
+    /// assume it does.
+    fn check_tunneling_supported(&mut self) {
+        self.tunneling_supported = self.sysfs_utils.tunneling_supported().unwrap_or_else(|e| {
+            error!("Failed to check whether PCI tunneling is supported: {}", e);
+            true
+        });
+        if !self.tunneling_supported {
+            warn!(
+                "PCI tunneling is unsupported: no thunderbolt bus, or no domain allows PCI \
+                tunnels. Staying Disabled."
+            );
         }
+        self.flags.activity.lock().unwrap().tunneling_supported = Some(self.tunneling_supported);
+    }
 
-        if let Some(_handle) = self.service_task_handle.take() {
-            info!("PciAuthorizerTask shutdown initiated. The task will be managed by the Tokio runtime.");
+    /// Checks again whether the platform supports PCI tunneling, and applies the policy if that
+    /// changed.
+    fn recheck_tunneling_supported(&mut self) {
+        let was_supported = self.tunneling_supported;
+        self.check_tunneling_supported();
+        if self.tunneling_supported != was_supported {
+            info!(
+                "PCI tunneling is now {}",
+                if self.tunneling_supported { "supported" } else { "unsupported" }
+            );
+            self.update_auth_state();
         }
     }
+
+    /// Returns whether the security level of the thunderbolt domains makes authorizing devices
+    /// meaningful. Errors are logged and assume it does.
+    fn is_pci_authorization_required(&self) -> bool {
+        self.sysfs_utils.is_pci_authorization_required().unwrap_or_else(|e| {
+            error!("Failed to read the thunderbolt security levels: {}", e);
+            true
+        })
+    }
+
+    /// Handles a received service event. Returns true if the service should continue running.
+    fn handle_service_event(&mut self, service_event: PciServiceEvent) -> bool {
+        match service_event {
+            PciServiceEvent::EnablePciTunnels(enable) => {
+                self.policy_data.pci_tunnels_enabled = enable;
+            }
+            PciServiceEvent::UpdateLockState(locked) => {
+                self.policy_data.is_locked = locked;
+            }
+            PciServiceEvent::UpdateLoggedInState { logged_in, user_id } => {
+                if logged_in {
+                    self.policy_data.logged_in_users.insert(user_id);
+                } else {
+                    self.policy_data.logged_in_users.remove(&user_id);
+                }
+            }
+            PciServiceEvent::SetLoggedInUsers(user_ids) => {
+                self.policy_data.logged_in_users = user_ids;
+            }
+            PciServiceEvent::SetIdleTimeout(timeout) => {
+                self.idle_timers.set_timeout(timeout);
+                if timeout.is_some() && self.current_pci_auth_state == PciAuthState::Authorized {
+                    self.start_idle_timers_of_authorized_devices();
+                }
+            }
+            PciServiceEvent::SetPolicy(auth_policy) => {
+                info!("Auth policy replaced.");
+                self.auth_policy = auth_policy;
+            }
+            PciServiceEvent::SetEnforcementPaused(paused) => {
+                if paused {
+                    info!("Enforcement paused.");
+                } else {
+                    // The devices plugged in meanwhile weren't handled either.
+                    info!("Enforcement resumed.");
+                    self.sweep_pending = true;
+                }
+            }
+            PciServiceEvent::SetEnabledSubsystems(subsystems) => {
+                if subsystems != self.policy_data.enabled_subsystems {
+                    info!("Enabled subsystems: {:?}", subsystems);
+                    self.policy_data.enabled_subsystems = subsystems;
+                    // Newly enabled subsystems are brought to the current state.
+                    self.sweep_pending = true;
+                    if !self.is_thunderbolt_enabled() {
+                        self.idle_timers.clear();
+                        self.pending_authorizations.clear();
+                        self.plugged_in.clear();
+                    }
+                }
+            }
+            PciServiceEvent::CancelIdleTimer(device) => {
+                // Handled by the task like the timers firing, so a timer either fired before and
+                // the device is already deauthorized, or never fires.
+                self.cancel_idle_timer(&device);
+                return true;
+            }
+            PciServiceEvent::Resumed => {
+                // Resumes from now on may have missed uevents after this reconciliation.
+                self.flags.resume_pending.store(false, Ordering::Relaxed);
+                self.reconcile_devices_after_resume();
+                return true;
+            }
+            PciServiceEvent::Flush(done) => {
+                // The events are handled in order, so all the events sent before are handled.
+                let _ = done.send(());
+                return true;
+            }
+            PciServiceEvent::Snapshot(reply) => {
+                let _ = reply.send(TaskSnapshot {
+                    auth_state: self.current_pci_auth_state,
+                    policy_data: self.policy_data.clone(),
+                    idle_timers: self.idle_timers.remaining(tokio::time::Instant::now()),
+                    metrics: self.metrics.clone(),
+                });
+                return true;
+            }
+            PciServiceEvent::PrepareForShutdown { deauthorize, done } => {
+                let result = if deauthorize {
+                    self.deauthorize_all_devices_before_shutdown()
+                } else {
+                    Ok(())
+                };
+                let _ = done.send(result);
+                return false;
+            }
+            PciServiceEvent::Shutdown => {
+                return false; // Signal to stop the loop
+            }
+        }
+        // After any policy update, recalculate and handle state transition
+        self.update_auth_state();
+        true // Keep running
+    }
+
+    /// Recalculates the authorization state from the policy data and applies the transition.
+    fn update_auth_state(&mut self) {
+        let old_state = self.current_pci_auth_state;
+        let new_state = if self.tunneling_supported {
+            self.apply_dma_protection_gate(self.auth_policy.auth_state(&self.policy_data))
+        } else {
+            PciAuthState::Disabled
+        };
+        self.flags.activity.lock().unwrap().auth_state = Some(new_state);
+
+        if old_state == new_state && !self.sweep_pending {
+            return;
+        }
+        if old_state != new_state {
+            self.notify_policy_event(PolicyEvent::StateChanged { from: old_state, to: new_state });
+        }
+
+        if self.is_enforcement_paused() {
+            // The transition is applied once enforcement resumes.
+            if old_state != new_state {
+                info!("State transition while paused: {:?} -> {:?}", old_state, new_state);
+                self.current_pci_auth_state = new_state;
+                self.sweep_pending = true;
+            }
+            return;
+        }
+
+        if old_state == new_state {
+            info!("Resuming the pending sweep of state {:?}", new_state);
+        } else {
+            info!("State transition: {:?} -> {:?}", old_state, new_state);
+        }
+        self.current_pci_auth_state = new_state;
+        self.sweep_pending = false;
+
+        if !self.tunneling_supported {
+            // There are no devices to sweep.
+            return;
+        }
+        if !self.is_thunderbolt_enabled() {
+            info!("Skipping the sweep: the thunderbolt subsystem is disabled");
+            return;
+        }
+        match action_for_transition(old_state, new_state) {
+            TransitionAction::AuthorizeAll | TransitionAction::ReconcileAuthorized
+                if !self.is_pci_authorization_required() =>
+            {
+                info!("Skipping authorization: no domain requires it at its security level");
+            }
+            TransitionAction::AuthorizeAll => self.authorize_all_devices(),
+            TransitionAction::ReconcileAuthorized => {
+                self.reconcile_authorized_devices("on unlock");
+            }
+            TransitionAction::DeauthorizeAll => self.deauthorize_all_devices(),
+            TransitionAction::LeaveDevices => self.authorize_boot_acl_devices(),
+        }
+    }
+
+    /// Deauthorizes all the devices and drops their idle timers.
+    fn deauthorize_all_devices(&mut self) {
+        self.idle_timers.clear();
+        let cancel = &self.flags.cancel_sweep;
+        cancel.store(false, Ordering::Relaxed);
+        let sysfs_utils = &self.sysfs_utils;
+        let subsystems = &self.policy_data.enabled_subsystems;
+        let mut outcome = SweepOutcome::Completed;
+        self.metrics.sweeps += 1;
+        self.run_guarded("deauthorize all devices", || {
+            outcome = sysfs_utils.deauthorize_all_devices_cancelable(subsystems, cancel)?;
+            Ok(())
+        });
+        self.sweep_pending = outcome == SweepOutcome::Cancelled;
+    }
+
+    /// Deauthorizes all the devices before the task stops, whatever the policy and even if
+    /// enforcement is paused. The sweep isn't cancelled by policy updates, which the task won't
+    /// apply anyway.
+    fn deauthorize_all_devices_before_shutdown(&mut self) -> Result<()> {
+        info!("Deauthorizing all the devices before shutdown.");
+        self.idle_timers.clear();
+        self.pending_authorizations.clear();
+        let sysfs_utils = &self.sysfs_utils;
+        self.metrics.sweeps += 1;
+        if !self.run_guarded("deauthorize all devices before shutdown", || {
+            sysfs_utils.deauthorize_all_devices()
+        }) {
+            anyhow::bail!("Failed to deauthorize the devices before shutdown");
+        }
+        Ok(())
+    }
+
+    /// Authorizes all the devices and starts their idle timers.
+    fn authorize_all_devices(&mut self) {
+        // Only the policy updates sent from now on supersede this sweep.
+        let cancel = &self.flags.cancel_sweep;
+        cancel.store(false, Ordering::Relaxed);
+        let sysfs_utils = &self.sysfs_utils;
+        let mut outcome = SweepOutcome::Completed;
+        self.metrics.sweeps += 1;
+        self.run_guarded("authorize all devices", || {
+            outcome = sysfs_utils.authorize_all_devices_cancelable(cancel)?;
+            Ok(())
+        });
+        self.sweep_pending = outcome == SweepOutcome::Cancelled;
+        self.record_latencies_of_authorized_devices();
+        self.start_idle_timers_of_authorized_devices();
+    }
+
+    /// Brings the devices to the `Authorized` state, checking the state of each device instead of
+    /// trusting the state known so far: devices may have been authorized, deauthorized, plugged
+    /// in or unplugged without a uevent, e.g. during suspend. The unauthorized devices are
+    /// authorized, the authorized ones the policy doesn't allow are deauthorized, the others are
+    /// left as they are, and the idle timers of the devices which are gone are dropped. `when`
+    /// only describes the reconciliation in the log.
+    fn reconcile_authorized_devices(&mut self, when: &str) {
+        let unauthorized_devices = |sysfs_utils: &SysfsUtils| -> Option<Vec<String>> {
+            match sysfs_utils.list_thunderbolt_devices() {
+                Ok(devices) => Some(
+                    devices
+                        .into_iter()
+                        .filter(|device| !device.authorized)
+                        .map(|device| device.name)
+                        .collect(),
+                ),
+                Err(e) => {
+                    error!("Failed to list the devices to reconcile: {}", e);
+                    None
+                }
+            }
+        };
+        let drifted = unauthorized_devices(&self.sysfs_utils);
+        self.authorize_all_devices();
+        let disallowed = self.deauthorize_disallowed_devices();
+        self.drop_state_of_unplugged_devices();
+
+        if let (Some(drifted), Some(remaining)) = (drifted, unauthorized_devices(&self.sysfs_utils))
+        {
+            if !drifted.is_empty() || !remaining.is_empty() || !disallowed.is_empty() {
+                info!(
+                    "Reconciled the devices {}: {:?} were unauthorized, {:?} still are, {:?} were \
+                    deauthorized as not allowed",
+                    when, drifted, remaining, disallowed
+                );
+            }
+        }
+    }
+
+    /// Deauthorizes the authorized devices which the policy doesn't allow, e.g. below the minimum
+    /// generation, as they may have been authorized by someone else. The devices whose check
+    /// fails are deauthorized too. Returns the names of the deauthorized devices.
+    fn deauthorize_disallowed_devices(&mut self) -> Vec<String> {
+        let devices = match self.sysfs_utils.list_thunderbolt_devices() {
+            Ok(devices) => devices,
+            Err(e) => {
+                error!("Failed to list the devices to check whether they are allowed: {}", e);
+                return Vec::new();
+            }
+        };
+        let mut deauthorized = Vec::new();
+        for device in devices {
+            if !device.authorized || device.is_host_router() {
+                continue;
+            }
+            let devpath = self.sysfs_utils.thunderbolt_device_path(&device.name);
+            let allowed = self.sysfs_utils.is_generation_allowed(&devpath).unwrap_or_else(|e| {
+                error!("Failed to check whether {} is allowed: {}", device.name, e);
+                false
+            });
+            if allowed {
+                continue;
+            }
+            match self.sysfs_utils.deauthorize_thunderbolt_dev(&devpath) {
+                Ok(()) => {
+                    self.idle_timers.remove(&device.name);
+                    deauthorized.push(device.name);
+                }
+                Err(e) => error!("Failed to deauthorize the disallowed {}: {}", device.name, e),
+            }
+        }
+        deauthorized
+    }
+
+    /// Drops the idle timers and the pending authorizations of the devices which are gone.
+    fn drop_state_of_unplugged_devices(&mut self) {
+        match self.sysfs_utils.list_thunderbolt_devices() {
+            Ok(devices) => {
+                let names: HashSet<&str> =
+                    devices.iter().map(|device| device.name.as_str()).collect();
+                self.idle_timers.retain_devices(&names);
+                self.plugged_in.retain(|_, plugged_in| names.contains(plugged_in.name.as_str()));
+            }
+            Err(e) => error!("Failed to list the devices to reconcile their idle timers: {}", e),
+        }
+        let sysfs_utils = &self.sysfs_utils;
+        self.pending_authorizations
+            .retain(|devpath, _| sysfs_utils.devpath_to_syspath(devpath).exists());
+    }
+
+    /// Brings the devices to the current state after a resume from suspend, as the uevents of
+    /// the devices plugged in or unplugged meanwhile may have been missed.
+    fn reconcile_devices_after_resume(&mut self) {
+        if self.is_enforcement_paused()
+            || !self.tunneling_supported
+            || !self.is_thunderbolt_enabled()
+        {
+            // Resuming enforcement or enabling thunderbolt sweeps the devices anyway.
+            return;
+        }
+        info!("Reconciling the devices after resume in state {:?}", self.current_pci_auth_state);
+        match self.current_pci_auth_state {
+            PciAuthState::Authorized if self.is_pci_authorization_required() => {
+                self.reconcile_authorized_devices("after resume");
+            }
+            PciAuthState::Authorized => self.drop_state_of_unplugged_devices(),
+            PciAuthState::DeferNewDevices => {
+                self.drop_state_of_unplugged_devices();
+                self.authorize_boot_acl_devices();
+            }
+            PciAuthState::DenyNoUser | PciAuthState::Disabled => self.deauthorize_all_devices(),
+        }
+    }
+
+    /// Authorizes the unauthorized devices preauthorized by the boot ACL, which are authorized
+    /// even while new devices are deferred.
+    fn authorize_boot_acl_devices(&mut self) {
+        let devices = match self.sysfs_utils.list_thunderbolt_devices() {
+            Ok(devices) => devices,
+            Err(e) => {
+                error!("Failed to list the devices to check against the boot ACL: {}", e);
+                return;
+            }
+        };
+        for device in devices.iter().filter(|device| !device.authorized) {
+            let path = self.sysfs_utils.thunderbolt_device_path(&device.name);
+            let result = self.sysfs_utils.is_on_boot_acl(&path).and_then(|on_acl| {
+                if on_acl {
+                    self.sysfs_utils.authorize_thunderbolt_dev(&path)?;
+                }
+                Ok(on_acl)
+            });
+            match result {
+                Ok(true) => {
+                    info!("Authorized {} from the boot ACL", device.name);
+                    self.metrics.devices_authorized += 1;
+                    self.record_authorization_latency(&DeviceId(device.key().to_string()));
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    error!("Failed to authorize {} from the boot ACL: {}", device.name, e);
+                    self.metrics.authorization_failures += 1;
+                }
+            }
+        }
+    }
+
+    /// Returns `DeferNewDevices` instead of `Authorized` while a thunderbolt domain lacks IOMMU
+    /// DMA protection, as the devices behind the PCI tunnels could then access any memory. A
+    /// domain not reporting its protection counts as unprotected unless the config trusts it.
+    fn apply_dma_protection_gate(&self, state: PciAuthState) -> PciAuthState {
+        if state != PciAuthState::Authorized
+            || self.flags.allow_unprotected_dma.load(Ordering::Relaxed)
+        {
+            return state;
+        }
+        match self.sysfs_utils.is_dma_protection_enabled() {
+            Ok(Some(true)) => state,
+            Ok(None) if self.flags.trust_unknown_dma_protection.load(Ordering::Relaxed) => state,
+            Ok(None) => {
+                warn!("IOMMU DMA protection is unknown. Deferring new devices instead of authorizing them.");
+                PciAuthState::DeferNewDevices
+            }
+            Ok(Some(false)) => {
+                warn!("IOMMU DMA protection is off. Deferring new devices instead of authorizing them.");
+                PciAuthState::DeferNewDevices
+            }
+            Err(e) => {
+                error!("Failed to read the IOMMU DMA protection. Deferring new devices: {}", e);
+                PciAuthState::DeferNewDevices
+            }
+        }
+    }
+
+    /// Cancels the idle timer of the connected `device` until it is unplugged.
+    fn cancel_idle_timer(&mut self, device: &DeviceId) {
+        let devices = match self.sysfs_utils.list_thunderbolt_devices() {
+            Ok(devices) => devices,
+            Err(e) => {
+                error!("Failed to list devices to cancel the idle timer of {}: {}", device.0, e);
+                return;
+            }
+        };
+        let Some(connected) = devices.iter().find(|connected| connected.key() == device.0) else {
+            warn!("Can't cancel the idle timer of {}, which isn't connected", device.0);
+            return;
+        };
+        if self.idle_timers.cancel(&device.0, &connected.name) {
+            info!("Idle timer of {} cancelled, {} left", device.0, self.idle_timers.len());
+        } else {
+            info!("{} has no idle timer, it won't get one", device.0);
+        }
+    }
+
+    /// Starts the idle timers of the authorized devices which don't have one yet.
+    fn start_idle_timers_of_authorized_devices(&mut self) {
+        if self.idle_timers.timeout.is_none() {
+            return;
+        }
+        match self.sysfs_utils.list_thunderbolt_devices() {
+            Ok(devices) => {
+                for device in devices.iter().filter(|device| device.authorized) {
+                    let key = device.key();
+                    if !self.idle_timers.contains(key) {
+                        self.idle_timers.start(key.to_string(), &device.name);
+                    }
+                }
+            }
+            Err(e) => error!("Failed to list devices to start their idle timers: {}", e),
+        }
+    }
+
+    /// Deauthorizes the devices which have been idle for the idle timeout.
+    fn deauthorize_idle_devices(&mut self) {
+        for device_name in self.idle_timers.take_expired(tokio::time::Instant::now()) {
+            info!("Deauthorizing idle device {}", device_name);
+            self.metrics.idle_deauthorizations += 1;
+            let devpath = self.sysfs_utils.thunderbolt_device_path(&device_name);
+            if let Err(e) = self.sysfs_utils.deauthorize_thunderbolt_dev(&devpath) {
+                error!("Failed to deauthorize idle device {}: {}", device_name, e);
+            }
+        }
+    }
+
+    /// Runs a bulk sysfs operation. A panic in the operation is logged and marks the task as
+    /// degraded instead of killing the event loop. Returns false if the operation failed.
+    fn run_guarded<F>(&self, operation: &str, f: F) -> bool
+    where
+        F: FnOnce() -> crate::sysfs::Result<()>,
+    {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                error!("Failed to {}: {}", operation, e);
+                false
+            }
+            Err(_) => {
+                error!("Panicked while trying to {}. PciAuthorizerTask is degraded.", operation);
+                self.flags.degraded.store(true, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Handles the uevents which are ready to be read, without waiting for more.
+    async fn handle_ready_uevents(&mut self) {
+        loop {
+            let uevent_result = tokio::select! {
+                biased;
+                uevent_result = self.uevent_socket.read() => uevent_result,
+                _ = std::future::ready(()) => return,
+            };
+            if let Ok(uevent) = &uevent_result {
+                self.notify_uevent_observer(uevent).await;
+            }
+            self.handle_uevent_result(uevent_result);
+        }
+    }
+
+    /// Runs the event loop.
+    async fn run(mut self) {
+        info!("PciAuthorizerTask started.");
+        match self.sysfs_utils.list_domains() {
+            Ok(domains) if domains.is_empty() => info!("No thunderbolt domain found."),
+            Ok(domains) => info!("Found {} thunderbolt domains: {:?}", domains.len(), domains),
+            Err(e) => error!("Failed to list the thunderbolt domains: {}", e),
+        }
+        self.check_tunneling_supported();
+        // Apply the policy the task was started with.
+        self.update_auth_state();
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            self.flags.activity.lock().unwrap().last_heartbeat = Some(Instant::now());
+            // Idle devices are deauthorized once enforcement resumes.
+            let idle_deadline =
+                self.idle_timers.next_deadline().filter(|_| !self.is_enforcement_paused());
+            let retry_deadline =
+                self.pending_authorizations.values().map(|pending| pending.deadline).min();
+            let resume_sweep = self.should_resume_sweep();
+            tokio::select! {
+                uevent_result = self.uevent_socket.read() => {
+                    if let Ok(uevent) = &uevent_result {
+                        self.notify_uevent_observer(uevent).await;
+                    }
+                    self.handle_uevent_result(uevent_result);
+                }
+                _ = tokio::time::sleep_until(
+                    idle_deadline.unwrap_or_else(tokio::time::Instant::now)
+                ), if idle_deadline.is_some() => {
+                    self.deauthorize_idle_devices();
+                }
+                _ = tokio::time::sleep_until(
+                    retry_deadline.unwrap_or_else(tokio::time::Instant::now)
+                ), if retry_deadline.is_some() => {
+                    self.retry_pending_authorizations();
+                }
+                _ = std::future::ready(()), if resume_sweep => {
+                    self.update_auth_state();
+                }
+                _ = heartbeat.tick() => {}
+                Some(service_event) = self.event_receiver.recv() => {
+                    // The uevents received before a flush are handled before it, whichever
+                    // branch the loop picked first.
+                    if matches!(service_event, PciServiceEvent::Flush(_)) {
+                        self.handle_ready_uevents().await;
+                    }
+                    let event_receiver = &self.event_receiver;
+                    trace::counter(trace::EVENT_QUEUE_DEPTH, || event_receiver.len() as i64);
+                    if !self.handle_service_event(service_event) {
+                        info!("Shutdown event received.");
+                        break;
+                    }
+                }
+                else => {
+                    info!("Event channel closed. Shutting down.");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Builds a `PciAuthorizer`. Options which aren't set use the defaults of the device: the sysfs
+/// root of `SysfsUtils::from_env`, a netlink uevent socket, the default auth policy, the
+/// restrictive default policy inputs and no idle timeout.
+#[derive(Default)]
+pub struct PciAuthorizerBuilder {
+    sysfs_utils: Option<SysfsUtils>,
+    uevent_socket: Option<Arc<dyn AsyncUEventSocket>>,
+    policy_data: PolicySourceData,
+    idle_timeout: Option<Duration>,
+    auth_policy: Option<Arc<dyn AuthPolicy>>,
+    observers: TaskObservers,
+    allow_unprotected_dma: bool,
+    trust_unknown_dma_protection: bool,
+}
+
+impl PciAuthorizerBuilder {
+    /// Sets the sysfs backend the devices are authorized through.
+    pub fn with_sysfs_utils(mut self, sysfs_utils: SysfsUtils) -> Self {
+        self.sysfs_utils = Some(sysfs_utils);
+        self
+    }
+
+    /// Sets the socket the uevents are read from.
+    pub fn with_uevent_socket(mut self, uevent_socket: Arc<dyn AsyncUEventSocket>) -> Self {
+        self.uevent_socket = Some(uevent_socket);
+        self
+    }
+
+    /// Sets the policy inputs applied when the task starts, before any update.
+    pub fn with_policy_data(mut self, policy_data: PolicySourceData) -> Self {
+        self.policy_data = policy_data;
+        self
+    }
+
+    /// Sets the idle timeout. See `PciAuthorizer::set_idle_deauthorize_timeout`.
+    pub fn with_idle_deauthorize_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the policy deciding the authorization state from the policy inputs.
+    pub fn with_auth_policy(mut self, auth_policy: Box<dyn AuthPolicy>) -> Self {
+        self.auth_policy = Some(Arc::from(auth_policy));
+        self
+    }
+
+    /// Sets a channel receiving a copy of every uevent read, e.g. to keep a log of the events.
+    /// Copies are dropped while the channel is full, so a slow observer never delays the policy,
+    /// unless `with_uevent_observer_retry` is set.
+    pub fn with_uevent_observer(mut self, observer: mpsc::Sender<kobject_uevent::UEvent>) -> Self {
+        self.observers.uevents = Some(observer);
+        self
+    }
+
+    /// Waits up to `retry`, capped to `MAX_UEVENT_OBSERVER_RETRY`, for room in the uevent
+    /// observer before dropping a uevent, instead of dropping it right away. The observer then
+    /// receives every uevent as long as it keeps up, at the cost of delaying the policy while it
+    /// lags.
+    pub fn with_uevent_observer_retry(mut self, retry: Duration) -> Self {
+        self.observers.uevent_retry = Some(retry.min(MAX_UEVENT_OBSERVER_RETRY));
+        self
+    }
+
+    /// Sets a channel receiving the events of the policy, e.g. to tell the user that a device was
+    /// denied. Events are dropped while the channel is full.
+    pub fn with_policy_event_observer(mut self, observer: mpsc::Sender<PolicyEvent>) -> Self {
+        self.observers.policy_events = Some(observer);
+        self
+    }
+
+    /// Sets whether devices are only authorized while all the thunderbolt domains have IOMMU DMA
+    /// protection, which is the default. Without it, the state stays `DeferNewDevices` where it
+    /// would be `Authorized`. Only meant to be disabled for testing.
+    pub fn with_dma_protection_required(mut self, required: bool) -> Self {
+        self.allow_unprotected_dma = !required;
+        self
+    }
+
+    /// Sets whether devices are authorized when a thunderbolt domain doesn't report its IOMMU DMA
+    /// protection. By default, such a domain counts as unprotected.
+    pub fn with_unknown_dma_protection_trusted(mut self, trusted: bool) -> Self {
+        self.trust_unknown_dma_protection = trusted;
+        self
+    }
+
+    /// Applies the initial policy of `config`.
+    pub fn with_config(mut self, config: &PolicyConfig) -> Self {
+        self.policy_data.pci_tunnels_enabled = config.pci_tunnels_enabled;
+        self.idle_timeout = config.idle_deauthorize_timeout;
+        self.trust_unknown_dma_protection = config.trust_unknown_dma_protection;
+        self
+    }
+
+    /// Builds the `PciAuthorizer` and starts its task. Must be called from a Tokio runtime.
+    /// Panics if the netlink uevent socket can't be created, see `try_build`.
+    pub fn build(self) -> PciAuthorizer {
+        self.try_build().expect("Failed to create AsyncNetlinkKObjectUEventSocket in PciAuthorizer")
+    }
+
+    /// Builds the `PciAuthorizer` and starts its task. Must be called from a Tokio runtime.
+    /// Without a uevent socket set, a netlink uevent socket is created, retrying for a short
+    /// while if it transiently fails. The retries block the calling thread, so it should then be
+    /// called with the runtime entered rather than from an async task. Returns an error if
+    /// the socket can't be created.
+    pub fn try_build(self) -> Result<PciAuthorizer> {
+        let sysfs_utils = self.sysfs_utils.unwrap_or_else(SysfsUtils::from_env);
+        let uevent_socket: Arc<dyn AsyncUEventSocket> = match self.uevent_socket {
+            Some(uevent_socket) => uevent_socket,
+            None => Arc::new(
+                AsyncNetlinkKObjectUEventSocket::create_with_retry(
+                    NETLINK_SOCKET_ATTEMPTS,
+                    NETLINK_SOCKET_RETRY_DELAY,
+                )
+                .context("Failed to create the netlink uevent socket")?,
+            ),
+        };
+        let auth_policy = self.auth_policy.unwrap_or_else(|| Arc::new(DefaultAuthPolicy));
+        let flags = TaskFlags {
+            allow_unprotected_dma: Arc::new(AtomicBool::new(self.allow_unprotected_dma)),
+            trust_unknown_dma_protection: Arc::new(AtomicBool::new(
+                self.trust_unknown_dma_protection,
+            )),
+            ..Default::default()
+        };
+        let (event_sender, join_handle) = PciAuthorizer::spawn_task(
+            &sysfs_utils,
+            &uevent_socket,
+            self.policy_data.clone(),
+            &auth_policy,
+            self.idle_timeout,
+            self.observers.clone(),
+            &flags,
+        );
+
+        let handle = PciAuthorizerHandle {
+            shared: Arc::new(SharedControl {
+                event_sender: RwLock::new(event_sender),
+                policy_data: Mutex::new(self.policy_data),
+                auth_policy: RwLock::new(auth_policy),
+                runtime: tokio::runtime::Handle::current(),
+                uevent_socket,
+                observers: self.observers,
+                task: Mutex::new(TaskState {
+                    join_handle: Some(join_handle),
+                    idle_timeout: self.idle_timeout,
+                    shut_down: false,
+                    prepared_for_shutdown: false,
+                }),
+            }),
+            flags,
+            sysfs_utils,
+        };
+        Ok(PciAuthorizer { handle })
+    }
+}
+
+/// State shared by a PciAuthorizer and its handles.
+struct SharedControl {
+    /// Sends the events to the task. Replaced when the task is restarted.
+    event_sender: RwLock<mpsc::Sender<PciServiceEvent>>,
+    /// Copy of the policy data sent to the task, to start a new task with if it dies. Locked
+    /// while an update is sent, so that the copy and the task see the updates in the same order.
+    policy_data: Mutex<PolicySourceData>,
+    /// Policy the task decides the state with, to tell the updates changing the decision.
+    auth_policy: RwLock<Arc<dyn AuthPolicy>>,
+    /// The runtime running the task, on which a new task is started if it dies.
+    runtime: tokio::runtime::Handle,
+    /// The uevent socket and the observers of the task, to start a new task with if it dies.
+    uevent_socket: Arc<dyn AsyncUEventSocket>,
+    observers: TaskObservers,
+    /// The task, which any handle restarts if it dies.
+    task: Mutex<TaskState>,
+}
+
+/// The running PciAuthorizerTask, and what a new task is started with if it dies.
+struct TaskState {
+    join_handle: Option<tokio::task::JoinHandle<()>>,
+    /// Copy of the idle timeout sent to the task.
+    idle_timeout: Option<Duration>,
+    /// Set by `prepare_for_shutdown` or once the `PciAuthorizer` is dropped, after which the task
+    /// is never restarted.
+    shut_down: bool,
+    /// Set once `prepare_for_shutdown` succeeded.
+    prepared_for_shutdown: bool,
+}
+
+impl TaskState {
+    fn is_alive(&self) -> bool {
+        self.join_handle.as_ref().is_some_and(|handle| !handle.is_finished())
+    }
+}
+
+/// Controls the PciAuthorizerTask of a `PciAuthorizer` without a lock around the
+/// `PciAuthorizer`. Handles are cheap to clone, can be used from any thread, and keep reaching the
+/// task after it is restarted.
+#[derive(Clone)]
+pub struct PciAuthorizerHandle {
+    shared: Arc<SharedControl>,
+    flags: TaskFlags,
+    sysfs_utils: SysfsUtils,
+}
+
+impl PciAuthorizerHandle {
+    /// Sends an event, waiting until `deadline` for room in the event channel. Unlike policy
+    /// updates, the events sent this way are worth waiting for.
+    fn send_event_before(&self, mut event: PciServiceEvent, deadline: Instant) -> Result<()> {
+        loop {
+            let event_sender = self.shared.event_sender.read().unwrap();
+            match event_sender.try_send(event) {
+                Ok(()) => {
+                    trace_event_queue_depth(&event_sender);
+                    return Ok(());
+                }
+                Err(mpsc::error::TrySendError::Full(returned)) if Instant::now() < deadline => {
+                    event = returned;
+                    drop(event_sender);
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    bail!("Timed out waiting for room in the event channel")
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => bail!("Event channel closed"),
+            }
+        }
+    }
+
+    /// Sends an event without waiting. Returns false if the event couldn't be sent.
+    fn send_event(&self, event: PciServiceEvent) -> bool {
+        let event_sender = self.shared.event_sender.read().unwrap();
+        match event_sender.try_send(event) {
+            Ok(_) => {
+                trace_event_queue_depth(&event_sender);
+                true
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                error!("Event channel full. Policy update might be delayed/lost.");
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                error!("Event channel closed. Service might have crashed.");
+                false
+            }
+        }
+    }
+
+    /// Sends an update of the policy inputs. If the update changes the decision, also cancels the
+    /// bulk sysfs operation in progress, as the task reevaluates the state after handling it. The
+    /// operation is cancelled before sending, so that the cancellation can't hit the sweep of the
+    /// updated state.
+    fn send_policy_update(&self, event: PciServiceEvent, decision_changed: bool) {
+        if decision_changed {
+            self.flags.cancel_sweep.store(true, Ordering::Relaxed);
+        }
+        self.send_event(event);
+    }
+
+    /// Applies `update` to the copy of the policy data and sends `event` to the task.
+    fn update_policy_data(
+        &self,
+        update: impl FnOnce(&mut PolicySourceData),
+        event: PciServiceEvent,
+    ) {
+        let mut policy_data = self.shared.policy_data.lock().unwrap();
+        let auth_policy = self.shared.auth_policy.read().unwrap().clone();
+        let old_decision =
+            (auth_policy.auth_state(&policy_data), policy_data.enabled_subsystems.clone());
+        update(&mut policy_data);
+        let decision_changed = auth_policy.auth_state(&policy_data) != old_decision.0
+            || policy_data.enabled_subsystems != old_decision.1;
+        self.send_policy_update(event, decision_changed);
+    }
+}
+
+impl TunnelControl for PciAuthorizerHandle {
+    fn enable_pci_tunnels(&self, enable: bool) {
+        self.update_policy_data(
+            |policy_data| policy_data.pci_tunnels_enabled = enable,
+            PciServiceEvent::EnablePciTunnels(enable),
+        );
+    }
+
+    fn update_lock_state(&self, locked: bool) {
+        self.update_policy_data(
+            |policy_data| policy_data.is_locked = locked,
+            PciServiceEvent::UpdateLockState(locked),
+        );
+    }
+
+    fn update_logged_in_state(&self, logged_in: bool, user_id: UserId) {
+        let update_user_id = user_id.clone();
+        self.update_policy_data(
+            move |policy_data| {
+                if logged_in {
+                    policy_data.logged_in_users.insert(update_user_id);
+                } else {
+                    policy_data.logged_in_users.remove(&update_user_id);
+                }
+            },
+            PciServiceEvent::UpdateLoggedInState { logged_in, user_id },
+        );
+    }
+
+    fn set_logged_in_users(&self, user_ids: HashSet<UserId>) {
+        let update_user_ids = user_ids.clone();
+        self.update_policy_data(
+            move |policy_data| policy_data.logged_in_users = update_user_ids,
+            PciServiceEvent::SetLoggedInUsers(user_ids),
+        );
+    }
+
+    fn set_enforcement_paused(&self, paused: bool) {
+        // The flag takes effect right away, even on the sweep in progress, and survives restarts
+        // of the task. The event makes the task reconcile the devices on resume.
+        let was_paused = self.flags.enforcement_paused.swap(paused, Ordering::Relaxed);
+        self.send_policy_update(
+            PciServiceEvent::SetEnforcementPaused(paused),
+            was_paused != paused,
+        );
+    }
+
+    fn set_enabled_subsystems(&self, subsystems: HashSet<Subsystem>) {
+        let update_subsystems = subsystems.clone();
+        self.update_policy_data(
+            move |policy_data| policy_data.enabled_subsystems = update_subsystems,
+            PciServiceEvent::SetEnabledSubsystems(subsystems),
+        );
+    }
+
+    fn remove_pci_device(&self, bdf: &str) -> Result<()> {
+        if !self.shared.policy_data.lock().unwrap().enabled_subsystems.contains(&Subsystem::Pci) {
+            anyhow::bail!("Failed to remove PCI device {}: the PCI subsystem is disabled", bdf);
+        }
+        // Removing a device doesn't depend on the policy state, so the task isn't involved.
+        self.sysfs_utils
+            .remove_pci_device(bdf)
+            .map_err(|e| anyhow::anyhow!("Failed to remove PCI device {}: {}", bdf, e))
+    }
+}
+
+impl PciAuthorizerHandle {
+    /// Returns true if the PciAuthorizerTask is still running.
+    pub fn is_task_alive(&self) -> bool {
+        self.shared.task.lock().unwrap().is_alive()
+    }
+
+    /// Starts a new PciAuthorizerTask with the current policy if the task died. Returns true if
+    /// the task was restarted. The new task runs on the runtime of the dead one. Never restarts
+    /// the task once `prepare_for_shutdown` was called, or once the `PciAuthorizer` is dropped.
+    pub fn restart_task(&self) -> bool {
+        let mut task = self.shared.task.lock().unwrap();
+        if task.is_alive() || task.shut_down {
+            return false;
+        }
+        error!("PciAuthorizerTask is not running. Restarting it.");
+        self.flags.degraded.store(false, Ordering::Relaxed);
+        // The new task sweeps the devices as it starts, which covers a lost `Resumed` event.
+        self.flags.resume_pending.store(false, Ordering::Relaxed);
+        // Holding the lock keeps the policy updates from reaching the dead task meanwhile.
+        let policy_data = self.shared.policy_data.lock().unwrap();
+        let auth_policy = self.shared.auth_policy.read().unwrap().clone();
+        let _runtime = self.shared.runtime.enter();
+        let (event_sender, join_handle) = PciAuthorizer::spawn_task(
+            &self.sysfs_utils,
+            &self.shared.uevent_socket,
+            policy_data.clone(),
+            &auth_policy,
+            task.idle_timeout,
+            self.shared.observers.clone(),
+            &self.flags,
+        );
+        *self.shared.event_sender.write().unwrap() = event_sender;
+        drop(policy_data);
+        task.join_handle = Some(join_handle);
+        true
+    }
+
+    /// Stops the task before the device shuts down, once all the devices are deauthorized if
+    /// `deauthorize` is set, so that they power down cleanly. Waits at most `timeout` for the
+    /// task. If the task already died, the devices are deauthorized on the calling thread. Once
+    /// a call succeeds, the next ones have no effect. After a failure, the task is stopped all
+    /// the same and a new call deauthorizes the devices on the calling thread. Must not be called
+    /// from the async context of the runtime running the task.
+    pub fn prepare_for_shutdown(&self, deauthorize: bool, timeout: Duration) -> Result<()> {
+        if self.shared.task.lock().unwrap().prepared_for_shutdown {
+            info!("PciAuthorizer already prepared for shutdown.");
+            return Ok(());
+        }
+        info!("Preparing for shutdown, deauthorize={}", deauthorize);
+        if !self.is_task_alive() {
+            self.shared.task.lock().unwrap().shut_down = true;
+            if deauthorize {
+                self.sysfs_utils.deauthorize_all_devices().map_err(|e| {
+                    anyhow::anyhow!("Failed to deauthorize the devices before shutdown: {}", e)
+                })?;
+            }
+            self.shared.task.lock().unwrap().prepared_for_shutdown = true;
+            return Ok(());
+        }
+        let deadline = Instant::now() + timeout;
+        let (done_sender, done_receiver) = std::sync::mpsc::channel();
+        self.send_event_before(
+            PciServiceEvent::PrepareForShutdown { deauthorize, done: done_sender },
+            deadline,
+        )?;
+        // The task stops once it handled the event, even if it fails to deauthorize the devices.
+        self.shared.task.lock().unwrap().shut_down = true;
+        done_receiver
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .context("Timed out waiting for the task to prepare for shutdown")??;
+        self.shared.task.lock().unwrap().prepared_for_shutdown = true;
+        Ok(())
+    }
+
+    /// Returns true if a bulk sysfs operation of the task panicked. The task keeps processing
+    /// events, but the devices may not reflect the current policy.
+    pub fn is_degraded(&self) -> bool {
+        self.flags.degraded.load(Ordering::Relaxed)
+    }
+
+    /// Returns the health of the engine. Unlike the other queries, it doesn't wait for the task,
+    /// so that a stuck task can be detected from a stale `last_heartbeat`.
+    pub fn health(&self) -> EngineHealth {
+        let activity = *self.flags.activity.lock().unwrap();
+        EngineHealth {
+            task_alive: self.is_task_alive(),
+            degraded: self.is_degraded(),
+            auth_state: activity.auth_state,
+            last_uevent: activity.last_uevent,
+            last_heartbeat: activity.last_heartbeat,
+            uevent_errors_since_success: activity.uevent_errors_since_success,
+            tunneling_supported: activity.tunneling_supported,
+        }
+    }
+
+    /// Returns true while enforcement is paused. See `TunnelControl::set_enforcement_paused`.
+    pub fn is_enforcement_paused(&self) -> bool {
+        self.flags.enforcement_paused.load(Ordering::Relaxed)
+    }
+
+    /// Notifies the task of a resume from suspend. The uevents of the devices plugged in or
+    /// unplugged during suspend may have been missed, so the task checks every device against the
+    /// current state. A notification while the previous one is still waiting to be handled is
+    /// ignored.
+    pub fn notify_resumed(&self) {
+        if self.flags.resume_pending.swap(true, Ordering::Relaxed) {
+            debug!("Reconciliation after resume already pending.");
+            return;
+        }
+        if !self.send_event(PciServiceEvent::Resumed) {
+            self.flags.resume_pending.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Blocks until the task handled all the events sent so far, and the uevents it received, or
+    /// `timeout` elapses.
+    /// Must not be called from the async context of the runtime running the task.
+    pub fn flush(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let (done_sender, done_receiver) = std::sync::mpsc::channel();
+        self.send_event_before(PciServiceEvent::Flush(done_sender), deadline)?;
+        done_receiver
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .context("Timed out waiting for the pending events to be handled")
+    }
+
+    /// Returns the users the task considers logged in. Waits at most `timeout` for the task to
+    /// report them. Must not be called from the async context of the runtime running the task.
+    pub fn logged_in_users(&self, timeout: Duration) -> Result<HashSet<UserId>> {
+        Ok(self.snapshot(timeout)?.policy_data.logged_in_users)
+    }
+
+    /// Returns the devices with a pending idle timer, and the time left before they are
+    /// deauthorized. Waits at most `timeout` for the task to report them. Must not be called from
+    /// the async context of the runtime running the task.
+    pub fn pending_idle_timers(&self, timeout: Duration) -> Result<Vec<(DeviceId, Duration)>> {
+        Ok(self.snapshot(timeout)?.idle_timers)
+    }
+
+    /// Returns the time the devices took from their `Add` uevent to their authorization. Waits at
+    /// most `timeout` for the task to report it. Must not be called from the async context of the
+    /// runtime running the task.
+    pub fn authorization_latency(&self, timeout: Duration) -> Result<AuthorizationLatency> {
+        Ok(self.snapshot(timeout)?.metrics.authorization_latency)
+    }
+
+    /// Cancels the idle timer of `device`, e.g. once the user marks it trusted, so that it stays
+    /// authorized while idle. The device doesn't get a timer again while the task runs.
+    pub fn cancel_idle_timer(&self, device: DeviceId) {
+        self.send_event(PciServiceEvent::CancelIdleTimer(device));
+    }
+
+    /// Returns a human-readable report of the policy state, the task health and the connected
+    /// devices. Waits at most `timeout` for the task to report its state. Must not be called from
+    /// the async context of the runtime running the task.
+    pub fn dump(&self, timeout: Duration) -> String {
+        let mut report = String::new();
+        let alive = self.is_task_alive();
+        let _ = writeln!(report, "PciAuthorizer:");
+        let _ = writeln!(
+            report,
+            "  Task: {}{}{}{}",
+            if alive { "alive" } else { "dead" },
+            if self.is_degraded() { ", degraded" } else { "" },
+            if self.is_enforcement_paused() { ", enforcement paused" } else { "" },
+            if self.health().tunneling_supported == Some(false) {
+                ", tunneling unsupported"
+            } else {
+                ""
+            }
+        );
+        match self.snapshot(timeout) {
+            Ok(snapshot) => {
+                let policy_data = &snapshot.policy_data;
+                let mut users: Vec<usize> =
+                    policy_data.logged_in_users.iter().map(|user_id| user_id.0).collect();
+                users.sort_unstable();
+                let metrics = &snapshot.metrics;
+                let _ = writeln!(report, "  State: {:?}", snapshot.auth_state);
+                let _ = writeln!(
+                    report,
+                    "  Policy: pci_tunnels_enabled={} is_locked={} logged_in_users={:?}",
+                    policy_data.pci_tunnels_enabled, policy_data.is_locked, users
+                );
+                let mut subsystems: Vec<String> = policy_data
+                    .enabled_subsystems
+                    .iter()
+                    .map(|subsystem| format!("{:?}", subsystem))
+                    .collect();
+                subsystems.sort_unstable();
+                let _ = writeln!(report, "  Enabled subsystems: {:?}", subsystems);
+                let _ = writeln!(
+                    report,
+                    "  Idle timers: {} (timeout {:?})",
+                    snapshot.idle_timers.len(),
+                    self.shared.task.lock().unwrap().idle_timeout
+                );
+                let _ = writeln!(
+                    report,
+                    "  Metrics: uevents_received={} uevent_errors={} devices_authorized={} \
+                    authorization_failures={} idle_deauthorizations={} observer_drops={} \
+                    devices_denied={} sweeps={} empty_subsystem_uevents={}",
+                    metrics.uevents_received,
+                    metrics.uevent_errors,
+                    metrics.devices_authorized,
+                    metrics.authorization_failures,
+                    metrics.idle_deauthorizations,
+                    metrics.observer_drops,
+                    metrics.devices_denied,
+                    metrics.sweeps,
+                    metrics.empty_subsystem_uevents
+                );
+                let latency = &metrics.authorization_latency;
+                let _ = writeln!(
+                    report,
+                    "  Authorization latency: count={} last={:?} average={:?} max={:?}",
+                    latency.count,
+                    latency.last,
+                    latency.average(),
+                    latency.max
+                );
+            }
+            Err(e) => {
+                let _ = writeln!(report, "  State: unavailable ({:#})", e);
+            }
+        }
+        match self.sysfs_utils.list_domains() {
+            Ok(domains) => {
+                let _ = writeln!(report, "  Domains ({}): {:?}", domains.len(), domains);
+            }
+            Err(e) => {
+                let _ = writeln!(report, "  Domains: unavailable ({})", e);
+            }
+        }
+        match self.sysfs_utils.list_thunderbolt_devices() {
+            Ok(mut devices) => {
+                devices.sort_by(|a, b| a.name.cmp(&b.name));
+                let _ = writeln!(report, "  Devices ({}):", devices.len());
+                for device in devices {
+                    let _ = writeln!(
+                        report,
+                        "    {}: {} vendor={:?} device={:?} unique_id={:?}",
+                        device.name,
+                        if device.authorized { "authorized" } else { "unauthorized" },
+                        device.vendor_name,
+                        device.device_name,
+                        device.unique_id
+                    );
+                }
+            }
+            Err(e) => {
+                let _ = writeln!(report, "  Devices: unavailable ({})", e);
+            }
+        }
+        report
+    }
+
+    /// Requests a snapshot of the state of the task and waits at most `timeout` for it.
+    fn snapshot(&self, timeout: Duration) -> Result<TaskSnapshot> {
+        let deadline = Instant::now() + timeout;
+        let (reply_sender, reply_receiver) = std::sync::mpsc::channel();
+        self.send_event_before(PciServiceEvent::Snapshot(reply_sender), deadline)?;
+        reply_receiver
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .context("Timed out waiting for the task to report its state")
+    }
+}
+
+/// Publishes the number of events waiting in the event channel of `event_sender`.
+fn trace_event_queue_depth(event_sender: &mpsc::Sender<PciServiceEvent>) {
+    trace::counter(trace::EVENT_QUEUE_DEPTH, || {
+        (event_sender.max_capacity() - event_sender.capacity()) as i64
+    });
+}
+
+/// Orchestrates authorization policy and interacts with the PciAuthorizerTask. The task is
+/// stopped once the `PciAuthorizer` is dropped.
+pub struct PciAuthorizer {
+    /// Controls the task, shared with the handles returned by `handle`.
+    handle: PciAuthorizerHandle,
+}
+
+impl PciAuthorizer {
+    /// Returns a builder of `PciAuthorizer`.
+    pub fn builder() -> PciAuthorizerBuilder {
+        PciAuthorizerBuilder::default()
+    }
+
+    /// Creates a new PciAuthorizer.
+    pub fn new(sysfs_utils: SysfsUtils, uevent_socket: Arc<dyn AsyncUEventSocket>) -> Self {
+        Self::builder().with_sysfs_utils(sysfs_utils).with_uevent_socket(uevent_socket).build()
+    }
+
+    /// Creates a new PciAuthorizer starting from the policy of `config`.
+    pub fn with_config(
+        sysfs_utils: SysfsUtils,
+        uevent_socket: Arc<dyn AsyncUEventSocket>,
+        config: &PolicyConfig,
+    ) -> Self {
+        Self::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_config(config)
+            .build()
+    }
+
+    /// Creates a `PciAuthorizer` with the defaults of the device, starting from the policy of the
+    /// config file on the device. Returns an error if the netlink uevent socket can't be created,
+    /// after retrying for a short while.
+    pub fn try_default() -> Result<Self> {
+        let config = PolicyConfig::load(Path::new(DEFAULT_CONFIG_PATH));
+        Self::builder().with_config(&config).try_build()
+    }
+
+    /// Spawns a PciAuthorizerTask applying `policy_data`. Must be called from a Tokio runtime.
+    fn spawn_task(
+        sysfs_utils: &SysfsUtils,
+        uevent_socket: &Arc<dyn AsyncUEventSocket>,
+        policy_data: PolicySourceData,
+        auth_policy: &Arc<dyn AuthPolicy>,
+        idle_timeout: Option<Duration>,
+        observers: TaskObservers,
+        flags: &TaskFlags,
+    ) -> (mpsc::Sender<PciServiceEvent>, tokio::task::JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(MESSAGE_QUEUE_SIZE);
+
+        // The task starts from the default state, so that it applies the transition to the state
+        // of `policy_data` when it starts.
+        let initial_auth_state = auth_policy.auth_state(&PolicySourceData::default());
+
+        let service = PciAuthorizerTask {
+            uevent_socket: uevent_socket.clone(),
+            event_receiver: rx,
+            sysfs_utils: sysfs_utils.clone(),
+            policy_data,
+            auth_policy: auth_policy.clone(),
+            current_pci_auth_state: initial_auth_state,
+            sweep_pending: false,
+            tunneling_supported: true,
+            flags: flags.clone(),
+            uevent_error_throttle: ErrorLogThrottle::new(UEVENT_ERROR_LOG_INTERVAL),
+            log_uevent_error: Box::new(|message| error!("{}", message)),
+            log_link_downgrade: Box::new(|message| warn!("{}", message)),
+            idle_timers: IdleTimers::new(idle_timeout),
+            pending_authorizations: HashMap::new(),
+            plugged_in: HashMap::new(),
+            metrics: TaskMetrics::default(),
+            observers,
+        };
+        (tx, tokio::spawn(service.run()))
+    }
+
+    /// Returns a handle controlling the policy from any thread, without a lock around this
+    /// `PciAuthorizer`.
+    pub fn handle(&self) -> PciAuthorizerHandle {
+        self.handle.clone()
+    }
+
+    /// Returns true if the PciAuthorizerTask is still running.
+    pub fn is_task_alive(&self) -> bool {
+        self.handle.is_task_alive()
+    }
+
+    /// Starts a new task if the task died. See `PciAuthorizerHandle::restart_task`.
+    pub fn restart_task(&self) -> bool {
+        self.handle.restart_task()
+    }
+
+    /// Stops the task before the device shuts down. See
+    /// `PciAuthorizerHandle::prepare_for_shutdown`.
+    pub fn prepare_for_shutdown(&self, deauthorize: bool, timeout: Duration) -> Result<()> {
+        self.handle.prepare_for_shutdown(deauthorize, timeout)
+    }
+
+    /// Returns true if a bulk sysfs operation of the task panicked.
+    pub fn is_degraded(&self) -> bool {
+        self.handle.is_degraded()
+    }
+
+    /// Returns the health of the engine. See `PciAuthorizerHandle::health`.
+    pub fn health(&self) -> EngineHealth {
+        self.handle.health()
+    }
+
+    /// Returns true while enforcement is paused.
+    pub fn is_enforcement_paused(&self) -> bool {
+        self.handle.is_enforcement_paused()
+    }
+
+    /// Notifies the task of a resume from suspend. See `PciAuthorizerHandle::notify_resumed`.
+    pub fn notify_resumed(&self) {
+        self.handle.notify_resumed();
+    }
+
+    /// Sets the idle period after which a device authorized while unlocked is deauthorized, to
+    /// limit the exposure of forgotten devices. Uevents of a device reset its timer. None, the
+    /// default, disables the timeout.
+    pub fn set_idle_deauthorize_timeout(&mut self, timeout: Option<Duration>) {
+        self.handle.shared.task.lock().unwrap().idle_timeout = timeout;
+        self.handle.send_event(PciServiceEvent::SetIdleTimeout(timeout));
+    }
+
+    /// Replaces the policy deciding the authorization state from the policy inputs, e.g. to try
+    /// out another policy on a running device. The task swaps the policy between two events and
+    /// then applies the state decided by the new policy.
+    pub fn set_policy(&mut self, auth_policy: Box<dyn AuthPolicy>) {
+        let auth_policy: Arc<dyn AuthPolicy> = Arc::from(auth_policy);
+        let policy_data = self.handle.shared.policy_data.lock().unwrap();
+        let old_auth_policy = std::mem::replace(
+            &mut *self.handle.shared.auth_policy.write().unwrap(),
+            auth_policy.clone(),
+        );
+        let decision_changed =
+            old_auth_policy.auth_state(&policy_data) != auth_policy.auth_state(&policy_data);
+        self.handle.send_policy_update(PciServiceEvent::SetPolicy(auth_policy), decision_changed);
+    }
+
+    /// Blocks until the task handled the events sent so far. See `PciAuthorizerHandle::flush`.
+    pub fn flush(&self, timeout: Duration) -> Result<()> {
+        self.handle.flush(timeout)
+    }
+
+    /// Returns the users the task considers logged in, waiting at most `timeout` for the task.
+    pub fn logged_in_users(&self, timeout: Duration) -> Result<HashSet<UserId>> {
+        self.handle.logged_in_users(timeout)
+    }
+
+    /// Returns the devices with a pending idle timer, waiting at most `timeout` for the task.
+    pub fn pending_idle_timers(&self, timeout: Duration) -> Result<Vec<(DeviceId, Duration)>> {
+        self.handle.pending_idle_timers(timeout)
+    }
+
+    /// Returns the authorization latency of the devices, waiting at most `timeout` for the task.
+    pub fn authorization_latency(&self, timeout: Duration) -> Result<AuthorizationLatency> {
+        self.handle.authorization_latency(timeout)
+    }
+
+    /// Cancels the idle timer of `device`. See `PciAuthorizerHandle::cancel_idle_timer`.
+    pub fn cancel_idle_timer(&self, device: DeviceId) {
+        self.handle.cancel_idle_timer(device);
+    }
+
+    /// Returns a human-readable report of the engine. See `PciAuthorizerHandle::dump`.
+    pub fn dump(&self, timeout: Duration) -> String {
+        self.handle.dump(timeout)
+    }
+}
+
+impl Default for PciAuthorizer {
+    /// Creates a default `PciAuthorizer`, starting from the policy of the config file on the
+    /// device. Panics if the netlink uevent socket can't be created, see `try_default`.
+    fn default() -> Self {
+        Self::try_default().expect("Failed to create the default PciAuthorizer")
+    }
+}
+
+impl TunnelControl for PciAuthorizer {
+    fn enable_pci_tunnels(&self, enable: bool) {
+        self.handle.enable_pci_tunnels(enable);
+    }
+
+    fn update_lock_state(&self, locked: bool) {
+        self.handle.update_lock_state(locked);
+    }
+
+    fn update_logged_in_state(&self, logged_in: bool, user_id: UserId) {
+        self.handle.update_logged_in_state(logged_in, user_id);
+    }
+
+    fn set_logged_in_users(&self, user_ids: HashSet<UserId>) {
+        self.handle.set_logged_in_users(user_ids);
+    }
+
+    fn set_enforcement_paused(&self, paused: bool) {
+        self.handle.set_enforcement_paused(paused);
+    }
+
+    fn set_enabled_subsystems(&self, subsystems: HashSet<Subsystem>) {
+        self.handle.set_enabled_subsystems(subsystems);
+    }
+
+    fn remove_pci_device(&self, bdf: &str) -> Result<()> {
+        self.handle.remove_pci_device(bdf)
+    }
+}
+
+impl Drop for PciAuthorizer {
+    fn drop(&mut self) {
+        info!("PciAuthorizer dropping. Shutting down PciAuthorizerTask.");
+
+        let event_sender = self.handle.shared.event_sender.read().unwrap();
+        if event_sender.try_send(PciServiceEvent::Shutdown).is_err() {
+            error!("Failed to send shutdown signal to PciAuthorizerTask or channel already closed. Task might not shut down via signal.");
+        }
+
+        // The handles outliving this PciAuthorizer must not restart the task.
+        let mut task = self.handle.shared.task.lock().unwrap();
+        task.shut_down = true;
+        if task.join_handle.take().is_some() {
+            info!("PciAuthorizerTask shutdown initiated. The task will be managed by the Tokio runtime.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::fs;
+
+    /// Uevent socket which never yields any uevent.
+    struct IdleUEventSocket;
+
+    #[async_trait]
+    impl AsyncUEventSocket for IdleUEventSocket {
+        async fn read(&self) -> Result<kobject_uevent::UEvent> {
+            std::future::pending().await
+        }
+    }
+
+    fn new_task() -> PciAuthorizerTask {
+        let (_tx, rx) = mpsc::channel(MESSAGE_QUEUE_SIZE);
+        let policy_data = PolicySourceData::default();
+        let auth_policy: Arc<dyn AuthPolicy> = Arc::new(DefaultAuthPolicy);
+        let current_pci_auth_state = auth_policy.auth_state(&policy_data);
+        PciAuthorizerTask {
+            uevent_socket: Arc::new(IdleUEventSocket),
+            event_receiver: rx,
+            sysfs_utils: SysfsUtils::with_root_path("/nonexistent".into()),
+            policy_data,
+            auth_policy,
+            current_pci_auth_state,
+            sweep_pending: false,
+            tunneling_supported: true,
+            flags: TaskFlags::default(),
+            uevent_error_throttle: ErrorLogThrottle::new(UEVENT_ERROR_LOG_INTERVAL),
+            log_uevent_error: Box::new(|message| error!("{}", message)),
+            log_link_downgrade: Box::new(|message| warn!("{}", message)),
+            idle_timers: IdleTimers::new(None),
+            pending_authorizations: HashMap::new(),
+            plugged_in: HashMap::new(),
+            metrics: TaskMetrics::default(),
+            observers: TaskObservers::default(),
+        }
+    }
+
+    fn new_handle() -> (PciAuthorizerHandle, mpsc::Receiver<PciServiceEvent>) {
+        let (tx, rx) = mpsc::channel(MESSAGE_QUEUE_SIZE);
+        let handle = PciAuthorizerHandle {
+            shared: Arc::new(SharedControl {
+                event_sender: RwLock::new(tx),
+                policy_data: Mutex::new(PolicySourceData::default()),
+                auth_policy: RwLock::new(Arc::new(DefaultAuthPolicy)),
+                runtime: tokio::runtime::Handle::current(),
+                uevent_socket: Arc::new(IdleUEventSocket),
+                observers: TaskObservers::default(),
+                task: Mutex::new(TaskState {
+                    join_handle: None,
+                    idle_timeout: None,
+                    shut_down: false,
+                    prepared_for_shutdown: false,
+                }),
+            }),
+            flags: TaskFlags::default(),
+            sysfs_utils: SysfsUtils::with_root_path("/nonexistent".into()),
+        };
+        (handle, rx)
+    }
+
+    #[tokio::test]
+    async fn only_updates_changing_the_decision_cancel_the_sweep() {
+        let (handle, _rx) = new_handle();
+        handle.enable_pci_tunnels(true);
+        handle.update_logged_in_state(true, UserId(10));
+        handle.update_lock_state(false);
+        assert!(handle.flags.cancel_sweep.swap(false, Ordering::Relaxed));
+
+        // The screen is unlocked already, and another user keeps the state `Authorized`.
+        handle.update_lock_state(false);
+        handle.update_logged_in_state(true, UserId(11));
+        handle.set_enforcement_paused(false);
+        assert!(!handle.flags.cancel_sweep.load(Ordering::Relaxed));
+
+        handle.update_lock_state(true);
+        assert!(handle.flags.cancel_sweep.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn action_for_every_transition() {
+        use PciAuthState::{Authorized, DeferNewDevices, DenyNoUser, Disabled};
+        use TransitionAction::{AuthorizeAll, DeauthorizeAll, LeaveDevices, ReconcileAuthorized};
+        let expected = [
+            (Disabled, Disabled, DeauthorizeAll),
+            (Disabled, DenyNoUser, DeauthorizeAll),
+            (Disabled, DeferNewDevices, LeaveDevices),
+            (Disabled, Authorized, AuthorizeAll),
+            (DenyNoUser, Disabled, DeauthorizeAll),
+            (DenyNoUser, DenyNoUser, DeauthorizeAll),
+            (DenyNoUser, DeferNewDevices, LeaveDevices),
+            (DenyNoUser, Authorized, AuthorizeAll),
+            (DeferNewDevices, Disabled, DeauthorizeAll),
+            (DeferNewDevices, DenyNoUser, DeauthorizeAll),
+            (DeferNewDevices, DeferNewDevices, LeaveDevices),
+            (DeferNewDevices, Authorized, ReconcileAuthorized),
+            (Authorized, Disabled, DeauthorizeAll),
+            (Authorized, DenyNoUser, DeauthorizeAll),
+            (Authorized, DeferNewDevices, LeaveDevices),
+            (Authorized, Authorized, AuthorizeAll),
+        ];
+        let states = [Disabled, DenyNoUser, DeferNewDevices, Authorized];
+        assert_eq!(expected.len(), states.len() * states.len());
+        for old in states {
+            for new in states {
+                assert!(
+                    expected.iter().any(|&(from, to, _)| (from, to) == (old, new)),
+                    "{:?} -> {:?} isn't tested",
+                    old,
+                    new
+                );
+            }
+        }
+        for (old, new, action) in expected {
+            assert_eq!(action_for_transition(old, new), action, "{:?} -> {:?}", old, new);
+        }
+    }
+
+    #[test]
+    fn subsystem_from_str() {
+        assert_eq!(UeventSubsystem::from("thunderbolt"), UeventSubsystem::Thunderbolt);
+        assert_eq!(UeventSubsystem::from("pci"), UeventSubsystem::Pci);
+        assert_eq!(UeventSubsystem::from("usb"), UeventSubsystem::Other("usb".to_string()));
+        assert_eq!(UeventSubsystem::from(""), UeventSubsystem::Other("".to_string()));
+        // Subsystem names are case sensitive.
+        assert_eq!(
+            UeventSubsystem::from("Thunderbolt"),
+            UeventSubsystem::Other("Thunderbolt".to_string())
+        );
+    }
+
+    #[test]
+    fn panicking_sweep_marks_task_degraded() {
+        let mut task = new_task();
+
+        task.run_guarded("authorize all devices", || panic!("injected panic"));
+        assert!(task.flags.degraded.load(Ordering::Relaxed));
+
+        // Subsequent events are still processed.
+        assert!(task.handle_service_event(PciServiceEvent::EnablePciTunnels(true)));
+        assert_eq!(task.current_pci_auth_state, PciAuthState::DenyNoUser);
+    }
+
+    #[test]
+    fn failing_device_does_not_stop_sweep_or_mark_task_degraded() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut task = new_task();
+        task.sysfs_utils = SysfsUtils::with_root_path(temp_dir.path().to_path_buf());
+        let bus = temp_dir.path().join("sys/bus/thunderbolt");
+        fs::create_dir_all(bus.join("devices")).unwrap();
+        let [failing, working] = ["domain0/0-1", "domain1/1-1"].map(|topology_path| {
+            let devpath = temp_dir.path().join("sys/devices").join(topology_path);
+            fs::create_dir_all(&devpath).unwrap();
+            fs::write(devpath.join("authorized"), "0\n").unwrap();
+            std::os::unix::fs::symlink(&bus, devpath.join("subsystem")).unwrap();
+            let name = Path::new(topology_path).file_name().unwrap();
+            let target = Path::new("../../../devices").join(topology_path);
+            std::os::unix::fs::symlink(target, bus.join("devices").join(name)).unwrap();
+            devpath
+        });
+        // Writing to a directory fails, so the device can't be authorized.
+        fs::remove_file(failing.join("authorized")).unwrap();
+        fs::create_dir(failing.join("authorized")).unwrap();
+
+        task.flags.allow_unprotected_dma.store(true, Ordering::Relaxed);
+        task.handle_service_event(PciServiceEvent::EnablePciTunnels(true));
+        task.handle_service_event(PciServiceEvent::SetLoggedInUsers(HashSet::from([UserId(10)])));
+        task.handle_service_event(PciServiceEvent::UpdateLockState(false));
+
+        assert_eq!(task.current_pci_auth_state, PciAuthState::Authorized);
+        assert_eq!(fs::read_to_string(working.join("authorized")).unwrap().trim(), "1");
+        assert!(!task.flags.degraded.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn cancelled_sweep_is_resumed_without_another_event() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut task = new_task();
+        let bus = temp_dir.path().join("sys/bus/thunderbolt");
+        fs::create_dir_all(bus.join("devices")).unwrap();
+        let devpaths = ["domain0/0-1", "domain0/0-2"].map(|topology_path| {
+            let devpath = temp_dir.path().join("sys/devices").join(topology_path);
+            fs::create_dir_all(&devpath).unwrap();
+            fs::write(devpath.join("authorized"), "0\n").unwrap();
+            std::os::unix::fs::symlink(&bus, devpath.join("subsystem")).unwrap();
+            let name = Path::new(topology_path).file_name().unwrap();
+            let target = Path::new("../../../devices").join(topology_path);
+            std::os::unix::fs::symlink(target, bus.join("devices").join(name)).unwrap();
+            devpath
+        });
+        // Cancel the first sweep as a policy update would, while no update follows.
+        let cancel = task.flags.cancel_sweep.clone();
+        let cancelled = AtomicBool::new(false);
+        task.sysfs_utils = SysfsUtils::with_root_path(temp_dir.path().to_path_buf())
+            .with_before_authorize_hook(Arc::new(move |_| {
+                if !cancelled.swap(true, Ordering::Relaxed) {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+            }));
+        task.flags.allow_unprotected_dma.store(true, Ordering::Relaxed);
+        task.policy_data.pci_tunnels_enabled = true;
+        task.policy_data.logged_in_users = HashSet::from([UserId(10)]);
+        task.policy_data.is_locked = false;
+
+        let run = tokio::spawn(task.run());
+
+        let read_authorized = |devpath: &PathBuf| fs::read_to_string(devpath.join("authorized"));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while devpaths.iter().any(|devpath| read_authorized(devpath).unwrap().trim() != "1") {
+            assert!(Instant::now() < deadline, "The cancelled sweep was not resumed");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        run.abort();
+    }
+
+    #[test]
+    fn empty_subsystem_uevents_are_ignored() {
+        let mut task = new_task();
+        let uevent = uevent::mock::build_uevent(ActionType::Add, "", "/devices/domain0/0-1");
+
+        task.handle_uevent_result(Ok(uevent));
+
+        assert_eq!(task.metrics.uevents_received, 1);
+        assert_eq!(task.metrics.empty_subsystem_uevents, 1);
+        assert_eq!(task.metrics.authorization_failures, 0);
+        assert_eq!(task.metrics.devices_denied, 0);
+    }
+
+    #[test]
+    fn rapid_uevent_errors_are_rate_limited() {
+        let mut task = new_task();
+        let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = messages.clone();
+        task.log_uevent_error =
+            Box::new(move |message| recorded.lock().unwrap().push(message.to_string()));
+
+        for _ in 0..100 {
+            task.handle_uevent_result(Err(anyhow::anyhow!("socket failure")));
+        }
+
+        let messages = messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("socket failure"));
+    }
+
+    #[test]
+    fn error_log_throttle_reports_suppressed_count() {
+        let mut throttle = ErrorLogThrottle::new(Duration::from_secs(10));
+        let start = Instant::now();
+
+        assert_eq!(throttle.record(start), Some(0));
+        assert_eq!(throttle.record(start + Duration::from_secs(1)), None);
+        assert_eq!(throttle.record(start + Duration::from_secs(9)), None);
+        assert_eq!(throttle.record(start + Duration::from_secs(10)), Some(2));
+        assert_eq!(throttle.record(start + Duration::from_secs(11)), None);
+        assert_eq!(throttle.record(start + Duration::from_secs(30)), Some(1));
+    }
+
+    #[test]
+    fn link_speed_downgrade_is_logged() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut task = new_task();
+        task.sysfs_utils = SysfsUtils::with_root_path(temp_dir.path().to_path_buf());
+        let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = messages.clone();
+        task.log_link_downgrade =
+            Box::new(move |message| recorded.lock().unwrap().push(message.to_string()));
+        let devpath = temp_dir.path().join("sys/devices/domain0/0-0/0-1");
+        fs::create_dir_all(&devpath).unwrap();
+        for (attribute, value) in [
+            ("authorized", "1"),
+            ("generation", "3"),
+            ("rx_speed", "20.0 Gb/s"),
+            ("rx_lanes", "2"),
+            ("tx_speed", "20.0 Gb/s"),
+            ("tx_lanes", "2"),
+        ] {
+            fs::write(devpath.join(attribute), format!("{}\n", value)).unwrap();
+        }
+        let bus = temp_dir.path().join("sys/bus/thunderbolt");
+        fs::create_dir_all(&bus).unwrap();
+        std::os::unix::fs::symlink(&bus, devpath.join("subsystem")).unwrap();
+        let change_uevent = kobject_uevent::UEvent {
+            action: ActionType::Change,
+            devpath: "/devices/domain0/0-0/0-1".into(),
+            subsystem: "thunderbolt".to_string(),
+            env: HashMap::new(),
+            seq: 0,
+        };
+
+        task.handle_uevent_result(Ok(change_uevent.clone()));
+        assert!(messages.lock().unwrap().is_empty(), "Full speed link should not be reported");
+
+        fs::write(devpath.join("tx_lanes"), "1\n").unwrap();
+        task.handle_uevent_result(Ok(change_uevent.clone()));
+        assert_eq!(messages.lock().unwrap().len(), 1);
+        assert!(messages.lock().unwrap()[0].contains("20 Gb/s out of 40 Gb/s"));
+
+        // A Thunderbolt 1 device runs at its own maximum.
+        fs::write(devpath.join("rx_lanes"), "1\n").unwrap();
+        fs::write(devpath.join("rx_speed"), "10.0 Gb/s\n").unwrap();
+        fs::write(devpath.join("tx_speed"), "10.0 Gb/s\n").unwrap();
+        fs::write(devpath.join("generation"), "1\n").unwrap();
+        task.handle_uevent_result(Ok(change_uevent.clone()));
+        assert_eq!(messages.lock().unwrap().len(), 1);
+
+        // Devices not reporting their generation are not checked.
+        fs::remove_file(devpath.join("generation")).unwrap();
+        task.handle_uevent_result(Ok(change_uevent.clone()));
+        assert_eq!(messages.lock().unwrap().len(), 1);
+
+        // Unauthorized devices are not checked.
+        fs::write(devpath.join("generation"), "3\n").unwrap();
+        fs::write(devpath.join("authorized"), "0\n").unwrap();
+        task.handle_uevent_result(Ok(change_uevent));
+        assert_eq!(messages.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reconnected_device_replaces_its_idle_timer() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut task = new_task();
+        task.sysfs_utils = SysfsUtils::with_root_path(temp_dir.path().to_path_buf());
+        for bus in ["thunderbolt", "pci"] {
+            fs::create_dir_all(temp_dir.path().join("sys/bus").join(bus).join("devices")).unwrap();
+        }
+        task.current_pci_auth_state = PciAuthState::Authorized;
+        task.idle_timers.set_timeout(Some(Duration::from_secs(60)));
+        let add_uevent = |name: &str| {
+            let devpath = temp_dir.path().join("sys/devices/domain0/0-0").join(name);
+            fs::create_dir_all(&devpath).unwrap();
+            fs::write(devpath.join("authorized"), "0\n").unwrap();
+            std::os::unix::fs::symlink(
+                temp_dir.path().join("sys/bus/thunderbolt"),
+                devpath.join("subsystem"),
+            )
+            .unwrap();
+            fs::write(devpath.join("unique_id"), "d1d2d3d4-0000-1111-2222-333344445555\n").unwrap();
+            kobject_uevent::UEvent {
+                action: ActionType::Add,
+                devpath: format!("/devices/domain0/0-0/{}", name).into(),
+                subsystem: "thunderbolt".to_string(),
+                env: HashMap::new(),
+                seq: 0,
+            }
+        };
+
+        task.handle_uevent_result(Ok(add_uevent("0-1")));
+        // The device comes back on another port without its removal being seen.
+        task.handle_uevent_result(Ok(add_uevent("0-3")));
+
+        assert_eq!(task.idle_timers.len(), 1);
+        let timer = &task.idle_timers.timers["d1d2d3d4-0000-1111-2222-333344445555"];
+        assert_eq!(timer.name, "0-3");
+        task.idle_timers.remove("0-3");
+        assert_eq!(task.idle_timers.len(), 0);
+    }
+
+    #[test]
+    fn unplugged_device_forgets_its_cancelled_idle_timer() {
+        let mut idle_timers = IdleTimers::new(Some(Duration::from_secs(60)));
+        idle_timers.start("key".to_string(), "0-1");
+        assert!(idle_timers.cancel("key", "0-1"));
+        idle_timers.start("key".to_string(), "0-1");
+        assert_eq!(idle_timers.len(), 0);
+
+        idle_timers.forget("0-1");
+        idle_timers.start("key".to_string(), "0-3");
+        assert_eq!(idle_timers.len(), 1);
+        assert!(idle_timers.cancel("key", "0-3"));
+        idle_timers.retain_devices(&HashSet::new());
+        assert!(idle_timers.cancelled.is_empty());
+    }
 }
@@ -19,9 +19,27 @@
 //! The `PolicyEngine` struct is the primary entry point for consumers of this
 //! crate. It encapsulates the `PciAuthorizer`.
 
-use crate::common::{TunnelControl, UserId};
-use crate::pci_authorizer::PciAuthorizer;
+use crate::common::{Subsystem, TunnelControl, UserId};
+use crate::config::{PolicyConfig, DEFAULT_CONFIG_PATH};
+use crate::pci_authorizer::{AuthorizationLatency, EngineHealth, PciAuthorizer, PolicyEvent};
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Duration;
 use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+/// Maximum time `dump` waits for the policy task to report its state.
+pub const DUMP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum time the queries of the policy state wait for the policy task.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum time `prepare_for_shutdown` waits for the devices to be deauthorized.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of policy events kept until they are received.
+const POLICY_EVENT_QUEUE_SIZE: usize = 16;
 
 /// The main engine that encapsulates all policy and authorization logic.
 ///
@@ -29,20 +47,96 @@ use tokio::runtime::Runtime;
 pub struct PolicyEngine {
     /// The embedded `PciAuthorizer` that handles core logic.
     pub pci_authorizer: PciAuthorizer,
-    /// The Tokio runtime for the PciAuthorizer's async tasks.
+    /// The Tokio runtime for the PciAuthorizer's async tasks, only kept alive as the task and its
+    /// handles run on it.
     _runtime: Runtime,
+    /// The events of the policy, until taken by `take_policy_events`.
+    policy_events: Option<mpsc::Receiver<PolicyEvent>>,
 }
 
 impl PolicyEngine {
-    /// Create a new PolicyEngine and associated members.
+    /// Create a new PolicyEngine and associated members. Panics on failure, see `try_new`.
     pub fn new() -> Self {
+        Self::try_new().expect("Failed to create PolicyEngine")
+    }
+
+    /// Create a new PolicyEngine and associated members. Returns an error if its runtime or its
+    /// uevent socket can't be created.
+    pub fn try_new() -> Result<Self> {
         let runtime = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
-            .expect("Failed to create Tokio runtime for PolicyEngine");
-        let pci_authorizer = runtime.block_on(async { PciAuthorizer::default() });
+            .context("Failed to create Tokio runtime for PolicyEngine")?;
+        let (policy_event_sender, policy_events) = mpsc::channel(POLICY_EVENT_QUEUE_SIZE);
+        let config = PolicyConfig::load(Path::new(DEFAULT_CONFIG_PATH));
+        // The socket creation sleeps between its attempts, so the runtime is entered rather than
+        // blocked on.
+        let pci_authorizer = {
+            let _guard = runtime.enter();
+            PciAuthorizer::builder()
+                .with_config(&config)
+                .with_policy_event_observer(policy_event_sender)
+                .try_build()?
+        };
+
+        Ok(Self { pci_authorizer, _runtime: runtime, policy_events: Some(policy_events) })
+    }
+
+    /// Returns true if the policy task is running. Otherwise restarts it with the current policy
+    /// and returns false.
+    pub fn ensure_task_alive(&self) -> bool {
+        !self.pci_authorizer.restart_task()
+    }
+
+    /// Blocks until the policy updates sent so far are applied, or `timeout` elapses.
+    pub fn flush(&self, timeout: Duration) -> Result<()> {
+        self.pci_authorizer.flush(timeout)
+    }
+
+    /// Notifies the engine of a resume from suspend, so that the devices plugged in or unplugged
+    /// meanwhile are handled. See `PciAuthorizer::notify_resumed`.
+    pub fn notify_resumed(&self) {
+        self.pci_authorizer.notify_resumed();
+    }
+
+    /// Stops the policy task before the device reboots, once all the devices are deauthorized if
+    /// `deauthorize` is set. Safe to call several times, or after the task died. See
+    /// `PciAuthorizer::prepare_for_shutdown`.
+    pub fn prepare_for_shutdown(&self, deauthorize: bool) -> Result<()> {
+        self.pci_authorizer.prepare_for_shutdown(deauthorize, SHUTDOWN_TIMEOUT)
+    }
+
+    /// Takes the receiver of the events of the policy, e.g. denied devices. Returns None if it
+    /// was already taken.
+    pub fn take_policy_events(&mut self) -> Option<mpsc::Receiver<PolicyEvent>> {
+        self.policy_events.take()
+    }
+
+    /// Gives back the receiver taken by `take_policy_events` once its reader stops, so that the
+    /// next reader can take it.
+    pub fn restore_policy_events(&mut self, policy_events: mpsc::Receiver<PolicyEvent>) {
+        self.policy_events = Some(policy_events);
+    }
+
+    /// Returns the users the policy considers logged in.
+    pub fn logged_in_users(&self) -> Result<HashSet<UserId>> {
+        self.pci_authorizer.logged_in_users(QUERY_TIMEOUT)
+    }
+
+    /// Returns the time the devices took from their `Add` uevent to their authorization.
+    pub fn authorization_latency(&self) -> Result<AuthorizationLatency> {
+        self.pci_authorizer.authorization_latency(QUERY_TIMEOUT)
+    }
+
+    /// Returns the health of the engine, without waiting for the policy task.
+    pub fn health(&self) -> EngineHealth {
+        self.pci_authorizer.health()
+    }
 
-        Self { pci_authorizer, _runtime: runtime }
+    /// Returns a human-readable report of the state of the engine, for dumpsys. Never blocks
+    /// for more than a bounded time, even if the policy task is stuck.
+    pub fn dump(&self) -> String {
+        self.pci_authorizer.dump(DUMP_TIMEOUT)
     }
 }
 impl Default for PolicyEngine {
@@ -54,17 +148,37 @@ impl Default for PolicyEngine {
 
 impl TunnelControl for PolicyEngine {
     /// Enables or disables the PCI tunneling feature globally.
-    fn enable_pci_tunnels(&mut self, enable: bool) {
+    fn enable_pci_tunnels(&self, enable: bool) {
         self.pci_authorizer.enable_pci_tunnels(enable);
     }
 
     /// Notifies the engine of a screen lock state change.
-    fn update_lock_state(&mut self, locked: bool) {
+    fn update_lock_state(&self, locked: bool) {
         self.pci_authorizer.update_lock_state(locked);
     }
 
     /// Notifies the engine of a user login or logout event.
-    fn update_logged_in_state(&mut self, logged_in: bool, user_id: UserId) {
+    fn update_logged_in_state(&self, logged_in: bool, user_id: UserId) {
         self.pci_authorizer.update_logged_in_state(logged_in, user_id);
     }
+
+    /// Replaces the set of logged-in users at once.
+    fn set_logged_in_users(&self, user_ids: HashSet<UserId>) {
+        self.pci_authorizer.set_logged_in_users(user_ids);
+    }
+
+    /// Pauses or resumes the enforcement of the policy.
+    fn set_enforcement_paused(&self, paused: bool) {
+        self.pci_authorizer.set_enforcement_paused(paused);
+    }
+
+    /// Replaces the set of subsystems the engine acts on.
+    fn set_enabled_subsystems(&self, subsystems: HashSet<Subsystem>) {
+        self.pci_authorizer.set_enabled_subsystems(subsystems);
+    }
+
+    /// Removes the tunneled PCI device at the address `bdf`.
+    fn remove_pci_device(&self, bdf: &str) -> Result<()> {
+        self.pci_authorizer.remove_pci_device(bdf)
+    }
 }
@@ -12,25 +12,258 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
 use std::fs;
 use std::io::{self};
 use std::path::{Path, PathBuf}; // For Box<dyn Error>
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 // Import logging macros. A logger (e.g., simple_logger) should be initialized
 // in the binary (main.rs) that uses this library.
-use log::{error, info};
+use log::{error, info, warn};
+use rustutils::system_properties;
+
+use crate::common::Subsystem;
 
 /// A generic Result type for the application's operations,
 /// returning `Box<dyn std::error::Error>` on failure.
 pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
+/// Value of the thunderbolt device "generation" attribute for USB4 devices. Thunderbolt 1 to 3
+/// devices report their Thunderbolt generation.
+pub const USB4_GENERATION: u32 = 4;
+
+/// System property overriding the root directory of sysfs, e.g. to run the policy against a mock
+/// sysfs tree on a device.
+pub const SYSFS_ROOT_PROPERTY: &str = "persist.usb4.sysfs_root";
+
+/// Maximum number of thunderbolt domains authorized in parallel.
+const MAX_PARALLEL_DOMAINS: usize = 4;
+
+/// Prefix of the "class" attribute of PCI-to-PCI bridges.
+const PCI_BRIDGE_CLASS_PREFIX: &str = "0x0604";
+
+/// Level written to the "authorized" attribute of a thunderbolt device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthLevel {
+    /// The device is deauthorized.
+    Deauthorized,
+    /// The device is authorized.
+    Authorized,
+    /// The device is authorized through the challenge-response of its key.
+    AuthorizedWithKey,
+}
+
+impl AuthLevel {
+    /// The value the kernel documents for the level.
+    pub fn default_payload(self) -> Vec<u8> {
+        match self {
+            AuthLevel::Deauthorized => b"0".to_vec(),
+            AuthLevel::Authorized => b"1".to_vec(),
+            AuthLevel::AuthorizedWithKey => b"2".to_vec(),
+        }
+    }
+}
+
+/// Maps an authorization level to the bytes written to the "authorized" attribute.
+pub type AuthPayloadFormatter = Arc<dyn Fn(AuthLevel) -> Vec<u8> + Send + Sync>;
+
+/// Called with the path of each thunderbolt device a sweep is about to authorize.
+pub type AuthorizeHook = Arc<dyn Fn(&Path) + Send + Sync>;
+
+/// Security level of a thunderbolt domain, as reported by its "security" attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityLevel {
+    /// All devices are connected automatically by the firmware.
+    None,
+    /// Devices are connected once authorized by the user.
+    User,
+    /// Devices are connected once authorized by the user, with a challenge key.
+    Secure,
+    /// Only DisplayPort tunnels are created. PCI tunneling is impossible.
+    DpOnly,
+    /// Only USB tunnels are created. PCI tunneling is impossible.
+    UsbOnly,
+    /// PCIe tunneling is disabled by the firmware.
+    NoPcie,
+}
+
+impl SecurityLevel {
+    /// Parses the content of the "security" attribute.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(SecurityLevel::None),
+            "user" => Some(SecurityLevel::User),
+            "secure" => Some(SecurityLevel::Secure),
+            "dponly" => Some(SecurityLevel::DpOnly),
+            "usbonly" => Some(SecurityLevel::UsbOnly),
+            "nopcie" => Some(SecurityLevel::NoPcie),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the domain can create PCI tunnels at all.
+    pub fn allows_pci_tunnels(&self) -> bool {
+        !matches!(self, SecurityLevel::DpOnly | SecurityLevel::UsbOnly | SecurityLevel::NoPcie)
+    }
+
+    /// Returns whether PCI tunnels of the domain wait for our authorization.
+    pub fn requires_pci_authorization(&self) -> bool {
+        matches!(self, SecurityLevel::User | SecurityLevel::Secure)
+    }
+}
+
+/// Speed of the link a thunderbolt device negotiated with its parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkSpeed {
+    /// Receive speed per lane, in Gb/s.
+    pub rx_speed: u32,
+    pub rx_lanes: u32,
+    /// Transmit speed per lane, in Gb/s.
+    pub tx_speed: u32,
+    pub tx_lanes: u32,
+}
+
+impl LinkSpeed {
+    /// Returns the lowest bandwidth of the two directions, in Gb/s.
+    pub fn bandwidth(&self) -> u32 {
+        (self.rx_speed * self.rx_lanes).min(self.tx_speed * self.tx_lanes)
+    }
+}
+
+/// A thunderbolt device connected to the system, as described by sysfs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThunderboltDevice {
+    /// Kernel name of the device, e.g. "0-1".
+    pub name: String,
+    /// Whether the device is authorized.
+    pub authorized: bool,
+    /// Unique id of the device, if exposed.
+    pub unique_id: Option<String>,
+    /// Vendor name of the device, if exposed.
+    pub vendor_name: Option<String>,
+    /// Product name of the device, if exposed.
+    pub device_name: Option<String>,
+}
+
+impl ThunderboltDevice {
+    /// Returns the key identifying the device across reconnections, as `SysfsUtils::device_key`.
+    pub fn key(&self) -> &str {
+        self.unique_id.as_deref().filter(|unique_id| !unique_id.is_empty()).unwrap_or(&self.name)
+    }
+
+    /// Returns whether the device is the host router of its domain, e.g. "0-0", which is always
+    /// authorized.
+    pub fn is_host_router(&self) -> bool {
+        self.name.ends_with("-0")
+    }
+}
+
+/// Position of a thunderbolt domain or device in the thunderbolt topology.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TbtDeviceId {
+    /// Index of the domain, e.g. 0 for "domain0" and "0-1".
+    pub domain: u32,
+    /// Route string of the device, i.e. its kernel name, e.g. "0-1" or "0-301". None for the
+    /// domain itself.
+    pub route: Option<String>,
+}
+
+/// Type of an entry of the thunderbolt bus, told apart by its kernel name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThunderboltEntryType {
+    /// A domain, e.g. "domain0".
+    Domain,
+    /// A router: a host router, e.g. "0-0", or a device, e.g. "0-301".
+    Device,
+    /// A retimer on a port of a router, e.g. "0-0:1.1" for the first retimer on port 1 of
+    /// "0-0". Retimers have no authorization.
+    Retimer,
+}
+
+impl ThunderboltEntryType {
+    /// Returns the type of the entry of the thunderbolt bus named `name`.
+    pub fn from_name(name: &str) -> Self {
+        if name.starts_with("domain") {
+            ThunderboltEntryType::Domain
+        } else if name.contains(':') {
+            ThunderboltEntryType::Retimer
+        } else {
+            ThunderboltEntryType::Device
+        }
+    }
+}
+
+/// How a bulk authorization or deauthorization sweep ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SweepOutcome {
+    /// All the devices were processed.
+    Completed,
+    /// The sweep was cancelled before processing all the devices.
+    Cancelled,
+}
+
+/// How `SysfsUtils::deauthorize_all_devices` denies the removable PCI devices.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PciDenialMode {
+    /// Removes the devices. They come back only once their bus is rescanned.
+    #[default]
+    Remove,
+    /// Clears the "authorized" attribute of the devices, which is faster to undo. Devices
+    /// without the attribute, on kernels not supporting it, are removed instead.
+    Deauthorize,
+}
+
+/// Result of one phase of a deauthorization sweep.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SweepPhaseReport {
+    /// Number of devices the phase handled successfully.
+    pub succeeded: usize,
+    /// Devices the phase failed to handle.
+    pub failed: Vec<PathBuf>,
+}
+
+impl SweepPhaseReport {
+    /// Returns true if the phase didn't fail on any device.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Results of `SysfsUtils::deauthorize_all_devices_report`, per phase.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeauthorizationReport {
+    /// Removal, or deauthorization depending on the `PciDenialMode`, of the removable PCI
+    /// devices.
+    pub pci_removal: SweepPhaseReport,
+    /// Deauthorization of the thunderbolt devices.
+    pub thunderbolt_deauthorization: SweepPhaseReport,
+    /// Whether the sweep processed all the devices.
+    pub outcome: SweepOutcome,
+}
+
+impl DeauthorizationReport {
+    /// Returns true if neither phase failed on any device.
+    pub fn is_success(&self) -> bool {
+        self.pci_removal.is_success() && self.thunderbolt_deauthorization.is_success()
+    }
+}
+
 /// `SysfsUtils` struct.
 /// It holds paths to various sysfs entries related to PCI and Thunderbolt devices.
 #[derive(Clone)]
 pub struct SysfsUtils {
+    sys_path: PathBuf,
     tbt_devices_path: PathBuf,
     pci_devices_path: PathBuf,
+    skip_failed_subtrees: bool,
+    min_authorized_generation: Option<u32>,
+    auth_payload_formatter: AuthPayloadFormatter,
+    pci_denial_mode: PciDenialMode,
+    before_authorize: Option<AuthorizeHook>,
 }
 
 impl SysfsUtils {
@@ -39,14 +272,427 @@ impl SysfsUtils {
         Self::with_root_path(PathBuf::from("/"))
     }
 
+    /// Creates a `SysfsUtils` instance, initializing paths relative to the directory set in the
+    /// `SYSFS_ROOT_PROPERTY` system property, or `/` if it is unset or empty.
+    pub fn from_env() -> Self {
+        let root_override = system_properties::read(SYSFS_ROOT_PROPERTY).unwrap_or_else(|e| {
+            warn!("Failed to read {}: {:#}", SYSFS_ROOT_PROPERTY, e);
+            None
+        });
+        Self::with_root_override(root_override.as_deref())
+    }
+
+    /// Creates a `SysfsUtils` instance, initializing paths relative to `root_override`, or `/` if
+    /// it is unset or empty.
+    pub fn with_root_override(root_override: Option<&str>) -> Self {
+        match root_override {
+            Some(root) if !root.is_empty() => {
+                info!("Using sysfs root override {:?}", root);
+                Self::with_root_path(PathBuf::from(root))
+            }
+            _ => Self::new(),
+        }
+    }
+
     /// Creates a `SysfsUtils` instance, initializing paths relative to a specified root directory.
     pub fn with_root_path(root: PathBuf) -> Self {
         SysfsUtils {
+            sys_path: root.join("sys"),
             tbt_devices_path: root.join("sys/bus/thunderbolt/devices"),
             pci_devices_path: root.join("sys/bus/pci/devices"),
+            skip_failed_subtrees: false,
+            min_authorized_generation: None,
+            auth_payload_formatter: Arc::new(AuthLevel::default_payload),
+            pci_denial_mode: PciDenialMode::default(),
+            before_authorize: None,
+        }
+    }
+
+    /// Sets whether `authorize_all_devices` skips the descendants of a device that failed to be
+    /// authorized, instead of attempting (and failing) to authorize each of them.
+    pub fn with_failed_subtree_skipping(mut self, skip: bool) -> Self {
+        self.skip_failed_subtrees = skip;
+        self
+    }
+
+    /// Restricts authorization to devices whose generation is at least `generation`, e.g.
+    /// `USB4_GENERATION` to only authorize USB4 devices. Devices not reporting their generation
+    /// are not authorized while restricted. Deauthorization is not affected.
+    pub fn with_min_authorized_generation(mut self, generation: Option<u32>) -> Self {
+        self.min_authorized_generation = generation;
+        self
+    }
+
+    /// Sets the bytes written to the "authorized" attribute of the thunderbolt devices for each
+    /// level, for vendor kernels expecting another format than the default "0", "1" and "2".
+    pub fn with_auth_payload_formatter(mut self, formatter: AuthPayloadFormatter) -> Self {
+        self.auth_payload_formatter = formatter;
+        self
+    }
+
+    /// Sets how `deauthorize_all_devices` denies the removable PCI devices. Defaults to
+    /// `PciDenialMode::Remove`.
+    pub fn with_pci_denial_mode(mut self, mode: PciDenialMode) -> Self {
+        self.pci_denial_mode = mode;
+        self
+    }
+
+    /// Calls `hook` before each thunderbolt device is authorized by `authorize_all_devices`, e.g.
+    /// for tests to unplug a device or cancel the sweep at a given point of the sweep.
+    pub fn with_before_authorize_hook(mut self, hook: AuthorizeHook) -> Self {
+        self.before_authorize = Some(hook);
+        self
+    }
+
+    /// Reads a sysfs attribute, without surrounding whitespace.
+    /// Returns `Ok(None)` if the attribute doesn't exist.
+    fn read_optional_attribute(path: &Path) -> Result<Option<String>> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(content.trim().to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => {
+                Err(io::Error::new(e.kind(), format!("Failed to read {:?}: {}", path, e)).into())
+            }
+        }
+    }
+
+    /// Reads the "generation" attribute of a thunderbolt device.
+    /// Returns `Ok(None)` if the device doesn't expose the attribute.
+    pub fn read_device_generation(&self, devpath: &Path) -> Result<Option<u32>> {
+        let generation_path = devpath.join("generation");
+        let Some(content) = Self::read_optional_attribute(&generation_path)? else {
+            return Ok(None);
+        };
+        let generation = content.parse::<u32>().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid generation {:?} in {:?}: {}", content, generation_path, e),
+            )
+        })?;
+        Ok(Some(generation))
+    }
+
+    /// Reads the negotiated link speed of a thunderbolt device.
+    /// Returns `Ok(None)` if the device doesn't expose the link attributes, e.g. host routers.
+    pub fn read_link_speed(&self, devpath: &Path) -> Result<Option<LinkSpeed>> {
+        Self::read_link_attributes(devpath, ["rx_speed", "rx_lanes", "tx_speed", "tx_lanes"])
+    }
+
+    /// Returns the link bandwidth a thunderbolt device supports according to its generation, in
+    /// Gb/s, which it negotiates unless the cable or its parent is slower: a 10 Gb/s lane for
+    /// Thunderbolt 1, two bonded 10 Gb/s lanes for Thunderbolt 2 and two bonded 20 Gb/s lanes for
+    /// Thunderbolt 3 and USB4. Returns `Ok(None)` if the device doesn't expose its generation.
+    pub fn read_max_link_bandwidth(&self, devpath: &Path) -> Result<Option<u32>> {
+        let max_bandwidth = match self.read_device_generation(devpath)? {
+            Some(1) => Some(10),
+            Some(2) => Some(20),
+            Some(3 | USB4_GENERATION) => Some(40),
+            _ => None,
+        };
+        Ok(max_bandwidth)
+    }
+
+    /// Reads the receive speed, receive lanes, transmit speed and transmit lanes of a link from
+    /// `attributes`, in this order.
+    fn read_link_attributes(devpath: &Path, attributes: [&str; 4]) -> Result<Option<LinkSpeed>> {
+        let mut values = [0; 4];
+        for (value, attribute) in values.iter_mut().zip(attributes) {
+            let attribute_path = devpath.join(attribute);
+            let Some(content) = Self::read_optional_attribute(&attribute_path)? else {
+                return Ok(None);
+            };
+            // Speeds are formatted as "20.0 Gb/s", lanes as "2".
+            let number = content.split(['.', ' ']).next().unwrap_or_default();
+            *value = number.parse::<u32>().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid {} {:?} in {:?}: {}", attribute, content, attribute_path, e),
+                )
+            })?;
+        }
+        let [rx_speed, rx_lanes, tx_speed, tx_lanes] = values;
+        Ok(Some(LinkSpeed { rx_speed, rx_lanes, tx_speed, tx_lanes }))
+    }
+
+    /// Reads the "unique_id" attribute of a thunderbolt device, a UUID which stays the same when
+    /// the device is reconnected. Returns `Ok(None)` if the device doesn't expose the attribute.
+    pub fn read_unique_id(&self, devpath: &Path) -> Result<Option<String>> {
+        Self::read_optional_attribute(&devpath.join("unique_id"))
+    }
+
+    /// Returns whether the thunderbolt device at `devpath` is ready to be authorized. Some docks
+    /// report "1" in their "waiting_for_power" attribute until they are powered, and fail to be
+    /// authorized meanwhile. Devices without the attribute are ready.
+    pub fn device_ready(&self, devpath: &Path) -> Result<bool> {
+        let waiting_path = devpath.join("waiting_for_power");
+        match Self::read_optional_attribute(&waiting_path)?.as_deref() {
+            None | Some("0") => Ok(true),
+            Some("1") => Ok(false),
+            Some(content) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid waiting_for_power {:?} in {:?}", content, waiting_path),
+            )
+            .into()),
         }
     }
 
+    /// Returns the key identifying the device at `devpath` across reconnections: its unique id,
+    /// or its kernel name if the unique id can't be read.
+    pub fn device_key(&self, devpath: &Path) -> String {
+        match self.read_unique_id(devpath) {
+            Ok(Some(unique_id)) if !unique_id.is_empty() => return unique_id,
+            Ok(_) => {}
+            Err(e) => warn!("Failed to read the unique id of {}: {}", devpath.display(), e),
+        }
+        devpath
+            .file_name()
+            .map_or_else(|| devpath.display().to_string(), |name| name.to_string_lossy().into())
+    }
+
+    /// Returns the path of the thunderbolt device named `name` on the thunderbolt bus.
+    pub fn thunderbolt_device_path(&self, name: &str) -> PathBuf {
+        self.tbt_devices_path.join(name)
+    }
+
+    /// Returns whether `name` names a thunderbolt device on the thunderbolt bus. Domains
+    /// (e.g. "domain0") and retimers (e.g. "0-0:1.1") are not devices.
+    pub fn is_thunderbolt_device_name(name: &str) -> bool {
+        ThunderboltEntryType::from_name(name) == ThunderboltEntryType::Device
+    }
+
+    /// Parses the path of a thunderbolt domain or device, either a uevent devpath, e.g.
+    /// "/devices/pci0000:00/0000:00:0d.2/domain0/0-0/0-1", or a path on the thunderbolt bus.
+    /// Returns None for other paths, including retimers.
+    pub fn parse_thunderbolt_devpath(devpath: &Path) -> Option<TbtDeviceId> {
+        let name = devpath.file_name()?.to_str()?;
+        if let Some(index) = name.strip_prefix("domain") {
+            return Some(TbtDeviceId { domain: index.parse().ok()?, route: None });
+        }
+        if !Self::is_thunderbolt_device_name(name) {
+            return None;
+        }
+        let (domain, route) = name.split_once('-')?;
+        let domain = domain.parse().ok()?;
+        if route.is_empty() {
+            return None;
+        }
+        // Other buses use the same naming scheme, e.g. USB ports are named "1-1", so the device
+        // must be under its domain or on the thunderbolt bus.
+        let domain_name = format!("domain{}", domain);
+        let in_domain =
+            devpath.components().any(|component| component.as_os_str() == &*domain_name);
+        let on_bus =
+            devpath.parent().is_some_and(|parent| parent.ends_with("bus/thunderbolt/devices"));
+        if !in_domain && !on_bus {
+            return None;
+        }
+        Some(TbtDeviceId { domain, route: Some(name.to_string()) })
+    }
+
+    /// Lists the thunderbolt devices, sorted by name.
+    /// Domains and retimers are skipped, see `is_thunderbolt_device_name`.
+    pub fn list_thunderbolt_devices(&self) -> Result<Vec<ThunderboltDevice>> {
+        let mut devices = Vec::new();
+        for entry in fs::read_dir(&self.tbt_devices_path)? {
+            let devpath = entry?.path();
+            let Some(name) = devpath.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if !Self::is_thunderbolt_device_name(name) || !devpath.is_dir() {
+                continue;
+            }
+            let authorized = Self::read_optional_attribute(&devpath.join("authorized"))?
+                .is_some_and(|authorized| !authorized.is_empty() && authorized != "0");
+            devices.push(ThunderboltDevice {
+                name: name.to_string(),
+                authorized,
+                unique_id: self.read_unique_id(&devpath)?,
+                vendor_name: Self::read_optional_attribute(&devpath.join("vendor_name"))?,
+                device_name: Self::read_optional_attribute(&devpath.join("device_name"))?,
+            });
+        }
+        devices.sort_by(|device1, device2| device1.name.cmp(&device2.name));
+        Ok(devices)
+    }
+
+    /// Lists the thunderbolt domains, e.g. "domain0", sorted by index. Empty if the thunderbolt
+    /// bus doesn't exist or no domain is registered yet.
+    pub fn list_domains(&self) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.tbt_devices_path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut domains = Vec::new();
+        for entry in entries {
+            let Some(name) = entry?.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if ThunderboltEntryType::from_name(&name) != ThunderboltEntryType::Domain {
+                continue;
+            }
+            if let Some(index) = name.strip_prefix("domain").and_then(|index| index.parse().ok()) {
+                domains.push((index, name));
+            }
+        }
+        domains.sort_unstable();
+        Ok(domains.into_iter().map(|(_, name): (u32, String)| name).collect())
+    }
+
+    /// Reads the "boot_acl" attribute of a thunderbolt domain, e.g. "domain0", i.e. the unique ids
+    /// of the devices the firmware preauthorizes in the domain. The attribute has a fixed number
+    /// of comma-separated slots, unused slots being empty. Empty if the domain has no boot ACL.
+    pub fn read_boot_acl(&self, domain: &str) -> Result<Vec<String>> {
+        let acl_path = self.tbt_devices_path.join(domain).join("boot_acl");
+        let Some(content) = Self::read_optional_attribute(&acl_path)? else {
+            return Ok(Vec::new());
+        };
+        Ok(content
+            .split(',')
+            .map(str::trim)
+            .filter(|unique_id| !unique_id.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Reads the "security" attribute of a thunderbolt domain, e.g. "domain0".
+    pub fn read_security_level(&self, domain: &str) -> Result<SecurityLevel> {
+        let security_path = self.tbt_devices_path.join(domain).join("security");
+        let content = Self::read_optional_attribute(&security_path)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{:?} not found", security_path))
+        })?;
+        SecurityLevel::parse(&content).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid security level {:?} in {:?}", content, security_path),
+            )
+            .into()
+        })
+    }
+
+    /// Returns the security level of the domain of a thunderbolt device, or None if the domain
+    /// doesn't report it.
+    fn device_security_level(&self, devpath: &Path) -> Result<Option<SecurityLevel>> {
+        let Some(id) = Self::parse_thunderbolt_devpath(devpath) else {
+            return Ok(None);
+        };
+        let domain = format!("domain{}", id.domain);
+        if !self.tbt_devices_path.join(&domain).join("security").exists() {
+            return Ok(None);
+        }
+        self.read_security_level(&domain).map(Some)
+    }
+
+    /// Returns whether any thunderbolt domain waits for our authorization to create PCI tunnels.
+    /// Domains not reporting their security level are assumed to.
+    pub fn is_pci_authorization_required(&self) -> Result<bool> {
+        let mut has_domain = false;
+        for entry in fs::read_dir(&self.tbt_devices_path)? {
+            let Some(domain) = entry?.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if !domain.starts_with("domain") {
+                continue;
+            }
+            has_domain = true;
+            if !self.tbt_devices_path.join(&domain).join("security").exists()
+                || self.read_security_level(&domain)?.requires_pci_authorization()
+            {
+                return Ok(true);
+            }
+        }
+        Ok(!has_domain)
+    }
+
+    /// Returns whether the platform can create PCI tunnels: the thunderbolt bus must exist, which
+    /// requires a kernel with `CONFIG_USB4` and the thunderbolt module loaded, and at least one
+    /// domain must allow PCI tunnels at its security level. True while no domain is registered
+    /// yet, or if a domain doesn't report its security level.
+    pub fn tunneling_supported(&self) -> Result<bool> {
+        if !self.sys_path.join("bus/thunderbolt").is_dir() {
+            return Ok(false);
+        }
+        let domains = self.list_domains()?;
+        for domain in &domains {
+            if !self.tbt_devices_path.join(domain).join("security").exists()
+                || self.read_security_level(domain)?.allows_pci_tunnels()
+            {
+                return Ok(true);
+            }
+        }
+        Ok(domains.is_empty())
+    }
+
+    /// Reads the "iommu_dma_protection" attribute of a thunderbolt domain, e.g. "domain0".
+    /// Returns None if the domain doesn't report it, e.g. on kernels older than the attribute,
+    /// in which case the protection is unknown.
+    pub fn iommu_dma_protection_enabled(&self, domain: &str) -> Result<Option<bool>> {
+        let protection_path = self.tbt_devices_path.join(domain).join("iommu_dma_protection");
+        match Self::read_optional_attribute(&protection_path)?.as_deref() {
+            None => Ok(None),
+            Some("0") => Ok(Some(false)),
+            Some("1") => Ok(Some(true)),
+            Some(content) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid DMA protection {:?} in {:?}", content, protection_path),
+            )
+            .into()),
+        }
+    }
+
+    /// Returns whether all the thunderbolt domains have IOMMU DMA protection, so that the devices
+    /// behind PCI tunnels can't access arbitrary memory. True if there is no domain, false if a
+    /// domain is unprotected, and None if a domain doesn't report its protection.
+    pub fn is_dma_protection_enabled(&self) -> Result<Option<bool>> {
+        let mut protection = Some(true);
+        for entry in fs::read_dir(&self.tbt_devices_path)? {
+            let Some(domain) = entry?.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if !domain.starts_with("domain") {
+                continue;
+            }
+            match self.iommu_dma_protection_enabled(&domain)? {
+                Some(true) => {}
+                Some(false) => return Ok(Some(false)),
+                None => protection = None,
+            }
+        }
+        Ok(protection)
+    }
+
+    /// Returns whether the unique id of the device at `devpath` is on the boot ACL of its domain.
+    /// The ACL of a domain only preauthorizes devices connected to that domain.
+    pub fn is_on_boot_acl(&self, devpath: &Path) -> Result<bool> {
+        let Some(TbtDeviceId { domain, route: Some(_) }) = Self::parse_thunderbolt_devpath(devpath)
+        else {
+            return Ok(false);
+        };
+        let Some(unique_id) = self.read_unique_id(devpath)? else {
+            return Ok(false);
+        };
+        let acl = self.read_boot_acl(&format!("domain{}", domain))?;
+        Ok(acl.iter().any(|acl_id| acl_id.eq_ignore_ascii_case(&unique_id)))
+    }
+
+    /// Returns whether the authorization policy allows authorizing the device, see
+    /// `with_min_authorized_generation`.
+    pub fn is_generation_allowed(&self, devpath: &Path) -> Result<bool> {
+        let Some(min_generation) = self.min_authorized_generation else {
+            return Ok(true);
+        };
+        Ok(self.read_device_generation(devpath)?.is_some_and(|gen| gen >= min_generation))
+    }
+
+    /// Resolves a uevent `DEVPATH` to the device directory under sysfs.
+    ///
+    /// Uevent devpaths are relative to the sysfs mount point and normally start with `/`, but a
+    /// devpath without the leading slash is accepted as-is.
+    pub fn devpath_to_syspath(&self, devpath: &Path) -> PathBuf {
+        let relative_path = devpath.strip_prefix("/").unwrap_or(devpath);
+        self.sys_path.join(relative_path)
+    }
+
     /// Sets the "authorized" attribute for a given device path.
     /// Returns `Ok(())` on success, `Err` on failure.
     fn set_authorized_attribute(&self, devpath: &Path, enable: bool) -> Result<()> {
@@ -97,90 +743,612 @@ impl SysfsUtils {
         };
 
         // If the current state is already the desired state, do nothing and return success.
-        // If the file was empty (no chars), proceed to write the desired state.
+        // If the file was empty (no chars), proceed to write the desired state. "2" means
+        // authorized with a challenge key.
         if let Some(state_char) = current_state_char {
-            if (enable && state_char == '1') || (!enable && state_char == '0') {
+            if (enable && matches!(state_char, '1' | '2')) || (!enable && state_char == '0') {
                 return Ok(());
             }
         }
 
-        let val = if enable {
+        let level = if enable && self.has_challenge_key(devpath)? {
+            info!("Authorizing with the challenge key: {:?}", devpath);
+            AuthLevel::AuthorizedWithKey
+        } else if enable {
             info!("Authorizing: {:?}", devpath);
-            "1"
+            AuthLevel::Authorized
         } else {
             info!("Deauthorizing: {:?}", devpath);
-            "0"
+            AuthLevel::Deauthorized
         };
+        let val = (self.auth_payload_formatter)(level);
 
         // Write the new state to the 'authorized' file.
-        fs::write(&authorized_path, val).map_err(|e| {
+        fs::write(&authorized_path, &val).map_err(|e| {
             io::Error::new(
                 e.kind(),
-                format!("Couldn't write {} to {:?}: {}", val, authorized_path, e),
+                format!(
+                    "Couldn't write {:?} to {:?}: {}",
+                    String::from_utf8_lossy(&val),
+                    authorized_path,
+                    e
+                ),
             )
         })?;
 
         Ok(())
     }
 
+    /// Returns whether the device is in a domain with the "secure" level and already has a key,
+    /// in which case it is authorized through the challenge-response of the key.
+    fn has_challenge_key(&self, devpath: &Path) -> Result<bool> {
+        if self.device_security_level(devpath)? != Some(SecurityLevel::Secure) {
+            return Ok(false);
+        }
+        Ok(Self::read_optional_attribute(&devpath.join("key"))?.is_some_and(|key| !key.is_empty()))
+    }
+
     /// Deauthorizes a Thunderbolt device.
     pub fn deauthorize_thunderbolt_dev(&self, devpath: &Path) -> Result<()> {
         self.set_authorized_attribute(devpath, false)
     }
 
     /// Authorizes a Thunderbolt device.
+    /// Devices filtered out by `with_min_authorized_generation` are left untouched.
     pub fn authorize_thunderbolt_dev(&self, devpath: &Path) -> Result<()> {
+        if !self.is_generation_allowed(devpath)? {
+            info!("Not authorizing {:?}: generation below the allowed minimum", devpath);
+            return Ok(());
+        }
         self.set_authorized_attribute(devpath, true)
     }
 
+    /// Returns the path on the thunderbolt bus of the device with the unique id `unique_id`.
+    fn find_device_by_unique_id(&self, unique_id: &str) -> Result<Option<PathBuf>> {
+        for entry in fs::read_dir(&self.tbt_devices_path)? {
+            let devpath = entry?.path();
+            let Some(name) = devpath.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if !Self::is_thunderbolt_device_name(name) || !devpath.is_dir() {
+                continue;
+            }
+            if self.read_unique_id(&devpath)?.as_deref() == Some(unique_id) {
+                return Ok(Some(devpath));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Authorizes the connected thunderbolt device with the unique id `unique_id`, wherever it
+    /// is in the topology. Returns whether such a device is connected.
+    pub fn authorize_by_unique_id(&self, unique_id: &str) -> Result<bool> {
+        let Some(devpath) = self.find_device_by_unique_id(unique_id)? else {
+            return Ok(false);
+        };
+        self.authorize_thunderbolt_dev(&devpath)?;
+        Ok(true)
+    }
+
+    /// Deauthorizes the connected thunderbolt device with the unique id `unique_id`. Returns
+    /// whether such a device is connected.
+    pub fn deauthorize_by_unique_id(&self, unique_id: &str) -> Result<bool> {
+        let Some(devpath) = self.find_device_by_unique_id(unique_id)? else {
+            return Ok(false);
+        };
+        self.deauthorize_thunderbolt_dev(&devpath)?;
+        Ok(true)
+    }
+
+    /// Returns whether the thunderbolt device at `devpath` is authorized, at any level. Fails with
+    /// `io::ErrorKind::NotFound` if the device is gone, and with `io::ErrorKind::InvalidInput` if
+    /// it isn't a thunderbolt device with an "authorized" attribute, e.g. a domain.
+    pub fn is_authorized(&self, devpath: &Path) -> Result<bool> {
+        let not_found =
+            || io::Error::new(io::ErrorKind::NotFound, format!("Device {:?} not found", devpath));
+        let not_thunderbolt = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Not a thunderbolt device: {:?}", devpath),
+            )
+        };
+        if !devpath.exists() {
+            return Err(not_found().into());
+        }
+        let is_thunderbolt = fs::read_link(devpath.join("subsystem"))
+            .is_ok_and(|target| target.to_string_lossy().ends_with("/bus/thunderbolt"));
+        if !is_thunderbolt {
+            return Err(not_thunderbolt().into());
+        }
+        let authorized_path = devpath.join("authorized");
+        match Self::read_optional_attribute(&authorized_path)?.as_deref() {
+            Some("0") => Ok(false),
+            Some("1" | "2") => Ok(true),
+            Some(content) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid authorization {:?} in {:?}", content, authorized_path),
+            )
+            .into()),
+            // The device may have been unplugged since it was checked.
+            None if !devpath.exists() => Err(not_found().into()),
+            None => Err(not_thunderbolt().into()),
+        }
+    }
+
+    /// Returns whether the connected thunderbolt device with the unique id `unique_id` is
+    /// authorized, or None if no such device is connected.
+    pub fn is_authorized_by_unique_id(&self, unique_id: &str) -> Result<Option<bool>> {
+        let Some(devpath) = self.find_device_by_unique_id(unique_id)? else {
+            return Ok(None);
+        };
+        self.is_authorized(&devpath).map(Some)
+    }
+
+    /// Sets the "authorized" attribute of a PCI device.
+    /// Platforms which don't expose the attribute don't gate PCI devices this way, so a missing
+    /// attribute is not an error.
+    pub fn set_pci_authorized(&self, devpath: &Path, enable: bool) -> Result<()> {
+        let authorized_path = devpath.join("authorized");
+        let Some(current) = Self::read_optional_attribute(&authorized_path)? else {
+            info!("'authorized' file not found at {:?}, skipping authorization.", authorized_path);
+            return Ok(());
+        };
+        let val = if enable { "1" } else { "0" };
+        if current == val {
+            return Ok(());
+        }
+        info!("{} PCI device: {:?}", if enable { "Authorizing" } else { "Deauthorizing" }, devpath);
+        fs::write(&authorized_path, val).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Couldn't write {} to {:?}: {}", val, authorized_path, e),
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Returns whether `name` is the address of a PCI device, i.e. domain:bus:device.function in
+    /// hexadecimal, e.g. "0000:05:00.0". The domain has at least 4 digits, as the domains of the
+    /// devices behind a VMD controller have 5, e.g. "10000:e1:00.0", and the function is 0 to 7.
+    pub fn is_pci_address(name: &str) -> bool {
+        let is_hex = |field: &str| field.chars().all(|c| c.is_ascii_hexdigit());
+        let Some((domain_bus_device, function)) = name.rsplit_once('.') else {
+            return false;
+        };
+        let fields: Vec<&str> = domain_bus_device.split(':').collect();
+        matches!(fields[..], [domain, bus, device]
+            if domain.len() >= 4 && is_hex(domain)
+                && bus.len() == 2 && is_hex(bus)
+                && device.len() == 2 && is_hex(device))
+            && matches!(function.as_bytes(), [b'0'..=b'7'])
+    }
+
+    /// Removes the PCI device at the address `bdf`, e.g. "0000:05:00.0", and the devices behind
+    /// it, leaving the other devices in place. Only the devices connected through a thunderbolt
+    /// tunnel can be removed, see `is_externally_connected`.
+    pub fn remove_pci_device(&self, bdf: &str) -> Result<()> {
+        // The address is validated so that it can't name anything outside of the PCI bus.
+        if !Self::is_pci_address(bdf) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a PCI device address", bdf),
+            )
+            .into());
+        }
+        let devpath = self.pci_devices_path.join(bdf);
+        if !devpath.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("PCI device {} not found", bdf),
+            )
+            .into());
+        }
+        if Self::read_optional_attribute(&devpath.join("removable"))?.as_deref() != Some("1") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("PCI device {} is not removable", bdf),
+            )
+            .into());
+        }
+        // Internal devices in hotplug slots are removable too, but must stay.
+        if !self.is_externally_connected(&devpath)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("PCI device {} is not external", bdf),
+            )
+            .into());
+        }
+        info!("Removing PCI device {}", bdf);
+        let remove_path = devpath.join("remove");
+        fs::write(&remove_path, "1").map_err(|e| {
+            io::Error::new(e.kind(), format!("Couldn't write 1 to {:?}: {}", remove_path, e))
+        })?;
+        Ok(())
+    }
+
+    /// Returns whether the PCI device at `devpath` is connected through a thunderbolt tunnel,
+    /// rather than integrated or plugged in an internal hotplug slot.
+    ///
+    /// A tunnel ends in the PCIe switch of the thunderbolt device, whose ports are removable
+    /// bridges: the device is external if it is such a bridge, or if a removable bridge is on its
+    /// path to the root complex. Otherwise a removable device, e.g. an endpoint right below a
+    /// root port, is external if its root port may be a tunnel port, see
+    /// `may_be_tunnel_root_port`. Devices whose place in the PCI topology is unknown are external
+    /// if they are removable.
+    pub fn is_externally_connected(&self, devpath: &Path) -> Result<bool> {
+        let devpath = fs::canonicalize(devpath)?;
+        let is_removable = |path: &Path| -> Result<bool> {
+            Ok(Self::read_optional_attribute(&path.join("removable"))?.as_deref() == Some("1"))
+        };
+        let is_bridge = |path: &Path| -> Result<bool> {
+            Ok(Self::read_optional_attribute(&path.join("class"))?
+                .is_some_and(|class| class.starts_with(PCI_BRIDGE_CLASS_PREFIX)))
+        };
+        let removable = is_removable(&devpath)?;
+        if removable && is_bridge(&devpath)? {
+            return Ok(true);
+        }
+        let mut root_port = None;
+        let mut child = devpath.as_path();
+        for ancestor in devpath.ancestors().skip(1) {
+            let Some(name) = ancestor.file_name().and_then(|name| name.to_str()) else {
+                break;
+            };
+            if name.starts_with("pci") {
+                // The root complex, e.g. "pci0000:00", right above the root port.
+                root_port = Some(child);
+                break;
+            }
+            if !Self::is_pci_address(name) {
+                break;
+            }
+            if is_removable(ancestor)? {
+                return Ok(true);
+            }
+            child = ancestor;
+        }
+        match root_port {
+            // The device is the root port itself.
+            Some(root_port) if root_port == devpath => Ok(false),
+            Some(root_port) => Ok(removable && self.may_be_tunnel_root_port(root_port)?),
+            None => Ok(removable),
+        }
+    }
+
+    /// Returns whether the root port at the canonical path `root_port` may lead to thunderbolt
+    /// tunnels: the firmware marks it as external facing, or a thunderbolt controller or a
+    /// removable bridge is below it. The integrated USB4 controllers sit on the root bus next to
+    /// the other root ports, so being on the bus of a controller doesn't make a root port a tunnel
+    /// port. All the root ports may be tunnel ports if a domain isn't in the PCI topology.
+    fn may_be_tunnel_root_port(&self, root_port: &Path) -> Result<bool> {
+        if Self::read_optional_attribute(&root_port.join("external_facing"))?.as_deref()
+            == Some("1")
+        {
+            return Ok(true);
+        }
+        for domain in self.list_domains()? {
+            let domain_path = fs::canonicalize(self.tbt_devices_path.join(&domain))?;
+            if domain_path.starts_with(root_port) {
+                return Ok(true);
+            }
+            // The domain is right below the PCI device of its controller.
+            let controller = domain_path.parent();
+            let in_topology = controller
+                .and_then(|controller| controller.file_name())
+                .and_then(|name| name.to_str())
+                .is_some_and(Self::is_pci_address);
+            if !in_topology {
+                return Ok(true);
+            }
+        }
+        for bridge in self.tunneled_pci_bridges()? {
+            if fs::canonicalize(&bridge)?.starts_with(root_port) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Lists the removable PCI bridges, i.e. the bridges tunneled through thunderbolt, sorted by
+    /// path.
+    fn tunneled_pci_bridges(&self) -> Result<Vec<PathBuf>> {
+        let mut bridges = Vec::new();
+        for entry in fs::read_dir(&self.pci_devices_path)? {
+            let devpath = entry?.path();
+            let removable = Self::read_optional_attribute(&devpath.join("removable"))?;
+            let class = Self::read_optional_attribute(&devpath.join("class"))?;
+            if removable.as_deref() == Some("1")
+                && class.is_some_and(|class| class.starts_with(PCI_BRIDGE_CLASS_PREFIX))
+            {
+                bridges.push(devpath);
+            }
+        }
+        bridges.sort();
+        Ok(bridges)
+    }
+
+    /// Lists the PCI devices a denial in `PciDenialMode::Deauthorize` deauthorizes, i.e. the
+    /// removable PCI devices with an "authorized" attribute which may be external, sorted by path.
+    fn deauthorizable_pci_devices(&self) -> Result<Vec<PathBuf>> {
+        let mut devices = Vec::new();
+        for entry in fs::read_dir(&self.pci_devices_path)? {
+            let devpath = entry?.path();
+            let removable = Self::read_optional_attribute(&devpath.join("removable"))?;
+            if removable.as_deref() != Some("1") || !devpath.join("authorized").exists() {
+                continue;
+            }
+            // As in the denial, the device counts as external if its topology can't be read.
+            if !self.is_externally_connected(&devpath).unwrap_or(true) {
+                continue;
+            }
+            devices.push(devpath);
+        }
+        devices.sort();
+        Ok(devices)
+    }
+
+    /// Returns the index of the domain of a thunderbolt device from its name, e.g. "0" for
+    /// "domain0", "0-1" and "0-0:1.1".
+    fn domain_index(name: &str) -> String {
+        let name = name.strip_prefix("domain").unwrap_or(name);
+        name.split(['-', ':']).next().unwrap_or(name).to_string()
+    }
+
+    /// Returns whether a thunderbolt device waits for authorization, i.e. its "authorized"
+    /// attribute isn't set at any level. Devices without the attribute, e.g. domains, never wait.
+    /// True if the attribute can't be read, so that the error is reported by the authorization.
+    fn is_tbt_device_unauthorized(devpath: &Path) -> bool {
+        match Self::read_optional_attribute(&devpath.join("authorized")) {
+            Ok(Some(authorized)) => !matches!(authorized.as_str(), "1" | "2"),
+            Ok(None) => false,
+            Err(_) => true,
+        }
+    }
+
+    /// Authorizes the thunderbolt devices of a domain, parents before children, until `cancel` is
+    /// set. Devices already authorized along with all their ancestors are skipped without being
+    /// inspected further, so that a sweep after a device is plugged in only handles the subtree
+    /// of the new device. Returns the devices which failed to be authorized, the number of
+    /// skipped devices and whether the sweep was cancelled.
+    fn authorize_domain_devices(
+        &self,
+        mut devs: Vec<(PathBuf, PathBuf)>,
+        cancel: &AtomicBool,
+    ) -> (Vec<PathBuf>, usize, bool) {
+        // Sort thunderbolt devices based on their symbolic link targets to achieve BFS order.
+        // Authorization should be parent before children.
+        devs.sort_by(|(_, symlink1), (_, symlink2)| symlink1.cmp(symlink2));
+
+        // Symbolic link targets of the devices which failed to be authorized.
+        let mut failed_subtrees: Vec<PathBuf> = Vec::new();
+        // Symbolic link targets of the devices which waited for authorization at the start of the
+        // sweep.
+        let mut unauthorized_subtrees: Vec<PathBuf> = Vec::new();
+        let mut failed_devs: Vec<PathBuf> = Vec::new();
+        let mut skipped_count = 0;
+        let mut already_authorized_count = 0;
+        // Authorize each thunderbolt device.
+        for (dev, symlink) in devs {
+            if cancel.load(Ordering::Relaxed) {
+                return (failed_devs, skipped_count, true);
+            }
+            if self.skip_failed_subtrees
+                && failed_subtrees.iter().any(|failed| symlink.starts_with(failed))
+            {
+                skipped_count += 1;
+                continue;
+            }
+            let has_unauthorized_ancestor =
+                unauthorized_subtrees.iter().any(|unauthorized| symlink.starts_with(unauthorized));
+            if !has_unauthorized_ancestor && !Self::is_tbt_device_unauthorized(&dev) {
+                already_authorized_count += 1;
+                continue;
+            }
+            if !symlink.as_os_str().is_empty() {
+                unauthorized_subtrees.push(symlink.clone());
+            }
+            if let Some(before_authorize) = &self.before_authorize {
+                before_authorize(&dev);
+            }
+            if let Err(e) = self.authorize_thunderbolt_dev(&dev) {
+                // The device was unplugged since the devices were listed.
+                if !dev.exists() {
+                    info!("Thunderbolt device {:?} was removed, skipping it", dev);
+                    continue;
+                }
+                error!("Failed to authorize thunderbolt device {:?}: {}", dev, e);
+                if !symlink.as_os_str().is_empty() {
+                    failed_subtrees.push(symlink);
+                }
+                failed_devs.push(dev);
+            }
+        }
+        if already_authorized_count > 0 {
+            info!("Skipped {} already authorized thunderbolt devices", already_authorized_count);
+        }
+        (failed_devs, skipped_count, false)
+    }
+
     /// Authorizes all external PCI devices.
     /// Returns `Ok(())` on success, `Err` on failure.
     pub fn authorize_all_devices(&self) -> Result<()> {
+        self.authorize_all_devices_cancelable(&AtomicBool::new(false)).map(|_| ())
+    }
+
+    /// Authorizes all external PCI devices, stopping early once `cancel` is set.
+    /// Returns `Ok(SweepOutcome::Cancelled)` if the sweep stopped before processing all the
+    /// devices, in which case the failures met so far are only logged.
+    pub fn authorize_all_devices_cancelable(&self, cancel: &AtomicBool) -> Result<SweepOutcome> {
         info!("Authorizing all external PCI devices");
 
-        // Collect all thunderbolt device paths.
-        let mut thunderbolt_devs: Vec<PathBuf> = Vec::new();
+        // Collect all thunderbolt device paths along with their symbolic link targets, grouped
+        // by domain.
+        let mut domains: BTreeMap<String, Vec<(PathBuf, PathBuf)>> = BTreeMap::new();
         for entry in fs::read_dir(&self.tbt_devices_path)? {
             let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if ThunderboltEntryType::from_name(&name) == ThunderboltEntryType::Retimer {
+                continue;
+            }
             let devpath = entry.path();
             if devpath.is_dir() {
-                thunderbolt_devs.push(devpath);
+                let symlink = fs::read_link(&devpath).unwrap_or_else(|_| PathBuf::new());
+                let domain = Self::domain_index(&name);
+                domains.entry(domain).or_default().push((devpath, symlink));
             }
         }
 
-        // Sort thunderbolt devices based on their symbolic link targets to achieve BFS order.
-        // Authorization should be parent before children.
-        thunderbolt_devs.sort_by(|dev1, dev2| {
-            let symlink1 = fs::read_link(dev1).unwrap_or_else(|_| PathBuf::new());
-            let symlink2 = fs::read_link(dev2).unwrap_or_else(|_| PathBuf::new());
-            symlink1.cmp(&symlink2)
-        });
+        // Domains are independent, so a slow domain must not delay the others.
+        let domains = Mutex::new(domains.into_values().collect::<Vec<_>>());
+        let results = Mutex::new(Vec::new());
+        let worker_count = domains.lock().unwrap().len().min(MAX_PARALLEL_DOMAINS);
+        let authorize_domains = || {
+            while let Some(devs) = domains.lock().unwrap().pop() {
+                let result = self.authorize_domain_devices(devs, cancel);
+                results.lock().unwrap().push(result);
+            }
+        };
+        if worker_count > 1 {
+            thread::scope(|scope| {
+                for _ in 0..worker_count {
+                    scope.spawn(authorize_domains);
+                }
+            });
+        } else {
+            authorize_domains();
+        }
 
-        let mut overall_success = true;
-        // Authorize each thunderbolt device.
-        for dev in thunderbolt_devs {
-            if let Err(e) = self.authorize_thunderbolt_dev(&dev) {
-                error!("Failed to authorize thunderbolt device {:?}: {}", dev, e);
-                overall_success = false;
+        let mut failed_devs: Vec<PathBuf> = Vec::new();
+        let mut skipped_count = 0;
+        let mut cancelled = false;
+        for (domain_failed_devs, domain_skipped_count, domain_cancelled) in
+            results.into_inner().unwrap()
+        {
+            failed_devs.extend(domain_failed_devs);
+            skipped_count += domain_skipped_count;
+            cancelled |= domain_cancelled;
+        }
+        if cancelled {
+            info!("Authorization of all devices cancelled");
+            return Ok(SweepOutcome::Cancelled);
+        }
+        failed_devs.sort();
+
+        // Authorize the PCI bridges tunneled through the thunderbolt devices, on platforms gating
+        // them with their own "authorized" attribute. A denial in `PciDenialMode::Deauthorize`
+        // deauthorized the devices behind them too, so those are authorized after their bridges.
+        let mut pci_devs = self.tunneled_pci_bridges()?;
+        if self.pci_denial_mode == PciDenialMode::Deauthorize {
+            for devpath in self.deauthorizable_pci_devices()? {
+                if !pci_devs.contains(&devpath) {
+                    pci_devs.push(devpath);
+                }
+            }
+        }
+        let mut failed_pci_devs: Vec<PathBuf> = Vec::new();
+        for devpath in pci_devs {
+            if cancel.load(Ordering::Relaxed) {
+                info!("Authorization of all devices cancelled");
+                return Ok(SweepOutcome::Cancelled);
             }
+            if let Err(e) = self.set_pci_authorized(&devpath, true) {
+                error!("Failed to authorize PCI device {:?}: {}", devpath, e);
+                failed_pci_devs.push(devpath);
+            }
+        }
+
+        let mut errors: Vec<String> = Vec::new();
+        if !failed_devs.is_empty() && skipped_count > 0 {
+            errors.push(format!(
+                "Failed to authorize thunderbolt devices {:?}, skipped {} descendants",
+                failed_devs, skipped_count
+            ));
+        } else if !failed_devs.is_empty() {
+            errors.push(format!("Failed to authorize thunderbolt devices {:?}", failed_devs));
+        }
+        if !failed_pci_devs.is_empty() {
+            errors.push(format!("Failed to authorize PCI devices {:?}", failed_pci_devs));
         }
 
-        if overall_success {
-            Ok(())
+        if errors.is_empty() {
+            Ok(SweepOutcome::Completed)
         } else {
-            Err(io::Error::other("Failed to authorize all thunderbolt devices").into())
+            Err(io::Error::other(errors.join("; ")).into())
         }
     }
 
     /// Deauthorizes all external PCI devices.
     /// Returns `Ok(())` on success, `Err` on failure.
     pub fn deauthorize_all_devices(&self) -> Result<()> {
+        self.deauthorize_all_devices_cancelable(
+            &HashSet::from(Subsystem::ALL),
+            &AtomicBool::new(false),
+        )
+        .map(|_| ())
+    }
+
+    /// Deauthorizes the external PCI devices, stopping early once `cancel` is set. Only the
+    /// devices of `subsystems` are touched: without `Subsystem::Pci`, the PCI devices are left in
+    /// place, and without `Subsystem::Thunderbolt`, the thunderbolt devices stay authorized.
+    /// Returns `Ok(SweepOutcome::Cancelled)` if the sweep stopped before processing all the
+    /// devices, in which case the failures met so far are only logged.
+    pub fn deauthorize_all_devices_cancelable(
+        &self,
+        subsystems: &HashSet<Subsystem>,
+        cancel: &AtomicBool,
+    ) -> Result<SweepOutcome> {
+        let report = self.deauthorize_all_devices_report(subsystems, cancel)?;
+        if report.outcome == SweepOutcome::Cancelled {
+            return Ok(SweepOutcome::Cancelled);
+        }
+
+        let mut errors: Vec<String> = Vec::new();
+        if !report.pci_removal.is_success() {
+            errors.push(format!("Failed to deny PCI devices {:?}", report.pci_removal.failed));
+        }
+        if !report.thunderbolt_deauthorization.is_success() {
+            errors.push(format!(
+                "Failed to deauthorize thunderbolt devices {:?}",
+                report.thunderbolt_deauthorization.failed
+            ));
+        }
+        if errors.is_empty() {
+            Ok(SweepOutcome::Completed)
+        } else {
+            Err(io::Error::other(errors.join("; ")).into())
+        }
+    }
+
+    /// Deauthorizes all external PCI devices like `deauthorize_all_devices_cancelable`, reporting
+    /// the PCI removal and the thunderbolt deauthorization separately. Returns `Err` only if the
+    /// devices can't be listed.
+    pub fn deauthorize_all_devices_report(
+        &self,
+        subsystems: &HashSet<Subsystem>,
+        cancel: &AtomicBool,
+    ) -> Result<DeauthorizationReport> {
         info!("Deauthorizing all external PCI devices");
 
-        let mut overall_success = true;
+        let mut report = DeauthorizationReport {
+            pci_removal: SweepPhaseReport::default(),
+            thunderbolt_deauthorization: SweepPhaseReport::default(),
+            outcome: SweepOutcome::Completed,
+        };
 
-        // Iterate through all PCI devices.
-        for entry in fs::read_dir(&self.pci_devices_path)? {
+        // Iterate through all PCI devices, unless their subsystem is disabled.
+        let pci_entries = if subsystems.contains(&Subsystem::Pci) {
+            fs::read_dir(&self.pci_devices_path)?.collect()
+        } else {
+            info!("Leaving the PCI devices: the PCI subsystem is disabled");
+            Vec::new()
+        };
+        for entry in pci_entries {
+            if cancel.load(Ordering::Relaxed) {
+                info!("Deauthorization of all devices cancelled");
+                report.outcome = SweepOutcome::Cancelled;
+                return Ok(report);
+            }
             let entry = entry?;
             let devpath = entry.path();
             if !devpath.is_dir() {
@@ -201,32 +1369,69 @@ impl SysfsUtils {
                 continue;
             }
 
-            // Write "1" to the "remove" file to remove the device.
-            let remove_path = devpath.join("remove");
-            if let Err(e) = fs::write(&remove_path, "1") {
-                error!("Couldn't remove untrusted device {:?}: {}", devpath, e);
-                overall_success = false;
+            // Internal devices in hotplug slots may be marked as removable too. The device is
+            // still denied if its topology can't be read, as it may be external.
+            match self.is_externally_connected(&devpath) {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("Leaving internal removable PCI device {:?}", devpath);
+                    continue;
+                }
+                Err(e) => {
+                    error!("Failed to check whether {:?} is external: {}", devpath, e);
+                }
+            }
+
+            let result = if self.pci_denial_mode == PciDenialMode::Deauthorize
+                && devpath.join("authorized").exists()
+            {
+                self.set_pci_authorized(&devpath, false)
+            } else {
+                // Write "1" to the "remove" file to remove the device.
+                fs::write(devpath.join("remove"), "1").map_err(Into::into)
+            };
+            match result {
+                Ok(()) => report.pci_removal.succeeded += 1,
+                Err(e) => {
+                    error!("Couldn't deny untrusted device {:?}: {}", devpath, e);
+                    report.pci_removal.failed.push(devpath);
+                }
             }
         }
 
-        // Deauthorize all thunderbolt devices.
-        for entry in fs::read_dir(&self.tbt_devices_path)? {
+        // Deauthorize all thunderbolt devices, unless their subsystem is disabled.
+        let tbt_entries = if subsystems.contains(&Subsystem::Thunderbolt) {
+            fs::read_dir(&self.tbt_devices_path)?.collect()
+        } else {
+            Vec::new()
+        };
+        for entry in tbt_entries {
+            if cancel.load(Ordering::Relaxed) {
+                info!("Deauthorization of all devices cancelled");
+                report.outcome = SweepOutcome::Cancelled;
+                return Ok(report);
+            }
             let entry = entry?;
+            let name = entry.file_name();
+            if ThunderboltEntryType::from_name(&name.to_string_lossy())
+                == ThunderboltEntryType::Retimer
+            {
+                continue;
+            }
             let devpath = entry.path();
             if !devpath.is_dir() {
                 continue;
             }
-            if let Err(e) = self.deauthorize_thunderbolt_dev(&devpath) {
-                error!("Failed to deauthorize thunderbolt device {:?}: {}", devpath, e);
-                overall_success = false;
+            match self.deauthorize_thunderbolt_dev(&devpath) {
+                Ok(()) => report.thunderbolt_deauthorization.succeeded += 1,
+                Err(e) => {
+                    error!("Failed to deauthorize thunderbolt device {:?}: {}", devpath, e);
+                    report.thunderbolt_deauthorization.failed.push(devpath);
+                }
             }
         }
 
-        if overall_success {
-            Ok(())
-        } else {
-            Err(io::Error::other("Failed during deauthorization of all devices").into())
-        }
+        Ok(report)
     }
 }
 
@@ -0,0 +1,50 @@
+// Copyright (C) 2025 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Trace
+//!
+//! Counters of the policy engine shown in systrace, e.g. to spot the backpressure on the event
+//! channel during hotplug storms.
+
+/// Number of events waiting in the event channel of the policy task.
+pub(crate) const EVENT_QUEUE_DEPTH: &str = "usb4_policy_event_queue_depth";
+
+/// Number of uevents received by the policy task since it started.
+pub(crate) const UEVENTS_RECEIVED: &str = "usb4_policy_uevents_received";
+
+/// Sets the systrace counter `name` to the value computed by `value`, only while tracing is
+/// enabled.
+#[cfg(target_os = "android")]
+pub(crate) fn counter(name: &str, value: impl FnOnce() -> i64) {
+    // The policy engine runs in system_server.
+    let tag = atrace::AtraceTag::SystemServer;
+    if atrace::atrace_is_tag_enabled(tag) {
+        atrace::atrace_int64(tag, name, value());
+    }
+}
+
+/// atrace is only available on Android, so the counters are compiled out elsewhere.
+#[cfg(not(target_os = "android"))]
+pub(crate) fn counter(_name: &str, _value: impl FnOnce() -> i64) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(target_os = "android"))]
+    #[test]
+    fn counter_value_is_not_computed_off_device() {
+        counter(EVENT_QUEUE_DEPTH, || panic!("The counter should be compiled out"));
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright (C) 2025 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(test)]
+mod config_tests {
+    use std::fs;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use usb4_policies::config::PolicyConfig;
+
+    #[test]
+    fn test_parse_config() {
+        let config = PolicyConfig::parse(
+            "# Tunnel PCI by default.\n\
+             pci_tunnels_enabled = true\n\
+             \n\
+             idle_timeout_secs = 600 # Ten minutes.\n\
+             trust_unknown_dma_protection = true\n\
+             unknown_key = \"ignored\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            PolicyConfig {
+                pci_tunnels_enabled: true,
+                idle_deauthorize_timeout: Some(Duration::from_secs(600)),
+                trust_unknown_dma_protection: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_config_fails() {
+        for content in [
+            "pci_tunnels_enabled",
+            "pci_tunnels_enabled = yes",
+            "idle_timeout_secs = -1",
+            "trust_unknown_dma_protection = 1",
+        ] {
+            assert!(PolicyConfig::parse(content).is_err(), "{:?} should be rejected", content);
+        }
+    }
+
+    #[test]
+    fn test_load_falls_back_to_defaults() {
+        let temp_dir = TempDir::new().expect("Failed to create temp_dir");
+        let path = temp_dir.path().join("policy.toml");
+
+        assert_eq!(PolicyConfig::load(&path), PolicyConfig::default());
+
+        fs::write(&path, "pci_tunnels_enabled = maybe\n").unwrap();
+        assert_eq!(PolicyConfig::load(&path), PolicyConfig::default());
+
+        fs::write(&path, "pci_tunnels_enabled = true\n").unwrap();
+        assert!(PolicyConfig::load(&path).pci_tunnels_enabled);
+    }
+}
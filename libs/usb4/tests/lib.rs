@@ -12,4 +12,6 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod config_test;
 pub mod pci_authorizer_test;
+pub mod sysfs_test;
@@ -14,17 +14,27 @@
 
 #[cfg(test)]
 mod pci_authorizer_tests {
+    use async_trait::async_trait;
+    use kobject_uevent::{ActionType, UEvent};
+    use std::collections::HashSet;
     use std::fs;
     use std::os::unix::fs::symlink;
     use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::Arc;
     use std::time::Instant;
     use tempfile::TempDir;
+    use tokio::sync::mpsc;
     use tokio::time::{sleep, Duration};
+    use uevent::mock::{build_uevent, MockUEventSocket};
     use uevent::netlink::AsyncUEventSocket;
-    use usb4_policies::common::{TunnelControl, UserId};
-    use usb4_policies::pci_authorizer::PciAuthorizer;
-    use usb4_policies::sysfs::SysfsUtils;
+    use usb4_policies::common::{DeviceId, PolicySourceData, Subsystem, TunnelControl, UserId};
+    use usb4_policies::config::PolicyConfig;
+    use usb4_policies::pci_authorizer::{
+        AuthPolicy, AuthorizationLatency, DefaultAuthPolicy, PciAuthState, PciAuthorizer,
+        PciAuthorizerBuilder, PolicyEvent, MAX_UEVENT_OBSERVER_RETRY,
+    };
+    use usb4_policies::sysfs::{PciDenialMode, SysfsUtils, USB4_GENERATION};
 
     // Time between file reads.
     const POLL_DURATION: Duration = Duration::from_millis(30);
@@ -55,9 +65,52 @@ mod pci_authorizer_tests {
         (temp_dir, sysfs_utils, uevent_socket_trait)
     }
 
+    /// Uevent socket panicking on the first read, which kills the task reading it, and idle after.
+    #[derive(Default)]
+    struct CrashingUEventSocket {
+        crashed: AtomicBool,
+    }
+
+    #[async_trait]
+    impl AsyncUEventSocket for CrashingUEventSocket {
+        async fn read(&self) -> anyhow::Result<UEvent> {
+            if !self.crashed.swap(true, Ordering::SeqCst) {
+                panic!("injected uevent socket crash");
+            }
+            std::future::pending().await
+        }
+    }
+
+    fn setup_environment_with_scripted_uevents() -> (
+        TempDir,
+        SysfsUtils,
+        Arc<dyn AsyncUEventSocket>,
+        mpsc::UnboundedSender<anyhow::Result<UEvent>>,
+    ) {
+        let temp_dir = TempDir::new().expect("Failed to create temp_dir");
+        let root = temp_dir.path();
+
+        fs::create_dir_all(root.join("sys/bus/pci/devices"))
+            .expect("Failed to create mock pci devices dir");
+        fs::create_dir_all(root.join("sys/bus/thunderbolt/devices"))
+            .expect("Failed to create mock tbt devices dir");
+
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        let (uevent_socket, uevent_sender) = MockUEventSocket::new();
+        let uevent_socket: Arc<dyn AsyncUEventSocket> = Arc::new(uevent_socket);
+
+        (temp_dir, sysfs_utils, uevent_socket, uevent_sender)
+    }
+
     fn create_mock_tbt_device(sysfs_root: &Path, name: &str, initial_authorized: &str) -> PathBuf {
         let dev_path = sysfs_root.join("sys/bus/thunderbolt/devices").join(name);
-        fs::create_dir_all(&dev_path).expect("Failed to create mock tbt device dir");
+        create_mock_tbt_device_at(sysfs_root, &dev_path, initial_authorized);
+        dev_path
+    }
+
+    fn create_mock_tbt_device_at(sysfs_root: &Path, dev_path: &Path, initial_authorized: &str) {
+        fs::create_dir_all(dev_path).expect("Failed to create mock tbt device dir");
 
         let authorized_file = dev_path.join("authorized");
         fs::write(authorized_file, initial_authorized)
@@ -70,8 +123,6 @@ mod pci_authorizer_tests {
         let subsystem_symlink_path = dev_path.join("subsystem");
         symlink(&subsystem_symlink_target_dir, &subsystem_symlink_path)
             .expect("Failed to create mock tbt subsystem symlink");
-
-        dev_path
     }
 
     fn create_mock_pci_device(sysfs_root: &Path, name: &str, removable: bool) -> PathBuf {
@@ -84,6 +135,25 @@ mod pci_authorizer_tests {
         dev_path
     }
 
+    /// Builds a PciAuthorizer with the clock of the runtime paused, so that the test drives the
+    /// timers of the task with `advance` instead of sleeping. Only for current-thread runtimes.
+    fn build_with_paused_clock(builder: PciAuthorizerBuilder) -> PciAuthorizer {
+        tokio::time::pause();
+        builder.build()
+    }
+
+    /// Lets the task handle everything pending. With the clock paused, the runtime only moves the
+    /// clock to the end of a sleep once no task has anything left to do.
+    async fn settle() {
+        sleep(Duration::from_millis(1)).await;
+    }
+
+    /// Advances the paused clock by `duration`, and lets the task handle the timers expired.
+    async fn advance(duration: Duration) {
+        tokio::time::advance(duration).await;
+        settle().await;
+    }
+
     async fn assert_wait_for_path_eq(path: PathBuf, expected_value: &str, assert_why: &str) {
         let start = Instant::now();
         let mut read_value: String = Default::default();
@@ -106,7 +176,7 @@ mod pci_authorizer_tests {
         let _ = env_logger::try_init();
         let (temp_dir, sysfs_utils, uevent_socket) = setup_environment_for_pci_authorizer_new();
         let root = temp_dir.path();
-        let mut pci_authorizer = PciAuthorizer::new(sysfs_utils.clone(), uevent_socket);
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils.clone(), uevent_socket);
 
         let tbt_dev_path = create_mock_tbt_device(root, "0-0", "0");
 
@@ -152,7 +222,7 @@ mod pci_authorizer_tests {
         let _ = env_logger::try_init();
         let (temp_dir, sysfs_utils, uevent_socket) = setup_environment_for_pci_authorizer_new();
         let root = temp_dir.path();
-        let mut pci_authorizer = PciAuthorizer::new(sysfs_utils.clone(), uevent_socket);
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils.clone(), uevent_socket);
 
         let tbt_dev_path = create_mock_tbt_device(root, "1-0", "0");
         let removable_pci_dev_path = create_mock_pci_device(root, "pci0", true);
@@ -237,4 +307,1669 @@ mod pci_authorizer_tests {
         // A panic in the task during shutdown would be propagated by the await in Drop.
         // Allow a bit of time for async runtime to fully process the drop and task completion.
     }
+
+    #[tokio::test]
+    async fn test_uevent_devpath_without_leading_slash() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils.clone(), uevent_socket);
+
+        let bus_dev_path = create_mock_tbt_device(root, "0-0", "0");
+        let hotplugged_dev_path = root.join("sys/devices/domain0/0-0/0-1");
+        create_mock_tbt_device_at(root, &hotplugged_dev_path, "0");
+
+        pci_authorizer.enable_pci_tunnels(true);
+        pci_authorizer.update_logged_in_state(true, UserId(1));
+        pci_authorizer.update_lock_state(false);
+        assert_wait_for_path_eq(
+            bus_dev_path.join("authorized"),
+            "1",
+            "TBT device should be authorized on Authorized state",
+        )
+        .await;
+
+        uevent_sender
+            .send(Ok(build_uevent(ActionType::Add, "thunderbolt", "devices/domain0/0-0/0-1")))
+            .unwrap();
+        assert_wait_for_path_eq(
+            hotplugged_dev_path.join("authorized"),
+            "1",
+            "Device with a devpath lacking the leading slash should be authorized",
+        )
+        .await;
+
+        // The task must still be processing policy updates.
+        pci_authorizer.enable_pci_tunnels(false);
+        assert_wait_for_path_eq(
+            bus_dev_path.join("authorized"),
+            "0",
+            "TBT device should be deauthorized when tunnels are disabled",
+        )
+        .await;
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test]
+    async fn test_set_logged_in_users_replaces_set_at_once() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils.clone(), uevent_socket);
+
+        let tbt_dev_path = create_mock_tbt_device(root, "0-0", "0");
+        let removable_pci_dev_path = create_mock_pci_device(root, "pci0", true);
+
+        pci_authorizer.enable_pci_tunnels(true);
+        pci_authorizer.set_logged_in_users(HashSet::from([UserId(1)]));
+        pci_authorizer.update_lock_state(false);
+        assert_wait_for_path_eq(
+            tbt_dev_path.join("authorized"),
+            "1",
+            "TBT device should be authorized on Authorized state",
+        )
+        .await;
+
+        fs::write(removable_pci_dev_path.join("remove"), "0").unwrap(); // Reset remove state
+
+        // Switching users in one call must not go through DenyNoUser.
+        pci_authorizer.set_logged_in_users(HashSet::from([UserId(2)]));
+
+        // Lock and unlock around a new device to know when the updates above have been handled.
+        pci_authorizer.update_lock_state(true);
+        let new_tbt_dev_path = create_mock_tbt_device(root, "0-1", "0");
+        pci_authorizer.update_lock_state(false);
+        assert_wait_for_path_eq(
+            new_tbt_dev_path.join("authorized"),
+            "1",
+            "New TBT device should be authorized on unlock",
+        )
+        .await;
+
+        assert_eq!(
+            fs::read_to_string(removable_pci_dev_path.join("remove")).unwrap().trim(),
+            "0",
+            "Removable PCI device should not be removed when switching users"
+        );
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test]
+    async fn test_restart_task_after_task_died() {
+        let _ = env_logger::try_init();
+        let temp_dir = TempDir::new().expect("Failed to create temp_dir");
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("sys/bus/pci/devices"))
+            .expect("Failed to create mock pci devices dir");
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+        let uevent_socket: Arc<dyn AsyncUEventSocket> = Arc::new(CrashingUEventSocket::default());
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils, uevent_socket);
+        let tbt_dev_path = create_mock_tbt_device(root, "0-0", "0");
+
+        let start = Instant::now();
+        while pci_authorizer.is_task_alive() && start.elapsed() < WAIT_FOR_PATH_DURATION {
+            sleep(POLL_DURATION).await;
+        }
+        assert!(!pci_authorizer.is_task_alive(), "The crashed task should be detected");
+
+        // Policy updates sent while the task is dead are applied by the restarted task.
+        pci_authorizer.enable_pci_tunnels(true);
+        pci_authorizer.update_logged_in_state(true, UserId(1));
+        pci_authorizer.update_lock_state(false);
+        assert!(pci_authorizer.restart_task());
+        assert!(pci_authorizer.is_task_alive());
+        assert!(!pci_authorizer.restart_task(), "A running task should not be restarted");
+        assert_wait_for_path_eq(
+            tbt_dev_path.join("authorized"),
+            "1",
+            "TBT device should be authorized by the restarted task",
+        )
+        .await;
+
+        pci_authorizer.enable_pci_tunnels(false);
+        assert_wait_for_path_eq(
+            tbt_dev_path.join("authorized"),
+            "0",
+            "The restarted task should process policy updates",
+        )
+        .await;
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test]
+    async fn test_handle_restarts_the_task_outside_the_runtime() {
+        let _ = env_logger::try_init();
+        let temp_dir = TempDir::new().expect("Failed to create temp_dir");
+        let sysfs_utils = SysfsUtils::with_root_path(temp_dir.path().to_path_buf());
+        let uevent_socket: Arc<dyn AsyncUEventSocket> = Arc::new(CrashingUEventSocket::default());
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils, uevent_socket);
+        let handle = pci_authorizer.handle();
+
+        let start = Instant::now();
+        while handle.is_task_alive() && start.elapsed() < WAIT_FOR_PATH_DURATION {
+            sleep(POLL_DURATION).await;
+        }
+        assert!(!handle.is_task_alive(), "The crashed task should be detected");
+
+        // The new task is spawned on the runtime of the dead one.
+        let restart_handle = handle.clone();
+        assert!(std::thread::spawn(move || restart_handle.restart_task()).join().unwrap());
+        assert!(pci_authorizer.is_task_alive());
+
+        // The handles outliving the PciAuthorizer don't restart its task.
+        drop(pci_authorizer);
+        assert!(!handle.is_task_alive());
+        assert!(!handle.restart_task());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_device_is_deauthorized_after_timeout() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let mut pci_authorizer = PciAuthorizer::new(sysfs_utils.clone(), uevent_socket);
+        let idle_timeout = Duration::from_secs(60);
+        pci_authorizer.set_idle_deauthorize_timeout(Some(idle_timeout));
+
+        let tbt_dev_path = create_mock_tbt_device(root, "0-0", "0");
+        pci_authorizer.enable_pci_tunnels(true);
+        pci_authorizer.update_logged_in_state(true, UserId(1));
+        pci_authorizer.update_lock_state(false);
+        assert_wait_for_path_eq(
+            tbt_dev_path.join("authorized"),
+            "1",
+            "TBT device should be authorized on Authorized state",
+        )
+        .await;
+
+        // Activity on the device restarts its idle timer.
+        tokio::time::advance(Duration::from_secs(30)).await;
+        uevent_sender
+            .send(Ok(build_uevent(
+                ActionType::Change,
+                "thunderbolt",
+                "/bus/thunderbolt/devices/0-0",
+            )))
+            .unwrap();
+        sleep(POLL_DURATION).await;
+        tokio::time::advance(Duration::from_secs(45)).await;
+        sleep(POLL_DURATION).await;
+        assert_eq!(
+            fs::read_to_string(tbt_dev_path.join("authorized")).unwrap().trim(),
+            "1",
+            "TBT device should stay authorized while it is active"
+        );
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert_wait_for_path_eq(
+            tbt_dev_path.join("authorized"),
+            "0",
+            "TBT device should be deauthorized once idle for the timeout",
+        )
+        .await;
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test]
+    async fn test_device_on_boot_acl_is_authorized_while_deferred() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils.clone(), uevent_socket);
+
+        let domain_path = root.join("sys/bus/thunderbolt/devices/domain0");
+        fs::create_dir_all(&domain_path).unwrap();
+        fs::write(domain_path.join("boot_acl"), ",acl-uuid,,\n").unwrap();
+        let unknown_dev_path = root.join("sys/devices/domain0/0-0/0-1");
+        create_mock_tbt_device_at(root, &unknown_dev_path, "0");
+        fs::write(unknown_dev_path.join("unique_id"), "other-uuid\n").unwrap();
+        let acl_dev_path = root.join("sys/devices/domain0/0-0/0-2");
+        create_mock_tbt_device_at(root, &acl_dev_path, "0");
+        fs::write(acl_dev_path.join("unique_id"), "acl-uuid\n").unwrap();
+        for name in ["0-1", "0-2"] {
+            symlink(
+                Path::new("../../../devices/domain0/0-0").join(name),
+                root.join("sys/bus/thunderbolt/devices").join(name),
+            )
+            .unwrap();
+        }
+
+        // Enter DeferNewDevices without going through Authorized. The uevents may be handled
+        // before or after the transition, the device on the boot ACL is authorized either way.
+        pci_authorizer.enable_pci_tunnels(true);
+        pci_authorizer.update_lock_state(true);
+        pci_authorizer.update_logged_in_state(true, UserId(1));
+        uevent_sender
+            .send(Ok(build_uevent(ActionType::Add, "thunderbolt", "/devices/domain0/0-0/0-1")))
+            .unwrap();
+        uevent_sender
+            .send(Ok(build_uevent(ActionType::Add, "thunderbolt", "/devices/domain0/0-0/0-2")))
+            .unwrap();
+        assert_wait_for_path_eq(
+            acl_dev_path.join("authorized"),
+            "1",
+            "Device on the boot ACL should be authorized while new devices are deferred",
+        )
+        .await;
+        assert_eq!(
+            fs::read_to_string(unknown_dev_path.join("authorized")).unwrap().trim(),
+            "0",
+            "Device not on the boot ACL should stay deferred"
+        );
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test]
+    async fn test_uevents_interleaved_with_service_events_are_not_dropped() {
+        const DEVICE_COUNT: usize = 50;
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils.clone(), uevent_socket);
+
+        pci_authorizer.enable_pci_tunnels(true);
+        pci_authorizer.update_logged_in_state(true, UserId(1));
+        let bus_dev_path = create_mock_tbt_device(root, "0-0", "0");
+        pci_authorizer.update_lock_state(false);
+        // Wait for the sweep on the Authorized state, so that only uevents authorize the devices
+        // created below.
+        assert_wait_for_path_eq(
+            bus_dev_path.join("authorized"),
+            "1",
+            "TBT device should be authorized on Authorized state",
+        )
+        .await;
+
+        let dev_paths: Vec<PathBuf> = (0..DEVICE_COUNT)
+            .map(|i| {
+                let dev_path = root.join(format!("sys/devices/domain0/0-0/0-{}", i + 1));
+                create_mock_tbt_device_at(root, &dev_path, "0");
+                dev_path
+            })
+            .collect();
+        for i in 0..DEVICE_COUNT {
+            // Each service event makes the task cancel its pending uevent read.
+            pci_authorizer.update_lock_state(false);
+            uevent_sender
+                .send(Ok(build_uevent(
+                    ActionType::Add,
+                    "thunderbolt",
+                    &format!("/devices/domain0/0-0/0-{}", i + 1),
+                )))
+                .unwrap();
+            tokio::task::yield_now().await;
+        }
+
+        for dev_path in dev_paths {
+            assert_wait_for_path_eq(
+                dev_path.join("authorized"),
+                "1",
+                "Every hotplugged device should be authorized",
+            )
+            .await;
+        }
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_flush_waits_for_policy_updates() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils.clone(), uevent_socket);
+        let tbt_dev_path = create_mock_tbt_device(root, "0-0", "0");
+
+        pci_authorizer.enable_pci_tunnels(true);
+        pci_authorizer.update_logged_in_state(true, UserId(1));
+        pci_authorizer.update_lock_state(false);
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+        assert_eq!(
+            fs::read_to_string(tbt_dev_path.join("authorized")).unwrap().trim(),
+            "1",
+            "TBT device should be authorized once the updates are flushed"
+        );
+
+        pci_authorizer.enable_pci_tunnels(false);
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+        assert_eq!(
+            fs::read_to_string(tbt_dev_path.join("authorized")).unwrap().trim(),
+            "0",
+            "TBT device should be deauthorized once the updates are flushed"
+        );
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test]
+    async fn test_config_enables_tunnels_initially() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let config = PolicyConfig { pci_tunnels_enabled: true, ..PolicyConfig::default() };
+        let pci_authorizer =
+            PciAuthorizer::with_config(sysfs_utils.clone(), uevent_socket, &config);
+        let tbt_dev_path = create_mock_tbt_device(root, "0-0", "0");
+
+        // Tunnels are enabled without any call to enable_pci_tunnels.
+        pci_authorizer.update_logged_in_state(true, UserId(1));
+        pci_authorizer.update_lock_state(false);
+        assert_wait_for_path_eq(
+            tbt_dev_path.join("authorized"),
+            "1",
+            "TBT device should be authorized with tunnels enabled by the config",
+        )
+        .await;
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dump_reports_policy_state_and_devices() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils.clone(), uevent_socket);
+        create_mock_tbt_device(root, "0-0", "0");
+
+        pci_authorizer.enable_pci_tunnels(true);
+        pci_authorizer.update_logged_in_state(true, UserId(10));
+        pci_authorizer.update_lock_state(false);
+        let report = tokio::task::block_in_place(|| {
+            pci_authorizer.flush(Duration::from_secs(5)).unwrap();
+            pci_authorizer.dump(Duration::from_secs(5))
+        });
+
+        for expected in [
+            "Task: alive",
+            "State: Authorized",
+            "pci_tunnels_enabled=true is_locked=false logged_in_users=[10]",
+            "uevents_received=0",
+            "Devices (1):",
+            "0-0: authorized",
+        ] {
+            assert!(
+                report.contains(expected),
+                "{:?} missing from the report:\n{}",
+                expected,
+                report
+            );
+        }
+
+        drop(pci_authorizer);
+    }
+
+    /// Auth policy authorizing devices whenever a user is logged in, even while locked.
+    struct AllowWhileLockedPolicy;
+
+    impl AuthPolicy for AllowWhileLockedPolicy {
+        fn auth_state(&self, policy_data: &PolicySourceData) -> PciAuthState {
+            DefaultAuthPolicy
+                .auth_state(&PolicySourceData { is_locked: false, ..policy_data.clone() })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deauthorize_mode_reauthorizes_pci_devices_on_login() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let tbt_dev_path = create_mock_tbt_device(root, "1-0", "0");
+        let mut pci_devs = Vec::new();
+        for (name, class) in [("0000:03:00.0", "0x060400"), ("0000:06:00.0", "0x010802")] {
+            let dev_path = root.join("sys/bus/pci/devices").join(name);
+            fs::create_dir_all(&dev_path).unwrap();
+            fs::write(dev_path.join("removable"), "1").unwrap();
+            fs::write(dev_path.join("class"), class).unwrap();
+            fs::write(dev_path.join("authorized"), "1").unwrap();
+            pci_devs.push(dev_path);
+        }
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils.with_pci_denial_mode(PciDenialMode::Deauthorize))
+            .with_uevent_socket(uevent_socket)
+            .build();
+
+        pci_authorizer.enable_pci_tunnels(true);
+        pci_authorizer.update_logged_in_state(true, UserId(1));
+        pci_authorizer.update_lock_state(false);
+        assert_wait_for_path_eq(
+            tbt_dev_path.join("authorized"),
+            "1",
+            "TBT device should be authorized",
+        )
+        .await;
+
+        // The denial deauthorizes the bridge and the endpoint behind it.
+        pci_authorizer.update_logged_in_state(false, UserId(1));
+        for dev_path in &pci_devs {
+            assert_wait_for_path_eq(
+                dev_path.join("authorized"),
+                "0",
+                "PCI device should be deauthorized on DenyNoUser",
+            )
+            .await;
+        }
+
+        // Both come back once the user logs back in.
+        pci_authorizer.update_logged_in_state(true, UserId(1));
+        for dev_path in &pci_devs {
+            assert_wait_for_path_eq(
+                dev_path.join("authorized"),
+                "1",
+                "PCI device should be re-authorized on login",
+            )
+            .await;
+        }
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_builder_options_take_effect() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let tbt_dev_path = create_mock_tbt_device(root, "0-0", "0");
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: true,
+            logged_in_users: HashSet::from([UserId(3)]),
+            ..Default::default()
+        };
+
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(policy_data)
+            .with_auth_policy(Box::new(AllowWhileLockedPolicy))
+            .with_idle_deauthorize_timeout(Some(Duration::from_secs(60)))
+            .build();
+        let report = tokio::task::block_in_place(|| {
+            pci_authorizer.flush(Duration::from_secs(5)).unwrap();
+            pci_authorizer.dump(Duration::from_secs(5))
+        });
+
+        assert_eq!(
+            fs::read_to_string(tbt_dev_path.join("authorized")).unwrap().trim(),
+            "1",
+            "TBT device should be authorized while locked by the custom auth policy"
+        );
+        for expected in
+            ["State: Authorized", "logged_in_users=[3]", "Idle timers: 1 (timeout Some(60s))"]
+        {
+            assert!(
+                report.contains(expected),
+                "{:?} missing from the report:\n{}",
+                expected,
+                report
+            );
+        }
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test]
+    async fn test_uevent_observer_receives_every_uevent() {
+        let _ = env_logger::try_init();
+        let (_temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let (observer, mut observed) = mpsc::channel(4);
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_uevent_observer(observer)
+            .build();
+
+        // Uevents are observed even when the policy ignores them.
+        let add_uevent = build_uevent(ActionType::Add, "thunderbolt", "/devices/domain0/0-0/0-1");
+        let usb_uevent = build_uevent(ActionType::Add, "usb", "/devices/usb1");
+        uevent_sender.send(Ok(add_uevent.clone())).unwrap();
+        uevent_sender.send(Ok(usb_uevent.clone())).unwrap();
+
+        for expected in [add_uevent, usb_uevent] {
+            let uevent = tokio::time::timeout(WAIT_FOR_PATH_DURATION, observed.recv())
+                .await
+                .expect("Timed out waiting for the observed uevent");
+            assert_eq!(uevent, Some(expected));
+        }
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_deferred_device_add_emits_device_denied() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let (observer, mut policy_events) = mpsc::channel(8);
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_event_observer(observer)
+            .build();
+
+        let dev_path = root.join("sys/devices/domain0/0-0/0-1");
+        create_mock_tbt_device_at(root, &dev_path, "0");
+        fs::write(dev_path.join("unique_id"), "denied-uuid\n").unwrap();
+
+        // Enter DeferNewDevices.
+        pci_authorizer.enable_pci_tunnels(true);
+        pci_authorizer.update_logged_in_state(true, UserId(1));
+        pci_authorizer.update_lock_state(true);
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+
+        uevent_sender
+            .send(Ok(build_uevent(ActionType::Add, "thunderbolt", "/devices/domain0/0-0/0-1")))
+            .unwrap();
+
+        // The transitions into DeferNewDevices are reported before the denied device.
+        let mut states = Vec::new();
+        let event = loop {
+            let event = tokio::time::timeout(WAIT_FOR_PATH_DURATION, policy_events.recv())
+                .await
+                .expect("Timed out waiting for the policy event");
+            match event {
+                Some(PolicyEvent::StateChanged { to, .. }) => states.push(to),
+                event => break event,
+            }
+        };
+        assert_eq!(states.last(), Some(&PciAuthState::DeferNewDevices));
+        assert_eq!(
+            event,
+            Some(PolicyEvent::DeviceDenied {
+                unique_id: Some("denied-uuid".to_string()),
+                reason: PciAuthState::DeferNewDevices,
+            })
+        );
+        assert_eq!(fs::read_to_string(dev_path.join("authorized")).unwrap().trim(), "0");
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_logged_in_users_reflects_logins_and_logouts() {
+        let _ = env_logger::try_init();
+        let (_temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils, uevent_socket);
+        let timeout = Duration::from_secs(5);
+
+        let logged_in_users =
+            tokio::task::block_in_place(|| pci_authorizer.logged_in_users(timeout));
+        assert_eq!(logged_in_users.unwrap(), HashSet::new());
+
+        pci_authorizer.update_logged_in_state(true, UserId(0));
+        pci_authorizer.update_logged_in_state(true, UserId(10));
+        pci_authorizer.update_logged_in_state(true, UserId(11));
+        pci_authorizer.update_logged_in_state(false, UserId(10));
+        // Logging out a user which is not logged in is a no-op.
+        pci_authorizer.update_logged_in_state(false, UserId(12));
+        let logged_in_users =
+            tokio::task::block_in_place(|| pci_authorizer.logged_in_users(timeout));
+        assert_eq!(logged_in_users.unwrap(), HashSet::from([UserId(0), UserId(11)]));
+
+        pci_authorizer.set_logged_in_users(HashSet::from([UserId(12)]));
+        let logged_in_users =
+            tokio::task::block_in_place(|| pci_authorizer.logged_in_users(timeout));
+        assert_eq!(logged_in_users.unwrap(), HashSet::from([UserId(12)]));
+
+        drop(pci_authorizer);
+    }
+
+    /// Starts an authorizer whose policy authorizes devices, with a domain reporting
+    /// `dma_protection` as its "iommu_dma_protection", or not reporting it if None. Returns the
+    /// "authorized" attribute of a device of the domain and the dump of the authorizer once the
+    /// policy is applied.
+    fn authorize_with_dma_protection(
+        dma_protection: Option<&str>,
+        required: bool,
+        config: &PolicyConfig,
+    ) -> (String, String) {
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let domain_path = root.join("sys/bus/thunderbolt/devices/domain0");
+        fs::create_dir_all(&domain_path).unwrap();
+        if let Some(dma_protection) = dma_protection {
+            fs::write(domain_path.join("iommu_dma_protection"), dma_protection).unwrap();
+        }
+        let tbt_dev_path = create_mock_tbt_device(root, "0-0", "0");
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: false,
+            logged_in_users: HashSet::from([UserId(1)]),
+            ..Default::default()
+        };
+
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_config(config)
+            .with_policy_data(policy_data)
+            .with_dma_protection_required(required)
+            .build();
+        let report = tokio::task::block_in_place(|| {
+            pci_authorizer.flush(Duration::from_secs(5)).unwrap();
+            pci_authorizer.dump(Duration::from_secs(5))
+        });
+        let authorized = fs::read_to_string(tbt_dev_path.join("authorized")).unwrap();
+        (authorized.trim().to_string(), report)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_devices_authorized_with_dma_protection() {
+        let _ = env_logger::try_init();
+
+        let (authorized, report) =
+            authorize_with_dma_protection(Some("1\n"), true, &PolicyConfig::default());
+
+        assert_eq!(authorized, "1");
+        assert!(report.contains("State: Authorized"), "unexpected report:\n{}", report);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_devices_deferred_without_dma_protection() {
+        let _ = env_logger::try_init();
+
+        let (authorized, report) =
+            authorize_with_dma_protection(Some("0\n"), true, &PolicyConfig::default());
+
+        assert_eq!(authorized, "0");
+        assert!(report.contains("State: DeferNewDevices"), "unexpected report:\n{}", report);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_devices_deferred_with_unknown_dma_protection() {
+        let _ = env_logger::try_init();
+
+        let (authorized, report) =
+            authorize_with_dma_protection(None, true, &PolicyConfig::default());
+
+        assert_eq!(authorized, "0");
+        assert!(report.contains("State: DeferNewDevices"), "unexpected report:\n{}", report);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unknown_dma_protection_trusted_by_config() {
+        let _ = env_logger::try_init();
+        let config = PolicyConfig { trust_unknown_dma_protection: true, ..PolicyConfig::default() };
+
+        let (authorized, report) = authorize_with_dma_protection(None, true, &config);
+
+        assert_eq!(authorized, "1");
+        assert!(report.contains("State: Authorized"), "unexpected report:\n{}", report);
+
+        // The config doesn't trust a domain reporting that it is unprotected.
+        let (authorized, _) = authorize_with_dma_protection(Some("0\n"), true, &config);
+        assert_eq!(authorized, "0");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dma_protection_gate_can_be_disabled() {
+        let _ = env_logger::try_init();
+
+        let (authorized, report) =
+            authorize_with_dma_protection(Some("0\n"), false, &PolicyConfig::default());
+
+        assert_eq!(authorized, "1");
+        assert!(report.contains("State: Authorized"), "unexpected report:\n{}", report);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_uevent_observer_with_retry_receives_burst_in_order() {
+        let _ = env_logger::try_init();
+        let (_temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        // Much smaller than the burst, so that the task has to wait for the observer.
+        let (observer, mut observed) = mpsc::channel(2);
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_uevent_observer(observer)
+            .with_uevent_observer_retry(MAX_UEVENT_OBSERVER_RETRY)
+            .build();
+
+        let burst: Vec<UEvent> = (0..50)
+            .map(|i| build_uevent(ActionType::Change, "usb", &format!("/devices/usb{}", i)))
+            .collect();
+        for uevent in &burst {
+            uevent_sender.send(Ok(uevent.clone())).unwrap();
+        }
+
+        for expected in burst {
+            // A slow consumer.
+            sleep(Duration::from_millis(1)).await;
+            let uevent = tokio::time::timeout(WAIT_FOR_PATH_DURATION, observed.recv())
+                .await
+                .expect("Timed out waiting for the observed uevent");
+            assert_eq!(uevent, Some(expected));
+        }
+        let report = tokio::task::block_in_place(|| pci_authorizer.dump(Duration::from_secs(5)));
+        assert!(report.contains("observer_drops=0"), "unexpected report:\n{}", report);
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_uevent_observer_retry_is_capped() {
+        let _ = env_logger::try_init();
+        let (_temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        // Never drained, so that every uevent but the first waits for the observer.
+        let (observer, _observed) = mpsc::channel(1);
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_uevent_observer(observer)
+            .with_uevent_observer_retry(Duration::from_secs(60))
+            .build();
+
+        for i in 0..3 {
+            let uevent = build_uevent(ActionType::Change, "usb", &format!("/devices/usb{}", i));
+            uevent_sender.send(Ok(uevent)).unwrap();
+        }
+
+        // The policy keeps up despite the stuck observer, instead of waiting for it a minute per
+        // uevent.
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let report = loop {
+            let report = tokio::task::block_in_place(|| {
+                pci_authorizer.flush(Duration::from_secs(1)).unwrap();
+                pci_authorizer.dump(Duration::from_secs(1))
+            });
+            if report.contains("observer_drops=2") || Instant::now() > deadline {
+                break report;
+            }
+            sleep(POLL_DURATION).await;
+        };
+        assert!(report.contains("observer_drops=2"), "unexpected report:\n{}", report);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_health_reflects_recent_uevents() {
+        let _ = env_logger::try_init();
+        let (_temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils, uevent_socket);
+        let flush = || {
+            tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+            pci_authorizer.health()
+        };
+        let health = flush();
+        assert!(health.task_alive);
+        assert!(!health.degraded);
+        assert_eq!(health.auth_state, Some(PciAuthState::Disabled));
+        assert_eq!(health.last_uevent, None);
+
+        uevent_sender.send(Err(anyhow::anyhow!("socket failure"))).unwrap();
+        uevent_sender.send(Err(anyhow::anyhow!("socket failure"))).unwrap();
+        assert_eq!(flush().uevent_errors_since_success, 2);
+
+        let before_uevent = Instant::now();
+        uevent_sender.send(Ok(build_uevent(ActionType::Change, "usb", "/devices/usb1"))).unwrap();
+        let health = flush();
+        assert!(health.last_uevent.is_some_and(|last_uevent| last_uevent >= before_uevent));
+        assert_eq!(health.uevent_errors_since_success, 0);
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_health_heartbeat_does_not_depend_on_uevents() {
+        let _ = env_logger::try_init();
+        let (_temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils, uevent_socket);
+        let flush = || {
+            tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+            pci_authorizer.health()
+        };
+
+        let first_heartbeat = flush().last_heartbeat.expect("The task should have beaten");
+        sleep(POLL_DURATION).await;
+        // The task takes the heartbeat as it wakes up, before handling the first flush. The one
+        // taken after the sleep is only seen once the second flush is handled.
+        flush();
+        let health = flush();
+
+        // The task is idle, without any uevent, but still alive.
+        assert_eq!(health.last_uevent, None);
+        assert!(health.last_heartbeat.is_some_and(|heartbeat| heartbeat > first_heartbeat));
+    }
+
+    #[tokio::test]
+    async fn test_mock_socket_add_in_authorized_state_authorizes_device() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: false,
+            logged_in_users: HashSet::from([UserId(1)]),
+            ..Default::default()
+        };
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(policy_data)
+            .build();
+        // Plugged in after the initial sweep.
+        sleep(POLL_DURATION).await;
+        let target = root.join("sys/devices/domain0/0-0/0-1");
+        create_mock_tbt_device_at(root, &target, "0");
+        let bystander = root.join("sys/devices/domain0/0-0/0-2");
+        create_mock_tbt_device_at(root, &bystander, "0");
+
+        uevent_sender
+            .send(Ok(build_uevent(ActionType::Add, "thunderbolt", "/devices/domain0/0-0/0-1")))
+            .unwrap();
+
+        assert_wait_for_path_eq(
+            target.join("authorized"),
+            "1",
+            "The added device should be authorized in the Authorized state",
+        )
+        .await;
+        assert_eq!(
+            fs::read_to_string(bystander.join("authorized")).unwrap(),
+            "0",
+            "Only the device of the uevent should be authorized"
+        );
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_paused_enforcement_applies_net_effect_on_resume() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let tbt_dev_path = create_mock_tbt_device(root, "0-0", "0");
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: true,
+            logged_in_users: HashSet::from([UserId(1)]),
+            ..Default::default()
+        };
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(policy_data)
+            .build();
+
+        pci_authorizer.set_enforcement_paused(true);
+        pci_authorizer.update_lock_state(false);
+        uevent_sender
+            .send(Ok(build_uevent(ActionType::Add, "thunderbolt", "/devices/domain0/0-0")))
+            .unwrap();
+        pci_authorizer.update_logged_in_state(false, UserId(1));
+        pci_authorizer.update_lock_state(true);
+        pci_authorizer.update_logged_in_state(true, UserId(1));
+        pci_authorizer.update_lock_state(false);
+        let report = tokio::task::block_in_place(|| {
+            pci_authorizer.flush(Duration::from_secs(5)).unwrap();
+            pci_authorizer.dump(Duration::from_secs(5))
+        });
+
+        assert_eq!(
+            fs::read_to_string(tbt_dev_path.join("authorized")).unwrap().trim(),
+            "0",
+            "No device should be authorized while enforcement is paused"
+        );
+        for expected in ["enforcement paused", "State: Authorized", "sweeps=0"] {
+            assert!(
+                report.contains(expected),
+                "{:?} missing from the report:\n{}",
+                expected,
+                report
+            );
+        }
+
+        pci_authorizer.set_enforcement_paused(false);
+        let report = tokio::task::block_in_place(|| {
+            pci_authorizer.flush(Duration::from_secs(5)).unwrap();
+            pci_authorizer.dump(Duration::from_secs(5))
+        });
+
+        assert_eq!(
+            fs::read_to_string(tbt_dev_path.join("authorized")).unwrap().trim(),
+            "1",
+            "The device should be authorized once enforcement resumes"
+        );
+        assert!(report.contains("sweeps=1"), "Resuming should sweep once:\n{}", report);
+        assert!(!report.contains("enforcement paused"), "Unexpected report:\n{}", report);
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_set_policy_applies_new_policy() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let tbt_dev_path = create_mock_tbt_device(root, "0-0", "0");
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: true,
+            logged_in_users: HashSet::from([UserId(3)]),
+            ..Default::default()
+        };
+        let mut pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(policy_data)
+            .build();
+        let report = tokio::task::block_in_place(|| {
+            pci_authorizer.flush(Duration::from_secs(5)).unwrap();
+            pci_authorizer.dump(Duration::from_secs(5))
+        });
+        assert!(report.contains("State: DeferNewDevices"), "Unexpected report:\n{}", report);
+
+        pci_authorizer.set_policy(Box::new(AllowWhileLockedPolicy));
+        let report = tokio::task::block_in_place(|| {
+            pci_authorizer.flush(Duration::from_secs(5)).unwrap();
+            pci_authorizer.dump(Duration::from_secs(5))
+        });
+
+        assert!(report.contains("State: Authorized"), "Unexpected report:\n{}", report);
+        assert_eq!(
+            fs::read_to_string(tbt_dev_path.join("authorized")).unwrap().trim(),
+            "1",
+            "TBT device should be authorized while locked by the new auth policy"
+        );
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_engine_stays_disabled_without_thunderbolt_support() {
+        let _ = env_logger::try_init();
+        let temp_dir = TempDir::new().expect("Failed to create temp_dir");
+        let root = temp_dir.path();
+        // No sys/bus/thunderbolt, as on a kernel without CONFIG_USB4.
+        fs::create_dir_all(root.join("sys/bus/pci/devices")).unwrap();
+        let (uevent_socket, _uevent_sender) = MockUEventSocket::new();
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: false,
+            logged_in_users: HashSet::from([UserId(1)]),
+            ..Default::default()
+        };
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(SysfsUtils::with_root_path(root.to_path_buf()))
+            .with_uevent_socket(Arc::new(uevent_socket))
+            .with_policy_data(policy_data)
+            .build();
+
+        let report = tokio::task::block_in_place(|| {
+            pci_authorizer.flush(Duration::from_secs(5)).unwrap();
+            pci_authorizer.dump(Duration::from_secs(5))
+        });
+
+        let health = pci_authorizer.health();
+        assert_eq!(health.tunneling_supported, Some(false));
+        assert_eq!(health.auth_state, Some(PciAuthState::Disabled));
+        assert!(report.contains("tunneling unsupported"), "Unexpected report:\n{}", report);
+        assert!(report.contains("State: Disabled"), "Unexpected report:\n{}", report);
+        assert!(report.contains("sweeps=0"), "Unexpected report:\n{}", report);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_engine_leaves_disabled_once_a_domain_supports_tunneling() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let domain0 = create_mock_tbt_device(root, "domain0", "0");
+        fs::write(domain0.join("security"), "nopcie\n").unwrap();
+        fs::write(domain0.join("iommu_dma_protection"), "1\n").unwrap();
+        let tbt_dev_path = create_mock_tbt_device(root, "0-1", "0");
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: false,
+            logged_in_users: HashSet::from([UserId(1)]),
+            ..Default::default()
+        };
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(policy_data)
+            .build();
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5)).unwrap());
+        assert_eq!(pci_authorizer.health().tunneling_supported, Some(false));
+        assert_eq!(pci_authorizer.health().auth_state, Some(PciAuthState::Disabled));
+
+        // A second controller allowing PCI tunnels is probed.
+        let domain1 = create_mock_tbt_device(root, "domain1", "0");
+        fs::write(domain1.join("security"), "user\n").unwrap();
+        fs::write(domain1.join("iommu_dma_protection"), "1\n").unwrap();
+        uevent_sender
+            .send(Ok(build_uevent(ActionType::Add, "thunderbolt", "/devices/domain1")))
+            .unwrap();
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5)).unwrap());
+
+        let health = pci_authorizer.health();
+        assert_eq!(health.tunneling_supported, Some(true));
+        assert_eq!(health.auth_state, Some(PciAuthState::Authorized));
+        assert_eq!(
+            fs::read_to_string(tbt_dev_path.join("authorized")).unwrap().trim(),
+            "1",
+            "The device should be authorized once tunneling is supported"
+        );
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test]
+    async fn test_added_device_is_authorized_once_ready() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: false,
+            logged_in_users: HashSet::from([UserId(1)]),
+            ..Default::default()
+        };
+        let pci_authorizer = build_with_paused_clock(
+            PciAuthorizer::builder()
+                .with_sysfs_utils(sysfs_utils)
+                .with_uevent_socket(uevent_socket)
+                .with_policy_data(policy_data),
+        );
+        // Plugged in after the initial sweep, before being powered.
+        settle().await;
+        let dock = root.join("sys/devices/domain0/0-0/0-1");
+        create_mock_tbt_device_at(root, &dock, "0");
+        fs::write(dock.join("waiting_for_power"), "1").unwrap();
+
+        uevent_sender
+            .send(Ok(build_uevent(ActionType::Add, "thunderbolt", "/devices/domain0/0-0/0-1")))
+            .unwrap();
+        settle().await;
+        // Checked again every 100ms, up to 10 times.
+        advance(Duration::from_millis(250)).await;
+        assert_eq!(
+            fs::read_to_string(dock.join("authorized")).unwrap(),
+            "0",
+            "The device shouldn't be authorized before it is ready"
+        );
+
+        fs::write(dock.join("waiting_for_power"), "0").unwrap();
+        advance(Duration::from_millis(100)).await;
+        assert_eq!(
+            fs::read_to_string(dock.join("authorized")).unwrap(),
+            "1",
+            "The device should be authorized once it is ready"
+        );
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_thunderbolt_add_is_ignored_when_only_pci_is_enabled() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let connected = create_mock_tbt_device(root, "0-1", "0");
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: false,
+            logged_in_users: HashSet::from([UserId(1)]),
+            enabled_subsystems: HashSet::from([Subsystem::Pci]),
+        };
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(policy_data)
+            .build();
+        let added = root.join("sys/devices/domain0/0-0/0-3");
+        create_mock_tbt_device_at(root, &added, "0");
+
+        uevent_sender
+            .send(Ok(build_uevent(ActionType::Add, "thunderbolt", "/devices/domain0/0-0/0-3")))
+            .unwrap();
+        // The uevent is handled once the health reports it, and the flush waits for the end of
+        // its handling.
+        let start = Instant::now();
+        while pci_authorizer.health().last_uevent.is_none()
+            && start.elapsed() < WAIT_FOR_PATH_DURATION
+        {
+            sleep(POLL_DURATION).await;
+        }
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(connected.join("authorized")).unwrap(),
+            "0",
+            "The initial sweep shouldn't authorize thunderbolt devices"
+        );
+        assert_eq!(
+            fs::read_to_string(added.join("authorized")).unwrap(),
+            "0",
+            "Added thunderbolt devices shouldn't be authorized"
+        );
+
+        // Enabling thunderbolt brings the devices to the current state.
+        pci_authorizer.set_enabled_subsystems(HashSet::from(Subsystem::ALL));
+        assert_wait_for_path_eq(
+            connected.join("authorized"),
+            "1",
+            "Enabling thunderbolt should authorize the devices",
+        )
+        .await;
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pci_devices_are_left_when_pci_is_disabled() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket) = setup_environment_for_pci_authorizer_new();
+        let root = temp_dir.path();
+        let tbt_dev_path = create_mock_tbt_device(root, "0-1", "0");
+        let pci_dev_path = create_mock_pci_device(root, "0000:05:00.0", true);
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: false,
+            logged_in_users: HashSet::from([UserId(1)]),
+            enabled_subsystems: HashSet::from([Subsystem::Thunderbolt]),
+        };
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(policy_data)
+            .build();
+        assert_wait_for_path_eq(
+            tbt_dev_path.join("authorized"),
+            "1",
+            "The initial sweep should authorize thunderbolt devices",
+        )
+        .await;
+
+        // The thunderbolt devices are deauthorized after the PCI devices are handled.
+        pci_authorizer.update_logged_in_state(false, UserId(1));
+        assert_wait_for_path_eq(
+            tbt_dev_path.join("authorized"),
+            "0",
+            "The thunderbolt devices should be deauthorized on DenyNoUser",
+        )
+        .await;
+        assert_eq!(
+            fs::read_to_string(pci_dev_path.join("remove")).unwrap(),
+            "0",
+            "The PCI devices shouldn't be removed"
+        );
+        let err = pci_authorizer.remove_pci_device("0000:05:00.0").unwrap_err().to_string();
+        assert!(err.contains("the PCI subsystem is disabled"), "{}", err);
+        assert_eq!(fs::read_to_string(pci_dev_path.join("remove")).unwrap(), "0");
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unlock_corrects_devices_deauthorized_while_locked() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let dock = create_mock_tbt_device(root, "0-1", "0");
+        let display = create_mock_tbt_device(root, "0-3", "0");
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: false,
+            logged_in_users: HashSet::from([UserId(1)]),
+            ..Default::default()
+        };
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(policy_data)
+            .build();
+        assert_wait_for_path_eq(dock.join("authorized"), "1", "The dock should be authorized")
+            .await;
+        assert_wait_for_path_eq(
+            display.join("authorized"),
+            "1",
+            "The display should be authorized",
+        )
+        .await;
+
+        pci_authorizer.update_lock_state(true);
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+        // Drift without uevents while locked, e.g. during suspend.
+        fs::write(dock.join("authorized"), "0").unwrap();
+        fs::write(display.join("authorized"), "2").unwrap();
+        pci_authorizer.update_lock_state(false);
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dock.join("authorized")).unwrap(),
+            "1",
+            "The device deauthorized while locked should be authorized again"
+        );
+        assert_eq!(
+            fs::read_to_string(display.join("authorized")).unwrap(),
+            "2",
+            "The device still authorized should be left as it is"
+        );
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unlock_deauthorizes_devices_not_allowed() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let host = create_mock_tbt_device(root, "0-0", "1");
+        fs::write(host.join("generation"), "3").unwrap();
+        let usb4_dock = create_mock_tbt_device(root, "0-1", "0");
+        fs::write(usb4_dock.join("generation"), USB4_GENERATION.to_string()).unwrap();
+        let tbt3_dock = create_mock_tbt_device(root, "0-3", "0");
+        fs::write(tbt3_dock.join("generation"), "3").unwrap();
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: true,
+            logged_in_users: HashSet::from([UserId(1)]),
+            ..Default::default()
+        };
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils.with_min_authorized_generation(Some(USB4_GENERATION)))
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(policy_data)
+            .build();
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+
+        // Authorized by someone else while locked, e.g. through the boot ACL.
+        fs::write(tbt3_dock.join("authorized"), "1").unwrap();
+        pci_authorizer.update_lock_state(false);
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+
+        assert_eq!(fs::read_to_string(usb4_dock.join("authorized")).unwrap(), "1");
+        assert_eq!(
+            fs::read_to_string(tbt3_dock.join("authorized")).unwrap(),
+            "0",
+            "The device below the minimum generation should be deauthorized on unlock"
+        );
+        assert_eq!(
+            fs::read_to_string(host.join("authorized")).unwrap(),
+            "1",
+            "The host router should be left alone"
+        );
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_notify_resumed_handles_devices_plugged_in_during_suspend() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: false,
+            logged_in_users: HashSet::from([UserId(1)]),
+            ..Default::default()
+        };
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(policy_data)
+            .build();
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+
+        // Plugged in during suspend: no uevent is received.
+        let dock = create_mock_tbt_device(root, "0-1", "0");
+        pci_authorizer.notify_resumed();
+        // Ignored while the first notification is pending.
+        pci_authorizer.notify_resumed();
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+        assert_eq!(
+            fs::read_to_string(dock.join("authorized")).unwrap(),
+            "1",
+            "The device plugged in during suspend should be authorized after resume"
+        );
+
+        pci_authorizer.update_logged_in_state(false, UserId(1));
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+        // Authorized behind the policy's back during suspend, e.g. by the firmware.
+        let display = create_mock_tbt_device(root, "0-3", "1");
+        pci_authorizer.notify_resumed();
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+        assert_eq!(
+            fs::read_to_string(display.join("authorized")).unwrap(),
+            "0",
+            "The device plugged in during suspend should be denied without a logged-in user"
+        );
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_idle_timer_keeps_device_authorized() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let dock = create_mock_tbt_device(root, "0-1", "0");
+        let idle_timeout = Duration::from_secs(60);
+        let pci_authorizer = build_with_paused_clock(
+            PciAuthorizer::builder()
+                .with_sysfs_utils(sysfs_utils)
+                .with_uevent_socket(uevent_socket)
+                .with_policy_data(PolicySourceData {
+                    pci_tunnels_enabled: true,
+                    is_locked: false,
+                    logged_in_users: HashSet::from([UserId(1)]),
+                    ..Default::default()
+                })
+                .with_idle_deauthorize_timeout(Some(idle_timeout)),
+        );
+        settle().await;
+        assert_eq!(fs::read_to_string(dock.join("authorized")).unwrap(), "1");
+
+        // The query blocks until the task replies, so it can't run on the thread of the task.
+        let (mut pci_authorizer, timers) = tokio::task::spawn_blocking(move || {
+            let timers = pci_authorizer.pending_idle_timers(Duration::from_secs(5));
+            (pci_authorizer, timers)
+        })
+        .await
+        .unwrap();
+        let timers = timers.unwrap();
+        assert_eq!(timers.len(), 1);
+        assert_eq!(timers[0].0, DeviceId("0-1".to_string()));
+        // Only the few milliseconds of `settle` passed since the device was authorized.
+        assert!(timers[0].1 <= idle_timeout && timers[0].1 > idle_timeout / 2);
+
+        pci_authorizer.cancel_idle_timer(DeviceId("0-1".to_string()));
+        // A new timeout restarts the timers of the devices, except the cancelled one.
+        pci_authorizer.set_idle_deauthorize_timeout(Some(idle_timeout));
+        settle().await;
+        let (pci_authorizer, timers) = tokio::task::spawn_blocking(move || {
+            let timers = pci_authorizer.pending_idle_timers(Duration::from_secs(5));
+            (pci_authorizer, timers)
+        })
+        .await
+        .unwrap();
+        let timers = timers.unwrap();
+        assert!(timers.is_empty(), "Unexpected idle timers: {:?}", timers);
+
+        advance(idle_timeout * 2).await;
+        assert_eq!(
+            fs::read_to_string(dock.join("authorized")).unwrap(),
+            "1",
+            "The device whose idle timer was cancelled should stay authorized"
+        );
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_handles_control_the_policy_from_several_threads() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let dock = create_mock_tbt_device(root, "0-1", "0");
+        let pci_authorizer = PciAuthorizer::new(sysfs_utils, uevent_socket);
+        pci_authorizer.enable_pci_tunnels(true);
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+
+        // Few enough updates to fit in the event channel at once.
+        std::thread::scope(|scope| {
+            for user in 0..4 {
+                let handle = pci_authorizer.handle();
+                scope.spawn(move || {
+                    handle.update_logged_in_state(true, UserId(user));
+                    handle.update_lock_state(user % 2 == 0);
+                });
+            }
+        });
+        pci_authorizer.handle().update_lock_state(false);
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+
+        let users =
+            tokio::task::block_in_place(|| pci_authorizer.logged_in_users(Duration::from_secs(5)))
+                .unwrap();
+        assert_eq!(users, (0..4).map(UserId).collect::<HashSet<_>>());
+        assert_wait_for_path_eq(dock.join("authorized"), "1", "The dock should be authorized")
+            .await;
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_authorization_latency_measures_the_delay_until_the_device_is_ready() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: false,
+            logged_in_users: HashSet::from([UserId(1)]),
+            ..Default::default()
+        };
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(policy_data)
+            .build();
+        settle().await;
+        let dock = root.join("sys/devices/domain0/0-0/0-1");
+        create_mock_tbt_device_at(root, &dock, "0");
+        fs::write(dock.join("waiting_for_power"), "1").unwrap();
+
+        uevent_sender
+            .send(Ok(build_uevent(ActionType::Add, "thunderbolt", "/devices/domain0/0-0/0-1")))
+            .unwrap();
+        let added_at = tokio::time::Instant::now();
+        settle().await;
+        // Checked again every 100ms, and powered after the third check.
+        for _ in 0..2 {
+            advance(Duration::from_millis(100)).await;
+        }
+        assert_eq!(fs::read_to_string(dock.join("authorized")).unwrap(), "0");
+        fs::write(dock.join("waiting_for_power"), "0").unwrap();
+        advance(Duration::from_millis(100)).await;
+        assert_eq!(fs::read_to_string(dock.join("authorized")).unwrap(), "1");
+        let elapsed = added_at.elapsed();
+
+        // The query blocks until the task replies, so it can't run on the thread of the task.
+        let latency = tokio::task::spawn_blocking(move || {
+            pci_authorizer.authorization_latency(Duration::from_secs(1))
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        let last = latency.last.expect("The authorization should be measured");
+        assert!(
+            last >= Duration::from_millis(300) && last <= elapsed,
+            "Measured {:?}, expected the simulated delay of at most {:?}",
+            last,
+            elapsed
+        );
+        assert_eq!(
+            latency,
+            AuthorizationLatency { count: 1, last: Some(last), max: last, total: last }
+        );
+        assert_eq!(latency.average(), Some(last));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_authorization_latency_covers_devices_authorized_on_unlock() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let policy_data = PolicySourceData {
+            pci_tunnels_enabled: true,
+            is_locked: true,
+            logged_in_users: HashSet::from([UserId(1)]),
+            ..Default::default()
+        };
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(policy_data)
+            .build();
+        settle().await;
+        let dock = create_mock_tbt_device(root, "0-1", "0");
+        let unplugged = create_mock_tbt_device(root, "0-3", "0");
+
+        for devpath in ["/bus/thunderbolt/devices/0-1", "/bus/thunderbolt/devices/0-3"] {
+            uevent_sender.send(Ok(build_uevent(ActionType::Add, "thunderbolt", devpath))).unwrap();
+        }
+        settle().await;
+        // Removed while locked and plugged in again without a uevent, so its plug in time is gone.
+        uevent_sender
+            .send(Ok(build_uevent(
+                ActionType::Remove,
+                "thunderbolt",
+                "/bus/thunderbolt/devices/0-3",
+            )))
+            .unwrap();
+        settle().await;
+        advance(Duration::from_secs(2)).await;
+        assert_eq!(fs::read_to_string(dock.join("authorized")).unwrap(), "0");
+
+        pci_authorizer.update_lock_state(false);
+        settle().await;
+        assert_eq!(fs::read_to_string(dock.join("authorized")).unwrap(), "1");
+        assert_eq!(fs::read_to_string(unplugged.join("authorized")).unwrap(), "1");
+
+        // The query blocks until the task replies, so it can't run on the thread of the task.
+        let latency = tokio::task::spawn_blocking(move || {
+            pci_authorizer.authorization_latency(Duration::from_secs(1))
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        let last = latency.last.expect("The authorization on unlock should be measured");
+        assert!(last >= Duration::from_secs(2), "Measured {:?}, expected at least 2s", last);
+        assert_eq!(
+            latency,
+            AuthorizationLatency { count: 1, last: Some(last), max: last, total: last }
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_prepare_for_shutdown_deauthorizes_devices_and_stops_the_task() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let dock = create_mock_tbt_device(root, "0-1", "0");
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(PolicySourceData {
+                pci_tunnels_enabled: true,
+                is_locked: false,
+                logged_in_users: HashSet::from([UserId(1)]),
+                ..Default::default()
+            })
+            .build();
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+        assert_eq!(fs::read_to_string(dock.join("authorized")).unwrap(), "1");
+
+        tokio::task::block_in_place(|| {
+            pci_authorizer.prepare_for_shutdown(true, Duration::from_secs(5))
+        })
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(dock.join("authorized")).unwrap(),
+            "0",
+            "The device should be deauthorized before shutdown"
+        );
+        let start = Instant::now();
+        while pci_authorizer.is_task_alive() && start.elapsed() < WAIT_FOR_PATH_DURATION {
+            sleep(POLL_DURATION).await;
+        }
+        assert!(!pci_authorizer.is_task_alive(), "The task should stop after the deauthorization");
+
+        // Calling again, or trying to restart the task, has no effect once shut down.
+        fs::write(dock.join("authorized"), "1").unwrap();
+        tokio::task::block_in_place(|| {
+            pci_authorizer.prepare_for_shutdown(true, Duration::from_secs(5))
+        })
+        .unwrap();
+        assert!(!pci_authorizer.restart_task());
+        assert!(!pci_authorizer.is_task_alive());
+        assert_eq!(fs::read_to_string(dock.join("authorized")).unwrap(), "1");
+
+        drop(pci_authorizer);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_failed_prepare_for_shutdown_is_reported_and_can_be_retried() {
+        let _ = env_logger::try_init();
+        let (temp_dir, sysfs_utils, uevent_socket, _uevent_sender) =
+            setup_environment_with_scripted_uevents();
+        let root = temp_dir.path();
+        let dock = create_mock_tbt_device(root, "0-1", "0");
+        let pci_authorizer = PciAuthorizer::builder()
+            .with_sysfs_utils(sysfs_utils)
+            .with_uevent_socket(uevent_socket)
+            .with_policy_data(PolicySourceData {
+                pci_tunnels_enabled: true,
+                is_locked: false,
+                logged_in_users: HashSet::from([UserId(1)]),
+                ..Default::default()
+            })
+            .build();
+        tokio::task::block_in_place(|| pci_authorizer.flush(Duration::from_secs(5))).unwrap();
+        // The deauthorization fails while the attribute can't be written.
+        fs::remove_file(dock.join("authorized")).unwrap();
+        fs::create_dir(dock.join("authorized")).unwrap();
+
+        let result = tokio::task::block_in_place(|| {
+            pci_authorizer.prepare_for_shutdown(true, Duration::from_secs(5))
+        });
+        assert!(result.is_err(), "The failed deauthorization should be reported");
+
+        fs::remove_dir(dock.join("authorized")).unwrap();
+        fs::write(dock.join("authorized"), "1").unwrap();
+        let start = Instant::now();
+        while pci_authorizer.is_task_alive() && start.elapsed() < WAIT_FOR_PATH_DURATION {
+            sleep(POLL_DURATION).await;
+        }
+        assert!(!pci_authorizer.restart_task(), "The stopped task should not be restarted");
+        tokio::task::block_in_place(|| {
+            pci_authorizer.prepare_for_shutdown(true, Duration::from_secs(5))
+        })
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(dock.join("authorized")).unwrap(),
+            "0",
+            "The retry should deauthorize the device"
+        );
+        assert!(!pci_authorizer.is_task_alive());
+
+        drop(pci_authorizer);
+    }
 }
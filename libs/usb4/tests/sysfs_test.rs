@@ -0,0 +1,1024 @@
+// Copyright (C) 2025 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(test)]
+mod sysfs_tests {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use usb4_policies::common::Subsystem;
+    use usb4_policies::sysfs::{
+        AuthLevel, LinkSpeed, PciDenialMode, SecurityLevel, SweepOutcome, SweepPhaseReport,
+        SysfsUtils, TbtDeviceId, ThunderboltDevice, ThunderboltEntryType, USB4_GENERATION,
+    };
+
+    fn setup_sysfs_root() -> TempDir {
+        let temp_dir = TempDir::new().expect("Failed to create temp_dir");
+        let root = temp_dir.path();
+
+        fs::create_dir_all(root.join("sys/bus/pci/devices"))
+            .expect("Failed to create mock pci devices dir");
+        fs::create_dir_all(root.join("sys/bus/thunderbolt/devices"))
+            .expect("Failed to create mock tbt devices dir");
+
+        temp_dir
+    }
+
+    /// Creates a thunderbolt device at `sys/devices/<topology_path>` and links it from the
+    /// thunderbolt bus, the same way the kernel lays out the device tree.
+    fn create_mock_tbt_device(sysfs_root: &Path, topology_path: &str, authorized: &str) -> PathBuf {
+        let dev_path = sysfs_root.join("sys/devices").join(topology_path);
+        fs::create_dir_all(&dev_path).expect("Failed to create mock tbt device dir");
+        fs::write(dev_path.join("authorized"), authorized)
+            .expect("Failed to write mock tbt authorized file");
+        symlink(sysfs_root.join("sys/bus/thunderbolt"), dev_path.join("subsystem"))
+            .expect("Failed to create mock tbt subsystem symlink");
+
+        let name = Path::new(topology_path).file_name().unwrap();
+        let bus_link = sysfs_root.join("sys/bus/thunderbolt/devices").join(name);
+        symlink(Path::new("../../../devices").join(topology_path), &bus_link)
+            .expect("Failed to link mock tbt device from the bus");
+
+        dev_path
+    }
+
+    fn create_mock_pci_device(
+        sysfs_root: &Path,
+        name: &str,
+        class: &str,
+        authorized: Option<&str>,
+    ) -> PathBuf {
+        let dev_path = sysfs_root.join("sys/bus/pci/devices").join(name);
+        fs::create_dir_all(&dev_path).expect("Failed to create mock pci device dir");
+        fs::write(dev_path.join("removable"), "1")
+            .expect("Failed to write mock pci removable file");
+        fs::write(dev_path.join("class"), class).expect("Failed to write mock pci class file");
+        if let Some(authorized) = authorized {
+            fs::write(dev_path.join("authorized"), authorized)
+                .expect("Failed to write mock pci authorized file");
+        }
+        dev_path
+    }
+
+    /// Creates a PCI device at `sys/devices/<topology_path>` and links it from the PCI bus, the
+    /// same way the kernel lays out the device tree.
+    fn create_mock_pci_device_in_topology(
+        sysfs_root: &Path,
+        topology_path: &str,
+        class: &str,
+        removable: bool,
+    ) -> PathBuf {
+        let dev_path = sysfs_root.join("sys/devices").join(topology_path);
+        fs::create_dir_all(&dev_path).expect("Failed to create mock pci device dir");
+        fs::write(dev_path.join("removable"), if removable { "1" } else { "0" })
+            .expect("Failed to write mock pci removable file");
+        fs::write(dev_path.join("class"), class).expect("Failed to write mock pci class file");
+        let name = Path::new(topology_path).file_name().unwrap();
+        symlink(
+            Path::new("../../../devices").join(topology_path),
+            sysfs_root.join("sys/bus/pci/devices").join(name),
+        )
+        .expect("Failed to link mock pci device from the bus");
+        dev_path
+    }
+
+    /// Makes any attempt to authorize the device fail.
+    fn break_authorized_attribute(dev_path: &Path) {
+        fs::remove_file(dev_path.join("authorized")).unwrap();
+        fs::create_dir(dev_path.join("authorized")).unwrap();
+    }
+
+    fn read_authorized(dev_path: &Path) -> String {
+        fs::read_to_string(dev_path.join("authorized")).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn test_authorize_all_devices_authorizes_tree() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let parent = create_mock_tbt_device(root, "domain0/0-0", "0");
+        let child = create_mock_tbt_device(root, "domain0/0-0/0-1", "0");
+
+        SysfsUtils::with_root_path(root.to_path_buf()).authorize_all_devices().unwrap();
+
+        assert_eq!(read_authorized(&parent), "1");
+        assert_eq!(read_authorized(&child), "1");
+    }
+
+    #[test]
+    fn test_authorize_all_devices_skips_subtree_of_failed_parent() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let parent = create_mock_tbt_device(root, "domain0/0-0", "0");
+        let child = create_mock_tbt_device(root, "domain0/0-0/0-1", "0");
+        let sibling = create_mock_tbt_device(root, "domain1/1-0", "0");
+        break_authorized_attribute(&parent);
+
+        let sysfs_utils =
+            SysfsUtils::with_root_path(root.to_path_buf()).with_failed_subtree_skipping(true);
+        let err = sysfs_utils.authorize_all_devices().unwrap_err().to_string();
+
+        assert_eq!(err.matches("0-0").count(), 1, "Only the parent should be reported: {err}");
+        assert!(!err.contains("0-1"), "The child should not be reported: {err}");
+        assert!(err.contains("skipped 1 descendants"), "The child should be skipped: {err}");
+        assert_eq!(read_authorized(&child), "0", "The child should not be authorized");
+        assert_eq!(read_authorized(&sibling), "1", "Other subtrees should be authorized");
+    }
+
+    #[test]
+    fn test_authorize_all_devices_attempts_children_of_failed_parent_by_default() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let parent = create_mock_tbt_device(root, "domain0/0-0", "0");
+        let child = create_mock_tbt_device(root, "domain0/0-0/0-1", "0");
+        break_authorized_attribute(&parent);
+
+        let err = SysfsUtils::with_root_path(root.to_path_buf())
+            .authorize_all_devices()
+            .unwrap_err()
+            .to_string();
+
+        assert!(!err.contains("skipped"), "Nothing should be skipped: {err}");
+        assert_eq!(read_authorized(&child), "1");
+    }
+
+    #[test]
+    fn test_read_device_generation() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let usb4_dev = create_mock_tbt_device(root, "domain0/0-0", "0");
+        fs::write(usb4_dev.join("generation"), "4\n").unwrap();
+        let legacy_dev = create_mock_tbt_device(root, "domain1/1-0", "0");
+        let invalid_dev = create_mock_tbt_device(root, "domain2/2-0", "0");
+        fs::write(invalid_dev.join("generation"), "usb4\n").unwrap();
+
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        assert_eq!(sysfs_utils.read_device_generation(&usb4_dev).unwrap(), Some(4));
+        assert_eq!(sysfs_utils.read_device_generation(&legacy_dev).unwrap(), None);
+        assert!(sysfs_utils.read_device_generation(&invalid_dev).is_err());
+    }
+
+    #[test]
+    fn test_min_authorized_generation_only_authorizes_usb4_devices() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let usb4_dev = create_mock_tbt_device(root, "domain0/0-0", "0");
+        fs::write(usb4_dev.join("generation"), "4\n").unwrap();
+        let tbt3_dev = create_mock_tbt_device(root, "domain1/1-0", "0");
+        fs::write(tbt3_dev.join("generation"), "3\n").unwrap();
+        let unknown_dev = create_mock_tbt_device(root, "domain2/2-0", "0");
+
+        SysfsUtils::with_root_path(root.to_path_buf())
+            .with_min_authorized_generation(Some(USB4_GENERATION))
+            .authorize_all_devices()
+            .unwrap();
+
+        assert_eq!(read_authorized(&usb4_dev), "1");
+        assert_eq!(read_authorized(&tbt3_dev), "0");
+        assert_eq!(read_authorized(&unknown_dev), "0");
+    }
+
+    #[test]
+    fn test_list_thunderbolt_devices() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        create_mock_tbt_device(root, "domain0", "0");
+        let host = create_mock_tbt_device(root, "domain0/0-0", "1");
+        fs::write(host.join("unique_id"), "host-uuid\n").unwrap();
+        fs::write(host.join("vendor_name"), "Host Vendor\n").unwrap();
+        fs::write(host.join("device_name"), "Host Router\n").unwrap();
+        create_mock_tbt_device(root, "domain0/0-0/0-0:1.1", "0");
+        let dock = create_mock_tbt_device(root, "domain0/0-0/0-1", "0");
+        fs::write(dock.join("vendor_name"), "Dock Vendor\n").unwrap();
+
+        let devices = SysfsUtils::with_root_path(root.to_path_buf()).list_thunderbolt_devices();
+
+        assert_eq!(
+            devices.unwrap(),
+            [
+                ThunderboltDevice {
+                    name: "0-0".to_string(),
+                    authorized: true,
+                    unique_id: Some("host-uuid".to_string()),
+                    vendor_name: Some("Host Vendor".to_string()),
+                    device_name: Some("Host Router".to_string()),
+                },
+                ThunderboltDevice {
+                    name: "0-1".to_string(),
+                    authorized: false,
+                    unique_id: None,
+                    vendor_name: Some("Dock Vendor".to_string()),
+                    device_name: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_authorize_all_devices_authorizes_tunneled_pci_bridges() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let bridge = create_mock_pci_device(root, "0000:01:00.0", "0x060400", Some("0"));
+        let endpoint = create_mock_pci_device(root, "0000:02:00.0", "0x010802", Some("0"));
+        let ungated_bridge = create_mock_pci_device(root, "0000:03:00.0", "0x060400", None);
+
+        SysfsUtils::with_root_path(root.to_path_buf()).authorize_all_devices().unwrap();
+
+        assert_eq!(read_authorized(&bridge), "1");
+        assert_eq!(read_authorized(&endpoint), "0", "Only bridges should be authorized");
+        assert!(!ungated_bridge.join("authorized").exists());
+    }
+
+    #[test]
+    fn test_set_pci_authorized() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let gated = create_mock_pci_device(root, "0000:01:00.0", "0x060400", Some("0"));
+        let ungated = create_mock_pci_device(root, "0000:02:00.0", "0x060400", None);
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        sysfs_utils.set_pci_authorized(&gated, true).unwrap();
+        assert_eq!(read_authorized(&gated), "1");
+        sysfs_utils.set_pci_authorized(&gated, false).unwrap();
+        assert_eq!(read_authorized(&gated), "0");
+        sysfs_utils.set_pci_authorized(&ungated, true).unwrap();
+        assert!(!ungated.join("authorized").exists());
+    }
+
+    #[test]
+    fn test_remove_pci_device_leaves_siblings() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let target = create_mock_pci_device(root, "0000:05:00.0", "0x060400", None);
+        let sibling = create_mock_pci_device(root, "0000:06:00.0", "0x060400", None);
+        let internal = create_mock_pci_device(root, "0000:00:02.0", "0x030000", None);
+        fs::write(internal.join("removable"), "0").unwrap();
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        sysfs_utils.remove_pci_device("0000:05:00.0").unwrap();
+
+        assert_eq!(fs::read_to_string(target.join("remove")).unwrap(), "1");
+        assert!(!sibling.join("remove").exists(), "The sibling device should stay");
+        assert!(sysfs_utils.remove_pci_device("0000:00:02.0").is_err());
+        assert!(!internal.join("remove").exists(), "Non-removable devices should stay");
+        assert!(sysfs_utils.remove_pci_device("0000:07:00.0").is_err());
+        assert!(sysfs_utils.remove_pci_device("../../../devices").is_err());
+    }
+
+    #[test]
+    fn test_remove_pci_device_leaves_internal_removable_devices() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        // An NVMe drive in an internal hotplug slot, and a device tunneled through thunderbolt.
+        let nvme = create_mock_pci_device_in_topology(
+            root,
+            "pci0000:00/0000:00:1d.0/0000:05:00.0",
+            "0x010802",
+            true,
+        );
+        create_mock_pci_device_in_topology(root, "pci0000:00/0000:00:07.0", "0x060400", false);
+        let dock = create_mock_pci_device_in_topology(
+            root,
+            "pci0000:00/0000:00:07.0/0000:03:00.0",
+            "0x060400",
+            true,
+        );
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        assert!(sysfs_utils.remove_pci_device("0000:05:00.0").is_err());
+        assert!(!nvme.join("remove").exists(), "The internal device shouldn't be removed");
+        sysfs_utils.remove_pci_device("0000:03:00.0").unwrap();
+        assert_eq!(fs::read_to_string(dock.join("remove")).unwrap(), "1");
+    }
+
+    #[test]
+    fn test_is_pci_address() {
+        assert!(SysfsUtils::is_pci_address("0000:05:00.0"));
+        assert!(SysfsUtils::is_pci_address("0000:05:1f.7"));
+        assert!(SysfsUtils::is_pci_address("10000:e1:00.0"), "VMD domains have 5 digits");
+        assert!(!SysfsUtils::is_pci_address("0000:05:00.8"));
+        assert!(!SysfsUtils::is_pci_address("0000:05:00.a"));
+        assert!(!SysfsUtils::is_pci_address("000:05:00.0"));
+        assert!(!SysfsUtils::is_pci_address("0000:5:00.0"));
+        assert!(!SysfsUtils::is_pci_address("pci0000:00"));
+        assert!(!SysfsUtils::is_pci_address("../0000:05:00.0"));
+    }
+
+    #[test]
+    fn test_read_boot_acl() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let domain = create_mock_tbt_device(root, "domain0", "0");
+        fs::write(
+            domain.join("boot_acl"),
+            "8e6a2a3e-6e0a-4c5b-9f3b-1d2c3b4a5e6f,,,d1d2d3d4-0000-1111-2222-333344445555,,\n",
+        )
+        .unwrap();
+        let dock = create_mock_tbt_device(root, "domain0/0-0/0-1", "0");
+        fs::write(dock.join("unique_id"), "D1D2D3D4-0000-1111-2222-333344445555\n").unwrap();
+        let unknown = create_mock_tbt_device(root, "domain0/0-0/0-2", "0");
+        fs::write(unknown.join("unique_id"), "ffffffff-0000-1111-2222-333344445555\n").unwrap();
+        // A domain without a boot ACL doesn't preauthorize the devices on the ACL of another one.
+        create_mock_tbt_device(root, "domain1", "0");
+        let other_domain_dock = create_mock_tbt_device(root, "domain1/1-0/1-1", "0");
+        fs::write(other_domain_dock.join("unique_id"), "d1d2d3d4-0000-1111-2222-333344445555\n")
+            .unwrap();
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        assert_eq!(
+            sysfs_utils.read_boot_acl("domain0").unwrap(),
+            [
+                "8e6a2a3e-6e0a-4c5b-9f3b-1d2c3b4a5e6f".to_string(),
+                "d1d2d3d4-0000-1111-2222-333344445555".to_string(),
+            ]
+        );
+        assert!(sysfs_utils.read_boot_acl("domain1").unwrap().is_empty());
+        assert!(sysfs_utils.is_on_boot_acl(&dock).unwrap());
+        assert!(!sysfs_utils.is_on_boot_acl(&unknown).unwrap());
+        assert!(!sysfs_utils.is_on_boot_acl(&other_domain_dock).unwrap());
+    }
+
+    #[test]
+    fn test_read_security_level() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let domain = create_mock_tbt_device(root, "domain0", "0");
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        for (content, level, requires_authorization) in [
+            ("none\n", SecurityLevel::None, false),
+            ("user\n", SecurityLevel::User, true),
+            ("secure\n", SecurityLevel::Secure, true),
+            ("dponly\n", SecurityLevel::DpOnly, false),
+            ("usbonly\n", SecurityLevel::UsbOnly, false),
+            ("nopcie\n", SecurityLevel::NoPcie, false),
+        ] {
+            fs::write(domain.join("security"), content).unwrap();
+            assert_eq!(sysfs_utils.read_security_level("domain0").unwrap(), level);
+            assert_eq!(
+                sysfs_utils.is_pci_authorization_required().unwrap(),
+                requires_authorization,
+                "security level {:?}",
+                level
+            );
+        }
+
+        fs::write(domain.join("security"), "bogus\n").unwrap();
+        assert!(sysfs_utils.read_security_level("domain0").is_err());
+        assert!(sysfs_utils.read_security_level("domain1").is_err());
+    }
+
+    #[test]
+    fn test_secure_level_authorizes_with_challenge_key() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let domain = create_mock_tbt_device(root, "domain0", "0");
+        fs::write(domain.join("security"), "secure\n").unwrap();
+        let keyed = create_mock_tbt_device(root, "domain0/0-0/0-1", "0");
+        fs::write(keyed.join("key"), "0123456789abcdef\n").unwrap();
+        let unkeyed = create_mock_tbt_device(root, "domain0/0-0/0-2", "0");
+        fs::write(unkeyed.join("key"), "\n").unwrap();
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        sysfs_utils.authorize_thunderbolt_dev(&keyed).unwrap();
+        sysfs_utils.authorize_thunderbolt_dev(&unkeyed).unwrap();
+
+        assert_eq!(read_authorized(&keyed), "2");
+        assert_eq!(read_authorized(&unkeyed), "1");
+    }
+
+    #[test]
+    fn test_with_root_override_roots_paths() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        create_mock_tbt_device(root, "domain0/0-0", "1");
+
+        let sysfs_utils = SysfsUtils::with_root_override(root.to_str());
+
+        assert_eq!(
+            sysfs_utils.devpath_to_syspath(Path::new("/devices/domain0/0-0")),
+            root.join("sys/devices/domain0/0-0")
+        );
+        let devices = sysfs_utils.list_thunderbolt_devices().unwrap();
+        assert_eq!(devices.iter().map(|device| device.name.as_str()).collect::<Vec<_>>(), ["0-0"]);
+
+        for unset in [None, Some("")] {
+            assert_eq!(
+                SysfsUtils::with_root_override(unset)
+                    .devpath_to_syspath(Path::new("/devices/domain0/0-0")),
+                Path::new("/sys/devices/domain0/0-0")
+            );
+        }
+    }
+
+    #[test]
+    fn test_authorize_all_devices_authorizes_domains_independently() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let broken_parent = create_mock_tbt_device(root, "domain0/0-0", "0");
+        let skipped_child = create_mock_tbt_device(root, "domain0/0-0/0-1", "0");
+        break_authorized_attribute(&broken_parent);
+        let domain1_devs = [
+            create_mock_tbt_device(root, "domain1/1-0", "0"),
+            create_mock_tbt_device(root, "domain1/1-0/1-1", "0"),
+            create_mock_tbt_device(root, "domain1/1-0/1-1/1-3", "0"),
+        ];
+
+        let sysfs_utils =
+            SysfsUtils::with_root_path(root.to_path_buf()).with_failed_subtree_skipping(true);
+        let err = sysfs_utils.authorize_all_devices().unwrap_err().to_string();
+
+        // The child is only skipped if its parent was handled first within the domain.
+        assert!(err.contains("skipped 1 descendants"), "The child should be skipped: {err}");
+        assert_eq!(read_authorized(&skipped_child), "0");
+        for dev in domain1_devs {
+            assert_eq!(read_authorized(&dev), "1", "{:?} should be authorized", dev);
+        }
+    }
+
+    #[test]
+    fn test_read_link_speed() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let dev = create_mock_tbt_device(root, "domain0/0-0/0-1", "1");
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        assert_eq!(sysfs_utils.read_link_speed(&dev).unwrap(), None);
+
+        fs::write(dev.join("rx_speed"), "20.0 Gb/s\n").unwrap();
+        fs::write(dev.join("rx_lanes"), "2\n").unwrap();
+        fs::write(dev.join("tx_speed"), "10.0 Gb/s\n").unwrap();
+        assert_eq!(sysfs_utils.read_link_speed(&dev).unwrap(), None);
+
+        fs::write(dev.join("tx_lanes"), "2\n").unwrap();
+        let link_speed = sysfs_utils.read_link_speed(&dev).unwrap().unwrap();
+        assert_eq!(link_speed, LinkSpeed { rx_speed: 20, rx_lanes: 2, tx_speed: 10, tx_lanes: 2 });
+        assert_eq!(link_speed.bandwidth(), 20);
+        assert_eq!(sysfs_utils.read_max_link_bandwidth(&dev).unwrap(), None);
+
+        for (generation, max_bandwidth) in
+            [("1", Some(10)), ("2", Some(20)), ("3", Some(40)), ("4", Some(40)), ("9", None)]
+        {
+            fs::write(dev.join("generation"), format!("{}\n", generation)).unwrap();
+            assert_eq!(
+                sysfs_utils.read_max_link_bandwidth(&dev).unwrap(),
+                max_bandwidth,
+                "generation {}",
+                generation
+            );
+        }
+
+        fs::write(dev.join("tx_lanes"), "two\n").unwrap();
+        assert!(sysfs_utils.read_link_speed(&dev).is_err());
+    }
+
+    #[test]
+    fn test_read_unique_id_and_device_key() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let dock = create_mock_tbt_device(root, "domain0/0-0/0-1", "1");
+        fs::write(dock.join("unique_id"), "d1d2d3d4-0000-1111-2222-333344445555\n").unwrap();
+        let host = create_mock_tbt_device(root, "domain0/0-0", "1");
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        assert_eq!(
+            sysfs_utils.read_unique_id(&dock).unwrap().as_deref(),
+            Some("d1d2d3d4-0000-1111-2222-333344445555")
+        );
+        assert_eq!(sysfs_utils.device_key(&dock), "d1d2d3d4-0000-1111-2222-333344445555");
+        // Devices without a unique id fall back to their kernel name.
+        assert_eq!(sysfs_utils.read_unique_id(&host).unwrap(), None);
+        assert_eq!(sysfs_utils.device_key(&host), "0-0");
+    }
+
+    #[test]
+    fn test_parse_thunderbolt_devpath() {
+        let parse = |path: &str| SysfsUtils::parse_thunderbolt_devpath(Path::new(path));
+        let device = |domain, route: &str| TbtDeviceId { domain, route: Some(route.to_string()) };
+
+        assert_eq!(
+            parse("/devices/pci0000:00/0000:00:0d.2/domain1"),
+            Some(TbtDeviceId { domain: 1, route: None })
+        );
+        assert_eq!(parse("/devices/pci0000:00/0000:00:0d.2/domain0/0-0"), Some(device(0, "0-0")));
+        assert_eq!(parse("/devices/domain0/0-0/0-1/0-301"), Some(device(0, "0-301")));
+        assert_eq!(parse("/sys/bus/thunderbolt/devices/1-1"), Some(device(1, "1-1")));
+
+        // Retimers and devices of other buses are not thunderbolt devices.
+        assert_eq!(parse("/devices/domain0/0-0/0-0:1.1"), None);
+        assert_eq!(parse("/devices/pci0000:00/0000:00:14.0/usb1/1-1"), None);
+        assert_eq!(parse("/devices/pci0000:00/0000:00:1c.0"), None);
+        assert_eq!(parse("/devices/domain1/0-1"), None);
+    }
+
+    #[test]
+    fn test_authorize_by_unique_id_selects_matching_device() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        create_mock_tbt_device(root, "domain0", "0");
+        let host = create_mock_tbt_device(root, "domain0/0-0", "1");
+        let dock = create_mock_tbt_device(root, "domain0/0-0/0-1", "0");
+        fs::write(dock.join("unique_id"), "dock-uuid\n").unwrap();
+        let display = create_mock_tbt_device(root, "domain0/0-0/0-1/0-301", "0");
+        fs::write(display.join("unique_id"), "display-uuid\n").unwrap();
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        assert!(sysfs_utils.authorize_by_unique_id("display-uuid").unwrap());
+        assert_eq!(read_authorized(&display), "1");
+        assert_eq!(read_authorized(&dock), "0");
+
+        assert!(sysfs_utils.deauthorize_by_unique_id("display-uuid").unwrap());
+        assert_eq!(read_authorized(&display), "0");
+
+        assert!(!sysfs_utils.authorize_by_unique_id("unknown-uuid").unwrap());
+        assert_eq!(read_authorized(&host), "1");
+        assert_eq!(read_authorized(&dock), "0");
+    }
+
+    #[test]
+    fn test_authorize_all_devices_skips_device_removed_during_sweep() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        create_mock_tbt_device(root, "domain0", "1");
+        let host = create_mock_tbt_device(root, "domain0/0-0", "0");
+        let dock = create_mock_tbt_device(root, "domain0/0-0/0-1", "0");
+        // The dock is unplugged after the devices are listed, while the host is authorized.
+        let unplug_dock = {
+            let dock = dock.clone();
+            move |dev: &Path| {
+                if dev.ends_with("0-0") {
+                    fs::remove_dir_all(&dock).unwrap();
+                }
+            }
+        };
+
+        SysfsUtils::with_root_path(root.to_path_buf())
+            .with_before_authorize_hook(Arc::new(unplug_dock))
+            .authorize_all_devices()
+            .unwrap();
+
+        assert_eq!(read_authorized(&host), "1");
+        assert!(!dock.exists());
+    }
+
+    #[test]
+    fn test_authorize_all_devices_stops_when_cancelled() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        create_mock_tbt_device(root, "domain0", "1");
+        let host = create_mock_tbt_device(root, "domain0/0-0", "0");
+        let dock = create_mock_tbt_device(root, "domain0/0-0/0-1", "0");
+        // The sweep is cancelled while the host is authorized.
+        let cancel = Arc::new(AtomicBool::new(false));
+        let cancel_at_host = {
+            let cancel = cancel.clone();
+            move |dev: &Path| {
+                if dev.ends_with("0-0") {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+            }
+        };
+
+        let outcome = SysfsUtils::with_root_path(root.to_path_buf())
+            .with_before_authorize_hook(Arc::new(cancel_at_host))
+            .authorize_all_devices_cancelable(&cancel)
+            .unwrap();
+
+        assert_eq!(outcome, SweepOutcome::Cancelled);
+        // The device being authorized when the sweep was cancelled is authorized, the remaining
+        // ones aren't.
+        assert_eq!(read_authorized(&host), "1");
+        assert_eq!(read_authorized(&dock), "0");
+    }
+
+    #[test]
+    fn test_deauthorize_all_devices_reports_the_failed_phase() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let pci_dev = create_mock_pci_device(root, "0000:05:00.0", "0x088000", None);
+        let dock = create_mock_tbt_device(root, "domain0/0-0/0-1", "1");
+        break_authorized_attribute(&dock);
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        let report = sysfs_utils
+            .deauthorize_all_devices_report(&HashSet::from(Subsystem::ALL), &AtomicBool::new(false))
+            .unwrap();
+
+        assert_eq!(report.pci_removal, SweepPhaseReport { succeeded: 1, failed: vec![] });
+        assert_eq!(fs::read_to_string(pci_dev.join("remove")).unwrap(), "1");
+        assert!(!report.thunderbolt_deauthorization.is_success());
+        assert_eq!(
+            report.thunderbolt_deauthorization.failed,
+            [root.join("sys/bus/thunderbolt/devices/0-1")]
+        );
+        assert_eq!(report.outcome, SweepOutcome::Completed);
+        assert!(!report.is_success());
+        let err = sysfs_utils.deauthorize_all_devices().unwrap_err().to_string();
+        assert!(err.contains("thunderbolt"), "{}", err);
+        assert!(!err.contains("PCI"), "{}", err);
+    }
+
+    #[test]
+    fn test_deauthorize_all_devices_leaves_disabled_subsystems() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let pci_dev = create_mock_pci_device(root, "0000:05:00.0", "0x088000", None);
+        let dock = create_mock_tbt_device(root, "domain0/0-0/0-1", "1");
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        sysfs_utils
+            .deauthorize_all_devices_cancelable(
+                &HashSet::from([Subsystem::Thunderbolt]),
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+        assert!(!pci_dev.join("remove").exists());
+        assert_eq!(read_authorized(&dock), "0");
+
+        fs::write(dock.join("authorized"), "1").unwrap();
+        sysfs_utils
+            .deauthorize_all_devices_cancelable(
+                &HashSet::from([Subsystem::Pci]),
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+        assert_eq!(fs::read_to_string(pci_dev.join("remove")).unwrap(), "1");
+        assert_eq!(read_authorized(&dock), "1");
+    }
+
+    #[test]
+    fn test_deauthorize_all_devices_leaves_internal_removable_pci_devices() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        create_mock_pci_device_in_topology(root, "pci0000:00/0000:00:1d.0", "0x060400", false);
+        // An NVMe drive in an internal hotplug slot.
+        let nvme = create_mock_pci_device_in_topology(
+            root,
+            "pci0000:00/0000:00:1d.0/0000:05:00.0",
+            "0x010802",
+            true,
+        );
+        create_mock_pci_device_in_topology(root, "pci0000:00/0000:00:07.0", "0x060400", false);
+        // The PCIe switch of a dock tunneled through thunderbolt, and a device behind it.
+        let dock_upstream = create_mock_pci_device_in_topology(
+            root,
+            "pci0000:00/0000:00:07.0/0000:03:00.0",
+            "0x060400",
+            true,
+        );
+        let dock_downstream = create_mock_pci_device_in_topology(
+            root,
+            "pci0000:00/0000:00:07.0/0000:03:00.0/0000:04:01.0",
+            "0x060400",
+            true,
+        );
+        let ethernet = create_mock_pci_device_in_topology(
+            root,
+            "pci0000:00/0000:00:07.0/0000:03:00.0/0000:04:01.0/0000:06:00.0",
+            "0x020000",
+            true,
+        );
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+        let bus_path = |name: &str| root.join("sys/bus/pci/devices").join(name);
+
+        assert!(!sysfs_utils.is_externally_connected(&bus_path("0000:05:00.0")).unwrap());
+        assert!(!sysfs_utils.is_externally_connected(&bus_path("0000:00:07.0")).unwrap());
+        assert!(sysfs_utils.is_externally_connected(&bus_path("0000:03:00.0")).unwrap());
+        assert!(sysfs_utils.is_externally_connected(&bus_path("0000:06:00.0")).unwrap());
+
+        sysfs_utils.deauthorize_all_devices().unwrap();
+
+        assert!(!nvme.join("remove").exists(), "The internal device shouldn't be removed");
+        for external in [dock_upstream, dock_downstream, ethernet] {
+            assert_eq!(fs::read_to_string(external.join("remove")).unwrap(), "1");
+        }
+    }
+
+    #[test]
+    fn test_endpoint_behind_integrated_tunnel_root_port_is_external() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        // An integrated USB4 controller, whose tunnel ports are root ports of the same root bus
+        // marked as external facing by the firmware.
+        create_mock_tbt_device(root, "pci0000:00/0000:00:0d.2/domain0", "1");
+        let root_port =
+            create_mock_pci_device_in_topology(root, "pci0000:00/0000:00:07.0", "0x060400", false);
+        fs::write(root_port.join("external_facing"), "1").unwrap();
+        // An NVMe enclosure tunneled right below the root port, without a PCIe switch.
+        let enclosure = create_mock_pci_device_in_topology(
+            root,
+            "pci0000:00/0000:00:07.0/0000:06:00.0",
+            "0x010802",
+            true,
+        );
+        // An NVMe drive in a hotplug slot of a root complex without thunderbolt controller.
+        let nvme = create_mock_pci_device_in_topology(
+            root,
+            "pci0000:80/0000:80:1d.0/0000:81:00.0",
+            "0x010802",
+            true,
+        );
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+        let bus_path = |name: &str| root.join("sys/bus/pci/devices").join(name);
+
+        assert!(!sysfs_utils.is_externally_connected(&bus_path("0000:00:07.0")).unwrap());
+        assert!(sysfs_utils.is_externally_connected(&bus_path("0000:06:00.0")).unwrap());
+        assert!(!sysfs_utils.is_externally_connected(&bus_path("0000:81:00.0")).unwrap());
+
+        sysfs_utils.deauthorize_all_devices().unwrap();
+
+        assert_eq!(fs::read_to_string(enclosure.join("remove")).unwrap(), "1");
+        assert!(!nvme.join("remove").exists(), "The internal device shouldn't be removed");
+    }
+
+    #[test]
+    fn test_internal_device_next_to_integrated_controller_is_internal() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        create_mock_tbt_device(root, "pci0000:00/0000:00:0d.2/domain0", "1");
+        create_mock_pci_device_in_topology(root, "pci0000:00/0000:00:1d.0", "0x060400", false);
+        // An NVMe drive in a hotplug slot of the root bus of the controller.
+        let nvme = create_mock_pci_device_in_topology(
+            root,
+            "pci0000:00/0000:00:1d.0/0000:05:00.0",
+            "0x010802",
+            true,
+        );
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+        let bus_path = |name: &str| root.join("sys/bus/pci/devices").join(name);
+
+        assert!(!sysfs_utils.is_externally_connected(&bus_path("0000:05:00.0")).unwrap());
+
+        sysfs_utils.deauthorize_all_devices().unwrap();
+
+        assert!(!nvme.join("remove").exists(), "The internal device shouldn't be removed");
+    }
+
+    #[test]
+    fn test_deauthorize_all_devices_removes_pci_devices_by_default() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let pci_dev = create_mock_pci_device(root, "0000:05:00.0", "0x088000", Some("1"));
+
+        SysfsUtils::with_root_path(root.to_path_buf()).deauthorize_all_devices().unwrap();
+
+        assert_eq!(fs::read_to_string(pci_dev.join("remove")).unwrap(), "1");
+        assert_eq!(read_authorized(&pci_dev), "1");
+    }
+
+    #[test]
+    fn test_deauthorize_all_devices_deauthorizes_pci_devices_in_deauthorize_mode() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let pci_dev = create_mock_pci_device(root, "0000:05:00.0", "0x088000", Some("1"));
+        // Without the "authorized" attribute, the device is removed instead.
+        let legacy_pci_dev = create_mock_pci_device(root, "0000:06:00.0", "0x088000", None);
+
+        SysfsUtils::with_root_path(root.to_path_buf())
+            .with_pci_denial_mode(PciDenialMode::Deauthorize)
+            .deauthorize_all_devices()
+            .unwrap();
+
+        assert_eq!(read_authorized(&pci_dev), "0");
+        assert!(!pci_dev.join("remove").exists());
+        assert_eq!(fs::read_to_string(legacy_pci_dev.join("remove")).unwrap(), "1");
+    }
+
+    #[test]
+    fn test_deauthorize_all_devices_cancelled_before_start_leaves_devices() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let host = create_mock_tbt_device(root, "domain0/0-0", "1");
+
+        let outcome = SysfsUtils::with_root_path(root.to_path_buf())
+            .deauthorize_all_devices_cancelable(
+                &HashSet::from(Subsystem::ALL),
+                &AtomicBool::new(true),
+            )
+            .unwrap();
+
+        assert_eq!(outcome, SweepOutcome::Cancelled);
+        assert_eq!(read_authorized(&host), "1");
+    }
+
+    #[test]
+    fn test_iommu_dma_protection_enabled() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+        let domains = root.join("sys/bus/thunderbolt/devices");
+        for (domain, protection) in
+            [("domain0", Some("1\n")), ("domain1", Some("0\n")), ("domain2", None)]
+        {
+            fs::create_dir_all(domains.join(domain)).unwrap();
+            if let Some(protection) = protection {
+                fs::write(domains.join(domain).join("iommu_dma_protection"), protection).unwrap();
+            }
+        }
+
+        assert_eq!(sysfs_utils.iommu_dma_protection_enabled("domain0").unwrap(), Some(true));
+        assert_eq!(sysfs_utils.iommu_dma_protection_enabled("domain1").unwrap(), Some(false));
+        assert_eq!(sysfs_utils.iommu_dma_protection_enabled("domain2").unwrap(), None);
+        fs::write(domains.join("domain2/iommu_dma_protection"), "yes").unwrap();
+        assert!(sysfs_utils.iommu_dma_protection_enabled("domain2").is_err());
+    }
+
+    #[test]
+    fn test_is_dma_protection_enabled_requires_all_domains() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+        let domains = root.join("sys/bus/thunderbolt/devices");
+        // Without any domain, there is nothing to protect.
+        assert_eq!(sysfs_utils.is_dma_protection_enabled().unwrap(), Some(true));
+
+        fs::create_dir_all(domains.join("domain0")).unwrap();
+        fs::write(domains.join("domain0/iommu_dma_protection"), "1").unwrap();
+        create_mock_tbt_device(root, "domain0/0-0", "0");
+        assert_eq!(sysfs_utils.is_dma_protection_enabled().unwrap(), Some(true));
+
+        // A domain not reporting its protection makes it unknown.
+        fs::create_dir_all(domains.join("domain1")).unwrap();
+        assert_eq!(sysfs_utils.is_dma_protection_enabled().unwrap(), None);
+
+        // An unprotected domain wins over an unknown one.
+        fs::create_dir_all(domains.join("domain2")).unwrap();
+        fs::write(domains.join("domain2/iommu_dma_protection"), "0").unwrap();
+        assert_eq!(sysfs_utils.is_dma_protection_enabled().unwrap(), Some(false));
+    }
+
+    #[test]
+    fn test_auth_payload_formatter_is_used() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let host = create_mock_tbt_device(root, "domain0/0-0", "0");
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf())
+            .with_auth_payload_formatter(Arc::new(|level: AuthLevel| {
+                let mut payload = level.default_payload();
+                payload.push(b'\n');
+                payload
+            }));
+
+        sysfs_utils.authorize_thunderbolt_dev(&host).unwrap();
+        assert_eq!(fs::read_to_string(host.join("authorized")).unwrap(), "1\n");
+
+        sysfs_utils.deauthorize_thunderbolt_dev(&host).unwrap();
+        assert_eq!(fs::read_to_string(host.join("authorized")).unwrap(), "0\n");
+    }
+
+    #[test]
+    fn test_tunneling_supported() {
+        let temp_dir = TempDir::new().expect("Failed to create temp_dir");
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("sys/bus/pci/devices")).unwrap();
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+        // Without the thunderbolt bus, e.g. on a kernel without CONFIG_USB4.
+        assert!(!sysfs_utils.tunneling_supported().unwrap());
+
+        let domains = root.join("sys/bus/thunderbolt/devices");
+        fs::create_dir_all(&domains).unwrap();
+        assert!(sysfs_utils.tunneling_supported().unwrap());
+
+        fs::create_dir_all(domains.join("domain0")).unwrap();
+        fs::write(domains.join("domain0/security"), "dponly\n").unwrap();
+        assert!(!sysfs_utils.tunneling_supported().unwrap());
+
+        fs::create_dir_all(domains.join("domain1")).unwrap();
+        fs::write(domains.join("domain1/security"), "user\n").unwrap();
+        assert!(sysfs_utils.tunneling_supported().unwrap());
+    }
+
+    #[test]
+    fn test_list_domains() {
+        let temp_dir = TempDir::new().expect("Failed to create temp_dir");
+        let root = temp_dir.path();
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+        // Without the thunderbolt bus.
+        assert!(sysfs_utils.list_domains().unwrap().is_empty());
+
+        let devices = root.join("sys/bus/thunderbolt/devices");
+        fs::create_dir_all(&devices).unwrap();
+        assert!(sysfs_utils.list_domains().unwrap().is_empty());
+
+        for entry in ["domain10", "domain1", "0-0", "0-1", "0-0:1.1", "1-0:2.1"] {
+            fs::create_dir_all(devices.join(entry)).unwrap();
+        }
+        assert_eq!(sysfs_utils.list_domains().unwrap(), ["domain1", "domain10"]);
+    }
+
+    #[test]
+    fn test_authorize_all_devices_only_writes_new_device_of_authorized_tree() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let host = create_mock_tbt_device(root, "domain0/0-0", "1\n");
+        let hub = create_mock_tbt_device(root, "domain0/0-0/0-1", "1\n");
+        let dock = create_mock_tbt_device(root, "domain0/0-0/0-3", "1\n");
+        let new_device = create_mock_tbt_device(root, "domain0/0-0/0-1/0-301", "0\n");
+        let writes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded_writes = writes.clone();
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf())
+            .with_auth_payload_formatter(Arc::new(move |level: AuthLevel| {
+                recorded_writes.lock().unwrap().push(level);
+                level.default_payload()
+            }));
+
+        sysfs_utils.authorize_all_devices().unwrap();
+
+        assert_eq!(*writes.lock().unwrap(), [AuthLevel::Authorized]);
+        assert_eq!(read_authorized(&new_device), "1");
+        for dev_path in [host, hub, dock] {
+            assert_eq!(fs::read_to_string(dev_path.join("authorized")).unwrap(), "1\n");
+        }
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let host = create_mock_tbt_device(root, "domain0/0-0", "1\n");
+        let dock = create_mock_tbt_device(root, "domain0/0-0/0-1", "0\n");
+        fs::write(dock.join("unique_id"), "dock-uuid\n").unwrap();
+        let pci_device = create_mock_pci_device(root, "0000:05:00.0", "0x060400", Some("0"));
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        assert!(sysfs_utils.is_authorized(&host).unwrap());
+        assert!(!sysfs_utils.is_authorized(&dock).unwrap());
+        assert_eq!(sysfs_utils.is_authorized_by_unique_id("dock-uuid").unwrap(), Some(false));
+
+        sysfs_utils.authorize_thunderbolt_dev(&dock).unwrap();
+        assert!(sysfs_utils.is_authorized(&dock).unwrap());
+        assert_eq!(sysfs_utils.is_authorized_by_unique_id("dock-uuid").unwrap(), Some(true));
+        assert_eq!(sysfs_utils.is_authorized_by_unique_id("unknown-uuid").unwrap(), None);
+
+        let error_kind = |devpath: &Path| {
+            sysfs_utils.is_authorized(devpath).unwrap_err().downcast::<io::Error>().unwrap().kind()
+        };
+        assert_eq!(error_kind(&pci_device), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(&dock).unwrap();
+        assert_eq!(error_kind(&dock), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_device_ready() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let waiting_dock = create_mock_tbt_device(root, "domain0/0-0/0-1", "0");
+        fs::write(waiting_dock.join("waiting_for_power"), "1\n").unwrap();
+        let powered_dock = create_mock_tbt_device(root, "domain0/0-0/0-3", "0");
+        fs::write(powered_dock.join("waiting_for_power"), "0\n").unwrap();
+        let legacy_dock = create_mock_tbt_device(root, "domain1/1-0/1-1", "0");
+        let invalid_dock = create_mock_tbt_device(root, "domain2/2-0/2-1", "0");
+        fs::write(invalid_dock.join("waiting_for_power"), "soon\n").unwrap();
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        assert!(!sysfs_utils.device_ready(&waiting_dock).unwrap());
+        assert!(sysfs_utils.device_ready(&powered_dock).unwrap());
+        // Devices without the attribute are ready.
+        assert!(sysfs_utils.device_ready(&legacy_dock).unwrap());
+        assert!(sysfs_utils.device_ready(&invalid_dock).is_err());
+    }
+
+    #[test]
+    fn test_sweeps_skip_retimers() {
+        let temp_dir = setup_sysfs_root();
+        let root = temp_dir.path();
+        let dock = create_mock_tbt_device(root, "domain0/0-0/0-1", "0");
+        // Retimers have no "authorized" attribute. The one written here would reveal an attempt
+        // to authorize the retimer.
+        let retimer = create_mock_tbt_device(root, "domain0/0-0/0-0:1.1", "0");
+        let sysfs_utils = SysfsUtils::with_root_path(root.to_path_buf());
+
+        assert_eq!(ThunderboltEntryType::from_name("0-0:1.1"), ThunderboltEntryType::Retimer);
+        assert_eq!(ThunderboltEntryType::from_name("0-301"), ThunderboltEntryType::Device);
+        assert_eq!(ThunderboltEntryType::from_name("domain0"), ThunderboltEntryType::Domain);
+
+        sysfs_utils.authorize_all_devices().unwrap();
+        assert_eq!(fs::read_to_string(dock.join("authorized")).unwrap(), "1");
+        assert_eq!(fs::read_to_string(retimer.join("authorized")).unwrap(), "0");
+
+        fs::write(retimer.join("authorized"), "1").unwrap();
+        sysfs_utils.deauthorize_all_devices().unwrap();
+        assert_eq!(fs::read_to_string(dock.join("authorized")).unwrap(), "0");
+        assert_eq!(fs::read_to_string(retimer.join("authorized")).unwrap(), "1");
+
+        let devices = sysfs_utils.list_thunderbolt_devices().unwrap();
+        assert_eq!(devices.iter().map(|device| device.name.as_str()).collect::<Vec<_>>(), ["0-1"]);
+    }
+}
@@ -0,0 +1,249 @@
+/*
+ * Copyright (C) 2025 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package com.android.server.usb;
+
+import android.annotation.NonNull;
+import android.annotation.Nullable;
+import android.util.Log;
+import android.util.Slog;
+
+/**
+ * Authorizes the USB4 and thunderbolt devices, and the PCI devices they tunnel, through the native
+ * policy engine, according to the lock state of the screen and the logged-in users.
+ *
+ * {@hide}
+ */
+public final class Usb4Manager {
+    private static final String TAG = "Usb4Manager";
+
+    /**
+     * States of the authorization of the PCI tunnels, as passed to the callbacks. They must match
+     * the values of policy_jni.rs.
+     */
+    public static final int AUTH_STATE_DISABLED = 0;
+    public static final int AUTH_STATE_DENY_NO_USER = 1;
+    public static final int AUTH_STATE_DEFER_NEW_DEVICES = 2;
+    public static final int AUTH_STATE_AUTHORIZED = 3;
+
+    // Indices of the fields of the array returned by getHealth. They must match the values of
+    // policy_jni.rs. Booleans are 0 or 1, and unknown values are -1.
+
+    /** Whether the policy task is running. */
+    public static final int HEALTH_TASK_ALIVE = 0;
+    /** Whether a bulk sysfs operation of the policy task failed badly. */
+    public static final int HEALTH_DEGRADED = 1;
+    /** The AUTH_STATE_* state last applied. */
+    public static final int HEALTH_AUTH_STATE = 2;
+    /** Milliseconds since the last uevent, which only tells how recent the device activity is. */
+    public static final int HEALTH_LAST_UEVENT_AGE_MS = 3;
+    /** Number of failed uevent reads since the last successful one. */
+    public static final int HEALTH_UEVENT_ERRORS = 4;
+    /** Whether the platform supports PCI tunneling. */
+    public static final int HEALTH_TUNNELING_SUPPORTED = 5;
+    /**
+     * Milliseconds since the last heartbeat of the policy task, which beats at least every
+     * {@link #HEARTBEAT_INTERVAL_MS} even when idle. A much older heartbeat means the task is
+     * stuck.
+     */
+    public static final int HEALTH_LAST_HEARTBEAT_AGE_MS = 6;
+
+    /**
+     * Flags of the subsystems passed to {@link #setEnabledSubsystems}. They must match the values
+     * of policy_jni.rs.
+     */
+    public static final int SUBSYSTEM_FLAG_THUNDERBOLT = 1 << 0;
+    public static final int SUBSYSTEM_FLAG_PCI = 1 << 1;
+
+    /** Longest interval between two heartbeats of an idle policy task, as in pci_authorizer.rs. */
+    public static final long HEARTBEAT_INTERVAL_MS = 10_000;
+
+    static {
+        System.loadLibrary("usb4_jni");
+    }
+
+    /** Receives the events of the policy engine, on a thread of the policy engine. */
+    public interface Callback {
+        /**
+         * Called when a thunderbolt device is plugged in but not authorized by the policy, e.g. to
+         * tell the user to unlock the screen.
+         *
+         * @param uniqueId the unique id of the device, or null if it doesn't expose one.
+         * @param authState the AUTH_STATE_* state which denied the device.
+         */
+        void onDeviceDenied(@Nullable String uniqueId, int authState);
+
+        /**
+         * Called when the authorization state of the PCI tunnels changes.
+         *
+         * @param fromState the AUTH_STATE_* state before the change.
+         * @param toState the AUTH_STATE_* state after the change.
+         */
+        void onPolicyStateChanged(int fromState, int toState);
+    }
+
+    /** A thunderbolt device connected to the system. */
+    public static final class ThunderboltDevice {
+        /** The kernel name of the device, e.g. "0-1". */
+        @NonNull public final String name;
+        public final boolean authorized;
+        @Nullable public final String uniqueId;
+        @Nullable public final String vendorName;
+        @Nullable public final String deviceName;
+
+        // Called from native code.
+        ThunderboltDevice(@NonNull String name, boolean authorized, @Nullable String uniqueId,
+                @Nullable String vendorName, @Nullable String deviceName) {
+            this.name = name;
+            this.authorized = authorized;
+            this.uniqueId = uniqueId;
+            this.vendorName = vendorName;
+            this.deviceName = deviceName;
+        }
+
+        @Override
+        public String toString() {
+            return "ThunderboltDevice{name=" + name + ", authorized=" + authorized
+                    + ", uniqueId=" + uniqueId + ", vendorName=" + vendorName
+                    + ", deviceName=" + deviceName + "}";
+        }
+    }
+
+    @Nullable private final Callback mCallback;
+
+    /**
+     * Initializes the policy engine, which delivers its events to {@code callback}.
+     *
+     * @throws IllegalStateException if the policy engine can't be created.
+     */
+    public Usb4Manager(@Nullable Callback callback) {
+        mCallback = callback;
+        nativeInit(Log.isLoggable(TAG, Log.VERBOSE) ? Log.VERBOSE : Log.INFO);
+    }
+
+    // Called from native code.
+    private void onDeviceDenied(@Nullable String uniqueId, int authState) {
+        Slog.i(TAG, "Denied the device " + uniqueId + " in the state " + authState);
+        if (mCallback != null) {
+            mCallback.onDeviceDenied(uniqueId, authState);
+        }
+    }
+
+    // Called from native code.
+    private void onPolicyStateChanged(int fromState, int toState) {
+        Slog.i(TAG, "Policy state changed from " + fromState + " to " + toState);
+        if (mCallback != null) {
+            mCallback.onPolicyStateChanged(fromState, toState);
+        }
+    }
+
+    /**
+     * Stops delivering the events of the policy engine to this manager. The policy engine keeps
+     * enforcing the policy, and a new manager receives the events from then on.
+     */
+    public void destroy() {
+        nativeDestroy();
+    }
+
+    private native void nativeInit(int logLevel);
+
+    private native void nativeDestroy();
+
+    /** Enables or disables the PCI tunnels. */
+    public native void enablePciTunnels(boolean enable);
+
+    /** Updates the lock state of the screen. */
+    public native void updateLockState(boolean locked);
+
+    /** Updates whether the user {@code userId} is logged in. */
+    public native void updateLoggedInState(boolean loggedIn, int userId);
+
+    /** Replaces the set of logged-in users. */
+    public native void setLoggedInUsers(@NonNull int[] userIds);
+
+    /**
+     * Pauses or resumes the enforcement of the policy, e.g. during an OTA or a factory test. While
+     * paused, the policy keeps track of its inputs without authorizing or deauthorizing any
+     * device. On resume, the devices are brought to the state of the current policy at once.
+     */
+    public native void setEnforcementPaused(boolean paused);
+
+    /**
+     * Sets the subsystems whose devices the policy authorizes, as a combination of the
+     * SUBSYSTEM_FLAG_* flags. The devices of the other subsystems are left alone. All the
+     * subsystems are enabled by default.
+     */
+    public native void setEnabledSubsystems(int flags);
+
+    /**
+     * Notifies the policy engine that the device resumed from suspend. The devices plugged in or
+     * unplugged during suspend may have been missed, so every device is checked against the
+     * current policy.
+     */
+    public native void notifyResumed();
+
+    /**
+     * Removes the PCI device at the address {@code bdf}, e.g. "0000:05:00.0", and the devices
+     * behind it, e.g. when it is found to be malicious. Only the devices connected through a
+     * thunderbolt tunnel can be removed.
+     *
+     * @return false if the device wasn't removed.
+     */
+    public native boolean removePciDevice(@NonNull String bdf);
+
+    /**
+     * Stops the policy engine before an orderly reboot, once all the devices are deauthorized if
+     * {@code deauthorize} is set, so that they power down cleanly. Once it succeeds, the next
+     * calls have no effect.
+     *
+     * @return false on timeout or failure, e.g. if a device couldn't be deauthorized.
+     */
+    public native boolean prepareForShutdown(boolean deauthorize);
+
+    /** Returns the ids of the users the policy considers logged in, sorted, or null on failure. */
+    @Nullable
+    public native int[] getLoggedInUsers();
+
+    /**
+     * Checks that the policy task is running, and restarts it if it died.
+     *
+     * @return false if the task had to be restarted.
+     */
+    public native boolean ensurePolicyTaskAlive();
+
+    /**
+     * Blocks until the policy updates made so far are applied, for at most {@code timeoutMs}
+     * milliseconds.
+     *
+     * @return false on timeout or failure.
+     */
+    public native boolean flushPendingPolicy(long timeoutMs);
+
+    /**
+     * Returns the health of the policy engine, indexed by the HEALTH_* constants, or null on
+     * failure. Doesn't wait for the policy task, so that a stuck task can be detected.
+     */
+    @Nullable
+    public native long[] getHealth();
+
+    /** Returns a human-readable report of the state of the policy engine, or null on failure. */
+    @Nullable
+    public native String dump();
+
+    /** Lists the connected thunderbolt devices, or returns null on failure. */
+    @Nullable
+    public native ThunderboltDevice[] listThunderboltDevices();
+}